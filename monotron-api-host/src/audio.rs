@@ -0,0 +1,253 @@
+//! The synthesiser. Notes played can be written to a log file, one line per
+//! note, and the sound itself can be written to a WAV file.
+//!
+//! Like the ROM's synthesiser there are three channels, each playing one
+//! waveform at one frequency and volume until told otherwise. Samples are
+//! generated to keep up with real time, at the ROM's sample rate.
+
+use monotron_shell::Waveform;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::Duration;
+
+/// Samples per second. The ROM makes a sample every 2112 CPU clocks.
+pub const SAMPLE_RATE: u32 = 80_000_000 / 2112;
+
+/// How many channels there are.
+const NUM_CHANNELS: usize = 3;
+
+/// One channel of the synthesiser.
+#[derive(Debug, Copy, Clone)]
+struct Voice {
+    /// How far through the waveform we are, where a whole cycle is 2**32
+    phase: u32,
+    /// How far `phase` moves each sample
+    step: u32,
+    volume: u8,
+    waveform: Waveform,
+}
+
+/// Makes samples.
+#[derive(Debug)]
+pub struct Synth {
+    voices: [Voice; NUM_CHANNELS],
+    /// State for the noise generator
+    noise: u32,
+}
+
+/// Plays notes, and writes down what was played.
+pub struct Recorder {
+    synth: Synth,
+    log: Option<BufWriter<File>>,
+    wav: Option<hound::WavWriter<BufWriter<File>>>,
+    /// How many samples have been made so far
+    samples: u64,
+}
+
+impl Synth {
+    /// A silent synthesiser.
+    pub fn new() -> Synth {
+        Synth {
+            voices: [Voice {
+                phase: 0,
+                step: 0,
+                volume: 0,
+                waveform: Waveform::Square,
+            }; NUM_CHANNELS],
+            noise: 1,
+        }
+    }
+
+    /// Start playing a note on a channel (0, 1 or 2), replacing whatever it
+    /// was playing. A volume of zero stops the channel.
+    pub fn play(&mut self, channel: u8, centi_hertz: u32, volume: u8, waveform: Waveform) {
+        if let Some(voice) = self.voices.get_mut(usize::from(channel)) {
+            voice.step = ((u64::from(centi_hertz) << 32) / (100 * u64::from(SAMPLE_RATE))) as u32;
+            voice.volume = volume;
+            voice.waveform = waveform;
+        }
+    }
+
+    /// Make the next sample.
+    pub fn next_sample(&mut self) -> i16 {
+        let mut total: i32 = 0;
+        for voice in self.voices.iter_mut() {
+            if voice.volume == 0 {
+                continue;
+            }
+            let level: i32 = match voice.waveform {
+                Waveform::Square => {
+                    if voice.phase < 0x8000_0000 {
+                        32767
+                    } else {
+                        -32767
+                    }
+                }
+                Waveform::Sine => {
+                    let angle =
+                        f64::from(voice.phase) / 4_294_967_296.0 * 2.0 * std::f64::consts::PI;
+                    (angle.sin() * 32767.0) as i32
+                }
+                Waveform::Sawtooth => (voice.phase >> 16) as i32 - 32768,
+                Waveform::Noise => {
+                    // A new random level each time the phase wraps
+                    if voice.phase.checked_add(voice.step).is_none() {
+                        self.noise ^= self.noise << 13;
+                        self.noise ^= self.noise >> 17;
+                        self.noise ^= self.noise << 5;
+                    }
+                    (self.noise >> 16) as i32 - 32768
+                }
+            };
+            voice.phase = voice.phase.wrapping_add(voice.step);
+            total += level * i32::from(voice.volume) / 255;
+        }
+        (total / NUM_CHANNELS as i32) as i16
+    }
+}
+
+impl Default for Synth {
+    fn default() -> Synth {
+        Synth::new()
+    }
+}
+
+impl Recorder {
+    /// Log notes to `log` and write the sound to `wav`, if given.
+    pub fn new(log: Option<&Path>, wav: Option<&Path>) -> io::Result<Recorder> {
+        let log = match log {
+            Some(path) => Some(BufWriter::new(File::create(path)?)),
+            None => None,
+        };
+        let wav = match wav {
+            Some(path) => {
+                let spec = hound::WavSpec {
+                    channels: 1,
+                    sample_rate: SAMPLE_RATE,
+                    bits_per_sample: 16,
+                    sample_format: hound::SampleFormat::Int,
+                };
+                Some(hound::WavWriter::create(path, spec).map_err(wav_error)?)
+            }
+            None => None,
+        };
+        Ok(Recorder {
+            synth: Synth::new(),
+            log,
+            wav,
+            samples: 0,
+        })
+    }
+
+    /// Play a note, `elapsed` after we started.
+    pub fn play(
+        &mut self,
+        elapsed: Duration,
+        channel: u8,
+        centi_hertz: u32,
+        volume: u8,
+        waveform: Waveform,
+    ) {
+        self.catch_up(elapsed);
+        if let Some(log) = self.log.as_mut() {
+            let _ = writeln!(
+                log,
+                "{:10.3} s: channel {} {}.{:02} Hz volume {} {:?}",
+                elapsed.as_secs_f64(),
+                channel,
+                centi_hertz / 100,
+                centi_hertz % 100,
+                volume,
+                waveform
+            );
+        }
+        self.synth.play(channel, centi_hertz, volume, waveform);
+    }
+
+    /// Make the samples up until `elapsed` after we started.
+    pub fn catch_up(&mut self, elapsed: Duration) {
+        let wav = match self.wav.as_mut() {
+            Some(wav) => wav,
+            None => return,
+        };
+        let due = elapsed.as_micros() as u64 * u64::from(SAMPLE_RATE) / 1_000_000;
+        while self.samples < due {
+            if wav.write_sample(self.synth.next_sample()).is_err() {
+                // Probably out of disk space, so give up recording
+                self.wav = None;
+                return;
+            }
+            self.samples += 1;
+        }
+    }
+
+    /// Make the samples up until `elapsed` after we started, and finish off
+    /// the files.
+    pub fn finish(&mut self, elapsed: Duration) -> io::Result<()> {
+        self.catch_up(elapsed);
+        if let Some(mut log) = self.log.take() {
+            log.flush()?;
+        }
+        if let Some(wav) = self.wav.take() {
+            wav.finalize().map_err(wav_error)?;
+        }
+        Ok(())
+    }
+}
+
+/// Convert an error from `hound` into an `io::Error`.
+fn wav_error(e: hound::Error) -> io::Error {
+    match e {
+        hound::Error::IoError(e) => e,
+        e => io::Error::new(io::ErrorKind::Other, e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn square_wave() {
+        let mut synth = Synth::new();
+        assert!((0..100).all(|_| synth.next_sample() == 0));
+        // 1 kHz is about 38 samples per cycle
+        synth.play(1, 100_000, 255, Waveform::Square);
+        let samples: Vec<i16> = (0..38).map(|_| synth.next_sample()).collect();
+        assert!(samples[0..18].iter().all(|&s| s == 10922));
+        assert!(samples[20..37].iter().all(|&s| s == -10922));
+        synth.play(1, 100_000, 0, Waveform::Square);
+        assert_eq!(synth.next_sample(), 0);
+    }
+
+    #[test]
+    fn write_wav() {
+        let dir = std::env::temp_dir();
+        let wav_path = dir.join(format!("monotron-audio-{}.wav", std::process::id()));
+        let log_path = dir.join(format!("monotron-audio-{}.txt", std::process::id()));
+        let mut recorder = Recorder::new(Some(&log_path), Some(&wav_path)).unwrap();
+        recorder.play(
+            Duration::from_millis(500),
+            0,
+            44_000,
+            128,
+            Waveform::Sawtooth,
+        );
+        recorder.finish(Duration::from_secs(1)).unwrap();
+        let reader = hound::WavReader::open(&wav_path).unwrap();
+        assert_eq!(reader.spec().sample_rate, SAMPLE_RATE);
+        assert_eq!(reader.len(), SAMPLE_RATE);
+        let samples: Vec<i16> = reader.into_samples().map(|s| s.unwrap()).collect();
+        let half = SAMPLE_RATE as usize / 2;
+        assert!(samples[0..half].iter().all(|&s| s == 0));
+        assert!(samples[half..].iter().any(|&s| s != 0));
+        let log = std::fs::read_to_string(&log_path).unwrap();
+        assert_eq!(
+            log,
+            "     0.500 s: channel 0 440.00 Hz volume 128 Sawtooth\n"
+        );
+        let _ = std::fs::remove_file(wav_path);
+        let _ = std::fs::remove_file(log_path);
+    }
+}
@@ -0,0 +1,487 @@
+//! Files, from a directory on the host which stands in for the SD card.
+//!
+//! Names are matched without caring about case, like they are on a FAT
+//! volume, and can include sub-directories (`GAMES/PONG.BIN`). They can't
+//! go above the top of the directory. Directory listings only include names
+//! which fit in 8.3 format, because that's all a `DirEntry` can hold.
+
+use monotron_api::{
+    DirEntry, EmptyResult, Error, FileMode, FileType, Handle, HandleResult, Offset, OpenMode,
+    SizeResult,
+};
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// How many files and directories can be open at once.
+const MAX_OPEN: usize = 8;
+
+/// The first handle we give out. The ones below are `STDOUT`, `STDERR` and
+/// `STDIN`.
+const FIRST_HANDLE: u16 = 3;
+
+/// Characters a FAT short name can have, besides letters and digits.
+const SHORT_NAME_PUNCTUATION: &str = "!#$%&'()-@^_`{}~";
+
+/// Something opened with `open` or `opendir`.
+enum Object {
+    File(File),
+    /// What was in the directory when it was opened, and what `readdir`
+    /// gives out next
+    Dir(std::vec::IntoIter<DirEntry>),
+}
+
+/// The open files and directories.
+pub struct Files {
+    root: PathBuf,
+    open: Vec<Option<Object>>,
+}
+
+/// Convert an error from the host into something the Api can report.
+fn api_error(e: io::Error) -> Error {
+    match e.kind() {
+        io::ErrorKind::NotFound => Error::FileNotFound,
+        _ => Error::IOError,
+    }
+}
+
+/// Convert a host file name into a FAT short name (eight characters of
+/// name and three of extension, padded with spaces), if it fits.
+fn short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, ext) = match name.rfind('.') {
+        Some(idx) => (&name[..idx], &name[idx + 1..]),
+        None => (name, ""),
+    };
+    let valid = |s: &str| {
+        s.chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || SHORT_NAME_PUNCTUATION.contains(ch))
+    };
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 || !valid(base) || !valid(ext) {
+        return None;
+    }
+    let mut result = [b' '; 11];
+    result[0..base.len()].copy_from_slice(base.to_ascii_uppercase().as_bytes());
+    result[8..8 + ext.len()].copy_from_slice(ext.to_ascii_uppercase().as_bytes());
+    Some(result)
+}
+
+/// Describe a file for `readdir` or `stat`.
+fn dir_entry(name: [u8; 11], metadata: &Metadata) -> DirEntry {
+    let mtime = metadata
+        .modified()
+        .map(crate::time::timestamp)
+        .unwrap_or_else(|_| crate::time::now());
+    let ctime = metadata
+        .created()
+        .map(crate::time::timestamp)
+        .unwrap_or_else(|_| mtime.clone());
+    DirEntry {
+        file_type: if metadata.is_dir() {
+            FileType::Directory
+        } else {
+            FileType::File
+        },
+        name,
+        size: metadata.len().min(u64::from(u32::MAX)) as u32,
+        mtime,
+        ctime,
+        mode: FileMode::new(metadata.permissions().readonly(), false, false, false),
+    }
+}
+
+impl Files {
+    /// Use the directory `root` as the SD card.
+    pub fn new(root: &Path) -> Files {
+        let mut open = Vec::new();
+        open.resize_with(MAX_OPEN, || None);
+        Files {
+            root: root.to_owned(),
+            open,
+        }
+    }
+
+    /// Open a file. The `non_blocking` flags make no difference, because
+    /// files never block.
+    pub fn open(&mut self, name: &str, mode: &OpenMode) -> HandleResult {
+        let mut options = OpenOptions::new();
+        let create = match *mode {
+            OpenMode::ReadOnly { .. } => {
+                options.read(true);
+                false
+            }
+            OpenMode::WriteOnly {
+                append,
+                create,
+                exclusive,
+                truncate,
+                ..
+            }
+            | OpenMode::ReadWrite {
+                append,
+                create,
+                exclusive,
+                truncate,
+                ..
+            } => {
+                let read = matches!(*mode, OpenMode::ReadWrite { .. });
+                options
+                    .read(read)
+                    .write(true)
+                    .append(append)
+                    .truncate(truncate)
+                    .create(create && !exclusive)
+                    .create_new(create && exclusive);
+                create
+            }
+        };
+        let result = self
+            .resolve(name, create)
+            .and_then(|path| options.open(path).map_err(api_error))
+            .and_then(|file| match file.metadata() {
+                Ok(m) if m.is_dir() => Err(Error::NotSupported),
+                Ok(_) => Ok(file),
+                Err(e) => Err(api_error(e)),
+            });
+        match result {
+            Ok(file) => self.add(Object::File(file)),
+            Err(e) => HandleResult::Error(e),
+        }
+    }
+
+    /// Close a file or directory.
+    pub fn close(&mut self, handle: Handle) -> EmptyResult {
+        match self.slot(handle).and_then(|slot| slot.take()) {
+            Some(_) => EmptyResult::Ok,
+            None => EmptyResult::Error(Error::BadFileHandle),
+        }
+    }
+
+    /// Read from a file.
+    pub fn read(&mut self, handle: Handle, buffer: &mut [u8]) -> SizeResult {
+        match self.slot(handle).and_then(|slot| slot.as_mut()) {
+            Some(Object::File(file)) => match file.read(buffer) {
+                Ok(count) => SizeResult::Ok(count),
+                Err(e) => SizeResult::Error(api_error(e)),
+            },
+            Some(Object::Dir(_)) => SizeResult::Error(Error::NotSupported),
+            None => SizeResult::Error(Error::BadFileHandle),
+        }
+    }
+
+    /// Write to a file.
+    pub fn write(&mut self, handle: Handle, buffer: &[u8]) -> SizeResult {
+        match self.slot(handle).and_then(|slot| slot.as_mut()) {
+            Some(Object::File(file)) => match file.write(buffer) {
+                Ok(count) => SizeResult::Ok(count),
+                Err(e) => SizeResult::Error(api_error(e)),
+            },
+            Some(Object::Dir(_)) => SizeResult::Error(Error::NotSupported),
+            None => SizeResult::Error(Error::BadFileHandle),
+        }
+    }
+
+    /// Move the read/write pointer in a file.
+    pub fn seek(&mut self, handle: Handle, offset: &Offset) -> EmptyResult {
+        let pos = match *offset {
+            Offset::FromStart(n) => SeekFrom::Start(u64::from(n)),
+            Offset::FromCurrent(n) => SeekFrom::Current(i64::from(n)),
+            Offset::FromEnd(n) => SeekFrom::End(-i64::from(n)),
+        };
+        match self.slot(handle).and_then(|slot| slot.as_mut()) {
+            Some(Object::File(file)) => match file.seek(pos) {
+                Ok(_) => EmptyResult::Ok,
+                Err(e) => EmptyResult::Error(api_error(e)),
+            },
+            Some(Object::Dir(_)) => EmptyResult::Error(Error::NotSupported),
+            None => EmptyResult::Error(Error::BadFileHandle),
+        }
+    }
+
+    /// Open a directory. `""` and `"/"` are the top.
+    pub fn opendir(&mut self, name: &str) -> HandleResult {
+        let result = self.resolve(name, false).and_then(|path| {
+            let mut entries = Vec::new();
+            for item in fs::read_dir(path).map_err(api_error)? {
+                let item = item.map_err(api_error)?;
+                let name = item.file_name();
+                let short = match name.to_str().and_then(short_name) {
+                    Some(short) => short,
+                    None => continue,
+                };
+                let metadata = item.metadata().map_err(api_error)?;
+                entries.push(dir_entry(short, &metadata));
+            }
+            // The host gives them to us in any order it likes
+            entries.sort_by_key(|e| e.name);
+            Ok(entries)
+        });
+        match result {
+            Ok(entries) => self.add(Object::Dir(entries.into_iter())),
+            Err(e) => HandleResult::Error(e),
+        }
+    }
+
+    /// Get the next entry in a directory. Reports `FileNotFound` when there
+    /// aren't any more.
+    pub fn readdir(&mut self, handle: Handle, entry: &mut DirEntry) -> EmptyResult {
+        match self.slot(handle).and_then(|slot| slot.as_mut()) {
+            Some(Object::Dir(entries)) => match entries.next() {
+                Some(next) => {
+                    *entry = next;
+                    EmptyResult::Ok
+                }
+                None => EmptyResult::Error(Error::FileNotFound),
+            },
+            Some(Object::File(_)) => EmptyResult::Error(Error::NotSupported),
+            None => EmptyResult::Error(Error::BadFileHandle),
+        }
+    }
+
+    /// Describe a file (or directory).
+    pub fn stat(&mut self, name: &str, entry: &mut DirEntry) -> EmptyResult {
+        let result = self.resolve(name, false).and_then(|path| {
+            let short = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .and_then(short_name)
+                .ok_or(Error::FileNotFound)?;
+            let metadata = fs::metadata(&path).map_err(api_error)?;
+            Ok(dir_entry(short, &metadata))
+        });
+        match result {
+            Ok(found) => {
+                *entry = found;
+                EmptyResult::Ok
+            }
+            Err(e) => EmptyResult::Error(e),
+        }
+    }
+
+    /// Find a file on the host. Each part of `name` is matched without
+    /// caring about case. If `create` is set, the last part doesn't have to
+    /// exist yet.
+    fn resolve(&self, name: &str, create: bool) -> Result<PathBuf, Error> {
+        let parts: Vec<&str> = name
+            .split(['/', '\\'])
+            .filter(|part| !part.is_empty() && *part != ".")
+            .collect();
+        let mut path = self.root.clone();
+        for (idx, part) in parts.iter().enumerate() {
+            if *part == ".." {
+                return Err(Error::FileNotFound);
+            }
+            let found = fs::read_dir(&path)
+                .map_err(api_error)?
+                .filter_map(|item| item.ok())
+                .find(|item| {
+                    item.file_name()
+                        .to_str()
+                        .map_or(false, |n| n.eq_ignore_ascii_case(part))
+                });
+            match found {
+                Some(item) => path.push(item.file_name()),
+                None if create && idx + 1 == parts.len() => path.push(part),
+                None => return Err(Error::FileNotFound),
+            }
+        }
+        Ok(path)
+    }
+
+    /// Find the slot for a handle.
+    fn slot(&mut self, handle: Handle) -> Option<&mut Option<Object>> {
+        let idx = handle.0.checked_sub(FIRST_HANDLE)?;
+        self.open.get_mut(usize::from(idx))
+    }
+
+    /// Put something in a free slot, and give out its handle.
+    fn add(&mut self, object: Object) -> HandleResult {
+        match self.open.iter().position(|slot| slot.is_none()) {
+            Some(idx) => {
+                self.open[idx] = Some(object);
+                HandleResult::Ok(Handle(FIRST_HANDLE + idx as u16))
+            }
+            // Too many open files
+            None => HandleResult::Error(Error::IOError),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use monotron_api::Timestamp;
+
+    /// Make a directory with some files in.
+    fn make_root(test: &str) -> PathBuf {
+        let root =
+            std::env::temp_dir().join(format!("monotron-files-{}-{}", std::process::id(), test));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("Games")).unwrap();
+        fs::write(root.join("HELLO.TXT"), b"Hello, world!").unwrap();
+        fs::write(root.join("notes.md"), b"").unwrap();
+        fs::write(root.join("a very long name.txt"), b"").unwrap();
+        fs::write(root.join("Games").join("PONG.BIN"), [0u8; 300]).unwrap();
+        root
+    }
+
+    fn blank_entry() -> DirEntry {
+        let t = Timestamp {
+            year_from_1970: 0,
+            month: 1,
+            days: 1,
+            hours: 0,
+            minutes: 0,
+            seconds: 0,
+        };
+        DirEntry {
+            file_type: FileType::CharDevice,
+            name: [0; 11],
+            size: 0,
+            mtime: t.clone(),
+            ctime: t,
+            mode: FileMode::new(false, false, false, false),
+        }
+    }
+
+    fn handle(result: HandleResult) -> Handle {
+        match result {
+            HandleResult::Ok(h) => h,
+            HandleResult::Error(e) => panic!("{:?}", e),
+        }
+    }
+
+    #[test]
+    fn short_names() {
+        assert_eq!(short_name("hello.txt"), Some(*b"HELLO   TXT"));
+        assert_eq!(short_name("MAKEFILE"), Some(*b"MAKEFILE   "));
+        assert_eq!(short_name("LONGERNAME.TXT"), None);
+        assert_eq!(short_name("A.TEXT"), None);
+        assert_eq!(short_name(".hidden"), None);
+        assert_eq!(short_name("two words"), None);
+    }
+
+    #[test]
+    fn read_and_seek() {
+        let root = make_root("read");
+        let mut files = Files::new(&root);
+        let mode = OpenMode::ReadOnly {
+            non_blocking: false,
+        };
+        let h = handle(files.open("/hello.txt", &mode));
+        let mut buffer = [0u8; 5];
+        assert_eq!(format!("{:?}", files.read(h, &mut buffer)), "Ok(5)");
+        assert_eq!(&buffer, b"Hello");
+        assert_eq!(format!("{:?}", files.seek(h, &Offset::FromEnd(6))), "Ok");
+        assert_eq!(format!("{:?}", files.read(h, &mut buffer)), "Ok(5)");
+        assert_eq!(&buffer, b"world");
+        assert_eq!(
+            format!("{:?}", files.write(h, b"x")),
+            "Error(IOError)",
+            "read-only files can't be written"
+        );
+        assert_eq!(format!("{:?}", files.close(h)), "Ok");
+        assert_eq!(format!("{:?}", files.close(h)), "Error(BadFileHandle)");
+        let h = handle(files.open("games/pong.bin", &mode));
+        assert_eq!(
+            format!("{:?}", files.seek(h, &Offset::FromStart(290))),
+            "Ok"
+        );
+        assert_eq!(format!("{:?}", files.read(h, &mut [0u8; 20])), "Ok(10)");
+        assert_eq!(
+            format!("{:?}", files.open("MISSING.TXT", &mode)),
+            "Error(FileNotFound)"
+        );
+        assert_eq!(
+            format!("{:?}", files.open("GAMES", &mode)),
+            "Error(NotSupported)"
+        );
+        assert_eq!(
+            format!("{:?}", files.open("GAMES/../HELLO.TXT", &mode)),
+            "Error(FileNotFound)"
+        );
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn create_and_write() {
+        let root = make_root("write");
+        let mut files = Files::new(&root);
+        let mode = OpenMode::WriteOnly {
+            append: false,
+            create: true,
+            exclusive: true,
+            truncate: false,
+            non_blocking: false,
+        };
+        let h = handle(files.open("GAMES/SCORES.DAT", &mode));
+        assert_eq!(format!("{:?}", files.write(h, b"123")), "Ok(3)");
+        assert_eq!(format!("{:?}", files.close(h)), "Ok");
+        assert_eq!(fs::read(root.join("Games/SCORES.DAT")).unwrap(), b"123");
+        assert_eq!(
+            format!("{:?}", files.open("games/scores.dat", &mode)),
+            "Error(IOError)",
+            "exclusive means it mustn't exist"
+        );
+        let mode = OpenMode::ReadWrite {
+            append: true,
+            create: false,
+            exclusive: false,
+            truncate: false,
+            non_blocking: false,
+        };
+        let h = handle(files.open("games/scores.dat", &mode));
+        assert_eq!(format!("{:?}", files.write(h, b"45")), "Ok(2)");
+        assert_eq!(format!("{:?}", files.close(h)), "Ok");
+        assert_eq!(fs::read(root.join("Games/SCORES.DAT")).unwrap(), b"12345");
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn list_and_stat() {
+        let root = make_root("list");
+        let mut files = Files::new(&root);
+        let h = handle(files.opendir("/"));
+        let mut entry = blank_entry();
+        let mut names = Vec::new();
+        while let EmptyResult::Ok = files.readdir(h, &mut entry) {
+            names.push((entry.name, entry.file_type, entry.size));
+        }
+        assert_eq!(
+            names,
+            vec![
+                (*b"GAMES      ", FileType::Directory, names[0].2),
+                (*b"HELLO   TXT", FileType::File, 13),
+                (*b"NOTES   MD ", FileType::File, 0),
+            ]
+        );
+        assert_eq!(
+            format!("{:?}", files.read(h, &mut [0u8; 4])),
+            "Error(NotSupported)"
+        );
+        assert_eq!(format!("{:?}", files.close(h)), "Ok");
+        assert_eq!(
+            format!("{:?}", files.stat("Games/Pong.bin", &mut entry)),
+            "Ok"
+        );
+        assert_eq!(&entry.name, b"PONG    BIN");
+        assert_eq!(entry.size, 300);
+        // It was only just written
+        assert_eq!(
+            entry.mtime.year_from_1970,
+            crate::time::now().year_from_1970
+        );
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn too_many_open() {
+        let root = make_root("many");
+        let mut files = Files::new(&root);
+        let handles: Vec<Handle> = (0..MAX_OPEN).map(|_| handle(files.opendir(""))).collect();
+        assert_eq!(handles[0], Handle(FIRST_HANDLE));
+        assert_eq!(format!("{:?}", files.opendir("")), "Error(IOError)");
+        assert_eq!(format!("{:?}", files.close(handles[3])), "Ok");
+        assert_eq!(handle(files.opendir("")), handles[3]);
+        let _ = fs::remove_dir_all(root);
+    }
+}
@@ -0,0 +1,785 @@
+//! # monotron-api-host
+//!
+//! Copyright (c) Jonathan 'theJPster' Pallant
+//!
+//! Licensed under either of
+//!
+//! - Apache License, Version 2.0 ([LICENSE-APACHE](LICENSE-APACHE) or
+//!   http://www.apache.org/licenses/LICENSE-2.0)
+//!
+//! - MIT license ([LICENSE-MIT](LICENSE-MIT) or http://opensource.org/licenses/MIT)
+//!
+//! at your option.
+//!
+//! A complete `monotron_api::Api` for Linux, so an application can be built
+//! for the host and debugged with the usual tools, instead of being flashed
+//! over serial every time.
+//!
+//! * The 48x36 Code Page 850 text screen is drawn on this terminal, 60 times
+//!   a second.
+//! * The keyboard is this terminal too (in raw mode, if it's a terminal).
+//!   Ctrl-] stops the application.
+//! * Files come from a directory on the host (see `Config::root`).
+//! * `gettime` is the host's clock, in local time.
+//! * `wfvbi`, `get_ticks` and the software timers run in real time.
+//! * Notes played can be logged to a text file, and the sound written to a
+//!   WAV file.
+//!
+//! There's no joystick, no custom fonts and no `map_line`, and a vertical
+//! blank hook runs when the application next makes an Api call after the
+//! frame starts (without being timed).
+//!
+//! Your application's entry point takes a `*const Api`, just as it does on
+//! the Monotron, so a small `main` can run it:
+//!
+//! ```no_run
+//! use monotron_api::Api;
+//!
+//! extern "C" fn entry(api: *const Api) -> i32 {
+//!     let api = unsafe { &*api };
+//!     (api.puts)(b"Hello, world!\n\0".as_ptr());
+//!     0
+//! }
+//!
+//! fn main() {
+//!     let mut config = monotron_api_host::Config::new("./sdcard");
+//!     config.args = std::env::args().collect();
+//!     config.wav = Some("sound.wav".into());
+//!     let status = monotron_api_host::run(config, |api| entry(api)).unwrap();
+//!     std::process::exit(status);
+//! }
+//! ```
+#![deny(missing_docs)]
+
+mod audio;
+mod files;
+mod screen;
+mod time;
+
+use monotron_api::*;
+use monotron_cli::cp850;
+use monotron_cli::port::wait_readable;
+use monotron_cli::term::{Encoder, RawMode};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Ctrl-] stops the application, like `monotron-cli term`.
+const QUIT_KEY: u8 = 0x1D;
+
+/// The exit status `run` gives when Ctrl-] stops the application.
+const QUIT_STATUS: i32 = 130;
+
+/// How long a video frame is.
+const FRAME: Duration = Duration::from_micros(16_667);
+
+/// How long to sleep for, while waiting for a key or a deadline.
+const IDLE: Duration = Duration::from_millis(1);
+
+/// The Monotron's CPU clock, for `get_cycle_budget`.
+const CLOCK_SPEED: u32 = 80_000_000;
+
+/// The optional features we offer applications.
+const CAPABILITIES: Capabilities = Capabilities::FILES
+    .union(Capabilities::GET_CURSOR)
+    .union(Capabilities::CLOCK)
+    .union(Capabilities::AUDIO);
+
+/// How to set up the pretend Monotron.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// The directory to use as the SD card
+    pub root: PathBuf,
+    /// What `get_args` gives the application, starting with its name
+    pub args: Vec<String>,
+    /// Write each note played to this file
+    pub audio_log: Option<PathBuf>,
+    /// Write the sound to this WAV file
+    pub wav: Option<PathBuf>,
+}
+
+/// Everything the Api needs while an application runs.
+struct Host {
+    screen: screen::Screen,
+    files: files::Files,
+    audio: audio::Recorder,
+    timers: time::Timers,
+    /// Where the screen is drawn
+    terminal: Box<dyn Write + Send>,
+    /// Whether to read keys from stdin
+    keyboard: bool,
+    encoder: Encoder,
+    /// Keys typed, in Code Page 850
+    keys: VecDeque<u8>,
+    /// Puts the terminal back when we stop
+    raw: Option<RawMode>,
+    /// When the application started
+    start: Instant,
+    /// The frame last drawn on the terminal
+    drawn_frame: u32,
+    /// The vertical blank hook, and its context
+    hook: Option<(VblankHook, *mut core::ffi::c_void)>,
+    /// The frame the hook last ran in
+    hook_frame: u32,
+    /// Ctrl-] has been pressed
+    quit: bool,
+    /// The text behind `argv`
+    _arg_text: Vec<String>,
+    /// What `get_args` points at
+    argv: Vec<BorrowedString>,
+}
+
+/// Stops the application when dropped, even if it panics.
+struct Running;
+
+lazy_static::lazy_static! {
+    /// The application's Api. Only set while `run` is running.
+    static ref HOST: Mutex<Option<Host>> = Mutex::new(None);
+}
+
+/// Set while the vertical blank hook runs, so it doesn't run again from its
+/// own Api calls.
+static IN_HOOK: AtomicBool = AtomicBool::new(false);
+
+/// The table we give to applications.
+static API: Api = Api {
+    header: ApiHeader::new(CAPABILITIES),
+    putchar,
+    puts,
+    readc,
+    kbhit,
+    move_cursor,
+    play,
+    change_font,
+    get_joystick,
+    set_cursor_visible,
+    read_char_at,
+    wfvbi,
+    open,
+    close,
+    read,
+    write,
+    write_then_read,
+    seek,
+    opendir,
+    readdir,
+    stat,
+    gettime,
+    puts_utf8,
+    map_line,
+    get_cursor,
+    get_service,
+    get_args,
+    get_cycle_budget,
+    get_ticks,
+    get_micros,
+    sleep_ms,
+    timer_start,
+    timer_stop,
+    get_event,
+    register_vblank_hook,
+};
+
+// The raw pointers (the hook's context and `argv`) are only ever used by
+// whoever has the lock on `HOST`, and `argv` only points into `_arg_text`,
+// which moves with it.
+unsafe impl Send for Host {}
+
+impl Config {
+    /// Use the directory `root` as the SD card, with no arguments and no
+    /// audio output.
+    pub fn new<P: AsRef<Path>>(root: P) -> Config {
+        Config {
+            root: root.as_ref().to_owned(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Run an application on this terminal, and return its exit status. `app`
+/// is given the `Api`, and should call your application's entry point.
+///
+/// Only one application can run at a time.
+pub fn run<F>(config: Config, app: F) -> io::Result<i32>
+where
+    F: FnOnce(*const Api) -> i32,
+{
+    run_on(config, Box::new(io::stdout()), true, app)
+}
+
+/// Run an application, drawing the screen on `terminal`, and reading keys
+/// from stdin if `keyboard` is set.
+fn run_on<F>(
+    config: Config,
+    terminal: Box<dyn Write + Send>,
+    keyboard: bool,
+    app: F,
+) -> io::Result<i32>
+where
+    F: FnOnce(*const Api) -> i32,
+{
+    if !config.root.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} is not a directory", config.root.display()),
+        ));
+    }
+    let audio = audio::Recorder::new(config.audio_log.as_deref(), config.wav.as_deref())?;
+    let argv = config
+        .args
+        .iter()
+        .map(|arg| BorrowedString {
+            ptr: arg.as_ptr(),
+            length: arg.len(),
+        })
+        .collect();
+    let host = Host {
+        screen: screen::Screen::new(),
+        files: files::Files::new(&config.root),
+        audio,
+        timers: time::Timers::default(),
+        terminal,
+        keyboard,
+        encoder: Encoder::new(),
+        keys: VecDeque::new(),
+        // Keys can still be piped in if stdin isn't a terminal
+        raw: if keyboard {
+            RawMode::enable(libc::STDIN_FILENO).ok()
+        } else {
+            None
+        },
+        start: Instant::now(),
+        drawn_frame: 0,
+        hook: None,
+        hook_frame: 0,
+        quit: false,
+        _arg_text: config.args,
+        argv,
+    };
+    {
+        let mut lock = HOST.lock().unwrap_or_else(|e| e.into_inner());
+        if lock.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "an application is already running",
+            ));
+        }
+        *lock = Some(host);
+    }
+    let running = Running;
+    let status = app(&API);
+    drop(running);
+    Ok(status)
+}
+
+/// Draw the screen one last time, finish the audio files and put the
+/// terminal back.
+fn stop() {
+    let host = HOST.lock().unwrap_or_else(|e| e.into_inner()).take();
+    if let Some(mut host) = host {
+        host.draw();
+        if let Err(e) = host.audio.finish(host.start.elapsed()) {
+            eprintln!("Error finishing the audio files: {}", e);
+        }
+        let _ = write!(
+            host.terminal,
+            "\x1b[0m\x1b[{};1H\x1b[?25h\r\n",
+            screen::HEIGHT + 1
+        );
+        let _ = host.terminal.flush();
+        drop(host.raw.take());
+    }
+}
+
+/// Do something with the `Host`, after catching up on the keyboard and the
+/// screen. Afterwards, stop if Ctrl-] was pressed, and run the vertical
+/// blank hook if it's due.
+fn with_host<T, F>(f: F) -> T
+where
+    F: FnOnce(&mut Host) -> T,
+{
+    let (result, quit, hook) = {
+        let mut lock = HOST.lock().unwrap_or_else(|e| e.into_inner());
+        let host = lock
+            .as_mut()
+            .expect("Api called when monotron_api_host::run isn't running");
+        host.service();
+        let result = f(host);
+        (result, host.quit, host.due_hook())
+    };
+    if quit {
+        stop();
+        std::process::exit(QUIT_STATUS);
+    }
+    if let Some((hook, context)) = hook {
+        IN_HOOK.store(true, Ordering::SeqCst);
+        hook(context);
+        IN_HOOK.store(false, Ordering::SeqCst);
+    }
+    result
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        stop();
+    }
+}
+
+impl Host {
+    /// How many frames since the application started.
+    fn frame(&self) -> u32 {
+        (self.start.elapsed().as_micros() / FRAME.as_micros()) as u32
+    }
+
+    /// How many microseconds since the application started.
+    fn micros(&self) -> u64 {
+        self.start.elapsed().as_micros() as u64
+    }
+
+    /// Read anything that's been typed, and draw the screen if a frame has
+    /// gone by.
+    fn service(&mut self) {
+        self.poll_keyboard();
+        if self.frame() != self.drawn_frame {
+            self.draw();
+        }
+    }
+
+    /// Draw the screen, and catch up on the audio.
+    fn draw(&mut self) {
+        self.drawn_frame = self.frame();
+        let mut out = String::new();
+        self.screen.render(&mut out);
+        let _ = self.terminal.write_all(out.as_bytes());
+        let _ = self.terminal.flush();
+        self.audio.catch_up(self.start.elapsed());
+    }
+
+    /// Read anything that's been typed.
+    fn poll_keyboard(&mut self) {
+        let mut buffer = [0u8; 64];
+        while self.keyboard {
+            match wait_readable(libc::STDIN_FILENO, Duration::from_millis(0)) {
+                Ok(true) => {}
+                _ => break,
+            }
+            let count = match io::stdin().read(&mut buffer) {
+                Ok(0) | Err(_) => {
+                    // Nothing more is coming
+                    self.keyboard = false;
+                    break;
+                }
+                Ok(count) => count,
+            };
+            let mut out = Vec::new();
+            for &b in &buffer[0..count] {
+                if b == QUIT_KEY {
+                    self.quit = true;
+                }
+                self.encoder.feed(b, &mut out);
+            }
+            self.keys.extend(out);
+        }
+    }
+
+    /// If the vertical blank hook should run, say so (once per frame).
+    fn due_hook(&mut self) -> Option<(VblankHook, *mut core::ffi::c_void)> {
+        let frame = self.frame();
+        if IN_HOOK.load(Ordering::SeqCst) || frame == self.hook_frame {
+            return None;
+        }
+        self.hook_frame = frame;
+        self.hook
+    }
+
+    /// Print a Code Page 850 string.
+    fn print(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.screen.write_u8(b);
+        }
+    }
+}
+
+impl monotron_shell::Audio for Host {
+    fn play(
+        &mut self,
+        channel: u8,
+        centi_hertz: u32,
+        volume: u8,
+        waveform: monotron_shell::Waveform,
+    ) {
+        let elapsed = self.start.elapsed();
+        self.audio
+            .play(elapsed, channel, centi_hertz, volume, waveform);
+    }
+}
+
+/// Print a single 8-bit character, in Code Page 850, to the screen.
+extern "C" fn putchar(ch: u8) -> i32 {
+    with_host(|h| h.screen.write_u8(ch));
+    i32::from(ch)
+}
+
+/// Print a null-terminated 8-bit string, in Code Page 850, to the screen.
+extern "C" fn puts(s: *const u8) -> i32 {
+    let bytes = unsafe { std::ffi::CStr::from_ptr(s as *const std::os::raw::c_char) }.to_bytes();
+    with_host(|h| h.print(bytes));
+    0
+}
+
+/// Read an 8-bit character from the keyboard, waiting for one if need be.
+extern "C" fn readc() -> i32 {
+    loop {
+        if let Some(ch) = with_host(|h| h.keys.pop_front()) {
+            return i32::from(ch);
+        }
+        std::thread::sleep(IDLE);
+    }
+}
+
+/// Returns 1 if a key has been pressed, and 0 otherwise.
+extern "C" fn kbhit() -> i32 {
+    with_host(|h| !h.keys.is_empty()) as i32
+}
+
+/// Move the cursor. Ignored if the position is off the screen.
+extern "C" fn move_cursor(row: u8, col: u8) {
+    with_host(|h| h.screen.move_cursor(row, col))
+}
+
+/// Play a note. The arguments are taken in the order the ROM takes them:
+/// the waveform, then the volume.
+extern "C" fn play(frequency: u32, channel: u8, waveform: u8, volume: u8) -> i32 {
+    with_host(|h| monotron_shell::api::play(h, frequency, channel, waveform, volume))
+}
+
+/// Change the font. A terminal has its own font, so this does nothing.
+extern "C" fn change_font(_mode: u32, _p_font: *const u8) {}
+
+/// There's no joystick, so it always reads as centred.
+extern "C" fn get_joystick() -> u8 {
+    0
+}
+
+/// Show or hide the cursor.
+extern "C" fn set_cursor_visible(visible: u8) {
+    with_host(|h| h.screen.set_cursor_visible(visible != 0))
+}
+
+/// What's on the screen at a position, as the glyph in the top byte and the
+/// attribute in the bottom byte.
+extern "C" fn read_char_at(row: u8, col: u8) -> u16 {
+    with_host(|h| h.screen.read_char_at(row, col))
+}
+
+/// Wait for the start of the next video frame.
+extern "C" fn wfvbi() {
+    let next = with_host(|h| h.start + FRAME * (h.frame() + 1));
+    let now = Instant::now();
+    if next > now {
+        std::thread::sleep(next - now);
+    }
+    with_host(|_| ())
+}
+
+/// Open a file.
+extern "C" fn open(filename: BorrowedString, mode: OpenMode) -> HandleResult {
+    match filename.as_str() {
+        Some(name) => with_host(|h| h.files.open(name, &mode)),
+        None => HandleResult::Error(Error::FileNotFound),
+    }
+}
+
+/// Close a file or directory.
+extern "C" fn close(handle: Handle) -> EmptyResult {
+    with_host(|h| h.files.close(handle))
+}
+
+/// Read from a file, or from `STDIN` (which doesn't wait for keys).
+extern "C" fn read(handle: Handle, buffer_ptr: *mut u8, buffer_len: usize) -> SizeResult {
+    let buffer = unsafe { std::slice::from_raw_parts_mut(buffer_ptr, buffer_len) };
+    with_host(|h| {
+        if handle == STDIN {
+            let count = buffer.len().min(h.keys.len());
+            for (slot, key) in buffer.iter_mut().zip(h.keys.drain(0..count)) {
+                *slot = key;
+            }
+            SizeResult::Ok(count)
+        } else if handle == STDOUT || handle == STDERR {
+            SizeResult::Error(Error::NotSupported)
+        } else {
+            h.files.read(handle, buffer)
+        }
+    })
+}
+
+/// Write to a file, or to `STDOUT` or `STDERR` (which both go to the screen).
+extern "C" fn write(handle: Handle, buffer_ptr: *const u8, buffer_len: usize) -> SizeResult {
+    let buffer = unsafe { std::slice::from_raw_parts(buffer_ptr, buffer_len) };
+    with_host(|h| {
+        if handle == STDOUT || handle == STDERR {
+            h.print(buffer);
+            SizeResult::Ok(buffer.len())
+        } else if handle == STDIN {
+            SizeResult::Error(Error::NotSupported)
+        } else {
+            h.files.write(handle, buffer)
+        }
+    })
+}
+
+/// There's no I2C bus to talk to.
+extern "C" fn write_then_read(
+    _handle: Handle,
+    _out_buffer: *const u8,
+    _out_buffer_len: usize,
+    _in_buffer: *mut u8,
+    _in_buffer_len: usize,
+) -> SizeResult {
+    SizeResult::Error(Error::NotSupported)
+}
+
+/// Move the read/write pointer in a file.
+extern "C" fn seek(handle: Handle, offset: Offset) -> EmptyResult {
+    with_host(|h| h.files.seek(handle, &offset))
+}
+
+/// Open a directory.
+extern "C" fn opendir(filename: BorrowedString) -> HandleResult {
+    match filename.as_str() {
+        Some(name) => with_host(|h| h.files.opendir(name)),
+        None => HandleResult::Error(Error::FileNotFound),
+    }
+}
+
+/// Read the next entry from a directory.
+extern "C" fn readdir(handle: Handle, dir_entry: &mut DirEntry) -> EmptyResult {
+    with_host(|h| h.files.readdir(handle, dir_entry))
+}
+
+/// Describe a file.
+extern "C" fn stat(filename: BorrowedString, stat_entry: &mut DirEntry) -> EmptyResult {
+    match filename.as_str() {
+        Some(name) => with_host(|h| h.files.stat(name, stat_entry)),
+        None => EmptyResult::Error(Error::FileNotFound),
+    }
+}
+
+/// Get the host's local time.
+extern "C" fn gettime() -> Timestamp {
+    time::now()
+}
+
+/// Print a UTF-8 string. Characters Code Page 850 doesn't have are shown
+/// as `?`.
+extern "C" fn puts_utf8(string: *const u8, length: usize) {
+    let bytes = unsafe { std::slice::from_raw_parts(string, length) };
+    let text = String::from_utf8_lossy(bytes);
+    with_host(|h| {
+        for ch in text.chars() {
+            h.screen.write_u8(cp850::from_char(ch).unwrap_or(b'?'));
+        }
+    })
+}
+
+/// A terminal can't move scan-lines around, so this does nothing.
+extern "C" fn map_line(_actual_scanline: u16, _drawn_scanline: u16) {}
+
+/// Get the cursor position.
+extern "C" fn get_cursor(row: *mut u8, col: *mut u8) {
+    let (r, c) = with_host(|h| h.screen.cursor());
+    if !row.is_null() {
+        unsafe {
+            *row = r;
+        }
+    }
+    if !col.is_null() {
+        unsafe {
+            *col = c;
+        }
+    }
+}
+
+/// Look up an optional service.
+extern "C" fn get_service(id: ServiceId) -> *const core::ffi::c_void {
+    match id {
+        ServiceId::GET_CURSOR => get_cursor as *const core::ffi::c_void,
+        _ => core::ptr::null(),
+    }
+}
+
+/// Get the arguments from `Config::args`.
+extern "C" fn get_args() -> Args {
+    with_host(|h| Args {
+        argc: h.argv.len(),
+        argv: h.argv.as_ptr(),
+    })
+}
+
+/// Say how much of the frame is left. The host has no video or audio
+/// interrupts, so all of the frame is the application's.
+extern "C" fn get_cycle_budget() -> CycleBudget {
+    let elapsed = with_host(|h| h.micros());
+    let frame = FRAME.as_micros() as u64;
+    let left = frame - elapsed % frame;
+    CycleBudget {
+        frame: CLOCK_SPEED / 60,
+        interrupts: 0,
+        idle: 0,
+        remaining: (left * u64::from(CLOCK_SPEED / 1_000_000)) as u32,
+    }
+}
+
+/// Get the number of video frames since the application started.
+extern "C" fn get_ticks() -> u32 {
+    with_host(|h| h.frame())
+}
+
+/// Get the number of microseconds since the application started.
+extern "C" fn get_micros() -> u64 {
+    with_host(|h| h.micros())
+}
+
+/// Wait for at least `ms` milliseconds, keeping the screen up to date.
+extern "C" fn sleep_ms(ms: u32) {
+    let deadline = Instant::now() + Duration::from_millis(u64::from(ms));
+    loop {
+        with_host(|_| ());
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        std::thread::sleep(IDLE.min(deadline - now));
+    }
+}
+
+/// Start a software timer.
+extern "C" fn timer_start(period_ms: u32, mode: TimerMode) -> TimerResult {
+    with_host(|h| {
+        let now = h.micros();
+        h.timers.start(now, period_ms, mode)
+    })
+}
+
+/// Stop a software timer.
+extern "C" fn timer_stop(timer: TimerId) -> EmptyResult {
+    with_host(|h| h.timers.stop(timer))
+}
+
+/// Collect the next event, if there is one.
+extern "C" fn get_event() -> Event {
+    with_host(|h| {
+        let now = h.micros();
+        h.timers.get_event(now)
+    })
+}
+
+/// Set (or clear) the function called once per video frame.
+extern "C" fn register_vblank_hook(hook: Option<VblankHook>, context: *mut core::ffi::c_void) {
+    with_host(|h| {
+        h.hook_frame = h.frame();
+        h.hook = hook.map(|hook| (hook, context));
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+
+    /// Collects what's drawn on the terminal.
+    #[derive(Clone, Default)]
+    struct Terminal(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Terminal {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    extern "C" fn count_frames(context: *mut core::ffi::c_void) {
+        let count = unsafe { &mut *(context as *mut u32) };
+        *count += 1;
+        // Api calls from the hook don't run the hook again
+        (API.wfvbi)();
+    }
+
+    fn app(api: *const Api) -> i32 {
+        let api = unsafe { &*api };
+        assert!(api.supports(API_VERSION));
+        assert!(api.has(Capabilities::FILES.union(Capabilities::CLOCK)));
+        assert!(!api.has(Capabilities::MAP_LINE));
+        let args = (api.get_args)();
+        assert_eq!(args.get(0), Some("TEST.BIN"));
+        assert_eq!(args.get(1), Some("--fast"));
+        assert_eq!(args.get(2), None);
+
+        (api.puts)(b"\x1bYHello\n\0".as_ptr());
+        (api.puts_utf8)("£1".as_ptr(), "£1".len());
+        assert_eq!((api.read_char_at)(0, 1), 0x6560);
+        assert_eq!((api.read_char_at)(1, 0), 0x9C60);
+        let (mut row, mut col) = (0, 0);
+        (api.get_cursor)(&mut row, &mut col);
+        assert_eq!((row, col), (1, 2));
+
+        let handle = match (api.open)(
+            BorrowedString::new("hello.txt"),
+            OpenMode::ReadOnly {
+                non_blocking: false,
+            },
+        ) {
+            HandleResult::Ok(handle) => handle,
+            HandleResult::Error(e) => panic!("{:?}", e),
+        };
+        let mut buffer = [0u8; 16];
+        match (api.read)(handle, buffer.as_mut_ptr(), buffer.len()) {
+            SizeResult::Ok(2) => assert_eq!(&buffer[0..2], b"Hi"),
+            other => panic!("{:?}", other),
+        }
+        assert!(matches!((api.close)(handle), EmptyResult::Ok));
+
+        let mut count: u32 = 0;
+        (api.register_vblank_hook)(Some(count_frames), &mut count as *mut u32 as *mut _);
+        let ticks = (api.get_ticks)();
+        let timer = match (api.timer_start)(20, TimerMode::OneShot) {
+            TimerResult::Ok(timer) => timer,
+            TimerResult::Error(e) => panic!("{:?}", e),
+        };
+        for _ in 0..3 {
+            (api.wfvbi)();
+        }
+        assert!((api.get_ticks)() >= ticks + 3);
+        assert_eq!((api.get_event)(), Event::Timer(timer));
+        (api.register_vblank_hook)(None, core::ptr::null_mut());
+        assert!(count >= 3);
+        assert!(count <= 10, "ran {} times", count);
+        42
+    }
+
+    #[test]
+    fn run_app() {
+        let root = std::env::temp_dir().join(format!("monotron-api-host-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("HELLO.TXT"), b"Hi").unwrap();
+        let config = Config {
+            args: vec!["TEST.BIN".into(), "--fast".into()],
+            ..Config::new(&root)
+        };
+        let terminal = Terminal::default();
+        let status = run_on(config, Box::new(terminal.clone()), false, app).unwrap();
+        assert_eq!(status, 42);
+        let drawn = String::from_utf8(terminal.0.lock().unwrap().clone()).unwrap();
+        assert!(drawn.contains("\x1b[33;40mHello"));
+        assert!(drawn.contains("£1"));
+        assert!(HOST.lock().unwrap().is_none());
+        let _ = std::fs::remove_dir_all(root);
+    }
+}
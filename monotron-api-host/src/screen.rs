@@ -0,0 +1,328 @@
+//! The Monotron's 48x36 text screen, drawn on an ANSI terminal.
+//!
+//! Bytes are Code Page 850, with the same control characters and escape
+//! sequences as the ROM (see `monotron_api::Api::puts`). Each cell has a
+//! foreground and background colour, stored like the ROM's attribute byte:
+//! the foreground in the top nibble and the background in the bottom, each
+//! as red (4), green (2) and blue (1) bits.
+
+use monotron_cli::cp850;
+use std::fmt::Write;
+
+/// How many characters across the screen is.
+pub const WIDTH: usize = 48;
+
+/// How many rows down the screen is.
+pub const HEIGHT: usize = 36;
+
+/// Tab stops are this many characters apart.
+const TAB_STOP: usize = 9;
+
+/// The escape character, which starts a colour change.
+const ESC: u8 = 0x1B;
+
+/// White text on a black background.
+const DEFAULT_ATTR: u8 = 0x70;
+
+/// What the font draws for Code Page 850 bytes 0x00 to 0x1F, which a
+/// terminal would treat as control characters.
+static LOW_GLYPHS: [char; 32] = [
+    ' ', '☺', '☻', '♥', '♦', '♣', '♠', '•', '◘', '○', '◙', '♂', '♀', '♪', '♫', '☼', //
+    '►', '◄', '↕', '‼', '¶', '§', '▬', '↨', '↑', '↓', '→', '←', '∟', '↔', '▲', '▼',
+];
+
+/// One character on the screen.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct Cell {
+    glyph: u8,
+    attr: u8,
+}
+
+/// The whole screen.
+type Cells = [[Cell; WIDTH]; HEIGHT];
+
+/// The text screen.
+pub struct Screen {
+    cells: Cells,
+    /// What the terminal is showing, or `None` if it needs drawing from
+    /// scratch
+    shown: Option<Box<Cells>>,
+    row: usize,
+    col: usize,
+    /// The attribute for characters written from now on
+    attr: u8,
+    /// We've had an `ESC`, so the next byte is a colour
+    in_escape: bool,
+    cursor_visible: bool,
+}
+
+/// Convert a Monotron colour letter (`K`, `B`, `G`, `C`, `R`, `M`, `Y` or
+/// `W`, in either case) into its red, green and blue bits.
+fn colour(letter: u8) -> Option<u8> {
+    match letter.to_ascii_uppercase() {
+        b'K' => Some(0),
+        b'B' => Some(1),
+        b'G' => Some(2),
+        b'C' => Some(3),
+        b'R' => Some(4),
+        b'M' => Some(5),
+        b'Y' => Some(6),
+        b'W' => Some(7),
+        _ => None,
+    }
+}
+
+/// Convert red (4), green (2) and blue (1) bits into an ANSI colour number.
+fn ansi_colour(rgb: u8) -> u8 {
+    ((rgb >> 2) & 1) | (rgb & 2) | ((rgb & 1) << 2)
+}
+
+/// How a terminal can show a glyph.
+fn to_char(glyph: u8) -> char {
+    match glyph {
+        0x00..=0x1F => LOW_GLYPHS[usize::from(glyph)],
+        0x7F => '⌂',
+        _ => cp850::to_char(glyph),
+    }
+}
+
+impl Screen {
+    /// A blank screen, with the cursor in the top left.
+    pub fn new() -> Screen {
+        Screen {
+            cells: [[Cell {
+                glyph: b' ',
+                attr: DEFAULT_ATTR,
+            }; WIDTH]; HEIGHT],
+            shown: None,
+            row: 0,
+            col: 0,
+            attr: DEFAULT_ATTR,
+            in_escape: false,
+            cursor_visible: true,
+        }
+    }
+
+    /// Print one Code Page 850 byte (or control character).
+    pub fn write_u8(&mut self, byte: u8) {
+        if self.in_escape {
+            self.in_escape = false;
+            if byte == b'Z' {
+                self.clear();
+            } else if let Some(rgb) = colour(byte) {
+                self.attr = if byte.is_ascii_uppercase() {
+                    (self.attr & 0x0F) | (rgb << 4)
+                } else {
+                    (self.attr & 0xF0) | rgb
+                };
+            }
+            return;
+        }
+        match byte {
+            ESC => self.in_escape = true,
+            b'\n' => self.new_line(),
+            b'\r' => self.col = 0,
+            b'\t' => {
+                self.col = (self.col / TAB_STOP + 1) * TAB_STOP;
+                if self.col >= WIDTH {
+                    self.new_line();
+                }
+            }
+            0x08 => self.col = self.col.saturating_sub(1),
+            _ => {
+                self.cells[self.row][self.col] = Cell {
+                    glyph: byte,
+                    attr: self.attr,
+                };
+                self.col += 1;
+                if self.col == WIDTH {
+                    self.new_line();
+                }
+            }
+        }
+    }
+
+    /// Move the cursor. Ignored if the position is off the screen.
+    pub fn move_cursor(&mut self, row: u8, col: u8) {
+        if usize::from(row) < HEIGHT && usize::from(col) < WIDTH {
+            self.row = usize::from(row);
+            self.col = usize::from(col);
+        }
+    }
+
+    /// Where the cursor is, as (row, column).
+    pub fn cursor(&self) -> (u8, u8) {
+        (self.row as u8, self.col as u8)
+    }
+
+    /// Show or hide the cursor.
+    pub fn set_cursor_visible(&mut self, visible: bool) {
+        self.cursor_visible = visible;
+    }
+
+    /// What's on the screen at a position, as the glyph in the top byte and
+    /// the attribute in the bottom byte, or zero if it's off the screen.
+    pub fn read_char_at(&self, row: u8, col: u8) -> u16 {
+        match self
+            .cells
+            .get(usize::from(row))
+            .and_then(|r| r.get(usize::from(col)))
+        {
+            Some(cell) => (u16::from(cell.glyph) << 8) | u16::from(cell.attr),
+            None => 0,
+        }
+    }
+
+    /// Make the terminal show what's changed since last time, and leave its
+    /// cursor where ours is.
+    pub fn render(&mut self, out: &mut String) {
+        let mut shown = match self.shown.take() {
+            Some(shown) => shown,
+            None => {
+                out.push_str("\x1b[0m\x1b[2J");
+                // Nothing will match this, so every cell is drawn
+                Box::new(
+                    [[Cell {
+                        glyph: 0,
+                        attr: 0xFF,
+                    }; WIDTH]; HEIGHT],
+                )
+            }
+        };
+        let mut attr = None;
+        let mut next = None;
+        for (row, (cells, shown_cells)) in self.cells.iter().zip(shown.iter_mut()).enumerate() {
+            for (col, (cell, shown_cell)) in cells.iter().zip(shown_cells.iter_mut()).enumerate() {
+                if cell == shown_cell {
+                    continue;
+                }
+                if next != Some((row, col)) {
+                    let _ = write!(out, "\x1b[{};{}H", row + 1, col + 1);
+                }
+                if attr != Some(cell.attr) {
+                    let _ = write!(
+                        out,
+                        "\x1b[{};{}m",
+                        30 + ansi_colour(cell.attr >> 4),
+                        40 + ansi_colour(cell.attr & 0x07)
+                    );
+                    attr = Some(cell.attr);
+                }
+                out.push(to_char(cell.glyph));
+                *shown_cell = *cell;
+                next = Some((row, col + 1));
+            }
+        }
+        if attr.is_some() {
+            out.push_str("\x1b[0m");
+        }
+        let _ = write!(
+            out,
+            "\x1b[{};{}H\x1b[?25{}",
+            self.row + 1,
+            self.col + 1,
+            if self.cursor_visible { 'h' } else { 'l' }
+        );
+        self.shown = Some(shown);
+    }
+
+    /// Move to the start of the next line, scrolling if we're at the bottom.
+    fn new_line(&mut self) {
+        self.col = 0;
+        if self.row + 1 < HEIGHT {
+            self.row += 1;
+        } else {
+            for row in 1..HEIGHT {
+                self.cells[row - 1] = self.cells[row];
+            }
+            self.cells[HEIGHT - 1] = [Cell {
+                glyph: b' ',
+                attr: self.attr,
+            }; WIDTH];
+        }
+    }
+
+    /// Blank the screen in the current colours, and go to the top left.
+    fn clear(&mut self) {
+        self.cells = [[Cell {
+            glyph: b' ',
+            attr: self.attr,
+        }; WIDTH]; HEIGHT];
+        self.row = 0;
+        self.col = 0;
+    }
+}
+
+impl Default for Screen {
+    fn default() -> Screen {
+        Screen::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write(screen: &mut Screen, bytes: &[u8]) {
+        for &b in bytes {
+            screen.write_u8(b);
+        }
+    }
+
+    fn row_text(screen: &Screen, row: u8) -> String {
+        (0..WIDTH as u8)
+            .map(|col| to_char((screen.read_char_at(row, col) >> 8) as u8))
+            .collect::<String>()
+            .trim_end()
+            .to_string()
+    }
+
+    #[test]
+    fn text_and_colours() {
+        let mut screen = Screen::new();
+        write(&mut screen, b"Hi\x1bRa\x1bb\x9c\r\nX\tY");
+        assert_eq!(row_text(&screen, 0), "Hia£");
+        assert_eq!(row_text(&screen, 1), "X        Y");
+        assert_eq!(screen.read_char_at(0, 0), 0x4870);
+        assert_eq!(screen.read_char_at(0, 2), 0x6140);
+        assert_eq!(screen.read_char_at(0, 3), 0x9C41);
+        assert_eq!(screen.read_char_at(0, WIDTH as u8), 0);
+        assert_eq!(screen.cursor(), (1, 10));
+        write(&mut screen, b"\x08\x08Z");
+        assert_eq!(row_text(&screen, 1), "X       ZY");
+    }
+
+    #[test]
+    fn wrap_and_scroll() {
+        let mut screen = Screen::new();
+        for row in 0..HEIGHT {
+            write(&mut screen, format!("{}\n", row).as_bytes());
+        }
+        assert_eq!(row_text(&screen, 0), "1");
+        assert_eq!(row_text(&screen, HEIGHT as u8 - 2), "35");
+        assert_eq!(screen.cursor(), (HEIGHT as u8 - 1, 0));
+        write(&mut screen, &[b'x'; WIDTH + 1]);
+        assert_eq!(row_text(&screen, HEIGHT as u8 - 2), "x".repeat(WIDTH));
+        assert_eq!(row_text(&screen, HEIGHT as u8 - 1), "x");
+        write(&mut screen, b"\x1bZ");
+        assert_eq!(row_text(&screen, 0), "");
+        assert_eq!(screen.cursor(), (0, 0));
+    }
+
+    #[test]
+    fn render_changes() {
+        let mut screen = Screen::new();
+        let mut out = String::new();
+        screen.render(&mut out);
+        assert!(out.starts_with("\x1b[0m\x1b[2J\x1b[1;1H\x1b[37;40m "));
+        out.clear();
+        screen.render(&mut out);
+        assert_eq!(out, "\x1b[1;1H\x1b[?25h");
+        out.clear();
+        screen.move_cursor(2, 3);
+        write(&mut screen, b"\x1bC\x01!");
+        screen.set_cursor_visible(false);
+        screen.render(&mut out);
+        assert_eq!(out, "\x1b[3;4H\x1b[36;40m☺!\x1b[0m\x1b[3;6H\x1b[?25l");
+    }
+}
@@ -0,0 +1,167 @@
+//! The calendar clock, which is the host's clock in local time, and the
+//! software timers applications can start.
+//!
+//! The timers work just like the ROM's: nothing happens when one goes off,
+//! and the application finds out the next time it calls `get_event`, which
+//! is when periodic timers are rescheduled.
+
+use monotron_api::{EmptyResult, Error, Event, TimerId, TimerMode, TimerResult, Timestamp};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How many software timers an application can have running.
+const MAX_TIMERS: usize = 8;
+
+/// A software timer.
+#[derive(Debug, Copy, Clone)]
+struct Timer {
+    /// When it next goes off, in microseconds since we started
+    deadline: u64,
+    /// How often it goes off, in microseconds, or `None` if it only goes off
+    /// once
+    period: Option<u64>,
+}
+
+/// The software timers, indexed by `TimerId`.
+#[derive(Debug, Default)]
+pub struct Timers {
+    timers: [Option<Timer>; MAX_TIMERS],
+}
+
+/// Convert a time on the host into a local date and time.
+pub fn timestamp(time: SystemTime) -> Timestamp {
+    let secs = match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as libc::time_t,
+        Err(_) => 0,
+    };
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe {
+        libc::localtime_r(&secs, &mut tm);
+    }
+    Timestamp {
+        year_from_1970: (tm.tm_year - 70).clamp(0, 255) as u8,
+        month: (tm.tm_mon + 1) as u8,
+        days: tm.tm_mday as u8,
+        hours: tm.tm_hour as u8,
+        minutes: tm.tm_min as u8,
+        // There might be a leap second
+        seconds: tm.tm_sec.min(59) as u8,
+    }
+}
+
+/// The local date and time now.
+pub fn now() -> Timestamp {
+    timestamp(SystemTime::now())
+}
+
+impl Timers {
+    /// Start a software timer. `now` is in microseconds.
+    pub fn start(&mut self, now: u64, period_ms: u32, mode: TimerMode) -> TimerResult {
+        let period = u64::from(period_ms.max(1)) * 1000;
+        let timer = Timer {
+            deadline: now + period,
+            period: match mode {
+                TimerMode::OneShot => None,
+                TimerMode::Periodic => Some(period),
+            },
+        };
+        match self.timers.iter().position(|t| t.is_none()) {
+            Some(idx) => {
+                self.timers[idx] = Some(timer);
+                TimerResult::Ok(TimerId(idx as u8))
+            }
+            None => TimerResult::Error(Error::NoFreeTimers),
+        }
+    }
+
+    /// Stop a software timer.
+    pub fn stop(&mut self, timer: TimerId) -> EmptyResult {
+        match self.timers.get_mut(timer.0 as usize) {
+            Some(slot) if slot.is_some() => {
+                *slot = None;
+                EmptyResult::Ok
+            }
+            _ => EmptyResult::Error(Error::BadTimerId),
+        }
+    }
+
+    /// Find the timer which went off first, if any have, and reschedule (or
+    /// stop) it. `now` is in microseconds.
+    pub fn get_event(&mut self, now: u64) -> Event {
+        let mut expired: Option<(usize, u64)> = None;
+        for (idx, timer) in self.timers.iter().enumerate() {
+            if let Some(timer) = timer {
+                let earliest = expired.map_or(true, |(_, deadline)| timer.deadline < deadline);
+                if timer.deadline <= now && earliest {
+                    expired = Some((idx, timer.deadline));
+                }
+            }
+        }
+        match expired {
+            Some((idx, _)) => {
+                let timer = self.timers[idx].as_mut().unwrap();
+                match timer.period {
+                    Some(period) => {
+                        timer.deadline += period;
+                        // If the application fell behind, don't try and catch up
+                        if timer.deadline <= now {
+                            timer.deadline = now + period;
+                        }
+                    }
+                    None => self.timers[idx] = None,
+                }
+                Event::Timer(TimerId(idx as u8))
+            }
+            None => Event::None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    fn id(result: TimerResult) -> TimerId {
+        match result {
+            TimerResult::Ok(id) => id,
+            TimerResult::Error(e) => panic!("{:?}", e),
+        }
+    }
+
+    #[test]
+    fn timers() {
+        let mut timers = Timers::default();
+        let slow = id(timers.start(0, 10, TimerMode::OneShot));
+        let fast = id(timers.start(0, 3, TimerMode::Periodic));
+        assert_eq!(timers.get_event(2_999), Event::None);
+        assert_eq!(timers.get_event(3_000), Event::Timer(fast));
+        assert_eq!(timers.get_event(3_000), Event::None);
+        // Both have gone off, and the earlier deadline comes first
+        assert_eq!(timers.get_event(12_500), Event::Timer(fast));
+        assert_eq!(timers.get_event(12_500), Event::Timer(slow));
+        // The periodic one doesn't catch up on the ones it missed
+        assert_eq!(timers.get_event(12_500), Event::None);
+        assert_eq!(timers.get_event(15_500), Event::Timer(fast));
+        assert!(matches!(
+            timers.stop(slow),
+            EmptyResult::Error(Error::BadTimerId)
+        ));
+        assert!(matches!(timers.stop(fast), EmptyResult::Ok));
+        assert_eq!(timers.get_event(100_000), Event::None);
+        for _ in 0..MAX_TIMERS {
+            id(timers.start(0, 1, TimerMode::OneShot));
+        }
+        assert!(matches!(
+            timers.start(0, 1, TimerMode::OneShot),
+            TimerResult::Error(Error::NoFreeTimers)
+        ));
+    }
+
+    #[test]
+    fn local_time() {
+        // Midday on 15 July 2019, UTC, is within a day of that anywhere
+        let t = timestamp(UNIX_EPOCH + Duration::from_secs(1_563_192_000));
+        assert_eq!((t.year_from_1970, t.month), (49, 7));
+        assert!((14..=16).contains(&t.days));
+    }
+}
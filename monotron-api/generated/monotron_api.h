@@ -0,0 +1,846 @@
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * How many CPU cycles a `VblankHook` may use each time it's called, not
+ * counting the video and audio interrupts. That's about a third of what's
+ * usually left over in a frame.
+ */
+#define Monotron_VBLANK_HOOK_BUDGET_CYCLES 200000
+
+/**
+ * Where the `AppHeader` goes in an application image, in bytes from the
+ * start of application RAM (i.e. just after the entry point address).
+ */
+#define Monotron_APP_HEADER_OFFSET 4
+
+/**
+ * The set of Error codes the API can report.
+ */
+typedef enum Monotron_Error {
+  /**
+   * The given filename was not found
+   */
+  FileNotFound,
+  /**
+   * The given file handle was not valid
+   */
+  BadFileHandle,
+  /**
+   * Error reading or writing
+   */
+  IOError,
+  /**
+   * You can't do that operation on that sort of file
+   */
+  NotSupported,
+  /**
+   * There are no free timers. Since 2.3.
+   */
+  NoFreeTimers,
+  /**
+   * The given timer was not valid. Since 2.3.
+   */
+  BadTimerId,
+  /**
+   * An unknown error occured
+   */
+  Unknown = 65535,
+} Monotron_Error;
+
+/**
+ * Describes the sort of files you will find in the system-wide virtual
+ * filesystem. Some exist on disk, and some do not.
+ */
+typedef enum Monotron_FileType {
+  /**
+   * A regular file
+   */
+  File,
+  /**
+   * A directory contains other files and directories
+   */
+  Directory,
+  /**
+   * A device you can read/write a block (e.g. 512 bytes) at a time
+   */
+  BlockDevice,
+  /**
+   * A device you can read/write one or more bytes at a time
+   */
+  CharDevice,
+} Monotron_FileType;
+
+/**
+ * Whether a software timer goes off once, or keeps going off.
+ */
+typedef enum Monotron_TimerMode {
+  /**
+   * Go off once, then stop
+   */
+  OneShot,
+  /**
+   * Go off every period, until stopped
+   */
+  Periodic,
+} Monotron_TimerMode;
+
+typedef struct Monotron_Option_VblankHook Monotron_Option_VblankHook;
+
+/**
+ * A bitfield indicating if a file is:
+ *
+ * * read-only
+ * * a volume label
+ * * a system file
+ * * in need of archiving
+ */
+typedef struct Monotron_FileMode {
+  uint8_t _0;
+} Monotron_FileMode;
+
+/**
+ * The ways in which we can open a file.
+ *
+ * TODO: Replace all these booleans with a u8 flag-set
+ */
+typedef enum Monotron_OpenMode_Tag {
+  /**
+   * Open file in read-only mode. No writes allowed. One file can be opened in read-only mode multiple times.
+   */
+  ReadOnly,
+  /**
+   * Open a file for writing, but not reading.
+   */
+  WriteOnly,
+  /**
+   * Open a file for reading and writing.
+   */
+  ReadWrite,
+} Monotron_OpenMode_Tag;
+
+typedef struct Monotron_ReadOnly_Body {
+  /**
+   * Set to true if read/write requests on this handle should be non-blocking
+   */
+  bool non_blocking;
+} Monotron_ReadOnly_Body;
+
+typedef struct Monotron_WriteOnly_Body {
+  /**
+   * If true, the write pointer will default to the end of the file
+   */
+  bool append;
+  /**
+   * If true, the file will be created if it doesn't exist. If false, the file must exist. See also the `exclusive` flag.
+   */
+  bool create;
+  /**
+   * If true AND the create flag is true, the open will fail if the file already exists.
+   */
+  bool exclusive;
+  /**
+   * If true, the file contents will be deleted on open, giving a zero byte file.
+   */
+  bool truncate;
+  /**
+   * Set to true if read/write requests on this handle should be non-blocking
+   */
+  bool non_blocking;
+} Monotron_WriteOnly_Body;
+
+typedef struct Monotron_ReadWrite_Body {
+  /**
+   * If true, the write pointer will default to the end of the file
+   */
+  bool append;
+  /**
+   * If true, the file will be created if it doesn't exist. If false, the file must exist. See also the `exclusive` flag.
+   */
+  bool create;
+  /**
+   * If true AND the create flag is true, the open will fail if the file already exists.
+   */
+  bool exclusive;
+  /**
+   * If true, the file contents will be deleted on open, giving a zero byte file.
+   */
+  bool truncate;
+  /**
+   * Set to true if read/write requests on this handle should be non-blocking
+   */
+  bool non_blocking;
+} Monotron_ReadWrite_Body;
+
+typedef struct Monotron_OpenMode {
+  Monotron_OpenMode_Tag tag;
+  union {
+    Monotron_ReadOnly_Body read_only;
+    Monotron_WriteOnly_Body write_only;
+    Monotron_ReadWrite_Body read_write;
+  };
+} Monotron_OpenMode;
+
+/**
+ * Identifies a version of the `Api` structure.
+ *
+ * The major version changes when existing entries move or change, so an
+ * application must have been built against the same major version as the
+ * ROM. The minor version changes when entries are added to the end, so an
+ * application will run on any ROM with the same or a higher minor version.
+ */
+typedef struct Monotron_ApiVersion {
+  /**
+   * Changes when the `Api` changes in an incompatible way
+   */
+  uint16_t major;
+  /**
+   * Changes when new entries are added to the end of the `Api`
+   */
+  uint16_t minor;
+} Monotron_ApiVersion;
+
+/**
+ * Optional features a ROM might have. Check them with `Api::has`.
+ */
+typedef struct Monotron_Capabilities {
+  uint32_t _0;
+} Monotron_Capabilities;
+/**
+ * No optional features.
+ */
+#define Monotron_Capabilities_NONE (Monotron_Capabilities){ ._0 = 0 }
+/**
+ * There is an SD card, so `open`, `read`, `opendir` and friends can
+ * access files.
+ */
+#define Monotron_Capabilities_FILES (Monotron_Capabilities){ ._0 = (1 << 0) }
+/**
+ * `map_line` works.
+ */
+#define Monotron_Capabilities_MAP_LINE (Monotron_Capabilities){ ._0 = (1 << 1) }
+/**
+ * `get_cursor` works.
+ */
+#define Monotron_Capabilities_GET_CURSOR (Monotron_Capabilities){ ._0 = (1 << 2) }
+/**
+ * There is a real-time clock, so `gettime` returns the actual time.
+ */
+#define Monotron_Capabilities_CLOCK (Monotron_Capabilities){ ._0 = (1 << 3) }
+/**
+ * There is a synthesiser, so `play` makes a noise.
+ */
+#define Monotron_Capabilities_AUDIO (Monotron_Capabilities){ ._0 = (1 << 4) }
+
+/**
+ * Comes at the start of the `Api` structure, so an application can find out
+ * what the ROM it is running on can do.
+ */
+typedef struct Monotron_ApiHeader {
+  /**
+   * The size of the ROM's `Api` structure in bytes, including this header.
+   */
+  uint32_t size;
+  /**
+   * The version of the ROM's `Api` structure.
+   */
+  struct Monotron_ApiVersion version;
+  /**
+   * The optional features the ROM has.
+   */
+  struct Monotron_Capabilities capabilities;
+} Monotron_ApiHeader;
+
+/**
+ * Describes a handle to some resource.
+ */
+typedef struct Monotron_Handle {
+  uint16_t _0;
+} Monotron_Handle;
+
+/**
+ * Describes the result of a function which may return a `Handle` if
+ * everything was Ok, or return an `Error` if something went wrong.
+ *
+ * This is not a standard Rust `Result` because they are not `#[repr(C)]`.
+ */
+typedef enum Monotron_HandleResult_Tag {
+  /**
+   * Success - a handle is returned
+   */
+  Ok,
+  /**
+   * Failure - an error is returned
+   */
+  Error,
+} Monotron_HandleResult_Tag;
+
+typedef struct Monotron_HandleResult {
+  Monotron_HandleResult_Tag tag;
+  union {
+    struct {
+      struct Monotron_Handle ok;
+    };
+    struct {
+      enum Monotron_Error error;
+    };
+  };
+} Monotron_HandleResult;
+
+/**
+ * Describes a string of fixed length, which must not be free'd by the
+ * recipient. The given length must not include any null terminators that may
+ * be present. The string must be valid UTF-8 (or 7-bit ASCII, which is a
+ * valid subset of UTF-8).
+ */
+typedef struct Monotron_BorrowedString {
+  /**
+   * The start of the string
+   */
+  const uint8_t *ptr;
+  /**
+   * The length of the string in bytes
+   */
+  uintptr_t length;
+} Monotron_BorrowedString;
+
+/**
+ * Describes the result of a function which may return nothing if everything
+ * was Ok, or return an `Error` if something went wrong.
+ *
+ * This is not a standard Rust `Result` because they are not `#[repr(C)]`.
+ */
+typedef enum Monotron_EmptyResult_Tag {
+  /**
+   * Success - nothing is returned
+   */
+  Ok,
+  /**
+   * Failure - an error is returned
+   */
+  Error,
+} Monotron_EmptyResult_Tag;
+
+typedef struct Monotron_EmptyResult {
+  Monotron_EmptyResult_Tag tag;
+  union {
+    struct {
+      enum Monotron_Error error;
+    };
+  };
+} Monotron_EmptyResult;
+
+/**
+ * Describes the result of a function which may return a numeric count of
+ * bytes read/written if everything was Ok, or return an `Error` if something
+ * went wrong.
+ *
+ * This is not a standard Rust `Result` because they are not `#[repr(C)]`.
+ */
+typedef enum Monotron_SizeResult_Tag {
+  /**
+   * Success - a size in bytes is returned
+   */
+  Ok,
+  /**
+   * Failure - an error is returned
+   */
+  Error,
+} Monotron_SizeResult_Tag;
+
+typedef struct Monotron_SizeResult {
+  Monotron_SizeResult_Tag tag;
+  union {
+    struct {
+      uintptr_t ok;
+    };
+    struct {
+      enum Monotron_Error error;
+    };
+  };
+} Monotron_SizeResult;
+
+/**
+ * Represents how far to move the current read/write pointer through a file.
+ * You can specify the position as relative to the start of the file,
+ * relative to the end of the file, or relative to the current pointer
+ * position.
+ */
+typedef enum Monotron_Offset_Tag {
+  /**
+   * Set the pointer to this many bytes from the start of the file
+   */
+  FromStart,
+  /**
+   * Set the pointer to this many bytes from the current position (+ve is forwards, -ve is backwards)
+   */
+  FromCurrent,
+  /**
+   * Set the pointer to this many bytes back from the end of the file
+   */
+  FromEnd,
+} Monotron_Offset_Tag;
+
+typedef struct Monotron_Offset {
+  Monotron_Offset_Tag tag;
+  union {
+    struct {
+      uint32_t from_start;
+    };
+    struct {
+      int32_t from_current;
+    };
+    struct {
+      uint32_t from_end;
+    };
+  };
+} Monotron_Offset;
+
+/**
+ * Describes an instant in time. The system only supports local time and has
+ * no concept of time zones.
+ */
+typedef struct Monotron_Timestamp {
+  /**
+   * The Gregorian calendar year, minus 1970 (so 10 is 1980, and 30 is the year 2000)
+   */
+  uint8_t year_from_1970;
+  /**
+   * The month of the year, where January is 1 and December is 12
+   */
+  uint8_t month;
+  /**
+   * The day of the month where 1 is the first of the month, through to 28,
+   * 29, 30 or 31 (as appropriate)
+   */
+  uint8_t days;
+  /**
+   * The hour in the day, from 0 to 23
+   */
+  uint8_t hours;
+  /**
+   * The minutes past the hour, from 0 to 59
+   */
+  uint8_t minutes;
+  /**
+   * The seconds past the minute, from 0 to 59. Note that some filesystems
+   * only have 2-second precision on their timestamps.
+   */
+  uint8_t seconds;
+} Monotron_Timestamp;
+
+/**
+ * Describes a file as it exists on disk.
+ */
+typedef struct Monotron_DirEntry {
+  /**
+   * The file of the file this entry represents
+   */
+  enum Monotron_FileType file_type;
+  /**
+   * The name of the file (not including its full path)
+   */
+  uint8_t name[11];
+  /**
+   * The sie of the file in bytes
+   */
+  uint32_t size;
+  /**
+   * When this file was last modified
+   */
+  struct Monotron_Timestamp mtime;
+  /**
+   * When this file was created
+   */
+  struct Monotron_Timestamp ctime;
+  /**
+   * The various mode bits set on this file
+   */
+  struct Monotron_FileMode mode;
+} Monotron_DirEntry;
+
+/**
+ * Identifies an optional service which can be found with
+ * `Api::get_service`. Each ID says what sort of pointer you get back.
+ */
+typedef struct Monotron_ServiceId {
+  uint32_t _0;
+} Monotron_ServiceId;
+/**
+ * An `extern "C" fn(actual_scanline: u16, drawn_scanline: u16)`, the same
+ * as `Api::map_line`.
+ */
+#define Monotron_ServiceId_MAP_LINE (Monotron_ServiceId){ ._0 = 1 }
+/**
+ * An `extern "C" fn(row: *mut u8, col: *mut u8)`, the same as
+ * `Api::get_cursor`.
+ */
+#define Monotron_ServiceId_GET_CURSOR (Monotron_ServiceId){ ._0 = 2 }
+
+/**
+ * The command-line arguments an application was run with, like `argc` and
+ * `argv` in C. The first argument is the name of the program. The strings
+ * are valid until the application exits.
+ */
+typedef struct Monotron_Args {
+  /**
+   * The number of arguments
+   */
+  uintptr_t argc;
+  /**
+   * Points at `argc` strings
+   */
+  const struct Monotron_BorrowedString *argv;
+} Monotron_Args;
+
+/**
+ * Where the CPU's time goes in each video frame, in CPU clock cycles. The
+ * video and audio interrupts come first; what's left is shared between the
+ * application and the ROM.
+ */
+typedef struct Monotron_CycleBudget {
+  /**
+   * The length of a video frame
+   */
+  uint32_t frame;
+  /**
+   * How long the interrupts took in the last complete frame
+   */
+  uint32_t interrupts;
+  /**
+   * How long the CPU was asleep (waiting for an interrupt) in the last
+   * complete frame
+   */
+  uint32_t idle;
+  /**
+   * Roughly how much of the current frame is left for you, allowing for
+   * the interrupts still to come, before the next vertical blanking
+   * interval
+   */
+  uint32_t remaining;
+} Monotron_CycleBudget;
+
+/**
+ * Identifies a software timer, started with `Api::timer_start`.
+ */
+typedef struct Monotron_TimerId {
+  uint8_t _0;
+} Monotron_TimerId;
+
+/**
+ * Describes the result of a function which may return a `TimerId` if
+ * everything was Ok, or return an `Error` if something went wrong.
+ *
+ * This is not a standard Rust `Result` because they are not `#[repr(C)]`.
+ */
+typedef enum Monotron_TimerResult_Tag {
+  /**
+   * Success - the new timer is returned
+   */
+  Ok,
+  /**
+   * Failure - an error is returned
+   */
+  Error,
+} Monotron_TimerResult_Tag;
+
+typedef struct Monotron_TimerResult {
+  Monotron_TimerResult_Tag tag;
+  union {
+    struct {
+      struct Monotron_TimerId ok;
+    };
+    struct {
+      enum Monotron_Error error;
+    };
+  };
+} Monotron_TimerResult;
+
+/**
+ * Something which has happened, collected with `Api::get_event`.
+ */
+typedef enum Monotron_Event_Tag {
+  /**
+   * Nothing has happened
+   */
+  None,
+  /**
+   * A software timer went off
+   */
+  Timer,
+} Monotron_Event_Tag;
+
+typedef struct Monotron_Event {
+  Monotron_Event_Tag tag;
+  union {
+    struct {
+      struct Monotron_TimerId timer;
+    };
+  };
+} Monotron_Event;
+
+/**
+ * This structure contains all the function pointers the application can use
+ * to access OS functions.
+ *
+ * See the crate-level docs for the rules on adding to it.
+ */
+typedef struct Monotron_Api {
+  /**
+   * Says how big this structure is, what version it is, and what the ROM
+   * can do. Must always come first.
+   */
+  struct Monotron_ApiHeader header;
+  /**
+   * Old function for writing a single 8-bit character to the screen.
+   */
+  int32_t (*putchar)(uint8_t ch);
+  /**
+   * Old function for writing a null-terminated 8-bit string to the screen.
+   */
+  int32_t (*puts)(const uint8_t *string);
+  /**
+   * Old function for reading one byte from stdin, blocking.
+   */
+  int32_t (*readc)(void);
+  /**
+   * Old function for checking if readc() would block.
+   */
+  int32_t (*kbhit)(void);
+  /**
+   * Old function for moving the cursor on screen. To be replaced with ANSI
+   * escape codes.
+   */
+  void (*move_cursor)(uint8_t row, uint8_t col);
+  /**
+   * Old function for playing a note.
+   */
+  int32_t (*play)(uint32_t frequency, uint8_t channel, uint8_t volume, uint8_t waveform);
+  /**
+   * Old function for changing the on-screen font.
+   */
+  void (*change_font)(uint32_t font_id, const uint8_t *font_data);
+  /**
+   * Old function for reading the Joystick status.
+   */
+  uint8_t (*get_joystick)(void);
+  /**
+   * Old function for turning the cursor on/off.
+   */
+  void (*set_cursor_visible)(uint8_t enabled);
+  /**
+   * Old function for reading the contents of the screen.
+   */
+  uint16_t (*read_char_at)(uint8_t row, uint8_t col);
+  /**
+   * Wait for next vertical blanking interval.
+   */
+  void (*wfvbi)(void);
+  /**
+   * Open/create a device/file. Returns a file handle, or an error.
+   */
+  struct Monotron_HandleResult (*open)(struct Monotron_BorrowedString filename,
+                                       struct Monotron_OpenMode mode);
+  /**
+   * Close a previously opened handle.
+   */
+  struct Monotron_EmptyResult (*close)(struct Monotron_Handle handle);
+  /**
+   * Read from a file handle into the given buffer. Returns an error, or
+   * the number of bytes read (which may be less than `buffer_len`).
+   */
+  struct Monotron_SizeResult (*read)(struct Monotron_Handle handle,
+                                     uint8_t *buffer,
+                                     uintptr_t buffer_len);
+  /**
+   * Write the contents of the given buffer to a file handle. Returns an
+   * error, or the number of bytes written (which may be less than
+   * `buffer_len`).
+   */
+  struct Monotron_SizeResult (*write)(struct Monotron_Handle handle,
+                                      const uint8_t *buffer,
+                                      uintptr_t buffer_len);
+  /**
+   * Write to the handle and the read from the handle. Useful when doing an
+   * I2C read of a specific address. It is an error if the complete
+   * `out_buffer` could not be written.
+   */
+  struct Monotron_SizeResult (*write_then_read)(struct Monotron_Handle handle,
+                                                const uint8_t *out_buffer,
+                                                uintptr_t out_buffer_len,
+                                                uint8_t *in_buffer,
+                                                uintptr_t in_buffer_len);
+  /**
+   * Move the read/write pointer in a file.
+   */
+  struct Monotron_EmptyResult (*seek)(struct Monotron_Handle handle, struct Monotron_Offset offset);
+  /**
+   * Open a directory. Returns a file handle, or an error.
+   */
+  struct Monotron_HandleResult (*opendir)(struct Monotron_BorrowedString filename);
+  /**
+   * Read directory entry into given buffer.
+   */
+  struct Monotron_EmptyResult (*readdir)(struct Monotron_Handle handle,
+                                         struct Monotron_DirEntry *dir_entry);
+  /**
+   * Get information about a file by path
+   */
+  struct Monotron_EmptyResult (*stat)(struct Monotron_BorrowedString filename,
+                                      struct Monotron_DirEntry *stat_entry);
+  /**
+   * Get the current time
+   */
+  struct Monotron_Timestamp (*gettime)(void);
+  /**
+   * Old function for writing a UTF-8 string to the screen.
+   */
+  void (*puts_utf8)(const uint8_t *string, uintptr_t length);
+  /**
+   * Maps an actual line on the screen to be drawn as if it was somewhere else on the screen.
+   *
+   * So if you ran this, the image would look completely normal:
+   *
+   * ```rust
+   * # extern "C" fn map_line(_actual_scanline: u16, _drawn_scanline: u16) {}
+   * for x in 0..576 {
+   *     map_line(x, x);
+   * }
+   * ```
+   *
+   * But if you did this, the screen would be upside down.
+   *
+   * ```rust
+   * # extern "C" fn map_line(_actual_scanline: u16, _drawn_scanline: u16) {}
+   * for x in 0..576 {
+   *     map_line(x, 576 - x);
+   * }
+   * ```
+   *
+   * And if you did this, the top 32 scanlines on the screen would repeat
+   * all the way down.
+   *
+   * ```rust
+   * # extern "C" fn map_line(_actual_scanline: u16, _drawn_scanline: u16) {}
+   * for x in 0..576 {
+   *     map_line(x, x % 32);
+   * }
+   * ```
+   */
+  void (*map_line)(uint16_t actual_scanline, uint16_t drawn_scanline);
+  /**
+   * Get the current cursor position
+   */
+  void (*get_cursor)(uint8_t *row, uint8_t *col);
+  /**
+   * Look up an optional service. Returns null if the ROM doesn't have it.
+   * Since 2.0.
+   */
+  const void *(*get_service)(struct Monotron_ServiceId id);
+  /**
+   * Get the command-line arguments the application was run with. Since
+   * 2.1.
+   *
+   * The application's exit status is whatever its entry function returns.
+   * Zero means success.
+   */
+  struct Monotron_Args (*get_args)(void);
+  /**
+   * Find out how many CPU cycles the video and audio leave you, and how
+   * many are left before the next vertical blanking interval. Since 2.2.
+   */
+  struct Monotron_CycleBudget (*get_cycle_budget)(void);
+  /**
+   * Get the number of video frames (at 60 Hz) since boot. Wraps after
+   * about two years. Since 2.3.
+   */
+  uint32_t (*get_ticks)(void);
+  /**
+   * Get the number of microseconds since boot, from a free-running
+   * hardware timer. Since 2.3.
+   */
+  uint64_t (*get_micros)(void);
+  /**
+   * Wait for at least the given number of milliseconds. Since 2.3.
+   */
+  void (*sleep_ms)(uint32_t ms);
+  /**
+   * Start a software timer, which goes off after `period_ms`
+   * milliseconds (and then every `period_ms`, if it's periodic). Each
+   * time it does, `get_event` returns an `Event::Timer`. Timers are
+   * stopped when the application exits. Since 2.3.
+   */
+  struct Monotron_TimerResult (*timer_start)(uint32_t period_ms, enum Monotron_TimerMode mode);
+  /**
+   * Stop a software timer. Since 2.3.
+   */
+  struct Monotron_EmptyResult (*timer_stop)(struct Monotron_TimerId timer);
+  /**
+   * Collect the next event, without blocking. Returns `Event::None` if
+   * nothing has happened. Since 2.3.
+   */
+  struct Monotron_Event (*get_event)(void);
+  /**
+   * Ask the ROM to call `hook(context)` once per video frame, or pass
+   * `None` to stop. The hook runs in the application's context, on its
+   * stack, at the next point after the frame starts where the
+   * application isn't in the middle of an Api call (the calls which wait,
+   * like `readc`, `wfvbi` and `sleep_ms`, let it in while they wait) - so
+   * it may call the Api itself. It won't be called again until it
+   * returns (frames are skipped instead), and it must return within
+   * `VBLANK_HOOK_BUDGET_CYCLES` CPU cycles, including any time it spends
+   * waiting in the Api, or the application is stopped. The hook is
+   * removed when the application exits. Since 2.4.
+   */
+  void (*register_vblank_hook)(struct Monotron_Option_VblankHook hook, void *context);
+} Monotron_Api;
+
+/**
+ * The version of the `Api` structure described by this crate.
+ */
+#define Monotron_API_VERSION (Monotron_ApiVersion){ .major = 2, .minor = 4 }
+
+/**
+ * Is the read-only bit set in this FileMode bit-field?
+ */
+bool monotron_filemode_is_readonly(struct Monotron_FileMode flags);
+
+/**
+ * Is the volume label bit set in this FileMode bit-field?
+ */
+bool monotron_filemode_is_volume(struct Monotron_FileMode flags);
+
+/**
+ * Is the system bit set in this FileMode bit-field?
+ */
+bool monotron_filemode_is_system(struct Monotron_FileMode flags);
+
+/**
+ * Is the archive bit set in this FileMode bit-field?
+ */
+bool monotron_filemode_is_archive(struct Monotron_FileMode flags);
+
+/**
+ * Create a new Read Only open mode object, for passing to the `open` syscall.
+ */
+struct Monotron_OpenMode monotron_openmode_readonly(bool non_blocking);
+
+/**
+ * Create a new Write Only open mode object, for passing to the `open` syscall.
+ */
+struct Monotron_OpenMode monotron_openmode_writeonly(bool append,
+                                                     bool create,
+                                                     bool exclusive,
+                                                     bool truncate,
+                                                     bool non_blocking);
+
+/**
+ * Create a new Read Write open mode object, for passing to the `open` syscall.
+ */
+struct Monotron_OpenMode monotron_openmode_readwrite(bool append,
+                                                     bool create,
+                                                     bool exclusive,
+                                                     bool truncate,
+                                                     bool non_blocking);
@@ -11,9 +11,33 @@
 //! interrupts), provided in a structure. This structure is designed to be
 //! extensible.
 //!
-//! A C header file version of this API can be generated with `cbindgen`.
+//! A C header file version of this API is generated with `cbindgen` and
+//! checked in as `generated/monotron_api.h`. A test fails if it's out of
+//! date, so run `scripts/make_api.sh` after changing anything here.
 //!
-//! All types in this file must be `#[repr(C)]`.
+//! All types in this file must be `#[repr(C)]`. The tests also pin down the
+//! size and layout of the types in the `Api`, so an accidental change to the
+//! ABI shows up as a failing test.
+//!
+//! ## Extending the API
+//!
+//! The `Api` structure starts with an `ApiHeader`, which gives the size of
+//! the structure the ROM is actually providing, its version and which
+//! optional features the ROM has. So that one application binary can run on
+//! many ROM releases, these rules must be followed:
+//!
+//! 1. Existing entries are never removed, re-ordered or changed. If that ever
+//!    has to happen, `API_VERSION.major` goes up and every application must be
+//!    rebuilt.
+//! 2. New entries are only ever added to the end of `Api`, and each addition
+//!    bumps `API_VERSION.minor`. The entry's docs must say which version it
+//!    appeared in. An application must check `Api::supports` before using
+//!    an entry newer than the version it asked for in its `AppHeader`.
+//! 3. Features which not every ROM can offer (say, because the hardware
+//!    doesn't have them) get a bit in `Capabilities`. Bits are never re-used.
+//! 4. Optional services which don't warrant an `Api` entry of their own get a
+//!    `ServiceId`, and are found with `Api::get_service`. IDs are never
+//!    re-used, and a null result means the ROM doesn't offer that service.
 #![cfg_attr(not(test), no_std)]
 #![deny(missing_docs)]
 
@@ -29,6 +53,10 @@ pub enum Error {
     IOError,
     /// You can't do that operation on that sort of file
     NotSupported,
+    /// There are no free timers. Since 2.3.
+    NoFreeTimers,
+    /// The given timer was not valid. Since 2.3.
+    BadTimerId,
     /// An unknown error occured
     Unknown = 0xFFFF,
 }
@@ -43,7 +71,7 @@ pub struct Handle(pub u16);
 /// be present. The string must be valid UTF-8 (or 7-bit ASCII, which is a
 /// valid subset of UTF-8).
 #[repr(C)]
-#[derive(Debug, Clone, Eq)]
+#[derive(Debug, Copy, Clone, Eq)]
 pub struct BorrowedString {
     /// The start of the string
     pub ptr: *const u8,
@@ -61,6 +89,17 @@ impl BorrowedString {
     }
 }
 
+impl BorrowedString {
+    /// Get the string back. Returns `None` if it isn't valid UTF-8.
+    pub fn as_str(&self) -> Option<&str> {
+        if self.ptr.is_null() {
+            return Some("");
+        }
+        let bytes = unsafe { core::slice::from_raw_parts(self.ptr, self.length) };
+        core::str::from_utf8(bytes).ok()
+    }
+}
+
 impl core::cmp::PartialEq for BorrowedString {
     fn eq(&self, rhs: &BorrowedString) -> bool {
         if self.length == rhs.length {
@@ -73,6 +112,109 @@ impl core::cmp::PartialEq for BorrowedString {
     }
 }
 
+/// The command-line arguments an application was run with, like `argc` and
+/// `argv` in C. The first argument is the name of the program. The strings
+/// are valid until the application exits.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct Args {
+    /// The number of arguments
+    pub argc: usize,
+    /// Points at `argc` strings
+    pub argv: *const BorrowedString,
+}
+
+impl Args {
+    /// Get all the arguments.
+    pub fn as_slice(&self) -> &[BorrowedString] {
+        if self.argv.is_null() {
+            &[]
+        } else {
+            unsafe { core::slice::from_raw_parts(self.argv, self.argc) }
+        }
+    }
+
+    /// Get one argument. Argument 0 is the name of the program.
+    pub fn get(&self, idx: usize) -> Option<&str> {
+        self.as_slice().get(idx).and_then(|s| s.as_str())
+    }
+}
+
+/// A function for the ROM to call once per video frame. It's given the
+/// `context` pointer it was registered with. See
+/// `Api::register_vblank_hook`.
+pub type VblankHook = extern "C" fn(context: *mut core::ffi::c_void);
+
+/// How many CPU cycles a `VblankHook` may use each time it's called, not
+/// counting the video and audio interrupts. That's about a third of what's
+/// usually left over in a frame.
+pub const VBLANK_HOOK_BUDGET_CYCLES: u32 = 200_000;
+
+/// Where the CPU's time goes in each video frame, in CPU clock cycles. The
+/// video and audio interrupts come first; what's left is shared between the
+/// application and the ROM.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct CycleBudget {
+    /// The length of a video frame
+    pub frame: u32,
+    /// How long the interrupts took in the last complete frame
+    pub interrupts: u32,
+    /// How long the CPU was asleep (waiting for an interrupt) in the last
+    /// complete frame
+    pub idle: u32,
+    /// Roughly how much of the current frame is left for you, allowing for
+    /// the interrupts still to come, before the next vertical blanking
+    /// interval
+    pub remaining: u32,
+}
+
+impl CycleBudget {
+    /// How much of the frame is left for the application and the ROM, once
+    /// the interrupts have had their share.
+    pub fn available(&self) -> u32 {
+        self.frame.saturating_sub(self.interrupts)
+    }
+}
+
+/// Identifies a software timer, started with `Api::timer_start`.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TimerId(pub u8);
+
+/// Whether a software timer goes off once, or keeps going off.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TimerMode {
+    /// Go off once, then stop
+    OneShot,
+    /// Go off every period, until stopped
+    Periodic,
+}
+
+/// Describes the result of a function which may return a `TimerId` if
+/// everything was Ok, or return an `Error` if something went wrong.
+///
+/// This is not a standard Rust `Result` because they are not `#[repr(C)]`.
+#[repr(C)]
+#[derive(Debug)]
+pub enum TimerResult {
+    /// Success - the new timer is returned
+    Ok(TimerId),
+    /// Failure - an error is returned
+    Error(Error),
+}
+
+/// Something which has happened, collected with `Api::get_event`.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// Nothing has happened
+    None,
+    /// A software timer went off
+    Timer(TimerId),
+}
+
 /// Describes the result of a function which may return a `Handle` if
 /// everything was Ok, or return an `Error` if something went wrong.
 ///
@@ -347,6 +489,16 @@ impl FileMode {
     const VOLUME: u8 = 2;
     const SYSTEM: u8 = 4;
     const ARCHIVE: u8 = 8;
+
+    /// Create a FileMode bit-field, for filling in a `DirEntry`.
+    pub const fn new(read_only: bool, volume: bool, system: bool, archive: bool) -> FileMode {
+        FileMode(
+            (read_only as u8 * FileMode::READ_ONLY)
+                | (volume as u8 * FileMode::VOLUME)
+                | (system as u8 * FileMode::SYSTEM)
+                | (archive as u8 * FileMode::ARCHIVE),
+        )
+    }
 }
 
 /// Represents how far to move the current read/write pointer through a file.
@@ -453,10 +605,270 @@ pub static STDERR: Handle = Handle(1);
 /// Standard Input
 pub static STDIN: Handle = Handle(2);
 
+/// Identifies a version of the `Api` structure.
+///
+/// The major version changes when existing entries move or change, so an
+/// application must have been built against the same major version as the
+/// ROM. The minor version changes when entries are added to the end, so an
+/// application will run on any ROM with the same or a higher minor version.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ApiVersion {
+    /// Changes when the `Api` changes in an incompatible way
+    pub major: u16,
+    /// Changes when new entries are added to the end of the `Api`
+    pub minor: u16,
+}
+
+impl ApiVersion {
+    /// Can an `Api` of this version be used by an application which needs
+    /// the `required` version?
+    pub fn satisfies(&self, required: ApiVersion) -> bool {
+        (self.major == required.major) && (self.minor >= required.minor)
+    }
+}
+
+impl core::fmt::Display for ApiVersion {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// The version of the `Api` structure described by this crate.
+pub const API_VERSION: ApiVersion = ApiVersion { major: 2, minor: 4 };
+
+/// Optional features a ROM might have. Check them with `Api::has`.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    /// No optional features.
+    pub const NONE: Capabilities = Capabilities(0);
+    /// There is an SD card, so `open`, `read`, `opendir` and friends can
+    /// access files.
+    pub const FILES: Capabilities = Capabilities(1 << 0);
+    /// `map_line` works.
+    pub const MAP_LINE: Capabilities = Capabilities(1 << 1);
+    /// `get_cursor` works.
+    pub const GET_CURSOR: Capabilities = Capabilities(1 << 2);
+    /// There is a real-time clock, so `gettime` returns the actual time.
+    pub const CLOCK: Capabilities = Capabilities(1 << 3);
+    /// There is a synthesiser, so `play` makes a noise.
+    pub const AUDIO: Capabilities = Capabilities(1 << 4);
+
+    /// Combine two sets of capabilities.
+    #[must_use]
+    pub const fn union(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+
+    /// Does this set include every capability in `other`?
+    pub fn contains(self, other: Capabilities) -> bool {
+        (self.0 & other.0) == other.0
+    }
+}
+
+/// Comes at the start of the `Api` structure, so an application can find out
+/// what the ROM it is running on can do.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ApiHeader {
+    /// The size of the ROM's `Api` structure in bytes, including this header.
+    pub size: u32,
+    /// The version of the ROM's `Api` structure.
+    pub version: ApiVersion,
+    /// The optional features the ROM has.
+    pub capabilities: Capabilities,
+}
+
+impl ApiHeader {
+    /// Make the header for an `Api` structure built against this crate.
+    pub const fn new(capabilities: Capabilities) -> ApiHeader {
+        ApiHeader {
+            size: core::mem::size_of::<Api>() as u32,
+            version: API_VERSION,
+            capabilities,
+        }
+    }
+}
+
+/// Identifies an optional service which can be found with
+/// `Api::get_service`. Each ID says what sort of pointer you get back.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ServiceId(pub u32);
+
+impl ServiceId {
+    /// An `extern "C" fn(actual_scanline: u16, drawn_scanline: u16)`, the same
+    /// as `Api::map_line`.
+    pub const MAP_LINE: ServiceId = ServiceId(1);
+    /// An `extern "C" fn(row: *mut u8, col: *mut u8)`, the same as
+    /// `Api::get_cursor`.
+    pub const GET_CURSOR: ServiceId = ServiceId(2);
+}
+
+/// The value of `AppHeader::magic`.
+pub const APP_HEADER_MAGIC: [u8; 4] = *b"MTAP";
+
+/// Where the `AppHeader` goes in an application image, in bytes from the
+/// start of application RAM (i.e. just after the entry point address).
+pub const APP_HEADER_OFFSET: usize = 4;
+
+/// An optional header which an application can put at `APP_HEADER_OFFSET`.
+/// It lets the ROM check the application was loaded correctly, and that it
+/// will work with this ROM, before running it.
+///
+/// In Rust, you might write:
+///
+/// ```rust
+/// # use monotron_api::*;
+/// #[link_section = ".app_header"]
+/// #[no_mangle]
+/// pub static APP_HEADER: AppHeader = AppHeader::new(API_VERSION, 1024, *b"HELLO\0\0\0\0\0\0\0\0\0\0\0");
+/// ```
+///
+/// The `image_len` and `crc32` fields can only be filled in after linking,
+/// with `monotron-cli stamp`. If `image_len` is zero, the image isn't
+/// checked.
+#[repr(C)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppHeader {
+    /// Must be `APP_HEADER_MAGIC`
+    pub magic: [u8; 4],
+    /// The oldest `Api` this application will work with
+    pub min_api_version: ApiVersion,
+    /// How many bytes, from the start of application RAM, are covered by
+    /// `crc32`. Zero if the image hasn't been stamped.
+    pub image_len: u32,
+    /// The CRC-32 of the first `image_len` bytes, calculated as if this
+    /// field was zero.
+    pub crc32: u32,
+    /// How many bytes of stack the application needs
+    pub stack_len: u32,
+    /// The application's name, in UTF-8, padded with nulls
+    pub name: [u8; 16],
+}
+
+impl AppHeader {
+    /// The size of the header in bytes.
+    pub const LEN: usize = 36;
+
+    /// Where `crc32` is, relative to the start of the header.
+    const CRC32_OFFSET: usize = 12;
+
+    /// Create a new, unstamped, header.
+    pub const fn new(min_api_version: ApiVersion, stack_len: u32, name: [u8; 16]) -> AppHeader {
+        AppHeader {
+            magic: APP_HEADER_MAGIC,
+            min_api_version,
+            image_len: 0,
+            crc32: 0,
+            stack_len,
+            name,
+        }
+    }
+
+    /// Find the header in an application image (which starts at the start of
+    /// application RAM). Returns `None` if there isn't one.
+    pub fn parse(image: &[u8]) -> Option<AppHeader> {
+        let data = image.get(APP_HEADER_OFFSET..APP_HEADER_OFFSET + Self::LEN)?;
+        if data[0..4] != APP_HEADER_MAGIC {
+            return None;
+        }
+        let read_u16 = |offset: usize| u16::from(data[offset]) | (u16::from(data[offset + 1]) << 8);
+        let read_u32 =
+            |offset: usize| u32::from(read_u16(offset)) | (u32::from(read_u16(offset + 2)) << 16);
+        let mut name = [0u8; 16];
+        name.copy_from_slice(&data[20..36]);
+        Some(AppHeader {
+            magic: APP_HEADER_MAGIC,
+            min_api_version: ApiVersion {
+                major: read_u16(4),
+                minor: read_u16(6),
+            },
+            image_len: read_u32(8),
+            crc32: read_u32(12),
+            stack_len: read_u32(16),
+            name,
+        })
+    }
+
+    /// The application name, up to the first null. Returns `"?"` if it isn't
+    /// valid UTF-8.
+    pub fn name(&self) -> &str {
+        let len = self
+            .name
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(self.name.len());
+        core::str::from_utf8(&self.name[0..len]).unwrap_or("?")
+    }
+
+    /// Has `monotron-cli stamp` filled in the length and CRC?
+    pub fn is_stamped(&self) -> bool {
+        self.image_len != 0
+    }
+
+    /// Calculate the CRC-32 of an image, in the same way as `crc32` (that is,
+    /// as if the `crc32` field was zero). The image must contain a header.
+    pub fn calculate_crc32(image: &[u8]) -> u32 {
+        let crc_start = APP_HEADER_OFFSET + Self::CRC32_OFFSET;
+        let mut crc = 0xFFFF_FFFFu32;
+        for (idx, &b) in image.iter().enumerate() {
+            let b = if idx >= crc_start && idx < crc_start + 4 {
+                0
+            } else {
+                b
+            };
+            crc ^= u32::from(b);
+            for _ in 0..8 {
+                crc = if (crc & 1) != 0 {
+                    (crc >> 1) ^ 0xEDB8_8320
+                } else {
+                    crc >> 1
+                };
+            }
+        }
+        !crc
+    }
+
+    /// Fill in the `image_len` and `crc32` fields of the header in the given
+    /// image, so that it covers the whole image. Returns `false` if the image
+    /// doesn't have a header.
+    pub fn stamp(image: &mut [u8]) -> bool {
+        if AppHeader::parse(image).is_none() {
+            return false;
+        }
+        let len_offset = APP_HEADER_OFFSET + 8;
+        let crc_offset = APP_HEADER_OFFSET + Self::CRC32_OFFSET;
+        let len = image.len() as u32;
+        image[len_offset..len_offset + 4].copy_from_slice(&len.to_le_bytes());
+        let crc = AppHeader::calculate_crc32(image);
+        image[crc_offset..crc_offset + 4].copy_from_slice(&crc.to_le_bytes());
+        true
+    }
+
+    /// Check the CRC-32 of an image against the one in this header. The
+    /// image must be at least `image_len` bytes long.
+    pub fn check_crc32(&self, image: &[u8]) -> bool {
+        match image.get(0..self.image_len as usize) {
+            Some(image) => AppHeader::calculate_crc32(image) == self.crc32,
+            None => false,
+        }
+    }
+}
+
 /// This structure contains all the function pointers the application can use
 /// to access OS functions.
+///
+/// See the crate-level docs for the rules on adding to it.
 #[repr(C)]
 pub struct Api {
+    /// Says how big this structure is, what version it is, and what the ROM
+    /// can do. Must always come first.
+    pub header: ApiHeader,
+
     /// Old function for writing a single 8-bit character to the screen.
     pub putchar: extern "C" fn(ch: u8) -> i32,
 
@@ -540,6 +952,7 @@ pub struct Api {
     /// So if you ran this, the image would look completely normal:
     ///
     /// ```rust
+    /// # extern "C" fn map_line(_actual_scanline: u16, _drawn_scanline: u16) {}
     /// for x in 0..576 {
     ///     map_line(x, x);
     /// }
@@ -548,6 +961,7 @@ pub struct Api {
     /// But if you did this, the screen would be upside down.
     ///
     /// ```rust
+    /// # extern "C" fn map_line(_actual_scanline: u16, _drawn_scanline: u16) {}
     /// for x in 0..576 {
     ///     map_line(x, 576 - x);
     /// }
@@ -557,6 +971,7 @@ pub struct Api {
     /// all the way down.
     ///
     /// ```rust
+    /// # extern "C" fn map_line(_actual_scanline: u16, _drawn_scanline: u16) {}
     /// for x in 0..576 {
     ///     map_line(x, x % 32);
     /// }
@@ -564,12 +979,147 @@ pub struct Api {
     pub map_line: extern "C" fn(actual_scanline: u16, drawn_scanline: u16),
     /// Get the current cursor position
     pub get_cursor: extern "C" fn(row: *mut u8, col: *mut u8),
+
+    /// Look up an optional service. Returns null if the ROM doesn't have it.
+    /// Since 2.0.
+    pub get_service: extern "C" fn(id: ServiceId) -> *const core::ffi::c_void,
+
+    /// Get the command-line arguments the application was run with. Since
+    /// 2.1.
+    ///
+    /// The application's exit status is whatever its entry function returns.
+    /// Zero means success.
+    pub get_args: extern "C" fn() -> Args,
+
+    /// Find out how many CPU cycles the video and audio leave you, and how
+    /// many are left before the next vertical blanking interval. Since 2.2.
+    pub get_cycle_budget: extern "C" fn() -> CycleBudget,
+
+    /// Get the number of video frames (at 60 Hz) since boot. Wraps after
+    /// about two years. Since 2.3.
+    pub get_ticks: extern "C" fn() -> u32,
+
+    /// Get the number of microseconds since boot, from a free-running
+    /// hardware timer. Since 2.3.
+    pub get_micros: extern "C" fn() -> u64,
+
+    /// Wait for at least the given number of milliseconds. Since 2.3.
+    pub sleep_ms: extern "C" fn(ms: u32),
+
+    /// Start a software timer, which goes off after `period_ms`
+    /// milliseconds (and then every `period_ms`, if it's periodic). Each
+    /// time it does, `get_event` returns an `Event::Timer`. Timers are
+    /// stopped when the application exits. Since 2.3.
+    pub timer_start: extern "C" fn(period_ms: u32, mode: TimerMode) -> TimerResult,
+
+    /// Stop a software timer. Since 2.3.
+    pub timer_stop: extern "C" fn(timer: TimerId) -> EmptyResult,
+
+    /// Collect the next event, without blocking. Returns `Event::None` if
+    /// nothing has happened. Since 2.3.
+    pub get_event: extern "C" fn() -> Event,
+
+    /// Ask the ROM to call `hook(context)` once per video frame, or pass
+    /// `None` to stop. The hook runs in the application's context, on its
+    /// stack, at the next point after the frame starts where the
+    /// application isn't in the middle of an Api call (the calls which wait,
+    /// like `readc`, `wfvbi` and `sleep_ms`, let it in while they wait) - so
+    /// it may call the Api itself. It won't be called again until it
+    /// returns (frames are skipped instead), and it must return within
+    /// `VBLANK_HOOK_BUDGET_CYCLES` CPU cycles, including any time it spends
+    /// waiting in the Api, or the application is stopped. The hook is
+    /// removed when the application exits. Since 2.4.
+    pub register_vblank_hook:
+        extern "C" fn(hook: Option<VblankHook>, context: *mut core::ffi::c_void),
+}
+
+impl Api {
+    /// The version of this `Api`.
+    pub fn version(&self) -> ApiVersion {
+        self.header.version
+    }
+
+    /// Does this `Api` have every entry which an `Api` of the `required`
+    /// version has?
+    pub fn supports(&self, required: ApiVersion) -> bool {
+        self.header.version.satisfies(required)
+    }
+
+    /// Does the ROM have all of the given optional features?
+    pub fn has(&self, capabilities: Capabilities) -> bool {
+        self.header.capabilities.contains(capabilities)
+    }
+
+    /// Look up an optional service. Returns `None` if the ROM doesn't have
+    /// it. See `ServiceId` for what the pointer points at.
+    pub fn service(&self, id: ServiceId) -> Option<*const core::ffi::c_void> {
+        let ptr = (self.get_service)(id);
+        if ptr.is_null() {
+            None
+        } else {
+            Some(ptr)
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn capabilities() {
+        let caps = Capabilities::FILES.union(Capabilities::MAP_LINE);
+        assert!(caps.contains(Capabilities::FILES));
+        assert!(caps.contains(Capabilities::MAP_LINE));
+        assert!(caps.contains(Capabilities::NONE));
+        assert!(!caps.contains(Capabilities::GET_CURSOR));
+        assert!(!caps.contains(Capabilities::FILES.union(Capabilities::CLOCK)));
+    }
+
+    #[test]
+    fn args() {
+        let argv = [
+            BorrowedString::new("PLAY.BIN"),
+            BorrowedString::new("SONG.MOD"),
+        ];
+        let args = Args {
+            argc: argv.len(),
+            argv: argv.as_ptr(),
+        };
+        assert_eq!(args.get(0), Some("PLAY.BIN"));
+        assert_eq!(args.get(1), Some("SONG.MOD"));
+        assert_eq!(args.get(2), None);
+        let empty = Args {
+            argc: 0,
+            argv: core::ptr::null(),
+        };
+        assert_eq!(empty.get(0), None);
+    }
+
+    #[test]
+    fn api_header() {
+        let header = ApiHeader::new(Capabilities::FILES);
+        assert_eq!(header.size as usize, core::mem::size_of::<Api>());
+        assert_eq!(header.version, API_VERSION);
+        assert_eq!(core::mem::size_of::<ApiHeader>(), 12);
+    }
+
+    #[test]
+    fn cycle_budget() {
+        let budget = CycleBudget {
+            frame: 1000,
+            interrupts: 300,
+            idle: 500,
+            remaining: 100,
+        };
+        assert_eq!(budget.available(), 700);
+        let overloaded = CycleBudget {
+            interrupts: 1200,
+            ..budget
+        };
+        assert_eq!(overloaded.available(), 0);
+    }
+
     #[test]
     fn day_of_week() {
         let samples = [
@@ -577,7 +1127,7 @@ mod test {
                 Timestamp {
                     year_from_1970: 49,
                     month: 7,
-                    day: 16,
+                    days: 16,
                     hours: 0,
                     minutes: 0,
                     seconds: 0,
@@ -588,7 +1138,7 @@ mod test {
                 Timestamp {
                     year_from_1970: 49,
                     month: 7,
-                    day: 17,
+                    days: 17,
                     hours: 0,
                     minutes: 0,
                     seconds: 0,
@@ -599,7 +1149,7 @@ mod test {
                 Timestamp {
                     year_from_1970: 49,
                     month: 7,
-                    day: 18,
+                    days: 18,
                     hours: 0,
                     minutes: 0,
                     seconds: 0,
@@ -611,4 +1161,258 @@ mod test {
             assert_eq!(timestamp.day_of_week(), *day);
         }
     }
+
+    #[test]
+    fn app_header() {
+        let header = AppHeader::new(
+            ApiVersion { major: 1, minor: 0 },
+            2048,
+            *b"TEST\0\0\0\0\0\0\0\0\0\0\0\0",
+        );
+        let mut image = vec![0xAAu8; 100];
+        image[APP_HEADER_OFFSET..APP_HEADER_OFFSET + 4].copy_from_slice(&header.magic);
+        image[APP_HEADER_OFFSET + 4..APP_HEADER_OFFSET + 8].copy_from_slice(&[1, 0, 0, 0]);
+        image[APP_HEADER_OFFSET + 8..APP_HEADER_OFFSET + 16].copy_from_slice(&[0u8; 8]);
+        image[APP_HEADER_OFFSET + 16..APP_HEADER_OFFSET + 20]
+            .copy_from_slice(&2048u32.to_le_bytes());
+        image[APP_HEADER_OFFSET + 20..APP_HEADER_OFFSET + 36].copy_from_slice(&header.name);
+        let parsed = AppHeader::parse(&image).unwrap();
+        assert_eq!(parsed, header);
+        assert_eq!(parsed.name(), "TEST");
+        assert!(!parsed.is_stamped());
+
+        assert!(AppHeader::stamp(&mut image));
+        let parsed = AppHeader::parse(&image).unwrap();
+        assert_eq!(parsed.image_len, 100);
+        assert!(parsed.check_crc32(&image));
+        image[99] = 0x55;
+        assert!(!parsed.check_crc32(&image));
+        assert!(!parsed.check_crc32(&image[0..50]));
+
+        assert!(AppHeader::parse(&[0u8; 100]).is_none());
+        assert!(!AppHeader::stamp(&mut [0u8; 100]));
+    }
+
+    #[test]
+    fn api_version() {
+        let rom = ApiVersion { major: 1, minor: 2 };
+        assert!(rom.satisfies(ApiVersion { major: 1, minor: 0 }));
+        assert!(rom.satisfies(ApiVersion { major: 1, minor: 2 }));
+        assert!(!rom.satisfies(ApiVersion { major: 1, minor: 3 }));
+        assert!(!rom.satisfies(ApiVersion { major: 2, minor: 0 }));
+        assert_eq!(core::mem::size_of::<AppHeader>(), AppHeader::LEN);
+    }
+
+    // The ABI tests below pin down the layout applications were built
+    // against. If one fails, you've broken every existing application -
+    // see the crate-level docs for how to change the `Api` safely. The
+    // expected values hold for the ROM (where pointers are four bytes) as
+    // well as for the host these tests run on.
+
+    /// The size of a pointer (and of `usize`).
+    const PTR: usize = core::mem::size_of::<usize>();
+
+    /// The offset of a field within a struct, in bytes.
+    macro_rules! offset_of {
+        ($type:ty, $field:ident) => {{
+            let value = core::mem::MaybeUninit::<$type>::uninit();
+            let base = value.as_ptr();
+            let field = unsafe { core::ptr::addr_of!((*base).$field) };
+            field as usize - base as usize
+        }};
+    }
+
+    /// The size and alignment of a type.
+    fn layout<T>() -> (usize, usize) {
+        (core::mem::size_of::<T>(), core::mem::align_of::<T>())
+    }
+
+    /// Read a `u32` from somewhere inside a value.
+    fn read_u32<T>(value: &T, offset: usize) -> u32 {
+        assert!(offset + 4 <= core::mem::size_of::<T>());
+        unsafe {
+            core::ptr::read_unaligned((value as *const T as *const u8).add(offset) as *const u32)
+        }
+    }
+
+    /// Read a byte from somewhere inside a value.
+    fn read_u8<T>(value: &T, offset: usize) -> u8 {
+        assert!(offset < core::mem::size_of::<T>());
+        unsafe { *(value as *const T as *const u8).add(offset) }
+    }
+
+    #[test]
+    fn abi_structs() {
+        assert_eq!(layout::<Handle>(), (2, 2));
+        assert_eq!(layout::<FileMode>(), (1, 1));
+        assert_eq!(layout::<TimerId>(), (1, 1));
+
+        assert_eq!(layout::<BorrowedString>(), (2 * PTR, PTR));
+        assert_eq!(offset_of!(BorrowedString, ptr), 0);
+        assert_eq!(offset_of!(BorrowedString, length), PTR);
+
+        assert_eq!(layout::<Timestamp>(), (6, 1));
+        assert_eq!(offset_of!(Timestamp, year_from_1970), 0);
+        assert_eq!(offset_of!(Timestamp, month), 1);
+        assert_eq!(offset_of!(Timestamp, days), 2);
+        assert_eq!(offset_of!(Timestamp, hours), 3);
+        assert_eq!(offset_of!(Timestamp, minutes), 4);
+        assert_eq!(offset_of!(Timestamp, seconds), 5);
+
+        assert_eq!(layout::<DirEntry>(), (36, 4));
+        assert_eq!(offset_of!(DirEntry, file_type), 0);
+        assert_eq!(offset_of!(DirEntry, name), 4);
+        assert_eq!(offset_of!(DirEntry, size), 16);
+        assert_eq!(offset_of!(DirEntry, mtime), 20);
+        assert_eq!(offset_of!(DirEntry, ctime), 26);
+        assert_eq!(offset_of!(DirEntry, mode), 32);
+
+        assert_eq!(layout::<ApiHeader>(), (12, 4));
+        assert_eq!(offset_of!(ApiHeader, size), 0);
+        assert_eq!(offset_of!(ApiHeader, version), 4);
+        assert_eq!(offset_of!(ApiHeader, capabilities), 8);
+    }
+
+    #[test]
+    fn abi_enums() {
+        assert_eq!(layout::<Error>(), (4, 4));
+        assert_eq!(Error::FileNotFound as u32, 0);
+        assert_eq!(Error::BadTimerId as u32, 5);
+        assert_eq!(Error::Unknown as u32, 0xFFFF);
+        assert_eq!(layout::<FileType>(), (4, 4));
+        assert_eq!(FileType::CharDevice as u32, 3);
+        assert_eq!(layout::<TimerMode>(), (4, 4));
+
+        // The data-carrying enums are a 32-bit tag followed by the payload
+        assert_eq!(layout::<HandleResult>(), (8, 4));
+        let ok = HandleResult::Ok(Handle(0x1234));
+        assert_eq!(
+            (read_u32(&ok, 0), read_u8(&ok, 4), read_u8(&ok, 5)),
+            (0, 0x34, 0x12)
+        );
+        let err = HandleResult::Error(Error::NotSupported);
+        assert_eq!((read_u32(&err, 0), read_u32(&err, 4)), (1, 3));
+
+        assert_eq!(layout::<EmptyResult>(), (8, 4));
+        let err = EmptyResult::Error(Error::IOError);
+        assert_eq!((read_u32(&err, 0), read_u32(&err, 4)), (1, 2));
+
+        assert_eq!(layout::<SizeResult>(), (2 * PTR, PTR));
+        let ok = SizeResult::Ok(0x5678);
+        assert_eq!((read_u32(&ok, 0), read_u32(&ok, PTR)), (0, 0x5678));
+        let err = SizeResult::Error(Error::BadFileHandle);
+        assert_eq!((read_u32(&err, 0), read_u32(&err, PTR)), (1, 1));
+
+        assert_eq!(layout::<TimerResult>(), (8, 4));
+        let ok = TimerResult::Ok(TimerId(7));
+        assert_eq!((read_u32(&ok, 0), read_u8(&ok, 4)), (0, 7));
+        let err = TimerResult::Error(Error::NoFreeTimers);
+        assert_eq!((read_u32(&err, 0), read_u32(&err, 4)), (1, 4));
+
+        assert_eq!(layout::<Event>(), (8, 4));
+        let timer = Event::Timer(TimerId(3));
+        assert_eq!((read_u32(&timer, 0), read_u8(&timer, 4)), (1, 3));
+        assert_eq!(read_u32(&Event::None, 0), 0);
+
+        assert_eq!(layout::<Offset>(), (8, 4));
+        let offset = Offset::FromCurrent(-2);
+        assert_eq!(
+            (read_u32(&offset, 0), read_u32(&offset, 4)),
+            (1, 0xFFFF_FFFE)
+        );
+
+        assert_eq!(layout::<OpenMode>(), (12, 4));
+        let mode = monotron_openmode_readonly(true);
+        assert_eq!((read_u32(&mode, 0), read_u8(&mode, 4)), (0, 1));
+        let mode = monotron_openmode_readwrite(true, false, true, false, true);
+        assert_eq!(read_u32(&mode, 0), 2);
+        let flags: Vec<u8> = (4..9).map(|offset| read_u8(&mode, offset)).collect();
+        assert_eq!(flags, [1, 0, 1, 0, 1]);
+    }
+
+    #[test]
+    fn abi_api() {
+        // The header, then one function pointer after another
+        let first = (core::mem::size_of::<ApiHeader>() + PTR - 1) / PTR * PTR;
+        let slot = |n: usize| first + n * PTR;
+        assert_eq!(offset_of!(Api, header), 0);
+        let offsets = [
+            offset_of!(Api, putchar),
+            offset_of!(Api, puts),
+            offset_of!(Api, readc),
+            offset_of!(Api, kbhit),
+            offset_of!(Api, move_cursor),
+            offset_of!(Api, play),
+            offset_of!(Api, change_font),
+            offset_of!(Api, get_joystick),
+            offset_of!(Api, set_cursor_visible),
+            offset_of!(Api, read_char_at),
+            offset_of!(Api, wfvbi),
+            offset_of!(Api, open),
+            offset_of!(Api, close),
+            offset_of!(Api, read),
+            offset_of!(Api, write),
+            offset_of!(Api, write_then_read),
+            offset_of!(Api, seek),
+            offset_of!(Api, opendir),
+            offset_of!(Api, readdir),
+            offset_of!(Api, stat),
+            offset_of!(Api, gettime),
+            offset_of!(Api, puts_utf8),
+            offset_of!(Api, map_line),
+            offset_of!(Api, get_cursor),
+            // 2.0
+            offset_of!(Api, get_service),
+            // 2.1
+            offset_of!(Api, get_args),
+            // 2.2
+            offset_of!(Api, get_cycle_budget),
+            // 2.3
+            offset_of!(Api, get_ticks),
+            offset_of!(Api, get_micros),
+            offset_of!(Api, sleep_ms),
+            offset_of!(Api, timer_start),
+            offset_of!(Api, timer_stop),
+            offset_of!(Api, get_event),
+            // 2.4
+            offset_of!(Api, register_vblank_hook),
+        ];
+        for (n, offset) in offsets.iter().enumerate() {
+            assert_eq!(*offset, slot(n), "entry {} has moved", n);
+        }
+        // Adding an entry means adding it here, and bumping the minor version
+        assert_eq!(layout::<Api>(), (slot(offsets.len()), PTR));
+        assert_eq!(API_VERSION, ApiVersion { major: 2, minor: 4 });
+        if PTR == 4 {
+            // What applications on the Monotron see
+            assert_eq!(core::mem::size_of::<Api>(), 148);
+        }
+    }
+
+    /// `generated/monotron_api.h` is checked in, so changes to the C `Api`
+    /// show up in review. Run `scripts/make_api.sh` to update it.
+    #[test]
+    fn c_header() {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR"));
+        let config = cbindgen::Config::from_file(dir.join("cbindgen.toml")).unwrap();
+        let mut generated = Vec::new();
+        cbindgen::Builder::new()
+            .with_config(config)
+            .with_language(cbindgen::Language::C)
+            .with_style(cbindgen::Style::Both)
+            .with_src(dir.join("src").join("lib.rs"))
+            .generate()
+            .unwrap()
+            .write(&mut generated);
+        let path = dir.join("generated").join("monotron_api.h");
+        if std::env::var_os("UPDATE_HEADER").is_some() {
+            std::fs::write(&path, &generated).unwrap();
+        }
+        let checked_in = std::fs::read(&path).unwrap_or_default();
+        assert!(
+            generated == checked_in,
+            "{} is out of date - run scripts/make_api.sh",
+            path.display()
+        );
+    }
 }
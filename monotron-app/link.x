@@ -0,0 +1,59 @@
+/* Lays out a Monotron application in application RAM. Use it with
+`-C link-arg=-Tlink.x`. */
+
+INCLUDE memory.x
+
+/* Generated by `monotron_app::entry!` */
+ENTRY(monotron_app_entry);
+
+SECTIONS
+{
+    /* The ROM looks for the entry point in the first word */
+    .entry_point ORIGIN(RAM) :
+    {
+        KEEP(*(.entry_point));
+    } > RAM
+
+    /* The optional `monotron_api::AppHeader` must come straight after it */
+    .app_header ORIGIN(RAM) + 4 :
+    {
+        KEEP(*(.app_header));
+    } > RAM
+
+    .text :
+    {
+        *(.text .text.*);
+    } > RAM
+
+    .rodata : ALIGN(4)
+    {
+        *(.rodata .rodata.*);
+        . = ALIGN(4);
+    } > RAM
+
+    /* The image is loaded straight into RAM, so .data needs no copying */
+    .data : ALIGN(4)
+    {
+        *(.data .data.*);
+        . = ALIGN(4);
+    } > RAM
+
+    /* Not in the image, so `monotron_app::rt` zeroes it */
+    .bss (NOLOAD) : ALIGN(4)
+    {
+        __sbss = .;
+        *(.bss .bss.*);
+        *(COMMON);
+        . = ALIGN(4);
+        __ebss = .;
+    } > RAM
+
+    /* We never unwind */
+    /DISCARD/ :
+    {
+        *(.ARM.exidx .ARM.exidx.* .ARM.extab.*);
+    }
+}
+
+ASSERT(SIZEOF(.entry_point) == 4, "
+Use monotron_app::entry! to say which function is the application's entry point.");
@@ -0,0 +1,6 @@
+MEMORY
+{
+    /* The first 8 KiB of SRAM belongs to the ROM. Applications get the
+    other 24 KiB, with their stack at the top. */
+    RAM   (rwx) : ORIGIN = 0x20002000, LENGTH = 24K
+}
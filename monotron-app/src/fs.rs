@@ -0,0 +1,312 @@
+//! Files and directories.
+//!
+//! Paths are passed to the ROM as they are. File names on the SD card are
+//! MS-DOS style 8.3 names, which `file_name` turns back into text.
+//!
+//! The ROM doesn't let applications at the SD card yet, so it doesn't say it
+//! has `Capabilities::FILES`. Without that, the only thing `File::open` can
+//! open is the RS-232 port, as `/dev/uart0@9600` or `/dev/uart0@115200`,
+//! and `stat`, `File::seek` and `Dir::open` return `Error::NotSupported`
+//! without asking. `monotron-api-host` has `FILES`, so all of this works
+//! there.
+
+use crate::api;
+use monotron_api::{
+    BorrowedString, Capabilities, DirEntry, EmptyResult, Error, Handle, HandleResult, Offset,
+    OpenMode, SizeResult,
+};
+
+/// An open file (or device). It's closed when it's dropped.
+#[derive(Debug)]
+pub struct File {
+    handle: Handle,
+}
+
+/// An open directory. Iterate over it to read the entries. It's closed when
+/// it's dropped.
+#[derive(Debug)]
+pub struct Dir {
+    handle: Handle,
+    finished: bool,
+}
+
+/// A file name from a `DirEntry`, like `README.TXT`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FileName {
+    buffer: [u8; 12],
+    len: usize,
+}
+
+/// Check the ROM can do files (as opposed to devices).
+fn files() -> Result<(), Error> {
+    if api().has(Capabilities::FILES) {
+        Ok(())
+    } else {
+        Err(Error::NotSupported)
+    }
+}
+
+/// Point a `BorrowedString` at a path, for as long as the call lasts.
+fn borrow(path: &str) -> BorrowedString {
+    BorrowedString {
+        ptr: path.as_ptr(),
+        length: path.len(),
+    }
+}
+
+/// Convert an `EmptyResult` into a `Result`.
+fn empty(result: EmptyResult) -> Result<(), Error> {
+    match result {
+        EmptyResult::Ok => Ok(()),
+        EmptyResult::Error(e) => Err(e),
+    }
+}
+
+/// Convert a `HandleResult` into a `Result`.
+fn handle(result: HandleResult) -> Result<Handle, Error> {
+    match result {
+        HandleResult::Ok(handle) => Ok(handle),
+        HandleResult::Error(e) => Err(e),
+    }
+}
+
+/// Convert a `SizeResult` into a `Result`.
+fn size(result: SizeResult) -> Result<usize, Error> {
+    match result {
+        SizeResult::Ok(size) => Ok(size),
+        SizeResult::Error(e) => Err(e),
+    }
+}
+
+/// A `DirEntry` full of nothing, for the ROM to fill in.
+fn blank_entry() -> DirEntry {
+    let never = monotron_api::Timestamp {
+        year_from_1970: 0,
+        month: 1,
+        days: 1,
+        hours: 0,
+        minutes: 0,
+        seconds: 0,
+    };
+    DirEntry {
+        file_type: monotron_api::FileType::File,
+        name: [b' '; 11],
+        size: 0,
+        mtime: never.clone(),
+        ctime: never,
+        mode: monotron_api::FileMode::new(false, false, false, false),
+    }
+}
+
+/// Find out about a file.
+pub fn stat(path: &str) -> Result<DirEntry, Error> {
+    files()?;
+    let mut entry = blank_entry();
+    empty((api().stat)(borrow(path), &mut entry))?;
+    Ok(entry)
+}
+
+/// Turn the 8.3 name in a `DirEntry` into text, like `README.TXT`.
+pub fn file_name(entry: &DirEntry) -> FileName {
+    let mut name = FileName {
+        buffer: [0; 12],
+        len: 0,
+    };
+    let (base, ext) = entry.name.split_at(8);
+    for &byte in base.iter().take_while(|&&b| b != b' ') {
+        name.push(byte);
+    }
+    if ext[0] != b' ' {
+        name.push(b'.');
+        for &byte in ext.iter().take_while(|&&b| b != b' ') {
+            name.push(byte);
+        }
+    }
+    name
+}
+
+impl File {
+    /// Open a file to read.
+    pub fn open(path: &str) -> Result<File, Error> {
+        File::open_with(
+            path,
+            OpenMode::ReadOnly {
+                non_blocking: false,
+            },
+        )
+    }
+
+    /// Open a file to write, creating it if it doesn't exist and emptying it
+    /// if it does.
+    pub fn create(path: &str) -> Result<File, Error> {
+        File::open_with(
+            path,
+            OpenMode::WriteOnly {
+                append: false,
+                create: true,
+                exclusive: false,
+                truncate: true,
+                non_blocking: false,
+            },
+        )
+    }
+
+    /// Open a file (or device) in any mode. Paths which don't start with
+    /// `/dev/` need `Capabilities::FILES`.
+    pub fn open_with(path: &str, mode: OpenMode) -> Result<File, Error> {
+        if !path.starts_with("/dev/") {
+            files()?;
+        }
+        let handle = handle((api().open)(borrow(path), mode))?;
+        Ok(File { handle })
+    }
+
+    /// Read into `buffer`. Returns how many bytes were read, which is zero
+    /// at the end of the file.
+    pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Error> {
+        size((api().read)(self.handle, buffer.as_mut_ptr(), buffer.len()))
+    }
+
+    /// Write some of `buffer`. Returns how many bytes were written.
+    pub fn write(&mut self, buffer: &[u8]) -> Result<usize, Error> {
+        size((api().write)(self.handle, buffer.as_ptr(), buffer.len()))
+    }
+
+    /// Write all of `buffer`.
+    pub fn write_all(&mut self, mut buffer: &[u8]) -> Result<(), Error> {
+        while !buffer.is_empty() {
+            match self.write(buffer)? {
+                0 => return Err(Error::IOError),
+                count => buffer = &buffer[count..],
+            }
+        }
+        Ok(())
+    }
+
+    /// Write `out_buffer`, then read into `in_buffer`, as one operation (for
+    /// example, an I2C register read). Returns how many bytes were read.
+    pub fn write_then_read(
+        &mut self,
+        out_buffer: &[u8],
+        in_buffer: &mut [u8],
+    ) -> Result<usize, Error> {
+        size((api().write_then_read)(
+            self.handle,
+            out_buffer.as_ptr(),
+            out_buffer.len(),
+            in_buffer.as_mut_ptr(),
+            in_buffer.len(),
+        ))
+    }
+
+    /// Move the read/write position.
+    pub fn seek(&mut self, offset: Offset) -> Result<(), Error> {
+        files()?;
+        empty((api().seek)(self.handle, offset))
+    }
+
+    /// The handle the ROM gave us, for calling the `Api` directly.
+    pub fn handle(&self) -> Handle {
+        self.handle
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        let _ = (api().close)(self.handle);
+    }
+}
+
+impl core::fmt::Write for File {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.write_all(s.as_bytes()).map_err(|_| core::fmt::Error)
+    }
+}
+
+impl Dir {
+    /// Open a directory.
+    pub fn open(path: &str) -> Result<Dir, Error> {
+        files()?;
+        let handle = handle((api().opendir)(borrow(path)))?;
+        Ok(Dir {
+            handle,
+            finished: false,
+        })
+    }
+}
+
+impl Iterator for Dir {
+    type Item = Result<DirEntry, Error>;
+
+    /// Read the next entry. The ROM says `FileNotFound` when there are no
+    /// more; any other error is returned, and ends the iteration. There's
+    /// nothing to check here, as `Dir::open` already needed `FILES`.
+    fn next(&mut self) -> Option<Result<DirEntry, Error>> {
+        if self.finished {
+            return None;
+        }
+        let mut entry = blank_entry();
+        match (api().readdir)(self.handle, &mut entry) {
+            EmptyResult::Ok => Some(Ok(entry)),
+            EmptyResult::Error(Error::FileNotFound) => {
+                self.finished = true;
+                None
+            }
+            EmptyResult::Error(e) => {
+                self.finished = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl Drop for Dir {
+    fn drop(&mut self) {
+        let _ = (api().close)(self.handle);
+    }
+}
+
+impl FileName {
+    /// The name as a string.
+    pub fn as_str(&self) -> &str {
+        // Anything which isn't ASCII was replaced in `push`
+        core::str::from_utf8(&self.buffer[0..self.len]).unwrap_or("")
+    }
+
+    fn push(&mut self, byte: u8) {
+        self.buffer[self.len] = if byte.is_ascii() { byte } else { b'?' };
+        self.len += 1;
+    }
+}
+
+impl core::ops::Deref for FileName {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl core::fmt::Display for FileName {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn names() {
+        let mut entry = blank_entry();
+        entry.name = *b"README  TXT";
+        assert_eq!(file_name(&entry).as_str(), "README.TXT");
+        entry.name = *b"MAKEFILE   ";
+        assert_eq!(file_name(&entry).as_str(), "MAKEFILE");
+        entry.name = *b"A       C  ";
+        assert_eq!(&*file_name(&entry), "A.C");
+        entry.name = *b"CAF\x90    TXT";
+        assert_eq!(format!("{}", file_name(&entry)), "CAF?.TXT");
+    }
+}
@@ -0,0 +1,71 @@
+//! The screen and the keyboard.
+//!
+//! Text is UTF-8, and the ROM converts it to Code Page 850 for the screen.
+//! The ROM's escape sequences work too: `ESC` and a colour letter (`K`, `B`,
+//! `G`, `C`, `R`, `M`, `Y` or `W`) changes the foreground colour, or the
+//! background colour if the letter is in lower case, and `ESC Z` clears the
+//! screen.
+
+use crate::api;
+
+/// How many characters across the screen is.
+pub const WIDTH: u8 = 48;
+
+/// How many rows down the screen is.
+pub const HEIGHT: u8 = 36;
+
+/// Writes to the screen. Use it with `write!`, or use `print!` and
+/// `println!`.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Console;
+
+impl core::fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        (api().puts_utf8)(s.as_ptr(), s.len());
+        Ok(())
+    }
+}
+
+/// Is there a key waiting to be read?
+pub fn key_pressed() -> bool {
+    (api().kbhit)() != 0
+}
+
+/// Wait for a key, and return it as a Code Page 850 byte. Returns `None` if
+/// someone pressed Ctrl-Break, in which case the ROM is about to stop the
+/// application anyway.
+pub fn read_byte() -> Option<u8> {
+    match (api().readc)() {
+        byte @ 0..=255 => Some(byte as u8),
+        _ => None,
+    }
+}
+
+/// Move the cursor. Rows and columns count from zero, from the top left.
+pub fn move_cursor(row: u8, col: u8) {
+    (api().move_cursor)(row, col);
+}
+
+/// Where the cursor is, as (row, column), if the ROM can say.
+pub fn cursor() -> Option<(u8, u8)> {
+    let api = api();
+    if !api.has(monotron_api::Capabilities::GET_CURSOR) {
+        return None;
+    }
+    let mut row = 0;
+    let mut col = 0;
+    (api.get_cursor)(&mut row, &mut col);
+    Some((row, col))
+}
+
+/// Show or hide the cursor. The ROM shows it again when the application
+/// exits.
+pub fn set_cursor_visible(visible: bool) {
+    (api().set_cursor_visible)(visible as u8);
+}
+
+/// Clear the screen, in the current colours, and move the cursor to the top
+/// left.
+pub fn clear_screen() {
+    crate::print!("\u{001B}Z");
+}
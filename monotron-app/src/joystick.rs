@@ -0,0 +1,84 @@
+//! The Atari-style joystick.
+
+use crate::api;
+
+/// Where the joystick is pointing, and whether fire is pressed.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct State(u8);
+
+impl State {
+    const UP: u8 = 0b10000;
+    const DOWN: u8 = 0b01000;
+    const LEFT: u8 = 0b00100;
+    const RIGHT: u8 = 0b00010;
+    const FIRE: u8 = 0b00001;
+
+    /// Decode the byte `Api::get_joystick` returns.
+    pub fn from_u8(bits: u8) -> State {
+        State(bits)
+    }
+
+    /// The byte `Api::get_joystick` returned.
+    pub fn as_u8(&self) -> u8 {
+        self.0
+    }
+
+    /// Is the stick pushed up (including diagonally)?
+    pub fn is_up(&self) -> bool {
+        (self.0 & State::UP) != 0
+    }
+
+    /// Is the stick pulled down (including diagonally)?
+    pub fn is_down(&self) -> bool {
+        (self.0 & State::DOWN) != 0
+    }
+
+    /// Is the stick pushed left (including diagonally)?
+    pub fn is_left(&self) -> bool {
+        (self.0 & State::LEFT) != 0
+    }
+
+    /// Is the stick pushed right (including diagonally)?
+    pub fn is_right(&self) -> bool {
+        (self.0 & State::RIGHT) != 0
+    }
+
+    /// Is the fire button pressed?
+    pub fn fire_pressed(&self) -> bool {
+        (self.0 & State::FIRE) != 0
+    }
+
+    /// Is the stick in the middle, with fire not pressed?
+    pub fn is_idle(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Which way the stick points, as (x, y) where each is -1, 0 or 1, and
+    /// up is -1 (so it moves a cursor down the screen).
+    pub fn direction(&self) -> (i8, i8) {
+        let x = self.is_right() as i8 - self.is_left() as i8;
+        let y = self.is_down() as i8 - self.is_up() as i8;
+        (x, y)
+    }
+}
+
+/// Read the joystick. It reads as centred if the ROM is busy with it.
+pub fn read() -> State {
+    State((api().get_joystick)())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode() {
+        let state = State::from_u8(0b10011);
+        assert!(state.is_up() && state.is_right() && state.fire_pressed());
+        assert!(!state.is_down() && !state.is_left() && !state.is_idle());
+        assert_eq!(state.direction(), (1, -1));
+        assert_eq!(State::from_u8(0b01100).direction(), (-1, 1));
+        assert!(State::default().is_idle());
+        assert_eq!(State::default().direction(), (0, 0));
+    }
+}
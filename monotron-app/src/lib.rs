@@ -0,0 +1,148 @@
+//! # monotron-app
+//!
+//! Copyright (c) Jonathan 'theJPster' Pallant
+//!
+//! Licensed under either of
+//!
+//! - Apache License, Version 2.0 ([LICENSE-APACHE](LICENSE-APACHE) or
+//!   http://www.apache.org/licenses/LICENSE-2.0)
+//!
+//! - MIT license ([LICENSE-MIT](LICENSE-MIT) or http://opensource.org/licenses/MIT)
+//!
+//! at your option.
+//!
+//! Safe wrappers around `monotron_api`, for writing Monotron applications in
+//! Rust. You get:
+//!
+//! * `entry!`, to say which function the ROM should run.
+//! * `print!` and `println!`, which write to the screen.
+//! * `fs::File` and `fs::Dir`, which close themselves when dropped.
+//! * The clock and timers in `time`, the joystick in `joystick` and the
+//!   synthesiser in `sound`.
+//! * A panic handler which prints the message and returns to the ROM (turn
+//!   off the `panic-handler` feature to write your own).
+//! * A linker script, `link.x`, which puts the application at `0x2000_2000`
+//!   with the entry point and `AppHeader` where the ROM expects them.
+//!
+//! An application looks like this:
+//!
+//! ```ignore
+//! #![no_std]
+//! #![no_main]
+//!
+//! use monotron_app::{entry, println};
+//!
+//! entry!(main);
+//!
+//! fn main() -> i32 {
+//!     println!("Hello, {}!", monotron_app::time::now());
+//!     0
+//! }
+//! ```
+//!
+//! Build it for `thumbv7em-none-eabihf`, with `-C link-arg=-Tlink.x` in the
+//! `rustflags` in your `.cargo/config`, then `objcopy -O binary` the result
+//! and load it with `monotron-cli` (or load the ELF file as it is). Anything
+//! this crate doesn't wrap is available through `api()`.
+//!
+//! Not everything works on today's ROM. It can't give applications the SD
+//! card, so `fs::File::open` only opens the RS-232 port (`/dev/uart0@9600`
+//! or `/dev/uart0@115200`), and `fs::stat`, `fs::File::seek` and
+//! `fs::Dir::open` return `Error::NotSupported`. The ROM doesn't do
+//! `write_then_read` either. Check `api().has(...)` for the other optional
+//! features. Under `monotron-api-host` files and directories work as well.
+#![cfg_attr(not(test), no_std)]
+#![deny(missing_docs)]
+
+pub mod fs;
+pub mod io;
+pub mod joystick;
+pub mod rt;
+pub mod sound;
+pub mod time;
+
+pub use monotron_api;
+pub use monotron_api::{Api, DirEntry, Error, Timestamp};
+
+/// The `Api` the ROM gave us.
+///
+/// # Panics
+///
+/// Panics if it's called before the application has started (which can only
+/// happen if `entry!` wasn't used).
+pub fn api() -> &'static Api {
+    rt::api().expect("monotron_app::entry! wasn't used")
+}
+
+/// Says which function is the application's entry point. It takes no
+/// arguments, and returns the application's exit status (zero for success):
+///
+/// ```ignore
+/// monotron_app::entry!(main);
+///
+/// fn main() -> i32 {
+///     0
+/// }
+/// ```
+#[macro_export]
+macro_rules! entry {
+    ($main:path) => {
+        /// Called by the ROM, with the `Api`.
+        #[doc(hidden)]
+        #[no_mangle]
+        pub extern "C" fn monotron_app_entry(api: *const $crate::Api) -> i32 {
+            // Check the function has the right signature
+            let main: fn() -> i32 = $main;
+            unsafe { $crate::rt::start(api, main) }
+        }
+
+        /// Tells the ROM where `monotron_app_entry` is. `link.x` puts it at
+        /// the start of the image.
+        #[doc(hidden)]
+        #[link_section = ".entry_point"]
+        #[no_mangle]
+        pub static MONOTRON_APP_ENTRY_POINT: extern "C" fn(*const $crate::Api) -> i32 =
+            monotron_app_entry;
+    };
+}
+
+/// Prints to the screen.
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => {
+        {
+            use core::fmt::Write as _;
+            write!($crate::io::Console, $($arg)*).unwrap();
+        }
+    };
+}
+
+/// Prints to the screen and puts a new-line on the end.
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => {
+        {
+            use core::fmt::Write as _;
+            writeln!($crate::io::Console, $($arg)*).unwrap();
+        }
+    };
+}
+
+#[cfg(test)]
+mod test {
+    fn main() -> i32 {
+        0
+    }
+
+    crate::entry!(main);
+
+    #[test]
+    fn entry_point() {
+        // The ROM finds the entry point through the pointer
+        assert_eq!(
+            MONOTRON_APP_ENTRY_POINT as *const (),
+            monotron_app_entry as *const ()
+        );
+    }
+}
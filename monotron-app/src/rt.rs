@@ -0,0 +1,97 @@
+//! Starting and stopping the application.
+//!
+//! The ROM calls the function `entry!` generates, which zeroes `.bss`, checks
+//! the ROM's `Api` is at least as new as `monotron_api::API_VERSION`, keeps
+//! it somewhere `crate::api` can find it, and runs your function. Returning
+//! from that function returns to the ROM.
+//!
+//! `exit` (and so the panic handler) returns to the ROM from anywhere, the
+//! same way the ROM's own code does when the entry function returns: with
+//! `SVC 1`, and the exit status in `r0`.
+
+use crate::Api;
+use core::sync::atomic::{AtomicPtr, Ordering};
+use monotron_api::API_VERSION;
+
+/// The exit status of an application which panicked.
+pub const PANIC_EXIT_STATUS: i32 = 101;
+
+/// The exit status of an application which didn't run, because the ROM's
+/// `Api` is older than the one this crate was built against.
+pub const NEWER_ROM_EXIT_STATUS: i32 = 102;
+
+/// The `Api` the ROM gave us, or null if we haven't started yet.
+static API: AtomicPtr<Api> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Start the application. Only for `entry!` to call.
+///
+/// # Safety
+///
+/// Must only be called once, by the entry point, with the `Api` the ROM
+/// gave it.
+#[doc(hidden)]
+pub unsafe fn start(api: *const Api, main: fn() -> i32) -> i32 {
+    // Whatever was in RAM before we were loaded is still there
+    #[cfg(all(target_arch = "arm", target_os = "none"))]
+    zero_bss();
+    // Everything we wrap is in the `Api` we were built against
+    if !(*api).supports(API_VERSION) {
+        ((*api).puts)(b"Error: This application needs a newer ROM.\n\0".as_ptr());
+        return NEWER_ROM_EXIT_STATUS;
+    }
+    API.store(api as *mut Api, Ordering::Relaxed);
+    main()
+}
+
+/// The `Api` the ROM gave us, if we've started.
+pub(crate) fn api() -> Option<&'static Api> {
+    let api = API.load(Ordering::Relaxed);
+    if api.is_null() {
+        None
+    } else {
+        Some(unsafe { &*api })
+    }
+}
+
+/// Return to the ROM straight away, as if the entry function had returned
+/// `status`. The ROM stops the audio, timers and so on, just as it does
+/// when an application returns.
+#[cfg(all(target_arch = "arm", target_os = "none"))]
+pub fn exit(status: i32) -> ! {
+    // The ROM never comes back
+    unsafe {
+        core::arch::asm!("svc #1", in("r0") status, options(noreturn));
+    }
+}
+
+/// Fill `.bss` with zeroes. `link.x` says where it is.
+#[cfg(all(target_arch = "arm", target_os = "none"))]
+unsafe fn zero_bss() {
+    extern "C" {
+        static mut __sbss: u32;
+        static mut __ebss: u32;
+    }
+    let mut word = core::ptr::addr_of_mut!(__sbss);
+    let end = core::ptr::addr_of_mut!(__ebss);
+    while word < end {
+        core::ptr::write_volatile(word, 0);
+        word = word.add(1);
+    }
+}
+
+/// Print the panic message in red, like the ROM does when it has to stop an
+/// application, then return to the ROM.
+#[cfg(all(feature = "panic-handler", target_arch = "arm", target_os = "none"))]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    use core::fmt::Write as _;
+    // If we panicked before we started, there's nowhere to print the message
+    if api().is_some() {
+        let _ = write!(
+            crate::io::Console,
+            "\u{001B}W\u{001B}k\n\u{001B}RPanic: {}\u{001B}W\n",
+            info
+        );
+    }
+    exit(PANIC_EXIT_STATUS)
+}
@@ -0,0 +1,104 @@
+//! The three-channel synthesiser.
+//!
+//! Each channel plays one note, at one volume, until it's told to play
+//! something else. The ROM silences every channel when the application
+//! exits.
+
+use crate::api;
+use monotron_api::Capabilities;
+
+/// One of the synthesiser's three channels.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Channel {
+    /// The first channel
+    Zero = 0,
+    /// The second channel
+    One = 1,
+    /// The third channel
+    Two = 2,
+}
+
+/// The shape of the sound wave a channel plays.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Waveform {
+    /// A harsh, buzzy sound
+    Square = 0,
+    /// A pure tone
+    Sine = 1,
+    /// Somewhere between the two
+    Sawtooth = 2,
+    /// White noise, for drums and explosions
+    Noise = 3,
+}
+
+/// A pitch, in hundredths of a Hertz.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Frequency(pub u32);
+
+impl Frequency {
+    /// A frequency in whole Hertz.
+    pub const fn from_hertz(hertz: u32) -> Frequency {
+        Frequency(hertz * 100)
+    }
+
+    /// A frequency in hundredths of a Hertz.
+    pub const fn from_centi_hertz(centi_hertz: u32) -> Frequency {
+        Frequency(centi_hertz)
+    }
+
+    /// The frequency of a MIDI note number, where 69 is the A above middle
+    /// C (440 Hz) and each step is a semitone.
+    pub fn from_midi_note(note: u8) -> Frequency {
+        // Middle C to the B above it, in centi-Hertz
+        const OCTAVE: [u32; 12] = [
+            26_163, 27_718, 29_366, 31_113, 32_963, 34_923, 36_999, 39_200, 41_530, 44_000, 46_616,
+            49_388,
+        ];
+        let centi_hertz = OCTAVE[usize::from(note % 12)];
+        // Each octave up doubles the frequency
+        match note / 12 {
+            octave if octave >= 5 => Frequency(centi_hertz << (octave - 5)),
+            octave => Frequency(centi_hertz >> (5 - octave)),
+        }
+    }
+}
+
+/// Does this Monotron have a synthesiser?
+pub fn is_available() -> bool {
+    api().has(Capabilities::AUDIO)
+}
+
+/// Start playing a note on a channel, replacing whatever it was playing. A
+/// volume of zero is silent.
+pub fn play(channel: Channel, frequency: Frequency, volume: u8, waveform: Waveform) {
+    // The ROM takes the waveform before the volume (whatever the argument
+    // names in `Api::play` say). It can only fail if the channel or waveform
+    // are out of range, and ours can't be.
+    let _ = (api().play)(frequency.0, channel as u8, waveform as u8, volume);
+}
+
+/// Silence a channel.
+pub fn stop(channel: Channel) {
+    play(channel, Frequency::from_hertz(440), 0, Waveform::Square);
+}
+
+/// Silence every channel.
+pub fn stop_all() {
+    for &channel in [Channel::Zero, Channel::One, Channel::Two].iter() {
+        stop(channel);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn midi_notes() {
+        assert_eq!(Frequency::from_midi_note(69), Frequency::from_hertz(440));
+        assert_eq!(Frequency::from_midi_note(57), Frequency::from_hertz(220));
+        assert_eq!(Frequency::from_midi_note(60), Frequency(26_163));
+        assert_eq!(Frequency::from_midi_note(0), Frequency(817));
+        assert_eq!(Frequency::from_midi_note(127), Frequency(1_254_400));
+    }
+}
@@ -0,0 +1,193 @@
+//! The calendar clock, the tick counters and the software timers.
+//!
+//! There are no time zones: `now` is whatever local time the Monotron's
+//! clock was set to.
+
+use crate::api;
+use monotron_api::{Error, Event, TimerId, TimerMode, TimerResult, Timestamp};
+
+/// How many times a second `ticks` goes up.
+pub const TICKS_PER_SECOND: u32 = 60;
+
+/// A software timer. Each time it goes off, `next_event` returns an
+/// `Event::Timer` with its `id`. It stops when it's dropped.
+#[derive(Debug)]
+pub struct Timer {
+    id: TimerId,
+}
+
+/// Extra things to do with a `Timestamp`.
+pub trait TimestampExt {
+    /// The year, e.g. 1984.
+    fn year(&self) -> u16;
+
+    /// How many seconds it is since midnight.
+    fn seconds_since_midnight(&self) -> u32;
+
+    /// How many whole days it is since 1 January 1970.
+    fn days_since_1970(&self) -> u32;
+
+    /// How many seconds it is since the start of 1970. Subtract one from
+    /// another to see how far apart two timestamps are.
+    fn seconds_since_1970(&self) -> u64 {
+        u64::from(self.days_since_1970()) * 86_400 + u64::from(self.seconds_since_midnight())
+    }
+}
+
+/// The date and time now.
+pub fn now() -> Timestamp {
+    (api().gettime)()
+}
+
+/// Make a `Timestamp`, or return `None` if the date or time doesn't exist
+/// (or is outside 1970 to 2225, which is all a `Timestamp` can hold).
+pub fn timestamp(
+    year: u16,
+    month: u8,
+    days: u8,
+    hours: u8,
+    minutes: u8,
+    seconds: u8,
+) -> Option<Timestamp> {
+    if !(1970..=1970 + 255).contains(&year) || !(1..=12).contains(&month) {
+        return None;
+    }
+    let timestamp = Timestamp {
+        year_from_1970: (year - 1970) as u8,
+        month,
+        days,
+        hours,
+        minutes,
+        seconds,
+    };
+    if days < 1 || days > timestamp.days_in_month() || hours > 23 || minutes > 59 || seconds > 59 {
+        return None;
+    }
+    Some(timestamp)
+}
+
+/// How many video frames (`TICKS_PER_SECOND` a second) there have been since
+/// the Monotron started.
+pub fn ticks() -> u32 {
+    (api().get_ticks)()
+}
+
+/// How many microseconds it is since the Monotron started.
+pub fn micros() -> u64 {
+    (api().get_micros)()
+}
+
+/// Wait for at least `ms` milliseconds.
+pub fn sleep_ms(ms: u32) {
+    (api().sleep_ms)(ms);
+}
+
+/// Wait for the next vertical blanking interval, when the screen isn't being
+/// drawn.
+pub fn wait_for_vblank() {
+    (api().wfvbi)();
+}
+
+/// Collect the next event, if anything has happened.
+pub fn next_event() -> Option<Event> {
+    match (api().get_event)() {
+        Event::None => None,
+        event => Some(event),
+    }
+}
+
+impl Timer {
+    /// Start a timer which goes off once, after `period_ms` milliseconds.
+    pub fn one_shot(period_ms: u32) -> Result<Timer, Error> {
+        Timer::start(period_ms, TimerMode::OneShot)
+    }
+
+    /// Start a timer which goes off every `period_ms` milliseconds.
+    pub fn periodic(period_ms: u32) -> Result<Timer, Error> {
+        Timer::start(period_ms, TimerMode::Periodic)
+    }
+
+    /// Which timer this is, as it appears in an `Event::Timer`.
+    pub fn id(&self) -> TimerId {
+        self.id
+    }
+
+    /// Has this timer gone off?
+    pub fn is(&self, event: &Event) -> bool {
+        *event == Event::Timer(self.id)
+    }
+
+    fn start(period_ms: u32, mode: TimerMode) -> Result<Timer, Error> {
+        match (api().timer_start)(period_ms, mode) {
+            TimerResult::Ok(id) => Ok(Timer { id }),
+            TimerResult::Error(e) => Err(e),
+        }
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        // A one-shot timer which has gone off has already stopped
+        let _ = (api().timer_stop)(self.id);
+    }
+}
+
+impl TimestampExt for Timestamp {
+    fn year(&self) -> u16 {
+        1970 + u16::from(self.year_from_1970)
+    }
+
+    fn seconds_since_midnight(&self) -> u32 {
+        (u32::from(self.hours) * 60 + u32::from(self.minutes)) * 60 + u32::from(self.seconds)
+    }
+
+    fn days_since_1970(&self) -> u32 {
+        let mut days = 0;
+        // Count up through the whole years, then the whole months
+        let mut start = Timestamp {
+            year_from_1970: 0,
+            month: 1,
+            ..self.clone()
+        };
+        while start.year_from_1970 < self.year_from_1970 {
+            days += if start.is_leap_year() { 366 } else { 365 };
+            start.year_from_1970 += 1;
+        }
+        while start.month < self.month {
+            days += u32::from(start.days_in_month());
+            start.month += 1;
+        }
+        days + u32::from(self.days) - 1
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn make_timestamp() {
+        let t = timestamp(2019, 7, 16, 12, 30, 5).unwrap();
+        assert_eq!(format!("{}", t), "2019-07-16T12:30:05");
+        assert_eq!(t.year(), 2019);
+        assert!(timestamp(2019, 2, 29, 0, 0, 0).is_none());
+        assert!(timestamp(2020, 2, 29, 0, 0, 0).is_some());
+        assert!(timestamp(2019, 13, 1, 0, 0, 0).is_none());
+        assert!(timestamp(2019, 1, 1, 24, 0, 0).is_none());
+        assert!(timestamp(1969, 12, 31, 0, 0, 0).is_none());
+        assert!(timestamp(2226, 1, 1, 0, 0, 0).is_none());
+    }
+
+    #[test]
+    fn seconds_since_1970() {
+        let epoch = timestamp(1970, 1, 1, 0, 0, 0).unwrap();
+        assert_eq!(epoch.seconds_since_1970(), 0);
+        // Checked with `date -u -d 2019-07-16T12:30:05 +%s`
+        let t = timestamp(2019, 7, 16, 12, 30, 5).unwrap();
+        assert_eq!(t.seconds_since_1970(), 1_563_280_205);
+        assert_eq!(t.seconds_since_midnight(), 45_005);
+        let leap_day = timestamp(2000, 2, 29, 0, 0, 0).unwrap();
+        let next_day = timestamp(2000, 3, 1, 0, 0, 0).unwrap();
+        assert_eq!(next_day.days_since_1970() - leap_day.days_since_1970(), 1);
+    }
+}
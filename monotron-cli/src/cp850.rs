@@ -0,0 +1,151 @@
+//! Converts between what the Monotron sends (Code Page 850, with its own
+//! escape sequences for colour) and what a host terminal expects (UTF-8,
+//! with ANSI escape sequences).
+
+/// The Unicode equivalents of Code Page 850 bytes 0x80 to 0xFF.
+static HIGH_HALF: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å', //
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', 'ø', '£', 'Ø', '×', 'ƒ', //
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '®', '¬', '½', '¼', '¡', '«', '»', //
+    '░', '▒', '▓', '│', '┤', 'Á', 'Â', 'À', '©', '╣', '║', '╗', '╝', '¢', '¥', '┐', //
+    '└', '┴', '┬', '├', '─', '┼', 'ã', 'Ã', '╚', '╔', '╩', '╦', '╠', '═', '╬', '¤', //
+    'ð', 'Ð', 'Ê', 'Ë', 'È', 'ı', 'Í', 'Î', 'Ï', '┘', '┌', '█', '▄', '¦', 'Ì', '▀', //
+    'Ó', 'ß', 'Ô', 'Ò', 'õ', 'Õ', 'µ', 'þ', 'Þ', 'Ú', 'Û', 'Ù', 'ý', 'Ý', '¯', '´', //
+    '\u{AD}', '±', '‗', '¾', '¶', '§', '÷', '¸', '°', '¨', '·', '¹', '³', '²', '■', '\u{A0}',
+];
+
+/// The escape character, which the Monotron uses to change colour.
+const ESC: u8 = 0x1B;
+
+/// How the output from a `Decoder` is going to be used.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Mode {
+    /// For display on an ANSI terminal in raw mode. Colours are converted
+    /// and new-lines get a carriage return.
+    Ansi,
+    /// For capturing as plain text. Colours and carriage returns are dropped.
+    Plain,
+}
+
+/// Converts a stream of bytes from the Monotron into a `String`.
+#[derive(Debug, Clone)]
+pub struct Decoder {
+    mode: Mode,
+    in_escape: bool,
+}
+
+/// Convert a Code Page 850 byte into a Unicode character.
+pub fn to_char(byte: u8) -> char {
+    if byte < 0x80 {
+        byte as char
+    } else {
+        HIGH_HALF[usize::from(byte - 0x80)]
+    }
+}
+
+/// Convert a Unicode character into a Code Page 850 byte, if it has one.
+pub fn from_char(ch: char) -> Option<u8> {
+    if ch.is_ascii() {
+        Some(ch as u8)
+    } else {
+        HIGH_HALF
+            .iter()
+            .position(|&x| x == ch)
+            .map(|idx| idx as u8 + 0x80)
+    }
+}
+
+/// Convert a Monotron colour letter into an ANSI SGR parameter.
+fn ansi_colour(letter: u8) -> Option<u8> {
+    let (base, letter) = if letter.is_ascii_uppercase() {
+        (30, letter)
+    } else {
+        (40, letter.to_ascii_uppercase())
+    };
+    let offset = match letter {
+        b'K' => 0,
+        b'R' => 1,
+        b'G' => 2,
+        b'Y' => 3,
+        b'B' => 4,
+        b'M' => 5,
+        b'C' => 6,
+        b'W' => 7,
+        _ => return None,
+    };
+    Some(base + offset)
+}
+
+impl Decoder {
+    /// Create a new decoder.
+    pub fn new(mode: Mode) -> Decoder {
+        Decoder {
+            mode,
+            in_escape: false,
+        }
+    }
+
+    /// Process one byte from the Monotron, appending the result to `out`.
+    pub fn feed(&mut self, byte: u8, out: &mut String) {
+        if self.in_escape {
+            self.in_escape = false;
+            if self.mode == Mode::Ansi {
+                if byte == b'Z' {
+                    out.push_str("\x1b[2J\x1b[H");
+                } else if let Some(sgr) = ansi_colour(byte) {
+                    out.push_str(&format!("\x1b[{}m", sgr));
+                }
+            }
+            return;
+        }
+        match (byte, self.mode) {
+            (ESC, _) => self.in_escape = true,
+            (b'\r', Mode::Plain) => {}
+            (b'\n', Mode::Ansi) => out.push_str("\r\n"),
+            (byte, _) => out.push(to_char(byte)),
+        }
+    }
+
+    /// Process a block of bytes from the Monotron.
+    pub fn decode(&mut self, bytes: &[u8]) -> String {
+        let mut out = String::new();
+        for &b in bytes {
+            self.feed(b, &mut out);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        for byte in 0..=255u8 {
+            assert_eq!(from_char(to_char(byte)), Some(byte));
+        }
+        assert_eq!(from_char('€'), None);
+    }
+
+    #[test]
+    fn plain() {
+        let mut d = Decoder::new(Mode::Plain);
+        assert_eq!(
+            d.decode(b"\x1bRHello\x1bk \x9c1\r\n"),
+            "Hello £1\n".to_string()
+        );
+    }
+
+    #[test]
+    fn ansi() {
+        let mut d = Decoder::new(Mode::Ansi);
+        assert_eq!(
+            d.decode(b"\x1bZ\x1bR\x1bbHi\n\xdb"),
+            "\x1b[2J\x1b[H\x1b[31m\x1b[44mHi\r\n█".to_string()
+        );
+        // Escapes can be split across reads
+        assert_eq!(d.decode(b"\x1b"), "".to_string());
+        assert_eq!(d.decode(b"Gx"), "\x1b[32mx".to_string());
+    }
+}
@@ -0,0 +1,311 @@
+//! A pretend Monotron on the other end of a pseudo-terminal, for testing.
+//!
+//! It understands just enough of the shell (`remote`, `dir`, `load
+//! --binary`, `yrecv` and `ysend`) to exercise the `Session`, and it uses the
+//! same protocol crates as the ROM.
+
+use crate::port::{wait_readable, Port};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// How big application RAM is on a real Monotron.
+const APPLICATION_LEN: usize = 24 * 1024;
+
+/// What the fake Monotron looked like when the host hung up.
+pub struct FakeState {
+    /// Every command typed at the shell
+    pub commands: Vec<String>,
+    /// The files on the 'SD card'
+    pub files: BTreeMap<String, Vec<u8>>,
+    /// The contents of application RAM
+    pub ram: Vec<u8>,
+}
+
+/// The device end of the pseudo-terminal.
+pub struct FakeMonotron {
+    master: File,
+    echo: bool,
+    state: FakeState,
+}
+
+/// Lets the transfer engines write to the pseudo-terminal.
+struct MasterChannel<'a>(&'a mut File);
+
+/// Feeds a file to a YMODEM sender.
+struct FakeSource<'a> {
+    name: &'a str,
+    data: &'a [u8],
+    offset: usize,
+    announced: bool,
+}
+
+/// Saves files from a YMODEM receiver.
+struct FakeSink<'a> {
+    files: &'a mut BTreeMap<String, Vec<u8>>,
+    current: Option<(String, Vec<u8>)>,
+    count: usize,
+}
+
+impl<'a> monotron_xmodem::Channel for MasterChannel<'a> {
+    fn write(&mut self, data: &[u8]) {
+        let _ = self.0.write_all(data);
+    }
+}
+
+impl<'a> monotron_xmodem::Source for FakeSource<'a> {
+    type Error = ();
+
+    fn next_file(&mut self) -> Option<monotron_xmodem::FileInfo<'_>> {
+        if self.announced {
+            None
+        } else {
+            self.announced = true;
+            Some(monotron_xmodem::FileInfo {
+                name: self.name,
+                size: self.data.len() as u32,
+            })
+        }
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, ()> {
+        let remaining = &self.data[self.offset..];
+        let len = remaining.len().min(buffer.len());
+        buffer[0..len].copy_from_slice(&remaining[0..len]);
+        self.offset += len;
+        Ok(len)
+    }
+}
+
+impl<'a> monotron_xmodem::Sink for FakeSink<'a> {
+    type Error = ();
+
+    fn start_file(&mut self, name: Option<&str>, _size: Option<u32>) -> Result<(), ()> {
+        self.current = Some((name.unwrap_or("XMODEM.BIN").to_string(), Vec::new()));
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), ()> {
+        if let Some((_, contents)) = self.current.as_mut() {
+            contents.extend_from_slice(data);
+        }
+        Ok(())
+    }
+
+    fn end_file(&mut self) -> Result<(), ()> {
+        if let Some((name, contents)) = self.current.take() {
+            self.files.insert(name, contents);
+            self.count += 1;
+        }
+        Ok(())
+    }
+}
+
+impl FakeMonotron {
+    /// Start a fake Monotron on a new thread. Returns the host end of the
+    /// pseudo-terminal, and a handle which gives back the final state once
+    /// the host end is closed.
+    pub fn spawn(files: Vec<(&str, Vec<u8>)>) -> (Port, JoinHandle<FakeState>) {
+        let mut master = 0;
+        let mut slave = 0;
+        let result = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                std::ptr::null(),
+            )
+        };
+        assert_eq!(result, 0, "openpty failed");
+        let master = unsafe { File::from_raw_fd(master) };
+        let slave = unsafe { File::from_raw_fd(slave) };
+        let port = Port::from_file(slave, 115_200).unwrap();
+        let fake = FakeMonotron {
+            master,
+            echo: false,
+            state: FakeState {
+                commands: Vec::new(),
+                files: files
+                    .into_iter()
+                    .map(|(name, data)| (name.to_string(), data))
+                    .collect(),
+                ram: vec![0u8; APPLICATION_LEN],
+            },
+        };
+        (port, std::thread::spawn(move || fake.run()))
+    }
+
+    /// Read a byte, waiting up to `timeout`. Returns `Err` if the host end
+    /// has been closed.
+    fn read_byte(&mut self, timeout: Duration) -> Result<Option<u8>, ()> {
+        if !wait_readable(self.master.as_raw_fd(), timeout).map_err(|_| ())? {
+            return Ok(None);
+        }
+        let mut buffer = [0u8; 1];
+        match self.master.read(&mut buffer) {
+            Ok(1) => Ok(Some(buffer[0])),
+            _ => Err(()),
+        }
+    }
+
+    /// Print on the 'screen' (which only the host sees if `remote on` has
+    /// been used).
+    fn print(&mut self, text: &str) {
+        if self.echo {
+            let _ = self.master.write_all(text.as_bytes());
+        }
+    }
+
+    /// Pretend to be the shell until the host hangs up.
+    fn run(mut self) -> FakeState {
+        let mut line = Vec::new();
+        while let Ok(byte) = self.read_byte(Duration::from_secs(60)) {
+            let byte = match byte {
+                Some(b) => b,
+                None => continue,
+            };
+            if byte == b'\r' {
+                self.print("\n");
+                let command = String::from_utf8_lossy(&line).trim().to_string();
+                line.clear();
+                if !command.is_empty() {
+                    self.state.commands.push(command.clone());
+                    if self.command(&command).is_err() {
+                        break;
+                    }
+                }
+                self.print("\n> ");
+            } else {
+                if self.echo {
+                    let _ = self.master.write_all(&[byte]);
+                }
+                line.push(byte);
+            }
+        }
+        self.state
+    }
+
+    /// Run a shell command.
+    fn command(&mut self, command: &str) -> Result<(), ()> {
+        let mut words = command.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("remote"), Some("on")) => self.echo = true,
+            (Some("remote"), Some("off")) => self.echo = false,
+            (Some("dir"), None) => {
+                let listing: Vec<String> = self
+                    .state
+                    .files
+                    .iter()
+                    .map(|(name, data)| format!("{:<12} {:>7}\n", name, data.len()))
+                    .collect();
+                for line in listing {
+                    self.print(&line);
+                }
+            }
+            (Some("load"), Some("--binary")) => self.load()?,
+            (Some("yrecv"), None) => self.yrecv()?,
+            (Some("ysend"), Some(name)) => self.ysend(name)?,
+            _ => self.print(&format!("Command {:?} not found.\n", command)),
+        }
+        Ok(())
+    }
+
+    /// Like `load --binary`.
+    fn load(&mut self) -> Result<(), ()> {
+        let echo = std::mem::replace(&mut self.echo, false);
+        let _ = self.master.write_all(b"READY\r\n");
+        let mut ram = vec![0u8; APPLICATION_LEN];
+        let mut loader = monotron_load_protocol::Loader::new(&mut ram);
+        while loader.status() == monotron_load_protocol::Status::Running {
+            let response = match self.read_byte(Duration::from_secs(1))? {
+                Some(b) => loader.handle_byte(b),
+                None => loader.handle_timeout(),
+            };
+            if let Some(response) = response {
+                let _ = self.master.write_all(&response.to_bytes());
+            }
+        }
+        let status = loader.status();
+        self.echo = echo;
+        match status {
+            monotron_load_protocol::Status::Complete { length, crc32 } => {
+                self.state.ram = ram;
+                self.print(&format!("Loaded {} bytes, CRC32 0x{:08x}\n", length, crc32));
+            }
+            status => self.print(&format!("Load failed: {:?}\n", status)),
+        }
+        Ok(())
+    }
+
+    /// Like `yrecv`.
+    fn yrecv(&mut self) -> Result<(), ()> {
+        let echo = std::mem::replace(&mut self.echo, false);
+        let mut files = std::mem::take(&mut self.state.files);
+        let mut sink = FakeSink {
+            files: &mut files,
+            current: None,
+            count: 0,
+        };
+        let mut receiver = monotron_xmodem::Receiver::new(monotron_xmodem::Protocol::Ymodem);
+        receiver.start(&mut MasterChannel(&mut self.master));
+        let timeout = Duration::from_millis(u64::from(monotron_xmodem::TIMEOUT_MS));
+        let status = loop {
+            let status = match self.read_byte(timeout)? {
+                Some(b) => receiver.handle_byte(b, &mut MasterChannel(&mut self.master), &mut sink),
+                None => receiver.handle_timeout(&mut MasterChannel(&mut self.master)),
+            };
+            if status != monotron_xmodem::Status::Running {
+                break status;
+            }
+        };
+        let count = sink.count;
+        self.state.files = files;
+        self.echo = echo;
+        if status == monotron_xmodem::Status::Complete {
+            self.print(&format!("Transfer complete. {} file(s) received.\n", count));
+        } else {
+            self.print(&format!("Transfer failed: {:?}\n", status));
+        }
+        Ok(())
+    }
+
+    /// Like `ysend FILE`.
+    fn ysend(&mut self, name: &str) -> Result<(), ()> {
+        let data = match self.state.files.get(name) {
+            Some(data) => data.clone(),
+            None => {
+                self.print("Error: NotFound\n");
+                return Ok(());
+            }
+        };
+        let echo = std::mem::replace(&mut self.echo, false);
+        let mut source = FakeSource {
+            name,
+            data: &data,
+            offset: 0,
+            announced: false,
+        };
+        let mut sender = monotron_xmodem::Sender::new(monotron_xmodem::Protocol::Ymodem);
+        let timeout = Duration::from_millis(u64::from(monotron_xmodem::TIMEOUT_MS));
+        let status = loop {
+            let status = match self.read_byte(timeout)? {
+                Some(b) => sender.handle_byte(b, &mut MasterChannel(&mut self.master), &mut source),
+                None => sender.handle_timeout(&mut MasterChannel(&mut self.master)),
+            };
+            if status != monotron_xmodem::Status::Running {
+                break status;
+            }
+        };
+        self.echo = echo;
+        if status == monotron_xmodem::Status::Complete {
+            self.print("Transfer complete.\n");
+        } else {
+            self.print(&format!("Transfer failed: {:?}\n", status));
+        }
+        Ok(())
+    }
+}
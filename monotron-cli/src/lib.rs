@@ -0,0 +1,25 @@
+//! # monotron-cli
+//!
+//! Copyright (c) Jonathan 'theJPster' Pallant
+//!
+//! Licensed under either of
+//!
+//! - Apache License, Version 2.0 ([LICENSE-APACHE](LICENSE-APACHE) or
+//!   http://www.apache.org/licenses/LICENSE-2.0)
+//!
+//! - MIT license ([LICENSE-MIT](LICENSE-MIT) or http://opensource.org/licenses/MIT)
+//!
+//! at your option.
+//!
+//! Talks to a Monotron over its USB serial port (or anything else that looks
+//! like a terminal device), so you can upload programs, move files to and
+//! from the SD card, and run shell commands from a script.
+#![deny(missing_docs)]
+
+pub mod cp850;
+pub mod port;
+pub mod session;
+pub mod term;
+
+#[cfg(test)]
+mod fake;
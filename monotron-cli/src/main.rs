@@ -0,0 +1,145 @@
+//! # monotron-cli
+//!
+//! Copyright (c) Jonathan 'theJPster' Pallant
+//!
+//! Licensed under either of
+//!
+//! - Apache License, Version 2.0 ([LICENSE-APACHE](LICENSE-APACHE) or
+//!   http://www.apache.org/licenses/LICENSE-2.0)
+//!
+//! - MIT license ([LICENSE-MIT](LICENSE-MIT) or http://opensource.org/licenses/MIT)
+//!
+//! at your option.
+//!
+//! The command-line front end. See `monotron-cli --help`.
+
+use monotron_api::AppHeader;
+use monotron_cli::port::Port;
+use monotron_cli::session::Session;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Talks to a Monotron over a serial port")]
+struct Opt {
+    /// The serial port the Monotron is connected to
+    #[structopt(short, long, default_value = "/dev/ttyACM0", parse(from_os_str))]
+    port: PathBuf,
+    /// The baud rate of the serial port
+    #[structopt(short, long, default_value = "115200")]
+    baud: u32,
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Upload a program into application RAM
+    Upload {
+        /// Run the program once it is uploaded, and print its output
+        #[structopt(long)]
+        run: bool,
+        /// The program binary
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+    /// Copy a file to the SD card
+    Put {
+        /// The file to copy
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+        /// The name to give it on the SD card (defaults to its name here)
+        name: Option<String>,
+    },
+    /// Copy a file from the SD card
+    Get {
+        /// The name of the file on the SD card
+        name: String,
+        /// Where to put it (defaults to the same name, in this directory)
+        #[structopt(parse(from_os_str))]
+        file: Option<PathBuf>,
+    },
+    /// List the files on the SD card
+    Ls,
+    /// Run a shell command and print its output
+    Exec {
+        /// The command line, e.g. "dload HELLO.BIN"
+        command: String,
+    },
+    /// Start an interactive session (press Ctrl-] to quit)
+    Term,
+    /// Fill in the length and CRC-32 in a program's application header
+    Stamp {
+        /// The program binary (which is modified in place)
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+}
+
+fn main() {
+    let opt = Opt::from_args();
+    if let Err(e) = run(opt) {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run(opt: Opt) -> std::io::Result<()> {
+    if let Command::Stamp { file } = opt.command {
+        return stamp(&file);
+    }
+    let port = Port::open(&opt.port, opt.baud)?;
+    let mut session = Session::connect(port)?;
+    match opt.command {
+        Command::Upload { run, file } => {
+            let image = std::fs::read(&file)?;
+            println!("{}", session.upload(&image)?);
+            if run {
+                print!("{}", session.exec_with_timeout("run", None)?);
+            }
+        }
+        Command::Put { file, name } => {
+            let data = std::fs::read(&file)?;
+            let name = match name {
+                Some(name) => name,
+                None => file
+                    .file_name()
+                    .map(|n| n.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+            };
+            println!("{}", session.put(&name, &data)?);
+        }
+        Command::Get { name, file } => {
+            let data = session.get(&name)?;
+            std::fs::write(file.unwrap_or_else(|| PathBuf::from(&name)), data)?;
+        }
+        Command::Ls => println!("{}", session.exec("dir")?),
+        Command::Exec { command } => println!("{}", session.exec(&command)?),
+        Command::Term => monotron_cli::term::run(session.into_port())?,
+        Command::Stamp { .. } => unreachable!(),
+    }
+    Ok(())
+}
+
+/// Fill in the application header in a program binary, so the ROM can check
+/// it was loaded correctly.
+fn stamp(file: &Path) -> std::io::Result<()> {
+    let mut image = std::fs::read(file)?;
+    if !AppHeader::stamp(&mut image) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("{} has no application header", file.display()),
+        ));
+    }
+    std::fs::write(file, &image)?;
+    if let Some(header) = AppHeader::parse(&image) {
+        println!(
+            "{:?}: {} bytes, CRC32 0x{:08x}, needs API {}",
+            header.name(),
+            header.image_len,
+            header.crc32,
+            header.min_api_version
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,132 @@
+//! Talks to a serial port (or a pseudo-terminal) in raw mode.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::time::Duration;
+
+/// A serial port, configured for 8-N-1 with no flow control and no
+/// processing of the data.
+#[derive(Debug)]
+pub struct Port {
+    file: File,
+}
+
+/// Convert a baud rate into a `termios` speed constant.
+fn speed(baud: u32) -> io::Result<libc::speed_t> {
+    match baud {
+        9600 => Ok(libc::B9600),
+        19200 => Ok(libc::B19200),
+        38400 => Ok(libc::B38400),
+        57600 => Ok(libc::B57600),
+        115_200 => Ok(libc::B115200),
+        230_400 => Ok(libc::B230400),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unsupported baud rate {}", baud),
+        )),
+    }
+}
+
+/// Put a terminal device into raw mode at the given baud rate.
+fn configure(fd: RawFd, baud: u32) -> io::Result<()> {
+    let speed = speed(baud)?;
+    unsafe {
+        let mut t: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(fd, &mut t) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        libc::cfmakeraw(&mut t);
+        t.c_cflag |= libc::CLOCAL | libc::CREAD;
+        t.c_cflag &= !libc::CRTSCTS;
+        t.c_cc[libc::VMIN] = 1;
+        t.c_cc[libc::VTIME] = 0;
+        libc::cfsetispeed(&mut t, speed);
+        libc::cfsetospeed(&mut t, speed);
+        if libc::tcsetattr(fd, libc::TCSANOW, &t) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Wait up to `timeout` for `fd` to become readable. Returns `false` if it
+/// didn't.
+pub fn wait_readable(fd: RawFd, timeout: Duration) -> io::Result<bool> {
+    let mut pfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let ms = timeout.as_millis().min(i32::MAX as u128) as i32;
+    loop {
+        let result = unsafe { libc::poll(&mut pfd, 1, ms) };
+        if result >= 0 {
+            return Ok(result > 0);
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+impl Port {
+    /// Open a serial port device (like `/dev/ttyACM0`).
+    pub fn open<P: AsRef<Path>>(path: P, baud: u32) -> io::Result<Port> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(path)?;
+        Port::from_file(file, baud)
+    }
+
+    /// Use a terminal device that is already open, like one half of a
+    /// pseudo-terminal.
+    pub fn from_file(file: File, baud: u32) -> io::Result<Port> {
+        configure(file.as_raw_fd(), baud)?;
+        Ok(Port { file })
+    }
+
+    /// Get another handle to the same port, so one thread can read while
+    /// another writes.
+    pub fn try_clone(&self) -> io::Result<Port> {
+        Ok(Port {
+            file: self.file.try_clone()?,
+        })
+    }
+
+    /// Read whatever is available, waiting up to `timeout` for something to
+    /// arrive. Returns `Ok(0)` if nothing did.
+    pub fn read_timeout(&mut self, buffer: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        if !wait_readable(self.file.as_raw_fd(), timeout)? {
+            return Ok(0);
+        }
+        match self.file.read(buffer)? {
+            0 => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the serial port has gone away",
+            )),
+            n => Ok(n),
+        }
+    }
+}
+
+impl Read for Port {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buffer)
+    }
+}
+
+impl Write for Port {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        self.file.write(buffer)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
@@ -0,0 +1,466 @@
+//! Drives the Monotron's shell over a serial port.
+//!
+//! We ask the ROM (with `remote on`) to copy everything it prints to the USB
+//! UART. Then we can type commands and collect the output up to the next
+//! prompt. The ROM stops copying while a file transfer or upload is using the
+//! UART, so the protocols don't get mixed up with the text.
+
+use crate::cp850::{Decoder, Mode};
+use crate::port::Port;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+/// How long to wait for the shell to print something.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The prompt must be followed by this much silence before we believe it is
+/// really a prompt.
+const QUIET_TIME: Duration = Duration::from_millis(200);
+
+/// How long to wait for a reply to each upload frame.
+const FRAME_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How many times to send an upload frame before giving up.
+const MAX_FRAME_ATTEMPTS: usize = 10;
+
+/// How long the ROM gets to report a problem before we start a transfer.
+const TRANSFER_SETTLE_TIME: Duration = Duration::from_millis(500);
+
+/// An open connection to a Monotron's shell.
+pub struct Session {
+    port: Port,
+}
+
+/// Something which isn't a file on the host, but which we can send to, or
+/// receive from, the Monotron with YMODEM.
+struct MemSource<'a> {
+    name: &'a str,
+    data: &'a [u8],
+    offset: usize,
+    announced: bool,
+}
+
+/// Collects the files YMODEM sends us.
+#[derive(Default)]
+struct MemSink {
+    files: Vec<(String, Vec<u8>)>,
+}
+
+/// Gives the transfer engine somewhere to send bytes.
+struct PortChannel<'a> {
+    port: &'a mut Port,
+    result: io::Result<()>,
+}
+
+/// Make an `io::Error` with some text.
+fn other_error<S: Into<String>>(message: S) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, message.into())
+}
+
+/// Does this output end with a shell prompt?
+fn ends_with_prompt(output: &[u8]) -> bool {
+    output == b"> " || output.ends_with(b"\n> ")
+}
+
+/// Remove the echoed command from the front of some output, and the prompt
+/// from the end.
+fn strip_output(output: &[u8]) -> &[u8] {
+    let output = match output.iter().position(|&b| b == b'\n') {
+        Some(idx) => &output[idx + 1..],
+        None => output,
+    };
+    strip_output_prompt(output)
+}
+
+/// Remove the prompt (and any blank lines before it) from the end of some
+/// output.
+fn strip_output_prompt(output: &[u8]) -> &[u8] {
+    let mut output = output.strip_suffix(b"> ").unwrap_or(output);
+    while let Some((b'\n', rest)) | Some((b'\r', rest)) = output.split_last() {
+        output = rest;
+    }
+    output
+}
+
+impl<'a> monotron_xmodem::Source for MemSource<'a> {
+    type Error = ();
+
+    fn next_file(&mut self) -> Option<monotron_xmodem::FileInfo<'_>> {
+        if self.announced {
+            None
+        } else {
+            self.announced = true;
+            Some(monotron_xmodem::FileInfo {
+                name: self.name,
+                size: self.data.len() as u32,
+            })
+        }
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, ()> {
+        let remaining = &self.data[self.offset..];
+        let len = remaining.len().min(buffer.len());
+        buffer[0..len].copy_from_slice(&remaining[0..len]);
+        self.offset += len;
+        Ok(len)
+    }
+}
+
+impl monotron_xmodem::Sink for MemSink {
+    type Error = ();
+
+    fn start_file(&mut self, name: Option<&str>, _size: Option<u32>) -> Result<(), ()> {
+        self.files
+            .push((name.unwrap_or("").to_string(), Vec::new()));
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), ()> {
+        match self.files.last_mut() {
+            Some((_, contents)) => {
+                contents.extend_from_slice(data);
+                Ok(())
+            }
+            None => Err(()),
+        }
+    }
+
+    fn end_file(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+}
+
+impl<'a> monotron_xmodem::Channel for PortChannel<'a> {
+    fn write(&mut self, data: &[u8]) {
+        if self.result.is_ok() {
+            self.result = self.port.write_all(data);
+        }
+    }
+}
+
+impl Session {
+    /// Start talking to the shell on the given port.
+    pub fn connect(port: Port) -> io::Result<Session> {
+        let mut session = Session { port };
+        session.port.write_all(b"\r")?;
+        session.drain()?;
+        session.exec("remote on")?;
+        Ok(session)
+    }
+
+    /// Give back the serial port.
+    pub fn into_port(self) -> Port {
+        self.port
+    }
+
+    /// Throw away anything waiting to be read.
+    fn drain(&mut self) -> io::Result<()> {
+        let mut buffer = [0u8; 256];
+        while self.port.read_timeout(&mut buffer, QUIET_TIME)? != 0 {
+            // Keep going
+        }
+        Ok(())
+    }
+
+    /// Read until the shell prompt appears, returning everything before it.
+    /// If `timeout` is `None`, wait forever.
+    fn read_until_prompt(&mut self, timeout: Option<Duration>) -> io::Result<Vec<u8>> {
+        self.read_more_until_prompt(Vec::new(), timeout)
+    }
+
+    /// Like `read_until_prompt`, for when we've already read the start of
+    /// the output.
+    fn read_more_until_prompt(
+        &mut self,
+        mut output: Vec<u8>,
+        timeout: Option<Duration>,
+    ) -> io::Result<Vec<u8>> {
+        let mut buffer = [0u8; 256];
+        let mut last_activity = Instant::now();
+        loop {
+            let n = self.port.read_timeout(&mut buffer, QUIET_TIME)?;
+            if n != 0 {
+                output.extend_from_slice(&buffer[0..n]);
+                last_activity = Instant::now();
+            } else if ends_with_prompt(&output) {
+                return Ok(output);
+            } else if let Some(timeout) = timeout {
+                if last_activity.elapsed() > timeout {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "timed out waiting for the Monotron",
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Read until we see `pattern`. Anything after it is lost.
+    fn wait_for(&mut self, pattern: &[u8]) -> io::Result<()> {
+        let mut seen = Vec::new();
+        let mut buffer = [0u8; 1];
+        let start = Instant::now();
+        while !seen.ends_with(pattern) {
+            if start.elapsed() > COMMAND_TIMEOUT {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "timed out waiting for {:?}",
+                        String::from_utf8_lossy(pattern)
+                    ),
+                ));
+            }
+            if self.port.read_timeout(&mut buffer, QUIET_TIME)? != 0 {
+                seen.push(buffer[0]);
+            }
+        }
+        Ok(())
+    }
+
+    /// Type a command into the shell, and wait for its echo to come back.
+    fn start_command(&mut self, command: &str) -> io::Result<()> {
+        self.drain()?;
+        self.port.write_all(command.as_bytes())?;
+        self.port.write_all(b"\r")?;
+        self.wait_for(b"\n")
+    }
+
+    /// Run a shell command and return what it printed.
+    pub fn exec(&mut self, command: &str) -> io::Result<String> {
+        self.exec_with_timeout(command, Some(COMMAND_TIMEOUT))
+    }
+
+    /// Run a shell command and return what it printed, waiting as long as
+    /// it takes (`None`) or giving up if it stays silent for `timeout`.
+    pub fn exec_with_timeout(
+        &mut self,
+        command: &str,
+        timeout: Option<Duration>,
+    ) -> io::Result<String> {
+        self.drain()?;
+        self.port.write_all(command.as_bytes())?;
+        self.port.write_all(b"\r")?;
+        let output = self.read_until_prompt(timeout)?;
+        Ok(Decoder::new(Mode::Plain).decode(strip_output(&output)))
+    }
+
+    /// Upload an application image into RAM with `load --binary`. Returns
+    /// the ROM's report.
+    pub fn upload(&mut self, image: &[u8]) -> io::Result<String> {
+        use monotron_load_protocol as proto;
+        self.drain()?;
+        self.port.write_all(b"load --binary\r")?;
+        self.wait_for(b"READY\r\n")?;
+        let mut buffer = [0u8; proto::MAX_FRAME_LEN];
+        let mut seq = 0u8;
+        let len = proto::encode_header(seq, image.len() as u32, proto::crc32(image), &mut buffer);
+        self.send_frame(seq, &buffer[0..len])?;
+        for (idx, block) in image.chunks(proto::MAX_DATA_LEN).enumerate() {
+            seq = seq.wrapping_add(1);
+            let offset = (idx * proto::MAX_DATA_LEN) as u32;
+            let len = proto::encode_data(seq, offset, block, &mut buffer);
+            self.send_frame(seq, &buffer[0..len])?;
+        }
+        seq = seq.wrapping_add(1);
+        let len = proto::encode_end(seq, &mut buffer);
+        self.send_frame(seq, &buffer[0..len])?;
+        let output = self.read_until_prompt(Some(COMMAND_TIMEOUT))?;
+        Ok(Decoder::new(Mode::Plain).decode(strip_output_prompt(&output)))
+    }
+
+    /// Send an upload frame until the ROM accepts it.
+    fn send_frame(&mut self, seq: u8, frame: &[u8]) -> io::Result<()> {
+        use monotron_load_protocol::Response;
+        for _ in 0..MAX_FRAME_ATTEMPTS {
+            self.port.write_all(frame)?;
+            let mut reply = [0u8; 2];
+            let mut got = 0;
+            let start = Instant::now();
+            while got < reply.len() && start.elapsed() < FRAME_TIMEOUT {
+                got += self.port.read_timeout(&mut reply[got..], FRAME_TIMEOUT)?;
+            }
+            match Response::from_bytes(reply) {
+                Some(Response::Ack(n)) if got == 2 && n == seq => return Ok(()),
+                Some(Response::Failed(e)) if got == 2 => {
+                    return Err(other_error(format!("upload failed: {:?}", e)));
+                }
+                _ => {
+                    // Damaged, missing or out of sequence - send it again
+                }
+            }
+        }
+        Err(other_error("upload failed: too many retries"))
+    }
+
+    /// Copy a file to the SD card with YMODEM (using `yrecv`).
+    pub fn put(&mut self, name: &str, data: &[u8]) -> io::Result<String> {
+        self.start_command("yrecv")?;
+        let mut source = MemSource {
+            name,
+            data,
+            offset: 0,
+            announced: false,
+        };
+        let mut sender = monotron_xmodem::Sender::new(monotron_xmodem::Protocol::Ymodem);
+        let mut buffer = [0u8; 256];
+        let mut recent = Vec::new();
+        let mut leftover = Vec::new();
+        let timeout = Duration::from_millis(u64::from(monotron_xmodem::TIMEOUT_MS));
+        let status = loop {
+            let n = self.port.read_timeout(&mut buffer, timeout)?;
+            let mut channel = PortChannel {
+                port: &mut self.port,
+                result: Ok(()),
+            };
+            let mut status = monotron_xmodem::Status::Running;
+            if n == 0 {
+                status = sender.handle_timeout(&mut channel);
+            }
+            for (idx, &b) in buffer[0..n].iter().enumerate() {
+                // The ROM only sends us protocol bytes, so if a prompt turns
+                // up, the command must have failed.
+                recent.push(b);
+                if ends_with_prompt(&recent) {
+                    let text = Decoder::new(Mode::Plain).decode(strip_output_prompt(&recent));
+                    return Err(other_error(text));
+                }
+                status = sender.handle_byte(b, &mut channel, &mut source);
+                if status != monotron_xmodem::Status::Running {
+                    // The ROM finishes first, so its report can arrive right
+                    // behind the last ACK
+                    leftover.extend_from_slice(&buffer[idx + 1..n]);
+                    break;
+                }
+            }
+            channel.result?;
+            if status != monotron_xmodem::Status::Running {
+                break status;
+            }
+        };
+        self.finish_transfer(status, leftover)
+    }
+
+    /// Copy a file from the SD card with YMODEM (using `ysend`).
+    pub fn get(&mut self, name: &str) -> io::Result<Vec<u8>> {
+        self.start_command(&format!("ysend {}", name))?;
+        // If the ROM can't open the file, it will say so, and give us a prompt
+        let mut buffer = [0u8; 256];
+        let n = self.port.read_timeout(&mut buffer, TRANSFER_SETTLE_TIME)?;
+        if n != 0 {
+            let mut output = buffer[0..n].to_vec();
+            if !ends_with_prompt(&output) {
+                output.extend(self.read_until_prompt(Some(COMMAND_TIMEOUT))?);
+            }
+            let text = Decoder::new(Mode::Plain).decode(strip_output_prompt(&output));
+            return Err(other_error(text));
+        }
+        let mut sink = MemSink::default();
+        let mut receiver = monotron_xmodem::Receiver::new(monotron_xmodem::Protocol::Ymodem);
+        let timeout = Duration::from_millis(u64::from(monotron_xmodem::TIMEOUT_MS));
+        {
+            let mut channel = PortChannel {
+                port: &mut self.port,
+                result: Ok(()),
+            };
+            receiver.start(&mut channel);
+            channel.result?;
+        }
+        let status = loop {
+            let n = self.port.read_timeout(&mut buffer, timeout)?;
+            let mut channel = PortChannel {
+                port: &mut self.port,
+                result: Ok(()),
+            };
+            let mut status = monotron_xmodem::Status::Running;
+            if n == 0 {
+                status = receiver.handle_timeout(&mut channel);
+            }
+            for &b in &buffer[0..n] {
+                status = receiver.handle_byte(b, &mut channel, &mut sink);
+            }
+            channel.result?;
+            if status != monotron_xmodem::Status::Running {
+                break status;
+            }
+        };
+        self.finish_transfer(status, Vec::new())?;
+        match sink.files.pop() {
+            Some((_, contents)) => Ok(contents),
+            None => Err(other_error("no file received")),
+        }
+    }
+
+    /// Check that the transfer worked at both ends, returning the ROM's
+    /// report. `output` is anything which arrived after the last protocol
+    /// byte.
+    fn finish_transfer(
+        &mut self,
+        status: monotron_xmodem::Status,
+        output: Vec<u8>,
+    ) -> io::Result<String> {
+        let output = self.read_more_until_prompt(output, Some(COMMAND_TIMEOUT))?;
+        let text = Decoder::new(Mode::Plain).decode(strip_output_prompt(&output));
+        if status != monotron_xmodem::Status::Complete {
+            return Err(other_error(format!("transfer failed: {:?}", status)));
+        }
+        if !text.contains("Transfer complete") {
+            return Err(other_error(text));
+        }
+        Ok(text)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fake::FakeMonotron;
+
+    #[test]
+    fn strips_echo_and_prompt() {
+        assert_eq!(strip_output(b"dir\nA.TXT\nB.TXT\n> "), b"A.TXT\nB.TXT");
+        assert_eq!(strip_output(b"mount\n> "), b"");
+        assert!(ends_with_prompt(b"> "));
+        assert!(ends_with_prompt(b"foo\n> "));
+        assert!(!ends_with_prompt(b"foo> "));
+    }
+
+    #[test]
+    fn exec_captures_output() {
+        let (port, fake) = FakeMonotron::spawn(vec![("HELLO.TXT", b"Hi!".to_vec())]);
+        let mut session = Session::connect(port).unwrap();
+        assert_eq!(
+            session.exec("dir").unwrap(),
+            "HELLO.TXT          3".to_string()
+        );
+        assert!(session.exec("bogus").unwrap().contains("not found"));
+        drop(session);
+        let state = fake.join().unwrap();
+        assert_eq!(state.commands, vec!["remote on", "dir", "bogus"]);
+    }
+
+    #[test]
+    fn upload_image() {
+        let image: Vec<u8> = (0..5000).map(|x| (x * 7) as u8).collect();
+        let (port, fake) = FakeMonotron::spawn(vec![]);
+        let mut session = Session::connect(port).unwrap();
+        let report = session.upload(&image).unwrap();
+        assert!(report.starts_with("Loaded 5000 bytes"), "{:?}", report);
+        drop(session);
+        let state = fake.join().unwrap();
+        assert_eq!(&state.ram[0..5000], &image[..]);
+    }
+
+    #[test]
+    fn put_and_get() {
+        let data: Vec<u8> = (0..3000).map(|x| (x * 3) as u8).collect();
+        let (port, fake) = FakeMonotron::spawn(vec![]);
+        let mut session = Session::connect(port).unwrap();
+        session.put("DATA.BIN", &data).unwrap();
+        assert_eq!(session.get("DATA.BIN").unwrap(), data);
+        let err = session.get("MISSING.BIN").unwrap_err();
+        assert!(err.to_string().contains("NotFound"), "{}", err);
+        drop(session);
+        let state = fake.join().unwrap();
+        assert_eq!(state.files.get("DATA.BIN"), Some(&data));
+    }
+}
@@ -0,0 +1,174 @@
+//! An interactive terminal session with the Monotron.
+//!
+//! What the Monotron prints is converted from Code Page 850 (with Monotron
+//! colour escapes) to UTF-8 (with ANSI escapes). What you type is converted
+//! from UTF-8 to Code Page 850. Press Ctrl-] to quit.
+
+use crate::cp850::{self, Decoder, Mode};
+use crate::port::Port;
+use std::io::{self, Read, Write};
+use std::os::unix::io::RawFd;
+
+/// Ctrl-] ends the session, like `telnet`.
+const QUIT_KEY: u8 = 0x1D;
+
+/// Puts a terminal into raw mode, and puts it back afterwards.
+pub struct RawMode {
+    fd: RawFd,
+    saved: libc::termios,
+}
+
+/// Tracks ANSI escape sequences typed on the host (for example, by the cursor
+/// keys) so we can throw them away - the Monotron doesn't understand them.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum KeyState {
+    Normal,
+    Escape,
+    Csi,
+}
+
+/// Converts what is typed on the host into what the Monotron expects.
+pub struct Encoder {
+    state: KeyState,
+    utf8: Vec<u8>,
+}
+
+impl RawMode {
+    /// Put the terminal on `fd` into raw mode, until this is dropped.
+    pub fn enable(fd: RawFd) -> io::Result<RawMode> {
+        unsafe {
+            let mut saved: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(fd, &mut saved) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut raw = saved;
+            libc::cfmakeraw(&mut raw);
+            if libc::tcsetattr(fd, libc::TCSANOW, &raw) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(RawMode { fd, saved })
+        }
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(self.fd, libc::TCSANOW, &self.saved);
+        }
+    }
+}
+
+impl Default for Encoder {
+    fn default() -> Encoder {
+        Encoder::new()
+    }
+}
+
+impl Encoder {
+    /// Create a new encoder.
+    pub fn new() -> Encoder {
+        Encoder {
+            state: KeyState::Normal,
+            utf8: Vec::new(),
+        }
+    }
+
+    /// Process a byte typed on the host, appending anything that should be
+    /// sent to the Monotron.
+    pub fn feed(&mut self, byte: u8, out: &mut Vec<u8>) {
+        match (self.state, byte) {
+            (KeyState::Normal, 0x1B) => self.state = KeyState::Escape,
+            (KeyState::Escape, b'[') | (KeyState::Escape, b'O') => self.state = KeyState::Csi,
+            (KeyState::Escape, _) => {
+                // Escape on its own, then a normal key
+                self.state = KeyState::Normal;
+                out.push(0x1B);
+                self.feed(byte, out);
+            }
+            (KeyState::Csi, 0x40..=0x7E) => self.state = KeyState::Normal,
+            (KeyState::Csi, _) => {}
+            (KeyState::Normal, b'\n') => out.push(b'\r'),
+            (KeyState::Normal, _) => {
+                self.utf8.push(byte);
+                match std::str::from_utf8(&self.utf8) {
+                    Ok(s) => {
+                        out.extend(s.chars().filter_map(cp850::from_char));
+                        self.utf8.clear();
+                    }
+                    Err(e) if e.error_len().is_some() => {
+                        // Not valid UTF-8. Give up on it.
+                        self.utf8.clear();
+                    }
+                    Err(_) => {
+                        // Need more bytes
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Connect the terminal to the Monotron until the user presses Ctrl-].
+pub fn run(port: Port) -> io::Result<()> {
+    let mut reader = port.try_clone()?;
+    let mut writer = port;
+    std::thread::spawn(move || {
+        let mut decoder = Decoder::new(Mode::Ansi);
+        let mut buffer = [0u8; 256];
+        let stdout = io::stdout();
+        while let Ok(n) = reader.read(&mut buffer) {
+            if n == 0 {
+                break;
+            }
+            let mut stdout = stdout.lock();
+            let _ = stdout.write_all(decoder.decode(&buffer[0..n]).as_bytes());
+            let _ = stdout.flush();
+        }
+    });
+    let stdin = io::stdin();
+    let _raw = RawMode::enable(libc::STDIN_FILENO)?;
+    eprint!("Connected. Press Ctrl-] to quit.\r\n");
+    let mut encoder = Encoder::new();
+    let mut buffer = [0u8; 64];
+    loop {
+        let n = stdin.lock().read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        let mut out = Vec::new();
+        for &b in &buffer[0..n] {
+            if b == QUIT_KEY {
+                writer.write_all(&out)?;
+                return Ok(());
+            }
+            encoder.feed(b, &mut out);
+        }
+        writer.write_all(&out)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn encode(input: &[u8]) -> Vec<u8> {
+        let mut encoder = Encoder::new();
+        let mut out = Vec::new();
+        for &b in input {
+            encoder.feed(b, &mut out);
+        }
+        out
+    }
+
+    #[test]
+    fn keys() {
+        assert_eq!(encode(b"dir\r"), b"dir\r".to_vec());
+        assert_eq!(encode(b"dir\n"), b"dir\r".to_vec());
+        assert_eq!(encode("£½".as_bytes()), vec![0x9C, 0xAB]);
+        // Cursor keys are dropped
+        assert_eq!(encode(b"a\x1b[Ab\x1bOBc"), b"abc".to_vec());
+        assert_eq!(encode("€x".as_bytes()), b"x".to_vec());
+    }
+}
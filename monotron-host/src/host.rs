@@ -0,0 +1,311 @@
+//! The Linux host, as a `monotron_shell::Platform`.
+//!
+//! * The console is this terminal, with Code Page 850 converted to UTF-8.
+//! * The keyboard is this terminal too, in raw mode.
+//! * The SD card is a disk image, read and written with `embedded_sdmmc`,
+//!   just like the real card.
+//! * The RS-232 port is a pseudo-terminal. Connect to the other end with
+//!   `picocom`, `screen` or `monotron-cli`.
+//! * Notes played on the synthesiser are written to a log file, if you give
+//!   one.
+//! * The clock is the host's clock, and a video frame is 1/60th of a second
+//!   of real time.
+
+use crate::image::{Clock, ImageFile};
+use monotron_api::{Error, Timestamp};
+use monotron_cli::cp850::{self, Decoder, Mode};
+use monotron_cli::port::{self, Port};
+use monotron_cli::term::{Encoder, RawMode};
+use monotron_shell::{FileInfo, Key, Waveform};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::FromRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Ctrl-] quits, like `monotron-cli term`.
+const QUIT_KEY: u8 = 0x1D;
+
+/// How long a video frame is.
+const FRAME: Duration = Duration::from_micros(16_667);
+
+/// How long `idle` waits for.
+const IDLE: Duration = Duration::from_millis(1);
+
+/// The SD card.
+type Controller = embedded_sdmmc::Controller<ImageFile, Clock>;
+
+/// The machine we're pretending to be.
+pub struct Host {
+    /// Puts the terminal back when we quit
+    raw: Option<RawMode>,
+    decoder: Decoder,
+    encoder: Encoder,
+    /// Keys typed, in Code Page 850
+    keys: VecDeque<u8>,
+    cont: Controller,
+    clock: Clock,
+    /// Our end of the pseudo-terminal
+    rs232: Port,
+    /// The name of the other end, for people to connect to
+    rs232_name: PathBuf,
+    audio_log: Option<File>,
+    /// When we started, for counting frames
+    start: Instant,
+    frames: u32,
+}
+
+/// Convert an SD card error into something the Api can report.
+fn api_error(e: embedded_sdmmc::Error<io::Error>) -> Error {
+    match e {
+        embedded_sdmmc::Error::FileNotFound | embedded_sdmmc::Error::FilenameError(_) => {
+            Error::FileNotFound
+        }
+        embedded_sdmmc::Error::OpenedDirAsFile | embedded_sdmmc::Error::Unsupported => {
+            Error::NotSupported
+        }
+        _ => Error::IOError,
+    }
+}
+
+/// Convert a timestamp from the SD card into one the Api uses.
+fn api_timestamp(t: &embedded_sdmmc::Timestamp) -> Timestamp {
+    Timestamp {
+        year_from_1970: t.year_since_1970,
+        month: t.zero_indexed_month + 1,
+        days: t.zero_indexed_day + 1,
+        hours: t.hours,
+        minutes: t.minutes,
+        seconds: t.seconds,
+    }
+}
+
+/// Open a pseudo-terminal. Returns our end, and the name of the other end.
+fn open_pty() -> io::Result<(File, PathBuf)> {
+    let mut master = 0;
+    let mut slave = 0;
+    let mut name = [0 as std::os::raw::c_char; 64];
+    let result = unsafe {
+        libc::openpty(
+            &mut master,
+            &mut slave,
+            name.as_mut_ptr(),
+            std::ptr::null(),
+            std::ptr::null(),
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let name = unsafe { std::ffi::CStr::from_ptr(name.as_ptr()) };
+    let name = PathBuf::from(name.to_string_lossy().into_owned());
+    // Whoever connects opens it themselves
+    unsafe {
+        libc::close(slave);
+    }
+    Ok((unsafe { File::from_raw_fd(master) }, name))
+}
+
+impl Host {
+    /// Use the disk image at `image` as the SD card, and log notes played to
+    /// `audio_log` (if given). Puts the terminal into raw mode.
+    pub fn new(image: &Path, audio_log: Option<&Path>) -> io::Result<Host> {
+        let clock = Clock::new();
+        let cont = embedded_sdmmc::Controller::new(ImageFile::open(image)?, clock.clone());
+        let (pty, rs232_name) = open_pty()?;
+        let audio_log = match audio_log {
+            Some(path) => Some(File::create(path)?),
+            None => None,
+        };
+        Ok(Host {
+            raw: Some(RawMode::enable(libc::STDIN_FILENO)?),
+            decoder: Decoder::new(Mode::Ansi),
+            encoder: Encoder::new(),
+            keys: VecDeque::new(),
+            cont,
+            clock,
+            rs232: Port::from_file(pty, 115_200)?,
+            rs232_name,
+            audio_log,
+            start: Instant::now(),
+            frames: 0,
+        })
+    }
+
+    /// Where to connect to the RS-232 port.
+    pub fn rs232_name(&self) -> &Path {
+        &self.rs232_name
+    }
+
+    /// Put the terminal back, and stop.
+    fn quit(&mut self) -> ! {
+        drop(self.raw.take());
+        println!();
+        std::process::exit(0);
+    }
+
+    /// Read anything that's been typed.
+    fn poll_keyboard(&mut self) {
+        let mut buffer = [0u8; 64];
+        while let Ok(true) = port::wait_readable(libc::STDIN_FILENO, Duration::from_millis(0)) {
+            let count = match io::stdin().read(&mut buffer) {
+                Ok(0) | Err(_) => self.quit(),
+                Ok(count) => count,
+            };
+            let mut out = Vec::new();
+            for &b in &buffer[0..count] {
+                if b == QUIT_KEY {
+                    self.quit();
+                }
+                self.encoder.feed(b, &mut out);
+            }
+            self.keys.extend(out);
+        }
+    }
+
+    /// Send what the Monotron would draw to the terminal.
+    fn show(&mut self, bytes: &[u8]) {
+        let text = self.decoder.decode(bytes);
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        let _ = stdout.write_all(text.as_bytes());
+        let _ = stdout.flush();
+    }
+}
+
+impl std::fmt::Write for Host {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        let bytes: Vec<u8> = s
+            .chars()
+            .map(|ch| cp850::from_char(ch).unwrap_or(b'?'))
+            .collect();
+        self.show(&bytes);
+        Ok(())
+    }
+}
+
+impl monotron_shell::Console for Host {
+    fn write_u8(&mut self, ch: u8) {
+        self.show(&[ch]);
+    }
+}
+
+impl monotron_shell::Input for Host {
+    fn read_key(&mut self) -> Option<Key> {
+        self.poll_keyboard();
+        self.keys.pop_front().map(Key::Cp850)
+    }
+}
+
+impl monotron_shell::Storage for Host {
+    fn list_root(&mut self, f: &mut dyn FnMut(&FileInfo)) -> Result<(), Error> {
+        let cont = &mut self.cont;
+        let volume = cont
+            .get_volume(embedded_sdmmc::VolumeIdx(0))
+            .map_err(api_error)?;
+        let dir = cont.open_root_dir(&volume).map_err(api_error)?;
+        let result = cont.iterate_dir(&volume, &dir, |x| {
+            if !x.attributes.is_hidden() && !x.attributes.is_volume() {
+                f(&FileInfo {
+                    name: &format!("{}", x.name),
+                    size: x.size,
+                    mtime: api_timestamp(&x.mtime),
+                    is_dir: x.attributes.is_directory(),
+                });
+            }
+        });
+        cont.close_dir(&volume, dir);
+        result.map_err(api_error)
+    }
+
+    fn read_file(&mut self, name: &str, offset: u32, buffer: &mut [u8]) -> Result<usize, Error> {
+        let cont = &mut self.cont;
+        let mut volume = cont
+            .get_volume(embedded_sdmmc::VolumeIdx(0))
+            .map_err(api_error)?;
+        let dir = cont.open_root_dir(&volume).map_err(api_error)?;
+        let result =
+            match cont.open_file_in_dir(&mut volume, &dir, name, embedded_sdmmc::Mode::ReadOnly) {
+                Ok(mut file) => {
+                    let result = if offset >= file.length() {
+                        Ok(0)
+                    } else {
+                        let _ = file.seek_from_start(offset);
+                        cont.read(&volume, &mut file, buffer)
+                    };
+                    cont.close_file(&volume, file).and(result)
+                }
+                Err(e) => Err(e),
+            };
+        cont.close_dir(&volume, dir);
+        result.map_err(api_error)
+    }
+}
+
+impl monotron_shell::Serial for Host {
+    fn set_bitrate(&mut self, _bps: u32) -> Result<(), Error> {
+        // A pseudo-terminal goes as fast as it goes
+        Ok(())
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Error> {
+        // We get an error if nobody has the other end open, which looks
+        // just like nothing arriving
+        Ok(self
+            .rs232
+            .read_timeout(buffer, Duration::from_millis(0))
+            .unwrap_or(0))
+    }
+
+    fn write(&mut self, buffer: &[u8]) -> Result<usize, Error> {
+        self.rs232.write(buffer).map_err(|_| Error::IOError)
+    }
+}
+
+impl monotron_shell::Audio for Host {
+    fn play(&mut self, channel: u8, centi_hertz: u32, volume: u8, waveform: Waveform) {
+        let frames = self.frames;
+        if let Some(log) = self.audio_log.as_mut() {
+            let _ = writeln!(
+                log,
+                "frame {}: channel {} {}.{:02} Hz volume {} {:?}",
+                frames,
+                channel,
+                centi_hertz / 100,
+                centi_hertz % 100,
+                volume,
+                waveform
+            );
+        }
+    }
+}
+
+impl monotron_shell::Time for Host {
+    fn now(&mut self) -> Timestamp {
+        self.clock.now()
+    }
+
+    fn set_now(&mut self, timestamp: Timestamp) {
+        self.clock.set(&timestamp);
+    }
+
+    fn wait_frame(&mut self) {
+        self.frames = self.frames.wrapping_add(1);
+        let due = self.start + FRAME * self.frames;
+        let now = Instant::now();
+        if due > now {
+            std::thread::sleep(due - now);
+        } else {
+            // We've fallen behind (or the host was suspended), so start
+            // counting again from here
+            self.start = now;
+            self.frames = 0;
+        }
+        self.poll_keyboard();
+    }
+
+    fn idle(&mut self) {
+        std::thread::sleep(IDLE);
+    }
+}
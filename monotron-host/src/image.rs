@@ -0,0 +1,158 @@
+//! The SD card, as a disk image file, and the calendar clock, from the host's
+//! clock.
+
+use embedded_sdmmc::{Block, BlockCount, BlockDevice, BlockIdx, TimeSource};
+use monotron_api::Timestamp;
+use std::cell::{Cell, RefCell};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+/// An image of a whole SD card (partition table and all), which
+/// `embedded_sdmmc` can use instead of a card.
+pub struct ImageFile {
+    file: RefCell<File>,
+}
+
+/// The calendar clock. It runs from the host's clock (in local time), but
+/// can be set to something else. Clones share the same setting.
+#[derive(Debug, Clone, Default)]
+pub struct Clock {
+    /// Seconds to add to the host's clock
+    offset: Rc<Cell<libc::time_t>>,
+}
+
+impl ImageFile {
+    /// Open a disk image, for reading and writing.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<ImageFile> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(ImageFile {
+            file: RefCell::new(file),
+        })
+    }
+
+    /// Move to the start of a block.
+    fn seek(file: &mut File, idx: BlockIdx) -> io::Result<()> {
+        file.seek(SeekFrom::Start(u64::from(idx.0) * Block::LEN as u64))?;
+        Ok(())
+    }
+}
+
+impl BlockDevice for ImageFile {
+    type Error = io::Error;
+
+    fn read(
+        &self,
+        blocks: &mut [Block],
+        start_block_idx: BlockIdx,
+        _reason: &str,
+    ) -> Result<(), io::Error> {
+        let mut file = self.file.borrow_mut();
+        ImageFile::seek(&mut file, start_block_idx)?;
+        for block in blocks.iter_mut() {
+            file.read_exact(&mut block.contents)?;
+        }
+        Ok(())
+    }
+
+    fn write(&self, blocks: &[Block], start_block_idx: BlockIdx) -> Result<(), io::Error> {
+        let mut file = self.file.borrow_mut();
+        ImageFile::seek(&mut file, start_block_idx)?;
+        for block in blocks.iter() {
+            file.write_all(&block.contents)?;
+        }
+        Ok(())
+    }
+
+    fn num_blocks(&self) -> Result<BlockCount, io::Error> {
+        let len = self.file.borrow().metadata()?.len();
+        Ok(BlockCount((len / Block::LEN as u64) as u32))
+    }
+}
+
+impl Clock {
+    /// A clock showing the host's local time.
+    pub fn new() -> Clock {
+        Clock::default()
+    }
+
+    /// The host's clock, in seconds since 1970.
+    fn host_time() -> libc::time_t {
+        unsafe { libc::time(std::ptr::null_mut()) }
+    }
+
+    /// Get the date and time.
+    pub fn now(&self) -> Timestamp {
+        let time = Clock::host_time() + self.offset.get();
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        unsafe {
+            libc::localtime_r(&time, &mut tm);
+        }
+        Timestamp {
+            year_from_1970: (tm.tm_year - 70).clamp(0, 255) as u8,
+            month: (tm.tm_mon + 1) as u8,
+            days: tm.tm_mday as u8,
+            hours: tm.tm_hour as u8,
+            minutes: tm.tm_min as u8,
+            // There might be a leap second
+            seconds: tm.tm_sec.min(59) as u8,
+        }
+    }
+
+    /// Set the date and time.
+    pub fn set(&self, timestamp: &Timestamp) {
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        tm.tm_year = i32::from(timestamp.year_from_1970) + 70;
+        tm.tm_mon = i32::from(timestamp.month) - 1;
+        tm.tm_mday = i32::from(timestamp.days);
+        tm.tm_hour = i32::from(timestamp.hours);
+        tm.tm_min = i32::from(timestamp.minutes);
+        tm.tm_sec = i32::from(timestamp.seconds);
+        // Let the C library work out if it's summer time
+        tm.tm_isdst = -1;
+        let time = unsafe { libc::mktime(&mut tm) };
+        if time != -1 {
+            self.offset.set(time - Clock::host_time());
+        }
+    }
+}
+
+impl TimeSource for Clock {
+    fn get_timestamp(&self) -> embedded_sdmmc::Timestamp {
+        let time = self.now();
+        embedded_sdmmc::Timestamp {
+            year_since_1970: time.year_from_1970,
+            zero_indexed_month: time.month - 1,
+            zero_indexed_day: time.days - 1,
+            hours: time.hours,
+            minutes: time.minutes,
+            seconds: time.seconds,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn set_clock() {
+        let clock = Clock::new();
+        let other = clock.clone();
+        let timestamp = Timestamp {
+            year_from_1970: 50,
+            month: 2,
+            days: 29,
+            hours: 12,
+            minutes: 34,
+            seconds: 56,
+        };
+        clock.set(&timestamp);
+        let now = other.now();
+        // We might have ticked over in the meantime
+        assert_eq!((now.year_from_1970, now.month, now.days), (50, 2, 29));
+        assert_eq!((now.hours, now.minutes), (12, 34));
+        assert!(now.seconds >= 56);
+    }
+}
@@ -0,0 +1,60 @@
+//! # monotron-host
+//!
+//! Copyright (c) Jonathan 'theJPster' Pallant
+//!
+//! Licensed under either of
+//!
+//! - Apache License, Version 2.0 ([LICENSE-APACHE](LICENSE-APACHE) or
+//!   http://www.apache.org/licenses/LICENSE-2.0)
+//!
+//! - MIT license ([LICENSE-MIT](LICENSE-MIT) or http://opensource.org/licenses/MIT)
+//!
+//! at your option.
+//!
+//! Runs the Monotron's shell commands (from `monotron-shell`) in a Linux
+//! terminal, with a disk image as the SD card. See `monotron-host --help`.
+
+mod host;
+mod image;
+
+use monotron_shell::commands::COMMANDS;
+use monotron_shell::{Input, Shell, Time};
+use std::fmt::Write;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Runs the Monotron's shell in this terminal")]
+struct Opt {
+    /// A disk image to use as the SD card (e.g. made with `mkfs.fat -C`)
+    #[structopt(parse(from_os_str))]
+    image: PathBuf,
+    /// Write every note played on the synthesiser to this file
+    #[structopt(long, parse(from_os_str))]
+    audio_log: Option<PathBuf>,
+}
+
+fn main() {
+    let opt = Opt::from_args();
+    if let Err(e) = run(opt) {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run(opt: Opt) -> std::io::Result<()> {
+    let mut host = host::Host::new(&opt.image, opt.audio_log.as_deref())?;
+    let mut shell = Shell::new();
+    let rs232 = host.rs232_name().display().to_string();
+    let names: Vec<&str> = COMMANDS.iter().map(|c| c.name).collect();
+    // The host converts new-lines for the terminal, like the Monotron does
+    let _ = writeln!(host, "RS-232 is on {}. Press Ctrl-] to quit.", rs232);
+    let _ = writeln!(host, "Commands: {}, help", names.join(", "));
+    let _ = write!(host, "{}", Shell::PROMPT);
+    loop {
+        host.wait_frame();
+        while let Some(key) = host.read_key() {
+            shell.key(&mut host, key);
+        }
+    }
+}
@@ -0,0 +1,679 @@
+//! # monotron-load-protocol
+//!
+//! Copyright (c) Jonathan 'theJPster' Pallant
+//!
+//! Licensed under either of
+//!
+//! - Apache License, Version 2.0 ([LICENSE-APACHE](LICENSE-APACHE) or
+//!   http://www.apache.org/licenses/LICENSE-2.0)
+//!
+//! - MIT license ([LICENSE-MIT](LICENSE-MIT) or http://opensource.org/licenses/MIT)
+//!
+//! at your option.
+//!
+//! Describes the binary protocol used by `load --binary` to upload an
+//! application image into the Monotron's RAM.
+//!
+//! After the ROM prints `READY\r\n`, the host sends a sequence of frames:
+//!
+//! 1. A `Header` frame, giving the image length and its CRC32.
+//! 2. Any number of `Data` frames, each with an offset and up to
+//!    `MAX_DATA_LEN` bytes of the image.
+//! 3. An `End` frame.
+//!
+//! Every frame looks like this:
+//!
+//! | Offset | Length | Contents                                        |
+//! |--------|--------|-------------------------------------------------|
+//! | 0      | 1      | `FRAME_START`                                   |
+//! | 1      | 1      | The `FrameType`                                 |
+//! | 2      | 1      | Sequence number (wraps at 255)                  |
+//! | 3      | 2      | Payload length (little-endian)                  |
+//! | 5      | N      | Payload                                         |
+//! | 5 + N  | 2      | CRC-16/XMODEM of bytes 1..5+N (big-endian)      |
+//!
+//! The host must wait for a `Response` to each frame before sending the
+//! next. A `Nak` means the frame was damaged and must be sent again. The
+//! `End` frame is only acknowledged if the CRC32 of what is now in RAM matches
+//! the one in the `Header` frame.
+#![cfg_attr(not(test), no_std)]
+#![deny(missing_docs)]
+
+// ===========================================================================
+// Constants
+// ===========================================================================
+
+/// Marks the start of every frame.
+pub const FRAME_START: u8 = 0x7E;
+
+/// The most image data a single `Data` frame can carry.
+pub const MAX_DATA_LEN: usize = 512;
+
+/// The largest payload in any frame (a `Data` frame with its offset).
+pub const MAX_PAYLOAD_LEN: usize = 4 + MAX_DATA_LEN;
+
+/// The largest possible frame, including the start byte, header and CRC.
+pub const MAX_FRAME_LEN: usize = 5 + MAX_PAYLOAD_LEN + 2;
+
+/// First byte of a positive `Response`.
+const ACK: u8 = 0x06;
+/// First byte of a negative `Response`.
+const NAK: u8 = 0x15;
+/// First byte of a fatal error `Response`.
+const CAN: u8 = 0x18;
+
+/// The loader gives up if it hears nothing after this many timeouts.
+const MAX_TIMEOUTS: u8 = 10;
+
+// ===========================================================================
+// Types
+// ===========================================================================
+
+/// The different sorts of frame the host can send.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FrameType {
+    /// Payload is the image length (u32 LE) then the image CRC32 (u32 LE).
+    Header = 1,
+    /// Payload is an offset into the image (u32 LE) then some image data.
+    Data = 2,
+    /// No payload. Asks the ROM to check the CRC32.
+    End = 3,
+}
+
+/// A frame that has been successfully decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame<'a> {
+    /// What sort of frame this is
+    pub frame_type: FrameType,
+    /// The sequence number the host gave it
+    pub seq: u8,
+    /// The frame contents
+    pub payload: &'a [u8],
+}
+
+/// Why a loader gave up.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The image won't fit in application RAM.
+    TooBig = 1,
+    /// The CRC32 of the loaded image didn't match the header.
+    BadCrc = 2,
+    /// The host sent something that doesn't make sense.
+    Protocol = 3,
+    /// Nothing was heard from the host for too long.
+    TimedOut = 4,
+}
+
+/// The two-byte reply the ROM sends after each frame.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Response {
+    /// The frame with this sequence number was accepted.
+    Ack(u8),
+    /// The frame with this sequence number was damaged or missing. Please
+    /// send it again.
+    Nak(u8),
+    /// The transfer has failed and cannot continue.
+    Failed(Error),
+}
+
+/// The state of a `Loader`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Status {
+    /// Still waiting for the `End` frame.
+    Running,
+    /// The whole image arrived and the CRC32 matched.
+    Complete {
+        /// How many bytes were loaded
+        length: u32,
+        /// The CRC32 of the loaded bytes
+        crc32: u32,
+    },
+    /// The upload failed.
+    Failed(Error),
+}
+
+/// Collects bytes from the serial port and spots complete frames.
+pub struct FrameDecoder {
+    buffer: [u8; MAX_FRAME_LEN],
+    used: usize,
+}
+
+/// What happened when we fed a byte to a `FrameDecoder`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeResult<'a> {
+    /// We need more bytes.
+    Incomplete,
+    /// A whole, valid, frame has arrived.
+    Frame(Frame<'a>),
+    /// A frame arrived but it was damaged. We have the sequence number, but
+    /// it might be wrong too.
+    Damaged(u8),
+}
+
+/// Receives an application image into a RAM buffer. The ROM feeds it bytes
+/// from the UART and sends back whatever `Response` it returns.
+pub struct Loader<'a> {
+    ram: &'a mut [u8],
+    decoder: FrameDecoder,
+    /// Image length and CRC32, once we have seen the header
+    header: Option<(u32, u32)>,
+    next_seq: u8,
+    timeouts: u8,
+    status: Status,
+}
+
+// ===========================================================================
+// Functions and Impls
+// ===========================================================================
+
+/// Calculate the CRC-16/XMODEM (polynomial 0x1021, initial value zero, not
+/// reflected) used to protect each frame.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &b in data {
+        crc ^= u16::from(b) << 8;
+        for _ in 0..8 {
+            crc = if (crc & 0x8000) != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Calculate the CRC-32 (as used by Ethernet, zlib, etc) of a whole image.
+/// This gives the same answer as `crc::crc32::checksum_ieee`.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &b in data {
+        crc ^= u32::from(b);
+        for _ in 0..8 {
+            crc = if (crc & 1) != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Write a frame into `buffer`, returning how many bytes were used.
+///
+/// Panics if the buffer is too small or the payload is longer than
+/// `MAX_PAYLOAD_LEN`.
+pub fn encode_frame(frame_type: FrameType, seq: u8, payload: &[u8], buffer: &mut [u8]) -> usize {
+    assert!(payload.len() <= MAX_PAYLOAD_LEN);
+    let payload_end = 5 + payload.len();
+    buffer[0] = FRAME_START;
+    buffer[1] = frame_type as u8;
+    buffer[2] = seq;
+    buffer[3..5].copy_from_slice(&(payload.len() as u16).to_le_bytes());
+    buffer[5..payload_end].copy_from_slice(payload);
+    let crc = crc16(&buffer[1..payload_end]);
+    buffer[payload_end..payload_end + 2].copy_from_slice(&crc.to_be_bytes());
+    payload_end + 2
+}
+
+/// Write a `Header` frame into `buffer`, returning how many bytes were used.
+pub fn encode_header(seq: u8, length: u32, crc32: u32, buffer: &mut [u8]) -> usize {
+    let mut payload = [0u8; 8];
+    payload[0..4].copy_from_slice(&length.to_le_bytes());
+    payload[4..8].copy_from_slice(&crc32.to_le_bytes());
+    encode_frame(FrameType::Header, seq, &payload, buffer)
+}
+
+/// Write a `Data` frame into `buffer`, returning how many bytes were used.
+///
+/// Panics if `data` is longer than `MAX_DATA_LEN`.
+pub fn encode_data(seq: u8, offset: u32, data: &[u8], buffer: &mut [u8]) -> usize {
+    assert!(data.len() <= MAX_DATA_LEN);
+    let mut payload = [0u8; MAX_PAYLOAD_LEN];
+    payload[0..4].copy_from_slice(&offset.to_le_bytes());
+    payload[4..4 + data.len()].copy_from_slice(data);
+    encode_frame(FrameType::Data, seq, &payload[0..4 + data.len()], buffer)
+}
+
+/// Write an `End` frame into `buffer`, returning how many bytes were used.
+pub fn encode_end(seq: u8, buffer: &mut [u8]) -> usize {
+    encode_frame(FrameType::End, seq, &[], buffer)
+}
+
+/// Read a little-endian `u32` from the start of a slice.
+fn read_u32(data: &[u8]) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&data[0..4]);
+    u32::from_le_bytes(bytes)
+}
+
+impl FrameType {
+    fn from_u8(value: u8) -> Option<FrameType> {
+        match value {
+            1 => Some(FrameType::Header),
+            2 => Some(FrameType::Data),
+            3 => Some(FrameType::End),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    fn from_u8(value: u8) -> Error {
+        match value {
+            1 => Error::TooBig,
+            2 => Error::BadCrc,
+            4 => Error::TimedOut,
+            _ => Error::Protocol,
+        }
+    }
+}
+
+impl Response {
+    /// Convert to the two bytes sent on the wire.
+    pub fn to_bytes(self) -> [u8; 2] {
+        match self {
+            Response::Ack(seq) => [ACK, seq],
+            Response::Nak(seq) => [NAK, seq],
+            Response::Failed(e) => [CAN, e as u8],
+        }
+    }
+
+    /// Convert from the two bytes sent on the wire.
+    pub fn from_bytes(bytes: [u8; 2]) -> Option<Response> {
+        match bytes[0] {
+            ACK => Some(Response::Ack(bytes[1])),
+            NAK => Some(Response::Nak(bytes[1])),
+            CAN => Some(Response::Failed(Error::from_u8(bytes[1]))),
+            _ => None,
+        }
+    }
+}
+
+impl Default for FrameDecoder {
+    fn default() -> FrameDecoder {
+        FrameDecoder::new()
+    }
+}
+
+impl FrameDecoder {
+    /// Create a new, empty, decoder.
+    pub fn new() -> FrameDecoder {
+        FrameDecoder {
+            buffer: [0u8; MAX_FRAME_LEN],
+            used: 0,
+        }
+    }
+
+    /// Throw away any partial frame.
+    pub fn reset(&mut self) {
+        self.used = 0;
+    }
+
+    /// Is there a partial frame in the buffer?
+    pub fn in_frame(&self) -> bool {
+        self.used != 0
+    }
+
+    /// Add a byte, and see if we have a frame yet.
+    pub fn feed(&mut self, byte: u8) -> DecodeResult<'_> {
+        if self.used == 0 && byte != FRAME_START {
+            // Junk between frames - ignore it
+            return DecodeResult::Incomplete;
+        }
+        self.buffer[self.used] = byte;
+        self.used += 1;
+        if self.used < 5 {
+            return DecodeResult::Incomplete;
+        }
+        let seq = self.buffer[2];
+        let payload_len = usize::from(u16::from_le_bytes([self.buffer[3], self.buffer[4]]));
+        if payload_len > MAX_PAYLOAD_LEN {
+            self.used = 0;
+            return DecodeResult::Damaged(seq);
+        }
+        let frame_len = 5 + payload_len + 2;
+        if self.used < frame_len {
+            return DecodeResult::Incomplete;
+        }
+        self.used = 0;
+        let payload_end = 5 + payload_len;
+        let expected = u16::from_be_bytes([self.buffer[payload_end], self.buffer[payload_end + 1]]);
+        if crc16(&self.buffer[1..payload_end]) != expected {
+            return DecodeResult::Damaged(seq);
+        }
+        match FrameType::from_u8(self.buffer[1]) {
+            Some(frame_type) => DecodeResult::Frame(Frame {
+                frame_type,
+                seq,
+                payload: &self.buffer[5..payload_end],
+            }),
+            None => DecodeResult::Damaged(seq),
+        }
+    }
+}
+
+impl<'a> Loader<'a> {
+    /// Create a loader which will write into the given RAM.
+    pub fn new(ram: &'a mut [u8]) -> Loader<'a> {
+        Loader {
+            ram,
+            decoder: FrameDecoder::new(),
+            header: None,
+            next_seq: 0,
+            timeouts: 0,
+            status: Status::Running,
+        }
+    }
+
+    /// How is the upload going?
+    pub fn status(&self) -> Status {
+        self.status
+    }
+
+    /// Process a byte from the host. If we return a `Response`, send it back.
+    pub fn handle_byte(&mut self, byte: u8) -> Option<Response> {
+        if self.status != Status::Running {
+            return None;
+        }
+        self.timeouts = 0;
+        let (frame_type, seq, payload_len) = match self.decoder.feed(byte) {
+            DecodeResult::Incomplete => return None,
+            DecodeResult::Damaged(seq) => return Some(Response::Nak(seq)),
+            DecodeResult::Frame(frame) => (frame.frame_type, frame.seq, frame.payload.len()),
+        };
+        // The decoder buffer is still borrowed by the frame, so copy out what
+        // we need.
+        let payload_start = 5;
+        let mut payload = [0u8; 8];
+        let copy_len = core::cmp::min(payload_len, 8);
+        payload[0..copy_len]
+            .copy_from_slice(&self.decoder.buffer[payload_start..payload_start + copy_len]);
+        match frame_type {
+            FrameType::Header => {
+                if payload_len != 8 {
+                    return Some(self.fail(Error::Protocol));
+                }
+                let length = read_u32(&payload[0..4]);
+                let crc32 = read_u32(&payload[4..8]);
+                if length as usize > self.ram.len() {
+                    return Some(self.fail(Error::TooBig));
+                }
+                for b in self.ram.iter_mut() {
+                    *b = 0x00;
+                }
+                self.header = Some((length, crc32));
+                self.next_seq = seq.wrapping_add(1);
+                Some(Response::Ack(seq))
+            }
+            FrameType::Data => {
+                let (length, _) = match self.header {
+                    Some(header) => header,
+                    None => return Some(self.fail(Error::Protocol)),
+                };
+                if payload_len < 4 {
+                    return Some(self.fail(Error::Protocol));
+                }
+                if seq == self.next_seq.wrapping_sub(1) {
+                    // The host missed our ACK. Offsets are explicit, so
+                    // writing it again does no harm.
+                } else if seq != self.next_seq {
+                    return Some(Response::Nak(self.next_seq));
+                }
+                let offset = read_u32(&payload[0..4]) as usize;
+                let data_len = payload_len - 4;
+                // On the Monotron, `usize` is only 32 bits wide
+                let end = match offset.checked_add(data_len) {
+                    Some(end) if end <= length as usize => end,
+                    _ => return Some(self.fail(Error::Protocol)),
+                };
+                let data = &self.decoder.buffer[payload_start + 4..payload_start + payload_len];
+                self.ram[offset..end].copy_from_slice(data);
+                if seq == self.next_seq {
+                    self.next_seq = seq.wrapping_add(1);
+                }
+                Some(Response::Ack(seq))
+            }
+            FrameType::End => {
+                let (length, expected_crc) = match self.header {
+                    Some(header) => header,
+                    None => return Some(self.fail(Error::Protocol)),
+                };
+                let crc32 = crc32(&self.ram[0..length as usize]);
+                if crc32 == expected_crc {
+                    self.status = Status::Complete { length, crc32 };
+                    Some(Response::Ack(seq))
+                } else {
+                    Some(self.fail(Error::BadCrc))
+                }
+            }
+        }
+    }
+
+    /// Call this when nothing has been received for a while (about a second
+    /// is sensible). If we return a `Response`, send it back.
+    pub fn handle_timeout(&mut self) -> Option<Response> {
+        if self.status != Status::Running {
+            return None;
+        }
+        self.timeouts += 1;
+        if self.timeouts > MAX_TIMEOUTS {
+            return Some(self.fail(Error::TimedOut));
+        }
+        if self.decoder.in_frame() {
+            // Part of a frame went missing. Ask for it again.
+            self.decoder.reset();
+            Some(Response::Nak(self.next_seq))
+        } else {
+            None
+        }
+    }
+
+    /// Give up, and tell the host why.
+    fn fail(&mut self, error: Error) -> Response {
+        self.status = Status::Failed(error);
+        Response::Failed(error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn image(len: usize) -> Vec<u8> {
+        (0..len).map(|x| (x * 13 + 7) as u8).collect()
+    }
+
+    /// Feed a whole frame to the loader, returning the final response.
+    fn send(loader: &mut Loader, frame: &[u8]) -> Option<Response> {
+        let mut response = None;
+        for &b in frame {
+            if let Some(r) = loader.handle_byte(b) {
+                response = Some(r);
+            }
+        }
+        response
+    }
+
+    /// Upload an image, returning the loader's final status.
+    fn upload(ram: &mut [u8], data: &[u8], chunk: usize) -> Status {
+        let mut loader = Loader::new(ram);
+        let mut buffer = [0u8; MAX_FRAME_LEN];
+        let len = encode_header(0, data.len() as u32, crc32(data), &mut buffer);
+        assert_eq!(send(&mut loader, &buffer[0..len]), Some(Response::Ack(0)));
+        let mut seq = 1u8;
+        for (idx, block) in data.chunks(chunk).enumerate() {
+            let len = encode_data(seq, (idx * chunk) as u32, block, &mut buffer);
+            assert_eq!(send(&mut loader, &buffer[0..len]), Some(Response::Ack(seq)));
+            seq = seq.wrapping_add(1);
+        }
+        let len = encode_end(seq, &mut buffer);
+        send(&mut loader, &buffer[0..len]);
+        loader.status()
+    }
+
+    #[test]
+    fn crc_check_values() {
+        assert_eq!(crc16(b"123456789"), 0x31C3);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn response_round_trip() {
+        for r in &[
+            Response::Ack(5),
+            Response::Nak(200),
+            Response::Failed(Error::BadCrc),
+            Response::Failed(Error::TimedOut),
+        ] {
+            assert_eq!(Response::from_bytes(r.to_bytes()), Some(*r));
+        }
+        assert_eq!(Response::from_bytes([b'X', 0]), None);
+    }
+
+    #[test]
+    fn decoder_skips_junk() {
+        let mut decoder = FrameDecoder::new();
+        let mut buffer = [0u8; MAX_FRAME_LEN];
+        let len = encode_end(42, &mut buffer);
+        for &b in b"\r\nREADY\r\n" {
+            assert_eq!(decoder.feed(b), DecodeResult::Incomplete);
+        }
+        for &b in &buffer[0..len - 1] {
+            assert_eq!(decoder.feed(b), DecodeResult::Incomplete);
+        }
+        assert_eq!(
+            decoder.feed(buffer[len - 1]),
+            DecodeResult::Frame(Frame {
+                frame_type: FrameType::End,
+                seq: 42,
+                payload: &[]
+            })
+        );
+    }
+
+    #[test]
+    fn upload_whole_image() {
+        let data = image(24 * 1024);
+        let mut ram = vec![0xFFu8; 24 * 1024];
+        let status = upload(&mut ram, &data, MAX_DATA_LEN);
+        assert_eq!(
+            status,
+            Status::Complete {
+                length: data.len() as u32,
+                crc32: crc32(&data)
+            }
+        );
+        assert_eq!(ram, data);
+    }
+
+    #[test]
+    fn short_image_is_zero_padded() {
+        let data = image(1000);
+        let mut ram = vec![0xFFu8; 2048];
+        let status = upload(&mut ram, &data, 128);
+        assert!(matches!(status, Status::Complete { length: 1000, .. }));
+        assert_eq!(&ram[0..1000], &data[..]);
+        assert!(ram[1000..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn too_big() {
+        let mut ram = [0u8; 16];
+        let mut loader = Loader::new(&mut ram);
+        let mut buffer = [0u8; MAX_FRAME_LEN];
+        let len = encode_header(0, 17, 0, &mut buffer);
+        assert_eq!(
+            send(&mut loader, &buffer[0..len]),
+            Some(Response::Failed(Error::TooBig))
+        );
+        assert_eq!(loader.status(), Status::Failed(Error::TooBig));
+    }
+
+    #[test]
+    fn damaged_frame_is_nakked_then_resent() {
+        let data = image(300);
+        let mut ram = [0u8; 512];
+        let mut loader = Loader::new(&mut ram);
+        let mut buffer = [0u8; MAX_FRAME_LEN];
+        let len = encode_header(7, data.len() as u32, crc32(&data), &mut buffer);
+        assert_eq!(send(&mut loader, &buffer[0..len]), Some(Response::Ack(7)));
+        let len = encode_data(8, 0, &data, &mut buffer);
+        let mut damaged = buffer;
+        damaged[20] ^= 0x01;
+        assert_eq!(send(&mut loader, &damaged[0..len]), Some(Response::Nak(8)));
+        assert_eq!(send(&mut loader, &buffer[0..len]), Some(Response::Ack(8)));
+        // Pretend our ACK got lost and the host sends it again
+        assert_eq!(send(&mut loader, &buffer[0..len]), Some(Response::Ack(8)));
+        let len = encode_end(9, &mut buffer);
+        assert_eq!(send(&mut loader, &buffer[0..len]), Some(Response::Ack(9)));
+        assert!(matches!(
+            loader.status(),
+            Status::Complete { length: 300, .. }
+        ));
+    }
+
+    #[test]
+    fn missing_frame_is_detected() {
+        let data = image(200);
+        let mut ram = [0u8; 512];
+        let mut loader = Loader::new(&mut ram);
+        let mut buffer = [0u8; MAX_FRAME_LEN];
+        let len = encode_header(0, data.len() as u32, crc32(&data), &mut buffer);
+        send(&mut loader, &buffer[0..len]);
+        // Skip sequence number 1
+        let len = encode_data(2, 100, &data[100..], &mut buffer);
+        assert_eq!(send(&mut loader, &buffer[0..len]), Some(Response::Nak(1)));
+    }
+
+    #[test]
+    fn bad_crc32_fails() {
+        let data = image(64);
+        let mut ram = [0u8; 128];
+        let mut loader = Loader::new(&mut ram);
+        let mut buffer = [0u8; MAX_FRAME_LEN];
+        let len = encode_header(0, 64, crc32(&data) ^ 1, &mut buffer);
+        send(&mut loader, &buffer[0..len]);
+        let len = encode_data(1, 0, &data, &mut buffer);
+        send(&mut loader, &buffer[0..len]);
+        let len = encode_end(2, &mut buffer);
+        assert_eq!(
+            send(&mut loader, &buffer[0..len]),
+            Some(Response::Failed(Error::BadCrc))
+        );
+    }
+
+    #[test]
+    fn offset_past_the_end_fails() {
+        let data = image(32);
+        let mut ram = [0u8; 64];
+        let mut loader = Loader::new(&mut ram);
+        let mut buffer = [0u8; MAX_FRAME_LEN];
+        let len = encode_header(0, 64, 0, &mut buffer);
+        send(&mut loader, &buffer[0..len]);
+        // Adding the length to this offset overflows a 32-bit `usize`
+        let len = encode_data(1, 0xFFFF_FFF0, &data, &mut buffer);
+        assert_eq!(
+            send(&mut loader, &buffer[0..len]),
+            Some(Response::Failed(Error::Protocol))
+        );
+        assert_eq!(loader.status(), Status::Failed(Error::Protocol));
+    }
+
+    #[test]
+    fn truncated_frame_times_out() {
+        let mut ram = [0u8; 128];
+        let mut loader = Loader::new(&mut ram);
+        let mut buffer = [0u8; MAX_FRAME_LEN];
+        let len = encode_header(0, 64, 0, &mut buffer);
+        send(&mut loader, &buffer[0..len - 3]);
+        assert_eq!(loader.handle_timeout(), Some(Response::Nak(0)));
+        assert_eq!(send(&mut loader, &buffer[0..len]), Some(Response::Ack(0)));
+        let mut response = None;
+        for _ in 0..=MAX_TIMEOUTS {
+            response = loader.handle_timeout();
+        }
+        assert_eq!(response, Some(Response::Failed(Error::TimedOut)));
+    }
+}
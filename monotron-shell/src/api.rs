@@ -0,0 +1,134 @@
+//! # Api functions
+//!
+//! The logic behind the `monotron_api::Api` functions which don't need to
+//! know what hardware they're running on. The ROM's `extern "C"` functions
+//! turn their raw pointers into slices and strings, and then call these.
+
+use crate::{Audio, Serial, Waveform};
+use monotron_api::{EmptyResult, Error, Handle, HandleResult, SizeResult};
+
+// ===========================================================================
+// Constants
+// ===========================================================================
+
+/// The handle `open` gives out for the RS-232 port.
+pub const UART0_HANDLE: Handle = Handle(100);
+
+// ===========================================================================
+// Functions and Impls
+// ===========================================================================
+
+/// Open a device. Only the RS-232 port is supported, as `/dev/uart0@9600`
+/// or `/dev/uart0@115200`.
+pub fn open(p: &mut dyn Serial, filename: &str) -> HandleResult {
+    let bitrate = match filename {
+        "/dev/uart0@9600" => 9600,
+        "/dev/uart0@115200" => 115_200,
+        _ => return HandleResult::Error(Error::FileNotFound),
+    };
+    match p.set_bitrate(bitrate) {
+        Ok(()) => HandleResult::Ok(UART0_HANDLE),
+        Err(e) => HandleResult::Error(e),
+    }
+}
+
+/// Close a handle from `open`.
+pub fn close(handle: Handle) -> EmptyResult {
+    if handle == UART0_HANDLE {
+        EmptyResult::Ok
+    } else {
+        EmptyResult::Error(Error::BadFileHandle)
+    }
+}
+
+/// Read from a handle, without waiting. Returns how many bytes were read,
+/// which may be none.
+pub fn read(p: &mut dyn Serial, handle: Handle, buffer: &mut [u8]) -> SizeResult {
+    if handle != UART0_HANDLE {
+        return SizeResult::Error(Error::BadFileHandle);
+    }
+    match p.read(buffer) {
+        Ok(count) => SizeResult::Ok(count),
+        Err(e) => SizeResult::Error(e),
+    }
+}
+
+/// Write to a handle, without waiting. Returns how many bytes were written,
+/// which may be fewer than `buffer.len()`.
+pub fn write(p: &mut dyn Serial, handle: Handle, buffer: &[u8]) -> SizeResult {
+    if handle != UART0_HANDLE {
+        return SizeResult::Error(Error::BadFileHandle);
+    }
+    match p.write(buffer) {
+        Ok(count) => SizeResult::Ok(count),
+        Err(e) => SizeResult::Error(e),
+    }
+}
+
+/// Play a note. See `monotron_api::Api::play` for the arguments. Returns 0
+/// on success, or -1 if the channel or waveform are out of range.
+pub fn play(p: &mut dyn Audio, frequency: u32, channel: u8, waveform: u8, volume: u8) -> i32 {
+    let waveform = match waveform {
+        0 => Waveform::Square,
+        1 => Waveform::Sine,
+        2 => Waveform::Sawtooth,
+        3 => Waveform::Noise,
+        _ => {
+            return -1;
+        }
+    };
+    if channel > 2 {
+        return -1;
+    }
+    p.play(channel, frequency, volume, waveform);
+    0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::TestPlatform;
+
+    #[test]
+    fn uart() {
+        let mut p = TestPlatform::new();
+        assert_eq!(
+            format!("{:?}", open(&mut p, "/dev/uart1@9600")),
+            "Error(FileNotFound)"
+        );
+        assert_eq!(
+            format!("{:?}", open(&mut p, "/dev/uart0@9600")),
+            "Ok(Handle(100))"
+        );
+        assert_eq!(p.bitrate, 9600);
+        p.serial_rx.extend(b"abc");
+        let mut buffer = [0u8; 2];
+        assert_eq!(
+            format!("{:?}", read(&mut p, UART0_HANDLE, &mut buffer)),
+            "Ok(2)"
+        );
+        assert_eq!(&buffer, b"ab");
+        assert_eq!(
+            format!("{:?}", read(&mut p, Handle(0), &mut buffer)),
+            "Error(BadFileHandle)"
+        );
+        assert_eq!(
+            format!("{:?}", write(&mut p, UART0_HANDLE, b"xyz")),
+            "Ok(3)"
+        );
+        assert_eq!(p.serial_tx, b"xyz".to_vec());
+        assert_eq!(format!("{:?}", close(UART0_HANDLE)), "Ok");
+        assert_eq!(format!("{:?}", close(Handle(0))), "Error(BadFileHandle)");
+    }
+
+    #[test]
+    fn play_checks_arguments() {
+        let mut p = TestPlatform::new();
+        assert_eq!(play(&mut p, 44_000, 1, 2, 100), 0);
+        assert_eq!(play(&mut p, 44_000, 3, 2, 100), -1);
+        assert_eq!(play(&mut p, 44_000, 1, 4, 100), -1);
+        assert_eq!(p.notes, vec![(1, 44_000, 100, Waveform::Sawtooth)]);
+    }
+}
+
+// End of file
@@ -0,0 +1,723 @@
+//! # Shell commands
+//!
+//! Each command is a function which takes the `Platform` and the words
+//! typed after the command's name. Arguments are either positional, or
+//! `--name=value`, the same as the `menu` crate the ROM uses. The ROM's menu
+//! calls these functions directly; on a host, `Shell` reads a command line
+//! and looks the command up in `COMMANDS`.
+
+use crate::{FileInfo, Key, Platform, Waveform};
+use crc::Hasher32 as _;
+use monotron_api::Timestamp;
+
+// ===========================================================================
+// Constants
+// ===========================================================================
+
+/// How many lines we print before waiting for a key. One less than the
+/// screen has, to leave room for the prompt.
+const PAGE_LINES: usize = 35;
+
+/// How many characters fit on a line.
+const LINE_CHARS: usize = 48;
+
+/// How many bytes `ddump` shows on each line.
+const DUMP_CHUNK: usize = 16;
+
+/// How much of a file we read at a time.
+const READ_CHUNK: usize = 512;
+
+/// Ctrl-Q, which ends `rterm`.
+const QUIT_KEY: u8 = 17;
+
+/// The loudest a note can be.
+const MAX_VOLUME: u8 = 255;
+
+/// How many directory entries `dir` takes from the storage at a time.
+const DIR_BATCH: usize = 16;
+
+/// The longest command line `Shell` takes.
+const MAX_LINE_LEN: usize = 64;
+
+/// The most words `Shell` splits a command line into.
+const MAX_WORDS: usize = 16;
+
+/// The commands `Shell` knows.
+pub static COMMANDS: [Command; 6] = [
+    Command {
+        name: "dir",
+        usage: "",
+        help: "List the root dir",
+        function: dir,
+    },
+    Command {
+        name: "ddump",
+        usage: "FILE",
+        help: "Hexdump a file",
+        function: ddump,
+    },
+    Command {
+        name: "dpage",
+        usage: "FILE",
+        help: "Show a text file",
+        function: dpage,
+    },
+    Command {
+        name: "date",
+        usage: "[TIMESTAMP]",
+        help: "Get/set the date/time",
+        function: date,
+    },
+    Command {
+        name: "beep",
+        usage: "[--wave=X] [--len=N] [--freq=HZ] [--chan=N]",
+        help: "Make a beep.",
+        function: beep,
+    },
+    Command {
+        name: "rterm",
+        usage: "[--bitrate=BPS]",
+        help: "RS232 serial terminal",
+        function: rterm,
+    },
+];
+
+// ===========================================================================
+// Types
+// ===========================================================================
+
+/// A shell command.
+pub struct Command {
+    /// What you type to run it
+    pub name: &'static str,
+    /// The arguments it takes, for `help`
+    pub usage: &'static str,
+    /// What it does, for `help`
+    pub help: &'static str,
+    /// Runs it, with the words typed after its name
+    pub function: fn(&mut dyn Platform, &[&str]),
+}
+
+/// Reads a command line, a key at a time, and runs the command on it when
+/// Enter is pressed.
+pub struct Shell {
+    buffer: [u8; MAX_LINE_LEN],
+    used: usize,
+}
+
+/// A copy of a `FileInfo`, which we can keep once the listing is over.
+struct Entry {
+    name: [u8; 12],
+    name_len: usize,
+    size: u32,
+    mtime: Timestamp,
+    is_dir: bool,
+}
+
+/// Counts the lines we've printed, and waits for a key when the screen is
+/// full.
+struct Pager {
+    lines: usize,
+}
+
+// ===========================================================================
+// Functions and Impls
+// ===========================================================================
+
+/// Find an argument which isn't `--name=value`. `idx` counts from zero.
+fn positional<'a>(args: &[&'a str], idx: usize) -> Option<&'a str> {
+    args.iter()
+        .filter(|arg| !arg.starts_with("--"))
+        .nth(idx)
+        .cloned()
+}
+
+/// Find the value of a `--name=value` argument.
+fn named_value<'a>(args: &[&'a str], name: &str) -> Option<&'a str> {
+    args.iter().find_map(|arg| {
+        let rest = arg.strip_prefix("--")?.strip_prefix(name)?;
+        rest.strip_prefix('=')
+    })
+}
+
+/// Wait for a key, doing any background work while we do.
+fn wait_for_key(p: &mut dyn Platform) {
+    loop {
+        p.wait_frame();
+        if p.read_key().is_some() {
+            break;
+        }
+    }
+}
+
+impl Pager {
+    fn new() -> Pager {
+        Pager { lines: 0 }
+    }
+
+    /// Count a line, and wait if that fills the screen.
+    fn line_done(&mut self, p: &mut dyn Platform) {
+        self.lines += 1;
+        if self.lines == PAGE_LINES {
+            self.lines = 0;
+            let _ = write!(p, "Press a key...");
+            wait_for_key(p);
+            let _ = write!(p, "\r                \r");
+        }
+    }
+}
+
+/// Call `f` with each block of a file in the root directory, along with its
+/// offset. Stops early if `f` returns `false`.
+fn for_each_block<F>(
+    p: &mut dyn Platform,
+    filename: &str,
+    mut f: F,
+) -> Result<(), monotron_api::Error>
+where
+    F: FnMut(&mut dyn Platform, u32, &[u8]) -> bool,
+{
+    let mut buffer = [0u8; READ_CHUNK];
+    let mut offset = 0;
+    loop {
+        let count = p.read_file(filename, offset, &mut buffer)?;
+        if count == 0 || !f(p, offset, &buffer[0..count]) {
+            return Ok(());
+        }
+        offset += count as u32;
+    }
+}
+
+/// List the root directory.
+pub fn dir(p: &mut dyn Platform, _args: &[&str]) {
+    // The storage is busy while it lists the directory, so we can't print
+    // anything until it's finished. Take a batch of entries at a time, and
+    // print them afterwards.
+    let mut skip = 0;
+    loop {
+        let mut entries: [Option<Entry>; DIR_BATCH] = Default::default();
+        let mut count = 0;
+        let mut seen = 0;
+        let result = p.list_root(&mut |info: &FileInfo| {
+            if seen >= skip && count < entries.len() {
+                entries[count] = Some(Entry::new(info));
+                count += 1;
+            }
+            seen += 1;
+        });
+        if let Err(e) = result {
+            let _ = writeln!(p, "Error: {:?}", e);
+            return;
+        }
+        for entry in entries.iter().flatten() {
+            if entry.is_dir {
+                let _ = writeln!(p, "{:13} {} <DIR>", entry.name(), entry.mtime);
+            } else {
+                let _ = writeln!(
+                    p,
+                    "{:13} {} {} bytes",
+                    entry.name(),
+                    entry.mtime,
+                    entry.size
+                );
+            }
+        }
+        skip += count;
+        if skip >= seen {
+            return;
+        }
+    }
+}
+
+impl Entry {
+    fn new(info: &FileInfo) -> Entry {
+        let mut name = [0u8; 12];
+        let name_len = info.name.len().min(name.len());
+        name[0..name_len].copy_from_slice(&info.name.as_bytes()[0..name_len]);
+        Entry {
+            name,
+            name_len,
+            size: info.size,
+            mtime: info.mtime.clone(),
+            is_dir: info.is_dir,
+        }
+    }
+
+    fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[0..self.name_len]).unwrap_or("")
+    }
+}
+
+/// Do a hex-dump of a file in the root directory.
+pub fn ddump(p: &mut dyn Platform, args: &[&str]) {
+    let filename = match positional(args, 0) {
+        Some(filename) => filename,
+        None => {
+            let _ = writeln!(p, "Error: Need a FILE");
+            return;
+        }
+    };
+    let _ = write!(p, "Dumping {:?}...", filename);
+    let mut digest = crc::crc32::Digest::new(crc::crc32::IEEE);
+    let mut len = 0;
+    let result = for_each_block(p, filename, |_p, _offset, block| {
+        digest.write(block);
+        len += block.len();
+        true
+    });
+    if let Err(e) = result {
+        let _ = writeln!(p, "Error: {:?}", e);
+        return;
+    }
+    let _ = writeln!(p, "Loaded {} bytes, CRC32 0x{:08x}", len, digest.sum32());
+    let mut pager = Pager::new();
+    let result = for_each_block(p, filename, |p, offset, block| {
+        for (idx, line) in block.chunks(DUMP_CHUNK).enumerate() {
+            let _ = write!(p, "{:06x}:", offset as usize + (idx * DUMP_CHUNK));
+            for (idx, b) in line.iter().enumerate() {
+                if (idx % 4) == 0 {
+                    let _ = write!(p, " ");
+                }
+                let _ = write!(p, "{:02x}", b);
+            }
+            let _ = writeln!(p);
+            pager.line_done(p);
+        }
+        true
+    });
+    if let Err(e) = result {
+        let _ = writeln!(p, "Error: {:?}", e);
+    }
+}
+
+/// Display a text file in the root directory, a page at a time.
+pub fn dpage(p: &mut dyn Platform, args: &[&str]) {
+    let filename = match positional(args, 0) {
+        Some(filename) => filename,
+        None => {
+            let _ = writeln!(p, "Error: Need a FILE");
+            return;
+        }
+    };
+    let _ = writeln!(p, "Displaying {:?}...", filename);
+    let mut pager = Pager::new();
+    let mut line_length = 0;
+    let result = for_each_block(p, filename, |p, _offset, block| {
+        for &b in block {
+            p.write_u8(b);
+            line_length += 1;
+            if (b == b'\n') || (line_length == LINE_CHARS) {
+                line_length = 0;
+                pager.line_done(p);
+            }
+        }
+        true
+    });
+    if let Err(e) = result {
+        let _ = writeln!(p, "Error: {:?}", e);
+    }
+}
+
+/// Parse a date/time in `YYYY-MM-DDTHH:MM:SS` format. Anything missing off
+/// the end is taken as the start of that year, month, and so on.
+fn parse_timestamp(text: &str) -> Result<Timestamp, (&'static str, core::num::ParseIntError)> {
+    let mut iter = text.split(|c| " -T:/".contains(c));
+    Ok(Timestamp {
+        year_from_1970: (iter
+            .next()
+            .unwrap_or("1970")
+            .parse::<u32>()
+            .map_err(|e| ("Bad year", e))?
+            .saturating_sub(1970)) as u8,
+        month: iter
+            .next()
+            .unwrap_or("1")
+            .parse::<u8>()
+            .map_err(|e| ("Bad month", e))?,
+        days: iter
+            .next()
+            .unwrap_or("1")
+            .parse::<u8>()
+            .map_err(|e| ("Bad days", e))?,
+        hours: iter
+            .next()
+            .unwrap_or("0")
+            .parse::<u8>()
+            .map_err(|e| ("Bad hours", e))?,
+        minutes: iter
+            .next()
+            .unwrap_or("0")
+            .parse::<u8>()
+            .map_err(|e| ("Bad minutes", e))?,
+        seconds: iter
+            .next()
+            .unwrap_or("0")
+            .parse::<u8>()
+            .map_err(|e| ("Bad seconds", e))?,
+    })
+}
+
+/// Get the date, or set it if we're given one.
+pub fn date(p: &mut dyn Platform, args: &[&str]) {
+    if let Some(text) = positional(args, 0) {
+        let result = parse_timestamp(text).map(|timestamp| p.set_now(timestamp));
+        let _ = writeln!(p, "Setting the time - {:?}", result);
+    }
+    let now = p.now();
+    let _ = writeln!(p, "Date: {}", now);
+}
+
+/// Makes a short beep.
+///
+/// * `--wave` sets the waveform (sine, sawtooth, square or noise).
+/// * `--freq` sets the frequency (in Hz).
+/// * `--len` sets the duration (in 60Hz frames).
+/// * `--chan` sets the channel.
+pub fn beep(p: &mut dyn Platform, args: &[&str]) {
+    let waveform = match named_value(args, "wave") {
+        Some("square") | None => Waveform::Square,
+        Some("sine") => Waveform::Sine,
+        Some("sawtooth") => Waveform::Sawtooth,
+        Some("noise") => Waveform::Noise,
+        Some(e) => {
+            let _ = writeln!(p, "Unknown wave argument {:?}", e);
+            return;
+        }
+    };
+    let frequency = match named_value(args, "freq").map(|arg| arg.parse::<u16>()) {
+        Some(Ok(f)) => f,
+        Some(Err(e)) => {
+            let _ = writeln!(p, "Bad frequency argument {:?}", e);
+            return;
+        }
+        None => 440,
+    };
+    let duration = match named_value(args, "len").map(|arg| arg.parse::<usize>()) {
+        Some(Ok(f)) => f,
+        Some(Err(e)) => {
+            let _ = writeln!(p, "Bad duration argument {:?}", e);
+            return;
+        }
+        None => 60,
+    };
+    let channel = match named_value(args, "chan") {
+        Some("0") | None => 0,
+        Some("1") => 1,
+        Some("2") => 2,
+        Some(e) => {
+            let _ = writeln!(p, "Unknown chan argument {:?}", e);
+            return;
+        }
+    };
+
+    let _ = writeln!(
+        p,
+        "Playing...\r\nWaveform: {:?}\r\nFreq: {} Hz\r\nDuration: {} frames",
+        waveform, frequency, duration
+    );
+
+    let centi_hertz = u32::from(frequency) * 100;
+    p.play(channel, centi_hertz, MAX_VOLUME, waveform);
+    for _ in 0..duration {
+        p.wait_frame();
+    }
+    p.play(channel, centi_hertz, 0, waveform);
+}
+
+/// Connect the keyboard and screen to the RS-232 port, until Ctrl-Q is
+/// pressed.
+pub fn rterm(p: &mut dyn Platform, args: &[&str]) {
+    let bitrate = match named_value(args, "bitrate")
+        .unwrap_or("115200")
+        .parse::<u32>()
+    {
+        Ok(bitrate) => bitrate,
+        Err(_) => {
+            let _ = writeln!(p, "Error: Need an integer baud rate (e.g. 115200)");
+            return;
+        }
+    };
+    if let Err(e) = p.set_bitrate(bitrate) {
+        let _ = writeln!(p, "Error: {:?}", e);
+        return;
+    }
+    let _ = writeln!(p, "Connected at {} bps. Ctrl-Q to quit.", bitrate);
+    loop {
+        let mut buffer = [0u8; 16];
+        while let Ok(count @ 1..=16) = p.read(&mut buffer) {
+            for &b in &buffer[0..count] {
+                p.write_u8(b);
+            }
+        }
+        let result = match p.read_key() {
+            Some(Key::Cp850(QUIT_KEY)) => break,
+            // Enter sends a whole new-line
+            Some(Key::Cp850(b'\r')) => p.write(b"\r\n"),
+            Some(Key::Cp850(ch)) => p.write(&[ch]),
+            Some(Key::Special) | None => Ok(0),
+        };
+        if let Err(e) = result {
+            let _ = writeln!(p, "Error: {:?}", e);
+            break;
+        }
+        // Not a whole frame, or the receive buffer could overflow
+        p.idle();
+    }
+    let _ = writeln!(p, "Disconnected!");
+}
+
+/// List the commands.
+fn help(p: &mut dyn Platform) {
+    for command in COMMANDS.iter() {
+        let _ = writeln!(p, "{} {}", command.name, command.usage);
+        let _ = writeln!(p, "    {}", command.help);
+    }
+}
+
+impl Shell {
+    /// Printed when we're ready for a command.
+    pub const PROMPT: &'static str = "> ";
+
+    /// Make a shell, with an empty command line.
+    pub const fn new() -> Shell {
+        Shell {
+            buffer: [0u8; MAX_LINE_LEN],
+            used: 0,
+        }
+    }
+
+    /// Handle a key pressed at the prompt. Printable characters are added
+    /// to the command line, Backspace takes one off, and Enter runs it and
+    /// prints a new prompt.
+    pub fn key(&mut self, p: &mut dyn Platform, key: Key) {
+        match key {
+            Key::Cp850(b'\r') | Key::Cp850(b'\n') => {
+                let _ = writeln!(p);
+                let mut line = [0u8; MAX_LINE_LEN];
+                line[0..self.used].copy_from_slice(&self.buffer[0..self.used]);
+                let len = self.used;
+                self.used = 0;
+                run_line(p, core::str::from_utf8(&line[0..len]).unwrap_or(""));
+                let _ = write!(p, "{}", Shell::PROMPT);
+            }
+            Key::Cp850(0x08) | Key::Cp850(0x7F) => {
+                if self.used > 0 {
+                    self.used -= 1;
+                    let _ = write!(p, "\u{0008} \u{0008}");
+                }
+            }
+            // We only take ASCII, so the line is always valid UTF-8
+            Key::Cp850(ch @ 0x20..=0x7E) if self.used < self.buffer.len() => {
+                self.buffer[self.used] = ch;
+                self.used += 1;
+                p.write_u8(ch);
+            }
+            Key::Cp850(_) | Key::Special => {}
+        }
+    }
+}
+
+impl Default for Shell {
+    fn default() -> Shell {
+        Shell::new()
+    }
+}
+
+/// Run a command line.
+pub fn run_line(p: &mut dyn Platform, line: &str) {
+    let mut words = line.split_whitespace();
+    let name = match words.next() {
+        Some(name) => name,
+        None => return,
+    };
+    let mut args = [""; MAX_WORDS];
+    let mut argc = 0;
+    for word in words {
+        if argc == args.len() {
+            let _ = writeln!(p, "Error: Too many arguments.");
+            return;
+        }
+        args[argc] = word;
+        argc += 1;
+    }
+    if name == "help" {
+        help(p);
+    } else if let Some(command) = COMMANDS.iter().find(|c| c.name == name) {
+        (command.function)(p, &args[0..argc]);
+    } else {
+        let _ = writeln!(p, "Error: Command {:?} not found. Try 'help'.", name);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::TestPlatform;
+
+    fn run(p: &mut TestPlatform, line: &str) -> String {
+        p.output.clear();
+        run_line(p, line);
+        p.output.clone()
+    }
+
+    #[test]
+    fn arguments() {
+        let args = ["--wave=sine", "FILE.TXT", "--freq=", "--len", "two"];
+        assert_eq!(positional(&args, 0), Some("FILE.TXT"));
+        assert_eq!(positional(&args, 1), Some("two"));
+        assert_eq!(positional(&args, 2), None);
+        assert_eq!(named_value(&args, "wave"), Some("sine"));
+        assert_eq!(named_value(&args, "freq"), Some(""));
+        assert_eq!(named_value(&args, "len"), None);
+        assert_eq!(named_value(&args, "wav"), None);
+    }
+
+    #[test]
+    fn dir_lists_files() {
+        let mut p = TestPlatform::new();
+        p.files.insert("HELLO.TXT".into(), b"Hello".to_vec());
+        p.files.insert("APP.BIN".into(), vec![0u8; 1000]);
+        assert_eq!(
+            run(&mut p, "dir"),
+            "APP.BIN       2019-03-04T05:06:07 1000 bytes\n\
+             HELLO.TXT     2019-03-04T05:06:07 5 bytes\n"
+        );
+    }
+
+    #[test]
+    fn dir_lists_more_than_a_screen() {
+        let mut p = TestPlatform::new();
+        for idx in 0..80 {
+            p.files.insert(format!("F{:02}.TXT", idx), vec![]);
+        }
+        let output = run(&mut p, "dir");
+        assert_eq!(output.lines().count(), 80);
+        assert!(output.lines().last().unwrap().starts_with("F79.TXT "));
+    }
+
+    #[test]
+    fn ddump() {
+        let mut p = TestPlatform::new();
+        p.files
+            .insert("DATA.BIN".into(), (0..20).collect::<Vec<u8>>());
+        assert_eq!(
+            run(&mut p, "ddump DATA.BIN"),
+            format!(
+                "Dumping \"DATA.BIN\"...Loaded 20 bytes, CRC32 0x{:08x}\n\
+                 000000: 00010203 04050607 08090a0b 0c0d0e0f\n\
+                 000010: 10111213\n",
+                crc::crc32::checksum_ieee(&(0..20).collect::<Vec<u8>>())
+            )
+        );
+        assert_eq!(
+            run(&mut p, "ddump MISSING.BIN"),
+            "Dumping \"MISSING.BIN\"...Error: FileNotFound\n"
+        );
+        assert_eq!(run(&mut p, "ddump"), "Error: Need a FILE\n");
+    }
+
+    #[test]
+    fn dpage_waits_for_a_key() {
+        let mut p = TestPlatform::new();
+        let mut text = String::new();
+        for idx in 0..40 {
+            text.push_str(&format!("Line {}\n", idx));
+        }
+        p.files.insert("TEXT.TXT".into(), text.into_bytes());
+        p.type_str("x");
+        let output = run(&mut p, "dpage TEXT.TXT");
+        assert!(p.keys.is_empty());
+        assert!(output.starts_with("Displaying \"TEXT.TXT\"...\nLine 0\n"));
+        assert!(output.contains("Line 34\nPress a key...\r                \rLine 35\n"));
+        assert!(output.ends_with("Line 39\n"));
+    }
+
+    #[test]
+    fn dpage_wraps_long_lines() {
+        let mut p = TestPlatform::new();
+        // Each of these fills two lines of the screen
+        p.files.insert("WIDE.TXT".into(), vec![b'x'; 48 * 35]);
+        p.type_str("x");
+        run(&mut p, "dpage WIDE.TXT");
+        assert!(p.keys.is_empty());
+    }
+
+    #[test]
+    fn date() {
+        let mut p = TestPlatform::new();
+        assert_eq!(run(&mut p, "date"), "Date: 2019-03-04T05:06:07\n");
+        assert_eq!(
+            run(&mut p, "date 2020-02-29T23:59:58"),
+            "Setting the time - Ok(())\nDate: 2020-02-29T23:59:58\n"
+        );
+        assert!(run(&mut p, "date 2020-xx").starts_with("Setting the time - Err((\"Bad month\""));
+        assert_eq!(p.now.as_ref().unwrap().year_from_1970, 50);
+    }
+
+    #[test]
+    fn beep() {
+        let mut p = TestPlatform::new();
+        run(&mut p, "beep --wave=sine --freq=1000 --len=3 --chan=2");
+        assert_eq!(
+            p.notes,
+            vec![
+                (2, 100_000, MAX_VOLUME, Waveform::Sine),
+                (2, 100_000, 0, Waveform::Sine)
+            ]
+        );
+        assert_eq!(p.frames, 3);
+        assert_eq!(
+            run(&mut p, "beep --wave=triangle"),
+            "Unknown wave argument \"triangle\"\n"
+        );
+        assert_eq!(
+            run(&mut p, "beep --chan=3"),
+            "Unknown chan argument \"3\"\n"
+        );
+        assert_eq!(p.notes.len(), 2);
+    }
+
+    #[test]
+    fn rterm() {
+        let mut p = TestPlatform::new();
+        p.serial_rx.extend(b"login: ");
+        p.type_str("me\r\x11");
+        let output = run(&mut p, "rterm --bitrate=9600");
+        assert_eq!(
+            output,
+            "Connected at 9600 bps. Ctrl-Q to quit.\nlogin: Disconnected!\n"
+        );
+        assert_eq!(p.bitrate, 9600);
+        assert_eq!(p.serial_tx, b"me\r\n".to_vec());
+        assert_eq!(
+            run(&mut p, "rterm --bitrate=fast"),
+            "Error: Need an integer baud rate (e.g. 115200)\n"
+        );
+    }
+
+    #[test]
+    fn shell() {
+        let mut p = TestPlatform::new();
+        let mut shell = Shell::new();
+        for b in b"daet\x08\x08te\r".iter() {
+            shell.key(&mut p, Key::Cp850(*b));
+        }
+        assert_eq!(
+            p.output,
+            "daet\u{8} \u{8}\u{8} \u{8}te\nDate: 2019-03-04T05:06:07\n> "
+        );
+        p.output.clear();
+        for b in b"fish\r".iter() {
+            shell.key(&mut p, Key::Cp850(*b));
+        }
+        assert!(p
+            .output
+            .ends_with("Error: Command \"fish\" not found. Try 'help'.\n> "));
+        p.output.clear();
+        shell.key(&mut p, Key::Cp850(b'\r'));
+        assert_eq!(p.output, "\n> ");
+        assert!(run(&mut p, "help").starts_with("dir \n    List the root dir\n"));
+    }
+}
+
+// End of file
@@ -0,0 +1,296 @@
+//! # monotron-shell
+//!
+//! Copyright (c) Jonathan 'theJPster' Pallant
+//!
+//! Licensed under either of
+//!
+//! - Apache License, Version 2.0 ([LICENSE-APACHE](LICENSE-APACHE) or
+//!   http://www.apache.org/licenses/LICENSE-2.0)
+//!
+//! - MIT license ([LICENSE-MIT](LICENSE-MIT) or http://opensource.org/licenses/MIT)
+//!
+//! at your option.
+//!
+//! The parts of the Monotron's shell and Api which don't care what hardware
+//! they're running on.
+//!
+//! Everything here talks to the machine through six traits - `Console`,
+//! `Input`, `Storage`, `Serial`, `Audio` and `Time` - which together make a
+//! `Platform`. The ROM implements them on top of the frame buffer, the
+//! keyboard, the SD card, the RS-232 UART and the synthesiser. The
+//! `monotron-host` binary implements them on top of a Linux terminal, an SD
+//! card image file and a pseudo-terminal, so the same commands can be run
+//! (and tested) without a board.
+//!
+//! * `commands` has the shell commands, and a `Shell` which reads a command
+//!   line and runs them.
+//! * `api` has the logic behind some of the `monotron_api::Api` functions.
+//!
+//! Not everything has moved yet. Batch files, output redirection, the `PATH`
+//! search, `run` and `load`, the XMODEM/YMODEM commands and the SD card file
+//! handles of the `Api` are still in the ROM, because `Storage` can only list
+//! and read files for now.
+#![cfg_attr(not(test), no_std)]
+#![deny(missing_docs)]
+
+pub mod api;
+pub mod commands;
+
+pub use commands::Shell;
+
+use monotron_api::{Error, Timestamp};
+
+// ===========================================================================
+// Types
+// ===========================================================================
+
+/// Something typed on the keyboard.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Key {
+    /// A character, in Code Page 850
+    Cp850(u8),
+    /// A key with no character, like the cursor keys
+    Special,
+}
+
+/// The waveforms the synthesiser can play.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Waveform {
+    /// A square wave
+    Square,
+    /// A sine wave
+    Sine,
+    /// A sawtooth wave
+    Sawtooth,
+    /// White noise
+    Noise,
+}
+
+/// Describes a file (or directory) in a directory listing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileInfo<'a> {
+    /// The name, in 8.3 format
+    pub name: &'a str,
+    /// How long it is, in bytes
+    pub size: u32,
+    /// When it was last modified
+    pub mtime: Timestamp,
+    /// Whether it's a directory
+    pub is_dir: bool,
+}
+
+/// Somewhere to print text. Bytes are Code Page 850, with the escape
+/// sequences described in `monotron_api::Api::puts`. Strings are UTF-8, and
+/// characters Code Page 850 doesn't have are printed as something else.
+pub trait Console: core::fmt::Write {
+    /// Print one Code Page 850 character (or control character).
+    fn write_u8(&mut self, ch: u8);
+}
+
+/// Somewhere to read keys from.
+pub trait Input {
+    /// Take the next key pressed, if there is one. Doesn't wait.
+    fn read_key(&mut self) -> Option<Key>;
+}
+
+/// Somewhere to keep files. Only the root directory of the first volume is
+/// used.
+pub trait Storage {
+    /// Call `f` for each file in the root directory, skipping hidden files
+    /// and volume labels.
+    fn list_root(&mut self, f: &mut dyn FnMut(&FileInfo)) -> Result<(), Error>;
+
+    /// Read from a file in the root directory, starting `offset` bytes in.
+    /// Returns how many bytes were read, which is zero at the end of the
+    /// file.
+    fn read_file(&mut self, name: &str, offset: u32, buffer: &mut [u8]) -> Result<usize, Error>;
+}
+
+/// The RS-232 serial port.
+pub trait Serial {
+    /// Change the bit rate (e.g. 115200).
+    fn set_bitrate(&mut self, bps: u32) -> Result<(), Error>;
+
+    /// Read whatever has arrived, up to `buffer.len()` bytes. Doesn't wait.
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Error>;
+
+    /// Write as much of `buffer` as will go without waiting. Returns how much
+    /// that was.
+    fn write(&mut self, buffer: &[u8]) -> Result<usize, Error>;
+}
+
+/// The synthesiser.
+pub trait Audio {
+    /// Start playing a note on a channel (0, 1 or 2), replacing whatever it
+    /// was playing. A volume of zero stops the channel.
+    fn play(&mut self, channel: u8, centi_hertz: u32, volume: u8, waveform: Waveform);
+}
+
+/// The calendar clock, and the video frame timer.
+pub trait Time {
+    /// Get the date and time.
+    fn now(&mut self) -> Timestamp;
+
+    /// Set the date and time.
+    fn set_now(&mut self, timestamp: Timestamp);
+
+    /// Wait for the next video frame (which is 1/60th of a second). The
+    /// machine does any background work it has while we wait.
+    fn wait_frame(&mut self);
+
+    /// Wait a moment - until the next interrupt, on the Monotron. Like
+    /// `wait_frame`, the machine does any background work it has.
+    fn idle(&mut self);
+}
+
+/// Everything the shell needs from the machine it runs on.
+pub trait Platform: Console + Input + Storage + Serial + Audio + Time {}
+
+// ===========================================================================
+// Functions and Impls
+// ===========================================================================
+
+impl<T> Platform for T where T: Console + Input + Storage + Serial + Audio + Time {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::{BTreeMap, VecDeque};
+
+    /// A pretend machine, which keeps everything in memory.
+    #[derive(Default)]
+    pub(crate) struct TestPlatform {
+        /// Everything printed, with Code Page 850 bytes as `char`s
+        pub(crate) output: String,
+        pub(crate) keys: VecDeque<Key>,
+        pub(crate) files: BTreeMap<String, Vec<u8>>,
+        /// What the other end of the serial port has sent us
+        pub(crate) serial_rx: VecDeque<u8>,
+        /// What we've sent the other end of the serial port
+        pub(crate) serial_tx: Vec<u8>,
+        pub(crate) bitrate: u32,
+        /// Every note played, as (channel, centi-hertz, volume, waveform)
+        pub(crate) notes: Vec<(u8, u32, u8, Waveform)>,
+        pub(crate) now: Option<Timestamp>,
+        pub(crate) frames: u32,
+    }
+
+    impl TestPlatform {
+        pub(crate) fn new() -> TestPlatform {
+            TestPlatform {
+                now: Some(Timestamp {
+                    year_from_1970: 49,
+                    month: 3,
+                    days: 4,
+                    hours: 5,
+                    minutes: 6,
+                    seconds: 7,
+                }),
+                ..Default::default()
+            }
+        }
+
+        pub(crate) fn type_str(&mut self, s: &str) {
+            self.keys.extend(s.bytes().map(Key::Cp850));
+        }
+    }
+
+    impl core::fmt::Write for TestPlatform {
+        fn write_str(&mut self, s: &str) -> core::fmt::Result {
+            self.output.push_str(s);
+            Ok(())
+        }
+    }
+
+    impl Console for TestPlatform {
+        fn write_u8(&mut self, ch: u8) {
+            self.output.push(ch as char);
+        }
+    }
+
+    impl Input for TestPlatform {
+        fn read_key(&mut self) -> Option<Key> {
+            self.keys.pop_front()
+        }
+    }
+
+    impl Storage for TestPlatform {
+        fn list_root(&mut self, f: &mut dyn FnMut(&FileInfo)) -> Result<(), Error> {
+            let mtime = self.now.clone().unwrap();
+            for (name, contents) in self.files.iter() {
+                f(&FileInfo {
+                    name,
+                    size: contents.len() as u32,
+                    mtime: mtime.clone(),
+                    is_dir: false,
+                });
+            }
+            Ok(())
+        }
+
+        fn read_file(
+            &mut self,
+            name: &str,
+            offset: u32,
+            buffer: &mut [u8],
+        ) -> Result<usize, Error> {
+            let contents = self.files.get(name).ok_or(Error::FileNotFound)?;
+            let start = (offset as usize).min(contents.len());
+            let count = buffer.len().min(contents.len() - start);
+            buffer[0..count].copy_from_slice(&contents[start..start + count]);
+            Ok(count)
+        }
+    }
+
+    impl Serial for TestPlatform {
+        fn set_bitrate(&mut self, bps: u32) -> Result<(), Error> {
+            self.bitrate = bps;
+            Ok(())
+        }
+
+        fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Error> {
+            let mut count = 0;
+            while count < buffer.len() {
+                match self.serial_rx.pop_front() {
+                    Some(b) => buffer[count] = b,
+                    None => break,
+                }
+                count += 1;
+            }
+            Ok(count)
+        }
+
+        fn write(&mut self, buffer: &[u8]) -> Result<usize, Error> {
+            self.serial_tx.extend_from_slice(buffer);
+            Ok(buffer.len())
+        }
+    }
+
+    impl Audio for TestPlatform {
+        fn play(&mut self, channel: u8, centi_hertz: u32, volume: u8, waveform: Waveform) {
+            self.notes.push((channel, centi_hertz, volume, waveform));
+        }
+    }
+
+    impl Time for TestPlatform {
+        fn now(&mut self) -> Timestamp {
+            self.now.clone().unwrap()
+        }
+
+        fn set_now(&mut self, timestamp: Timestamp) {
+            self.now = Some(timestamp);
+        }
+
+        fn wait_frame(&mut self) {
+            self.frames += 1;
+            // Stop any test which forgot to queue enough keys
+            assert!(self.frames < 100_000, "waited forever");
+        }
+
+        fn idle(&mut self) {
+            self.wait_frame();
+        }
+    }
+}
+
+// End of file
@@ -0,0 +1,1115 @@
+//! # monotron-xmodem
+//!
+//! Copyright (c) Jonathan 'theJPster' Pallant
+//!
+//! Licensed under either of
+//!
+//! - Apache License, Version 2.0 ([LICENSE-APACHE](LICENSE-APACHE) or
+//!   http://www.apache.org/licenses/LICENSE-2.0)
+//!
+//! - MIT license ([LICENSE-MIT](LICENSE-MIT) or http://opensource.org/licenses/MIT)
+//!
+//! at your option.
+//!
+//! An XMODEM / YMODEM file transfer engine.
+//!
+//! Supported variants are:
+//!
+//! * XMODEM with the original 8-bit checksum
+//! * XMODEM-CRC (128 byte blocks with a CRC-16)
+//! * XMODEM-1K (1024 byte blocks with a CRC-16)
+//! * YMODEM batch (1024 byte blocks, with file name and size sent in block 0)
+//!
+//! The engine doesn't do any I/O itself. You feed it each byte that arrives
+//! from the remote end, and tell it when nothing has arrived for
+//! `TIMEOUT_MS` milliseconds. It writes any bytes for the remote end to a
+//! `Channel`, and reads / writes file contents via a `Source` or `Sink`. That
+//! makes it easy to drive from a polled UART on the Monotron, or from a pty on
+//! a Linux host (e.g. talking to `sz` / `rz` from lrzsz).
+#![cfg_attr(not(test), no_std)]
+#![deny(missing_docs)]
+
+// ===========================================================================
+// Constants
+// ===========================================================================
+
+/// Start of a 128 byte block
+pub const SOH: u8 = 0x01;
+/// Start of a 1024 byte block
+pub const STX: u8 = 0x02;
+/// End of transmission
+pub const EOT: u8 = 0x04;
+/// Positive acknowledgement
+pub const ACK: u8 = 0x06;
+/// Negative acknowledgement. Also requests a checksum-mode transfer.
+pub const NAK: u8 = 0x15;
+/// Cancel the transfer (must be received twice)
+pub const CAN: u8 = 0x18;
+/// Requests a CRC-mode transfer
+pub const CRC_REQUEST: u8 = b'C';
+/// Used to pad the last block of a file
+pub const PADDING: u8 = 0x1A;
+
+/// How long to wait for the remote end before calling `handle_timeout`.
+pub const TIMEOUT_MS: u32 = 3000;
+
+/// The largest packet we handle (block number, inverse block number, 1 KiB
+/// payload and a two byte CRC).
+const MAX_PACKET_LEN: usize = 2 + 1024 + 2;
+
+/// How many times we'll retry a block before giving up.
+const MAX_ERRORS: u8 = 10;
+
+/// A receiver falls back to checksum mode after this many unanswered 'C's.
+const CRC_ATTEMPTS: u8 = 3;
+
+/// A sender gives up if the receiver hasn't started after this many timeouts
+/// (i.e. about one minute).
+const MAX_START_TIMEOUTS: u8 = 20;
+
+// ===========================================================================
+// Types
+// ===========================================================================
+
+/// Which variant of the protocol to use.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Protocol {
+    /// XMODEM with 128 byte blocks. A receiver will request CRC mode, but
+    /// will fall back to checksum mode if the sender doesn't support it.
+    Xmodem,
+    /// XMODEM with 1024 byte blocks. Only affects the sender - receivers
+    /// always accept either block size.
+    Xmodem1k,
+    /// YMODEM batch mode, with file names and sizes.
+    Ymodem,
+}
+
+/// The ways in which a transfer can fail.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The remote end cancelled the transfer.
+    Cancelled,
+    /// We gave up after too many bad blocks or timeouts.
+    TooManyErrors,
+    /// The remote end sent a block we weren't expecting.
+    OutOfSync,
+    /// The YMODEM header block was not valid.
+    BadHeader,
+    /// The `Sink` refused some data.
+    SinkError,
+    /// The `Source` couldn't supply some data.
+    SourceError,
+}
+
+/// The state of a transfer, as reported after each event.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Status {
+    /// The transfer is still going. Keep feeding us bytes.
+    Running,
+    /// The transfer completed successfully.
+    Complete,
+    /// The transfer failed. A cancel has been sent to the remote end if
+    /// appropriate.
+    Failed(Error),
+}
+
+/// Something we can send bytes to the remote end over.
+pub trait Channel {
+    /// Send all of the given bytes. Can block.
+    fn write(&mut self, data: &[u8]);
+}
+
+/// Describes a file we are about to send in YMODEM mode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileInfo<'a> {
+    /// The file name (without any directory)
+    pub name: &'a str,
+    /// The length of the file in bytes
+    pub size: u32,
+}
+
+/// Somewhere a `Sender` can get file contents from.
+pub trait Source {
+    /// The error returned if the file can't be read.
+    type Error;
+
+    /// Move on to the next file in the batch and describe it, or return
+    /// `None` if there are no more files. Only called in YMODEM mode.
+    fn next_file(&mut self) -> Option<FileInfo<'_>>;
+
+    /// Read file contents into `buffer`. Returning `Ok(0)` indicates the end
+    /// of the current file.
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Self::Error>;
+}
+
+/// Somewhere a `Receiver` can put file contents.
+pub trait Sink {
+    /// The error returned if the file can't be written.
+    type Error;
+
+    /// A new file is starting. In XMODEM mode, this is called once with no
+    /// name and no size, when the first block arrives.
+    fn start_file(&mut self, name: Option<&str>, size: Option<u32>) -> Result<(), Self::Error>;
+
+    /// Some more of the current file has arrived. In XMODEM mode, the last
+    /// block will include any padding added by the sender.
+    fn write(&mut self, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// The current file is complete.
+    fn end_file(&mut self) -> Result<(), Self::Error>;
+}
+
+/// Receives files using XMODEM or YMODEM.
+pub struct Receiver {
+    protocol: Protocol,
+    state: RxState,
+    use_crc: bool,
+    /// Block number, inverse block number, payload and checksum.
+    packet: [u8; MAX_PACKET_LEN],
+    received: usize,
+    payload_len: usize,
+    expected_block: u8,
+    errors: u8,
+    /// Set when we're waiting for the YMODEM header block (block 0).
+    want_header: bool,
+    /// Set once we've passed a file to the `Sink`.
+    file_open: bool,
+    /// How many bytes of this file are still to come, if the sender told us.
+    remaining: Option<u32>,
+    /// YMODEM senders must send EOT twice.
+    seen_eot: bool,
+    seen_can: bool,
+}
+
+/// The states a `Receiver` moves through.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum RxState {
+    /// We're sending 'C' (or NAK) until the first block turns up.
+    Starting { attempts: u8 },
+    /// Waiting for SOH, STX, EOT or CAN.
+    AwaitingPacket,
+    /// Collecting the rest of a block.
+    InPacket,
+    /// All done
+    Finished(Status),
+}
+
+/// Sends files using XMODEM or YMODEM.
+pub struct Sender {
+    protocol: Protocol,
+    state: TxState,
+    use_crc: bool,
+    /// A complete packet, ready to be (re-)sent.
+    packet: [u8; 1 + MAX_PACKET_LEN],
+    packet_len: usize,
+    block_num: u8,
+    errors: u8,
+    seen_can: bool,
+}
+
+/// The states a `Sender` moves through.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum TxState {
+    /// Waiting for the receiver to send 'C' or NAK.
+    AwaitingStart { timeouts: u8 },
+    /// Sent a YMODEM header; waiting for ACK. If `last` is set, the header
+    /// was the empty one that ends the batch.
+    AwaitingHeaderAck { last: bool },
+    /// Header was ACK'd; waiting for the 'C' that starts the data.
+    AwaitingDataStart,
+    /// Sent a data block; waiting for ACK.
+    AwaitingBlockAck,
+    /// Sent EOT; waiting for ACK.
+    AwaitingEotAck,
+    /// All done
+    Finished(Status),
+}
+
+// ===========================================================================
+// Functions and Impls
+// ===========================================================================
+
+/// Calculate the CRC-16 used by XMODEM (polynomial 0x1021, initial value
+/// zero, not reflected).
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &b in data {
+        crc ^= u16::from(b) << 8;
+        for _ in 0..8 {
+            crc = if (crc & 0x8000) != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Calculate the 8-bit arithmetic checksum used by the original XMODEM.
+pub fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |acc, &b| acc.wrapping_add(b))
+}
+
+/// Send a cancel sequence to the remote end.
+fn send_cancel<C>(channel: &mut C)
+where
+    C: Channel,
+{
+    channel.write(&[CAN, CAN, CAN, CAN, CAN]);
+}
+
+impl Receiver {
+    /// Create a new receiver. Call `start` to begin the transfer.
+    pub fn new(protocol: Protocol) -> Receiver {
+        Receiver {
+            protocol,
+            state: RxState::Starting { attempts: 0 },
+            use_crc: true,
+            packet: [0u8; MAX_PACKET_LEN],
+            received: 0,
+            payload_len: 0,
+            expected_block: if protocol == Protocol::Ymodem { 0 } else { 1 },
+            errors: 0,
+            want_header: protocol == Protocol::Ymodem,
+            file_open: false,
+            remaining: None,
+            seen_eot: false,
+            seen_can: false,
+        }
+    }
+
+    /// Ask the sender to begin. Call this once, before any other method.
+    pub fn start<C>(&mut self, channel: &mut C)
+    where
+        C: Channel,
+    {
+        self.state = RxState::Starting { attempts: 1 };
+        channel.write(&[CRC_REQUEST]);
+    }
+
+    /// Abort the transfer, telling the remote end.
+    pub fn cancel<C>(&mut self, channel: &mut C) -> Status
+    where
+        C: Channel,
+    {
+        self.fail(channel, Error::Cancelled)
+    }
+
+    /// Call this if nothing has been received for `TIMEOUT_MS`.
+    pub fn handle_timeout<C>(&mut self, channel: &mut C) -> Status
+    where
+        C: Channel,
+    {
+        match self.state {
+            RxState::Finished(status) => status,
+            RxState::Starting { attempts } => {
+                if attempts >= MAX_ERRORS {
+                    return self.fail(channel, Error::TooManyErrors);
+                }
+                if attempts >= CRC_ATTEMPTS && self.protocol != Protocol::Ymodem {
+                    // Perhaps the sender only does checksums
+                    self.use_crc = false;
+                }
+                self.state = RxState::Starting {
+                    attempts: attempts + 1,
+                };
+                channel.write(&[self.start_byte()]);
+                Status::Running
+            }
+            RxState::AwaitingPacket | RxState::InPacket => {
+                self.errors += 1;
+                if self.errors > MAX_ERRORS {
+                    return self.fail(channel, Error::TooManyErrors);
+                }
+                self.state = RxState::AwaitingPacket;
+                channel.write(&[NAK]);
+                Status::Running
+            }
+        }
+    }
+
+    /// Process a byte from the remote end.
+    pub fn handle_byte<C, S>(&mut self, byte: u8, channel: &mut C, sink: &mut S) -> Status
+    where
+        C: Channel,
+        S: Sink,
+    {
+        match self.state {
+            RxState::Finished(status) => status,
+            RxState::Starting { .. } | RxState::AwaitingPacket => {
+                let was_can = self.seen_can;
+                self.seen_can = false;
+                match byte {
+                    SOH => self.begin_packet(128),
+                    STX => self.begin_packet(1024),
+                    EOT => self.handle_eot(channel, sink),
+                    CAN if was_can => {
+                        self.state = RxState::Finished(Status::Failed(Error::Cancelled));
+                        Status::Failed(Error::Cancelled)
+                    }
+                    CAN => {
+                        self.seen_can = true;
+                        Status::Running
+                    }
+                    _ => {
+                        // Line noise - ignore it
+                        Status::Running
+                    }
+                }
+            }
+            RxState::InPacket => {
+                self.packet[self.received] = byte;
+                self.received += 1;
+                if self.received == self.packet_len() {
+                    self.state = RxState::AwaitingPacket;
+                    self.handle_packet(channel, sink)
+                } else {
+                    Status::Running
+                }
+            }
+        }
+    }
+
+    /// Are we using CRC-16 (true) or checksums (false)?
+    pub fn is_crc_mode(&self) -> bool {
+        self.use_crc
+    }
+
+    fn start_byte(&self) -> u8 {
+        if self.use_crc {
+            CRC_REQUEST
+        } else {
+            NAK
+        }
+    }
+
+    fn packet_len(&self) -> usize {
+        2 + self.payload_len + if self.use_crc { 2 } else { 1 }
+    }
+
+    fn begin_packet(&mut self, payload_len: usize) -> Status {
+        self.seen_eot = false;
+        self.payload_len = payload_len;
+        self.received = 0;
+        self.state = RxState::InPacket;
+        Status::Running
+    }
+
+    fn fail<C>(&mut self, channel: &mut C, error: Error) -> Status
+    where
+        C: Channel,
+    {
+        send_cancel(channel);
+        self.state = RxState::Finished(Status::Failed(error));
+        Status::Failed(error)
+    }
+
+    fn reject<C>(&mut self, channel: &mut C) -> Status
+    where
+        C: Channel,
+    {
+        self.errors += 1;
+        if self.errors > MAX_ERRORS {
+            return self.fail(channel, Error::TooManyErrors);
+        }
+        channel.write(&[NAK]);
+        Status::Running
+    }
+
+    fn handle_eot<C, S>(&mut self, channel: &mut C, sink: &mut S) -> Status
+    where
+        C: Channel,
+        S: Sink,
+    {
+        if !self.file_open {
+            // Nothing to end - probably a stray EOT after a cancelled
+            // transfer
+            channel.write(&[NAK]);
+            return Status::Running;
+        }
+        if self.protocol == Protocol::Ymodem && !self.seen_eot {
+            // YMODEM wants the EOT twice, to be sure it wasn't noise
+            self.seen_eot = true;
+            channel.write(&[NAK]);
+            return Status::Running;
+        }
+        self.seen_eot = false;
+        self.file_open = false;
+        if sink.end_file().is_err() {
+            return self.fail(channel, Error::SinkError);
+        }
+        channel.write(&[ACK]);
+        if self.protocol == Protocol::Ymodem {
+            // Ask for the next header
+            self.want_header = true;
+            self.expected_block = 0;
+            self.errors = 0;
+            channel.write(&[CRC_REQUEST]);
+            Status::Running
+        } else {
+            self.state = RxState::Finished(Status::Complete);
+            Status::Complete
+        }
+    }
+
+    fn handle_packet<C, S>(&mut self, channel: &mut C, sink: &mut S) -> Status
+    where
+        C: Channel,
+        S: Sink,
+    {
+        let block = self.packet[0];
+        let inverse = self.packet[1];
+        let payload_end = 2 + self.payload_len;
+        if block != !inverse {
+            return self.reject(channel);
+        }
+        let valid = if self.use_crc {
+            let expected = (u16::from(self.packet[payload_end]) << 8)
+                | u16::from(self.packet[payload_end + 1]);
+            crc16(&self.packet[2..payload_end]) == expected
+        } else {
+            checksum(&self.packet[2..payload_end]) == self.packet[payload_end]
+        };
+        if !valid {
+            return self.reject(channel);
+        }
+
+        if block == self.expected_block.wrapping_sub(1) && !self.want_header {
+            // The sender missed our ACK and sent the block again
+            channel.write(&[ACK]);
+            return Status::Running;
+        }
+        if block != self.expected_block {
+            return self.fail(channel, Error::OutOfSync);
+        }
+        self.errors = 0;
+        self.expected_block = self.expected_block.wrapping_add(1);
+
+        if self.want_header {
+            return self.handle_header(channel, sink);
+        }
+
+        if !self.file_open {
+            // XMODEM has no header, so the file starts with the first block
+            if sink.start_file(None, None).is_err() {
+                return self.fail(channel, Error::SinkError);
+            }
+            self.file_open = true;
+        }
+
+        let mut len = self.payload_len;
+        if let Some(remaining) = self.remaining.as_mut() {
+            // Strip the padding off the last block
+            len = core::cmp::min(len, *remaining as usize);
+            *remaining -= len as u32;
+        }
+        if len > 0 && sink.write(&self.packet[2..2 + len]).is_err() {
+            return self.fail(channel, Error::SinkError);
+        }
+        channel.write(&[ACK]);
+        Status::Running
+    }
+
+    /// Process YMODEM block 0, which contains the file name and length.
+    fn handle_header<C, S>(&mut self, channel: &mut C, sink: &mut S) -> Status
+    where
+        C: Channel,
+        S: Sink,
+    {
+        let payload = &self.packet[2..2 + self.payload_len];
+        let name_len = payload.iter().position(|&b| b == 0).unwrap_or(0);
+        if name_len == 0 {
+            // An empty file name marks the end of the batch
+            channel.write(&[ACK]);
+            self.state = RxState::Finished(Status::Complete);
+            return Status::Complete;
+        }
+        let name = match core::str::from_utf8(&payload[0..name_len]) {
+            Ok(name) => name,
+            Err(_) => return self.fail(channel, Error::BadHeader),
+        };
+        // The length is optional, and is followed by a space or a null
+        let size = parse_decimal(&payload[name_len + 1..]);
+        if sink.start_file(Some(name), size).is_err() {
+            return self.fail(channel, Error::SinkError);
+        }
+        self.file_open = true;
+        self.want_header = false;
+        self.remaining = size;
+        // Acknowledge the header, then ask for the data
+        channel.write(&[ACK, CRC_REQUEST]);
+        Status::Running
+    }
+}
+
+impl Sender {
+    /// Create a new sender. It will wait for the receiver to ask for the
+    /// first block.
+    pub fn new(protocol: Protocol) -> Sender {
+        Sender {
+            protocol,
+            state: TxState::AwaitingStart { timeouts: 0 },
+            use_crc: true,
+            packet: [0u8; 1 + MAX_PACKET_LEN],
+            packet_len: 0,
+            block_num: 1,
+            errors: 0,
+            seen_can: false,
+        }
+    }
+
+    /// Abort the transfer, telling the remote end.
+    pub fn cancel<C>(&mut self, channel: &mut C) -> Status
+    where
+        C: Channel,
+    {
+        self.fail(channel, Error::Cancelled)
+    }
+
+    /// Call this if nothing has been received for `TIMEOUT_MS`.
+    pub fn handle_timeout<C>(&mut self, channel: &mut C) -> Status
+    where
+        C: Channel,
+    {
+        match self.state {
+            TxState::Finished(status) => status,
+            TxState::AwaitingStart { timeouts } => {
+                if timeouts >= MAX_START_TIMEOUTS {
+                    return self.fail(channel, Error::TooManyErrors);
+                }
+                self.state = TxState::AwaitingStart {
+                    timeouts: timeouts + 1,
+                };
+                Status::Running
+            }
+            TxState::AwaitingDataStart => self.retry(channel, false),
+            TxState::AwaitingHeaderAck { .. } | TxState::AwaitingBlockAck => {
+                self.retry(channel, true)
+            }
+            TxState::AwaitingEotAck => {
+                self.errors += 1;
+                if self.errors > MAX_ERRORS {
+                    return self.fail(channel, Error::TooManyErrors);
+                }
+                channel.write(&[EOT]);
+                Status::Running
+            }
+        }
+    }
+
+    /// Process a byte from the remote end.
+    pub fn handle_byte<C, S>(&mut self, byte: u8, channel: &mut C, source: &mut S) -> Status
+    where
+        C: Channel,
+        S: Source,
+    {
+        if let TxState::Finished(status) = self.state {
+            return status;
+        }
+        let was_can = self.seen_can;
+        self.seen_can = byte == CAN;
+        if byte == CAN {
+            if was_can {
+                self.state = TxState::Finished(Status::Failed(Error::Cancelled));
+                return Status::Failed(Error::Cancelled);
+            }
+            return Status::Running;
+        }
+        match (self.state, byte) {
+            (TxState::AwaitingStart { .. }, CRC_REQUEST) => {
+                self.use_crc = true;
+                self.start_file(channel, source)
+            }
+            (TxState::AwaitingStart { .. }, NAK) if self.protocol != Protocol::Ymodem => {
+                self.use_crc = false;
+                self.start_file(channel, source)
+            }
+            (TxState::AwaitingHeaderAck { last }, ACK) => {
+                self.errors = 0;
+                if last {
+                    self.state = TxState::Finished(Status::Complete);
+                    Status::Complete
+                } else {
+                    self.state = TxState::AwaitingDataStart;
+                    Status::Running
+                }
+            }
+            (TxState::AwaitingHeaderAck { .. }, NAK)
+            | (TxState::AwaitingHeaderAck { .. }, CRC_REQUEST) => self.retry(channel, true),
+            (TxState::AwaitingDataStart, CRC_REQUEST) | (TxState::AwaitingDataStart, NAK) => {
+                self.block_num = 1;
+                self.send_next_block(channel, source)
+            }
+            (TxState::AwaitingBlockAck, ACK) => {
+                self.errors = 0;
+                self.block_num = self.block_num.wrapping_add(1);
+                self.send_next_block(channel, source)
+            }
+            (TxState::AwaitingBlockAck, NAK) => self.retry(channel, true),
+            (TxState::AwaitingBlockAck, CRC_REQUEST) if self.block_num == 1 => {
+                // The receiver didn't see our first block and is still
+                // asking for one
+                self.retry(channel, true)
+            }
+            (TxState::AwaitingEotAck, ACK) => {
+                self.errors = 0;
+                if self.protocol == Protocol::Ymodem {
+                    // Wait for the receiver to ask for the next header
+                    self.state = TxState::AwaitingStart { timeouts: 0 };
+                    Status::Running
+                } else {
+                    self.state = TxState::Finished(Status::Complete);
+                    Status::Complete
+                }
+            }
+            (TxState::AwaitingEotAck, NAK) => {
+                channel.write(&[EOT]);
+                Status::Running
+            }
+            _ => {
+                // Line noise, or something we don't care about
+                Status::Running
+            }
+        }
+    }
+
+    fn fail<C>(&mut self, channel: &mut C, error: Error) -> Status
+    where
+        C: Channel,
+    {
+        send_cancel(channel);
+        self.state = TxState::Finished(Status::Failed(error));
+        Status::Failed(error)
+    }
+
+    /// Send the current packet again (or just count the error, if `resend`
+    /// is false).
+    fn retry<C>(&mut self, channel: &mut C, resend: bool) -> Status
+    where
+        C: Channel,
+    {
+        self.errors += 1;
+        if self.errors > MAX_ERRORS {
+            return self.fail(channel, Error::TooManyErrors);
+        }
+        if resend {
+            channel.write(&self.packet[0..self.packet_len]);
+        }
+        Status::Running
+    }
+
+    /// The receiver is ready. Send a YMODEM header, or the first XMODEM block.
+    fn start_file<C, S>(&mut self, channel: &mut C, source: &mut S) -> Status
+    where
+        C: Channel,
+        S: Source,
+    {
+        self.errors = 0;
+        if self.protocol != Protocol::Ymodem {
+            self.block_num = 1;
+            return self.send_next_block(channel, source);
+        }
+        // Block 0 holds the file name, a null, and the length in decimal.
+        // An empty block 0 ends the batch.
+        let mut payload = [0u8; 128];
+        let last = match source.next_file() {
+            Some(info) => {
+                let name = info.name.as_bytes();
+                let name_len = core::cmp::min(name.len(), payload.len() - 12);
+                payload[0..name_len].copy_from_slice(&name[0..name_len]);
+                format_decimal(info.size, &mut payload[name_len + 1..]);
+                false
+            }
+            None => true,
+        };
+        self.block_num = 0;
+        self.build_packet(&payload);
+        channel.write(&self.packet[0..self.packet_len]);
+        self.state = TxState::AwaitingHeaderAck { last };
+        Status::Running
+    }
+
+    /// Read the next block from the source and send it, or send EOT if the
+    /// file is finished.
+    fn send_next_block<C, S>(&mut self, channel: &mut C, source: &mut S) -> Status
+    where
+        C: Channel,
+        S: Source,
+    {
+        let block_size = if self.protocol == Protocol::Xmodem {
+            128
+        } else {
+            1024
+        };
+        let mut payload = [PADDING; 1024];
+        let mut filled = 0;
+        while filled < block_size {
+            match source.read(&mut payload[filled..block_size]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(_) => return self.fail(channel, Error::SourceError),
+            }
+        }
+        if filled == 0 {
+            self.state = TxState::AwaitingEotAck;
+            channel.write(&[EOT]);
+            return Status::Running;
+        }
+        // Don't waste a 1 KiB block on a short tail
+        let len = if filled <= 128 { 128 } else { block_size };
+        self.build_packet(&payload[0..len]);
+        channel.write(&self.packet[0..self.packet_len]);
+        self.state = TxState::AwaitingBlockAck;
+        Status::Running
+    }
+
+    /// Wrap a payload (128 or 1024 bytes) in a header and a CRC / checksum.
+    fn build_packet(&mut self, payload: &[u8]) {
+        self.packet[0] = if payload.len() == 128 { SOH } else { STX };
+        self.packet[1] = self.block_num;
+        self.packet[2] = !self.block_num;
+        let payload_end = 3 + payload.len();
+        self.packet[3..payload_end].copy_from_slice(payload);
+        if self.use_crc {
+            let crc = crc16(payload);
+            self.packet[payload_end] = (crc >> 8) as u8;
+            self.packet[payload_end + 1] = crc as u8;
+            self.packet_len = payload_end + 2;
+        } else {
+            self.packet[payload_end] = checksum(payload);
+            self.packet_len = payload_end + 1;
+        }
+    }
+}
+
+/// Parse a decimal number at the start of a buffer, stopping at the first
+/// non-digit.
+fn parse_decimal(buffer: &[u8]) -> Option<u32> {
+    let mut result: Option<u32> = None;
+    for &b in buffer {
+        if !b.is_ascii_digit() {
+            break;
+        }
+        let digit = u32::from(b - b'0');
+        result = Some(result.unwrap_or(0).checked_mul(10)?.checked_add(digit)?);
+    }
+    result
+}
+
+/// Write a number in decimal to the start of a buffer.
+fn format_decimal(mut value: u32, buffer: &mut [u8]) {
+    let mut digits = [0u8; 10];
+    let mut count = 0;
+    loop {
+        digits[count] = b'0' + (value % 10) as u8;
+        count += 1;
+        value /= 10;
+        if value == 0 {
+            break;
+        }
+    }
+    for (dest, src) in buffer.iter_mut().zip(digits[0..count].iter().rev()) {
+        *dest = *src;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::VecDeque;
+
+    impl Channel for VecDeque<u8> {
+        fn write(&mut self, data: &[u8]) {
+            self.extend(data.iter());
+        }
+    }
+
+    #[derive(Default)]
+    struct TestSink {
+        files: Vec<(Option<String>, Option<u32>, Vec<u8>)>,
+        ended: usize,
+    }
+
+    impl Sink for TestSink {
+        type Error = ();
+
+        fn start_file(&mut self, name: Option<&str>, size: Option<u32>) -> Result<(), ()> {
+            self.files
+                .push((name.map(|s| s.to_owned()), size, Vec::new()));
+            Ok(())
+        }
+
+        fn write(&mut self, data: &[u8]) -> Result<(), ()> {
+            self.files.last_mut().unwrap().2.extend_from_slice(data);
+            Ok(())
+        }
+
+        fn end_file(&mut self) -> Result<(), ()> {
+            self.ended += 1;
+            Ok(())
+        }
+    }
+
+    struct TestSource {
+        files: Vec<(String, Vec<u8>)>,
+        current: Option<usize>,
+        offset: usize,
+    }
+
+    impl TestSource {
+        fn new(files: &[(&str, &[u8])]) -> TestSource {
+            TestSource {
+                files: files
+                    .iter()
+                    .map(|(n, d)| (n.to_string(), d.to_vec()))
+                    .collect(),
+                current: None,
+                offset: 0,
+            }
+        }
+    }
+
+    impl Source for TestSource {
+        type Error = ();
+
+        fn next_file(&mut self) -> Option<FileInfo<'_>> {
+            let next = self.current.map_or(0, |x| x + 1);
+            self.current = Some(next);
+            self.offset = 0;
+            self.files.get(next).map(|(name, data)| FileInfo {
+                name,
+                size: data.len() as u32,
+            })
+        }
+
+        fn read(&mut self, buffer: &mut [u8]) -> Result<usize, ()> {
+            let data = &self.files[self.current.unwrap_or(0)].1;
+            let n = core::cmp::min(buffer.len(), data.len() - self.offset);
+            buffer[0..n].copy_from_slice(&data[self.offset..self.offset + n]);
+            self.offset += n;
+            Ok(n)
+        }
+    }
+
+    /// Connect a sender and a receiver back to back. `corrupt` is called
+    /// for each byte going from the sender to the receiver and can alter it.
+    fn run_transfer<F>(
+        tx_protocol: Protocol,
+        rx_protocol: Protocol,
+        source: &mut TestSource,
+        sink: &mut TestSink,
+        mut corrupt: F,
+    ) -> (Status, Status)
+    where
+        F: FnMut(usize, u8) -> u8,
+    {
+        let mut sender = Sender::new(tx_protocol);
+        let mut receiver = Receiver::new(rx_protocol);
+        let mut to_sender = VecDeque::new();
+        let mut to_receiver = VecDeque::new();
+        let mut tx_status = Status::Running;
+        let mut rx_status = Status::Running;
+        let mut count = 0;
+        receiver.start(&mut to_sender);
+        for _ in 0..100_000 {
+            while let Some(b) = to_sender.pop_front() {
+                tx_status = sender.handle_byte(b, &mut to_receiver, source);
+            }
+            while let Some(b) = to_receiver.pop_front() {
+                let b = corrupt(count, b);
+                count += 1;
+                rx_status = receiver.handle_byte(b, &mut to_sender, sink);
+            }
+            if to_sender.is_empty() && to_receiver.is_empty() {
+                if tx_status != Status::Running && rx_status != Status::Running {
+                    break;
+                }
+                // Nothing moving - let the receiver kick things along
+                rx_status = receiver.handle_timeout(&mut to_sender);
+            }
+        }
+        (tx_status, rx_status)
+    }
+
+    fn test_data(len: usize) -> Vec<u8> {
+        (0..len).map(|x| (x * 7 + x / 256) as u8).collect()
+    }
+
+    #[test]
+    fn crc16_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x31C3);
+        assert_eq!(crc16(b""), 0);
+    }
+
+    #[test]
+    fn checksum_wraps() {
+        assert_eq!(checksum(&[0xFF, 0x02]), 0x01);
+    }
+
+    #[test]
+    fn decimal_round_trip() {
+        let mut buffer = [0u8; 12];
+        format_decimal(1_234_567, &mut buffer);
+        assert_eq!(&buffer[0..8], b"1234567\0");
+        assert_eq!(parse_decimal(&buffer), Some(1_234_567));
+        assert_eq!(parse_decimal(b"0 123"), Some(0));
+        assert_eq!(parse_decimal(b" 12"), None);
+        assert_eq!(parse_decimal(b"99999999999"), None);
+    }
+
+    #[test]
+    fn xmodem_crc() {
+        let data = test_data(1000);
+        let mut source = TestSource::new(&[("", &data)]);
+        let mut sink = TestSink::default();
+        let result = run_transfer(
+            Protocol::Xmodem,
+            Protocol::Xmodem,
+            &mut source,
+            &mut sink,
+            |_, b| b,
+        );
+        assert_eq!(result, (Status::Complete, Status::Complete));
+        assert_eq!(sink.files.len(), 1);
+        assert_eq!(sink.ended, 1);
+        let received = &sink.files[0].2;
+        // XMODEM pads to a whole number of blocks
+        assert_eq!(received.len(), 1024);
+        assert_eq!(&received[0..1000], &data[..]);
+        assert!(received[1000..].iter().all(|&b| b == PADDING));
+    }
+
+    #[test]
+    fn xmodem_1k() {
+        let data = test_data(3000);
+        let mut source = TestSource::new(&[("", &data)]);
+        let mut sink = TestSink::default();
+        let result = run_transfer(
+            Protocol::Xmodem1k,
+            Protocol::Xmodem,
+            &mut source,
+            &mut sink,
+            |_, b| b,
+        );
+        assert_eq!(result, (Status::Complete, Status::Complete));
+        // Two 1K blocks, then 952 bytes, which needs a third 1K block
+        assert_eq!(sink.files[0].2.len(), 3072);
+        assert_eq!(&sink.files[0].2[0..3000], &data[..]);
+    }
+
+    #[test]
+    fn xmodem_checksum_fallback() {
+        let data = test_data(200);
+        let mut source = TestSource::new(&[("", &data)]);
+        let mut sink = TestSink::default();
+        let mut sender = Sender::new(Protocol::Xmodem);
+        let mut receiver = Receiver::new(Protocol::Xmodem);
+        let mut to_sender = VecDeque::new();
+        let mut to_receiver = VecDeque::new();
+        receiver.start(&mut to_sender);
+        // Pretend the sender is an old one that ignores 'C'
+        while receiver.is_crc_mode() {
+            to_sender.clear();
+            receiver.handle_timeout(&mut to_sender);
+        }
+        assert_eq!(to_sender.pop_front(), Some(NAK));
+        sender.handle_byte(NAK, &mut to_receiver, &mut source);
+        let mut status = Status::Running;
+        while status == Status::Running {
+            while let Some(b) = to_receiver.pop_front() {
+                status = receiver.handle_byte(b, &mut to_sender, &mut sink);
+            }
+            while let Some(b) = to_sender.pop_front() {
+                sender.handle_byte(b, &mut to_receiver, &mut source);
+            }
+        }
+        assert_eq!(status, Status::Complete);
+        assert_eq!(&sink.files[0].2[0..200], &data[..]);
+    }
+
+    #[test]
+    fn xmodem_retransmits_corrupt_block() {
+        let data = test_data(512);
+        let mut source = TestSource::new(&[("", &data)]);
+        let mut sink = TestSink::default();
+        // Mangle one byte in the middle of the second block
+        let result = run_transfer(
+            Protocol::Xmodem,
+            Protocol::Xmodem,
+            &mut source,
+            &mut sink,
+            |idx, b| if idx == 200 { b ^ 0x55 } else { b },
+        );
+        assert_eq!(result, (Status::Complete, Status::Complete));
+        assert_eq!(&sink.files[0].2[..], &data[..]);
+    }
+
+    #[test]
+    fn ymodem_batch() {
+        let first = test_data(1500);
+        let second = test_data(10);
+        let mut source = TestSource::new(&[("HELLO.BIN", &first), ("NOTES.TXT", &second)]);
+        let mut sink = TestSink::default();
+        let result = run_transfer(
+            Protocol::Ymodem,
+            Protocol::Ymodem,
+            &mut source,
+            &mut sink,
+            |_, b| b,
+        );
+        assert_eq!(result, (Status::Complete, Status::Complete));
+        assert_eq!(sink.files.len(), 2);
+        assert_eq!(sink.ended, 2);
+        assert_eq!(sink.files[0].0.as_ref().unwrap(), "HELLO.BIN");
+        assert_eq!(sink.files[0].1, Some(1500));
+        // YMODEM knows the length, so there's no padding
+        assert_eq!(sink.files[0].2, first);
+        assert_eq!(sink.files[1].0.as_ref().unwrap(), "NOTES.TXT");
+        assert_eq!(sink.files[1].2, second);
+    }
+
+    #[test]
+    fn ymodem_empty_batch() {
+        let mut source = TestSource::new(&[]);
+        let mut sink = TestSink::default();
+        let result = run_transfer(
+            Protocol::Ymodem,
+            Protocol::Ymodem,
+            &mut source,
+            &mut sink,
+            |_, b| b,
+        );
+        assert_eq!(result, (Status::Complete, Status::Complete));
+        assert!(sink.files.is_empty());
+    }
+
+    #[test]
+    fn receiver_honours_cancel() {
+        let mut receiver = Receiver::new(Protocol::Xmodem);
+        let mut sink = TestSink::default();
+        let mut out = VecDeque::new();
+        receiver.start(&mut out);
+        assert_eq!(
+            receiver.handle_byte(CAN, &mut out, &mut sink),
+            Status::Running
+        );
+        assert_eq!(
+            receiver.handle_byte(CAN, &mut out, &mut sink),
+            Status::Failed(Error::Cancelled)
+        );
+    }
+
+    #[test]
+    fn receiver_gives_up() {
+        let mut receiver = Receiver::new(Protocol::Xmodem);
+        let mut out = VecDeque::new();
+        receiver.start(&mut out);
+        let mut status = Status::Running;
+        for _ in 0..20 {
+            status = receiver.handle_timeout(&mut out);
+        }
+        assert_eq!(status, Status::Failed(Error::TooManyErrors));
+        assert_eq!(out.back(), Some(&CAN));
+    }
+}
@@ -5,3 +5,20 @@ MEMORY
     for applications. */
     RAM   (rwx) : ORIGIN = 0x20000000, LENGTH = 8K
 }
+
+/* The sandbox (src/sandbox.rs) has its own SVCall and PendSV handlers,
+written in assembly. cortex-m-rt only PROVIDEs its default handlers, so ours
+win - but only if they're linked in, so make sure they are, and stop the
+link if the defaults ever come back. */
+EXTERN(SVCall);
+EXTERN(PendSV);
+ASSERT(SVCall != DefaultHandler, "SVCall must be the sandbox's handler (src/sandbox.rs)");
+ASSERT(PendSV != DefaultHandler, "PendSV must be the sandbox's handler (src/sandbox.rs)");
+
+/* The same goes for the fault handlers in src/fault.rs. */
+EXTERN(MemoryManagement);
+EXTERN(BusFault);
+EXTERN(UsageFault);
+ASSERT(MemoryManagement != DefaultHandler, "MemoryManagement must be the handler in src/fault.rs");
+ASSERT(BusFault != DefaultHandler, "BusFault must be the handler in src/fault.rs");
+ASSERT(UsageFault != DefaultHandler, "UsageFault must be the handler in src/fault.rs");
@@ -0,0 +1,193 @@
+//! # Stopping a stuck application
+//!
+//! While an application is running, we look for Ctrl-Break or Ctrl-Alt-Del
+//! on the PS/2 keyboard, or (once a frame, when the video interrupt calls
+//! `on_frame`) a break on the USB UART. When we see one, we pend `PendSV`,
+//! which stops the application (see `sandbox`) as soon as it's back in
+//! thread mode. The shell then tidies up, as it does however an application
+//! ends.
+//!
+//! So we see the keys even if the application never reads its input, the
+//! keyboard UART's interrupt (see `serial`) gives us every byte before
+//! anyone else sees it.
+
+use crate::serial::{self, Port};
+use crate::{cpu, sandbox, watchdog};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+// ===========================================================================
+// Constants
+// ===========================================================================
+
+/// UARTRIS/UARTICR: a break was received.
+const UART_BERIS: u32 = 1 << 9;
+
+/// SHCSR: the `SVCall` handler is active (i.e. the ROM is busy with an Api
+/// call).
+const SHCSR_SVCALLACT: u32 = 1 << 7;
+
+/// PS/2 Scan Code Set 2: the next byte is an extended key.
+const PS2_EXTENDED: u8 = 0xE0;
+
+/// PS/2 Scan Code Set 2: the next byte is a key being released.
+const PS2_RELEASE: u8 = 0xF0;
+
+/// PS/2 Scan Code Set 2: Ctrl (extended for the right-hand one).
+const PS2_CTRL: u8 = 0x14;
+
+/// PS/2 Scan Code Set 2: Alt (extended for AltGr).
+const PS2_ALT: u8 = 0x11;
+
+/// PS/2 Scan Code Set 2: Delete (extended).
+const PS2_DELETE: u8 = 0x71;
+
+/// PS/2 Scan Code Set 2: Pause, with Ctrl held down (extended).
+const PS2_BREAK: u8 = 0x7E;
+
+// ===========================================================================
+// Types
+// ===========================================================================
+
+/// Tracks the modifier keys, from the raw scan codes.
+struct Keys {
+    /// We've held back a `PS2_EXTENDED`, in case it's the start of the
+    /// combination.
+    held: bool,
+    /// The last byte was `PS2_EXTENDED`
+    extended: bool,
+    /// The last byte was `PS2_RELEASE`
+    release: bool,
+    /// Left and right Ctrl
+    ctrl: [bool; 2],
+    /// Left and right Alt
+    alt: [bool; 2],
+}
+
+// ===========================================================================
+// Static Variables
+// ===========================================================================
+
+/// Set when we want the running application stopped.
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Only touched by the keyboard UART's interrupt.
+static mut KEYS: Keys = Keys {
+    held: false,
+    extended: false,
+    release: false,
+    ctrl: [false; 2],
+    alt: [false; 2],
+};
+
+// ===========================================================================
+// Functions and Impls
+// ===========================================================================
+
+/// Called from the video interrupt, once a frame.
+pub(crate) fn on_frame() {
+    if !sandbox::is_running() {
+        return;
+    }
+    let usb = unsafe { &*cpu::UART0::ptr() };
+    if (usb.ris.read().bits() & UART_BERIS) != 0 {
+        usb.icr.write(|w| unsafe { w.bits(UART_BERIS) });
+        request();
+    }
+    // If the application is busy (rather than the ROM being stuck in an Api
+    // call), it's not a hang - they can press Ctrl-Break.
+    let scb = unsafe { &*cortex_m::peripheral::SCB::ptr() };
+    if (scb.shcsr.read() & SHCSR_SVCALLACT) == 0 {
+        watchdog::feed();
+    }
+}
+
+/// Called from the keyboard UART's interrupt with each byte received. While
+/// an application is running, we swallow Ctrl-Break and Ctrl-Alt-Del;
+/// everything else goes on to the keyboard's ring.
+pub(crate) fn on_keyboard_byte(byte: u8) {
+    if !sandbox::is_running() {
+        serial::deliver(Port::Keyboard, byte);
+    } else if unsafe { KEYS.scan(byte) } {
+        request();
+    }
+}
+
+/// Ask for the running application to be stopped.
+fn request() {
+    REQUESTED.store(true, Ordering::Relaxed);
+    cortex_m::peripheral::SCB::set_pendsv();
+}
+
+/// Has someone asked for the running application to be stopped? Api calls
+/// which wait should give up if so.
+pub(crate) fn requested() -> bool {
+    REQUESTED.load(Ordering::Relaxed)
+}
+
+/// Clear (and return) any request to stop the application.
+pub(crate) fn take_request() -> bool {
+    REQUESTED.swap(false, Ordering::Relaxed)
+}
+
+/// Forget about any earlier requests, and any break on the USB UART.
+/// Called just before an application starts.
+pub(crate) fn reset() {
+    let usb = unsafe { &*cpu::UART0::ptr() };
+    usb.icr.write(|w| unsafe { w.bits(UART_BERIS) });
+    take_request();
+}
+
+impl Keys {
+    /// Look at a byte from the keyboard and pass it on to the keyboard's ring -
+    /// unless it completes the combination, when we swallow it and return
+    /// `true`.
+    fn scan(&mut self, byte: u8) -> bool {
+        match byte {
+            PS2_EXTENDED => {
+                self.flush();
+                self.held = true;
+                self.extended = true;
+                false
+            }
+            PS2_RELEASE => {
+                self.flush();
+                serial::deliver(Port::Keyboard, byte);
+                self.release = true;
+                false
+            }
+            code => {
+                let pressed = !self.release;
+                let ctrl = self.ctrl[0] || self.ctrl[1];
+                let alt = self.alt[0] || self.alt[1];
+                let combo = pressed
+                    && self.extended
+                    && ((code == PS2_DELETE && ctrl && alt) || (code == PS2_BREAK && ctrl));
+                if combo {
+                    self.held = false;
+                } else {
+                    self.flush();
+                    serial::deliver(Port::Keyboard, code);
+                }
+                let side = self.extended as usize;
+                match code {
+                    PS2_CTRL => self.ctrl[side] = pressed,
+                    PS2_ALT => self.alt[side] = pressed,
+                    _ => {}
+                }
+                self.extended = false;
+                self.release = false;
+                combo
+            }
+        }
+    }
+
+    /// Pass on the `PS2_EXTENDED` we held back, if any.
+    fn flush(&mut self) {
+        if self.held {
+            serial::deliver(Port::Keyboard, PS2_EXTENDED);
+            self.held = false;
+        }
+    }
+}
+
+// End of file
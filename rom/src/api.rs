@@ -1,50 +1,81 @@
-use crate::fb::{AsciiConsole, BaseConsole, Col, Position, Row};
-use crate::hal::time::U32Ext;
-use crate::GLOBAL_CONTEXT;
-use crate::{Input, FRAMEBUFFER};
+use crate::fb::{BaseConsole, Col, Position, Row};
+use crate::platform::Rom;
+use crate::{Input, CONSOLE_INPUT, FRAMEBUFFER, JOYSTICK};
+use core::sync::atomic::{AtomicBool, Ordering};
 use cortex_m::asm;
 pub use monotron_api::*;
 
-const UART0_HANDLE: Handle = Handle(100);
-
-enum OpenFileObject {
-    Uart0,
-    StdIn,
-    StdOut,
-    StdErr,
-    File(embedded_sdmmc::File),
-    Directory(embedded_sdmmc::Directory),
-}
-
-static FILE_HANDLES: spin::Mutex<[Option<OpenFileObject>; 6]> =
-    spin::Mutex::new([None, None, None, None, None, None]);
-
-pub(crate) static CALLBACK_TABLE: Api = Api {
-    putchar,
-    puts,
-    readc,
-    wfvbi,
-    kbhit,
-    move_cursor,
-    play,
-    change_font,
-    get_joystick,
-    set_cursor_visible,
-    read_char_at,
-    open,
-    close,
-    read,
-    write,
-    write_then_read,
-    seek,
-    opendir,
-    readdir,
-    stat,
-    gettime,
-    puts_utf8,
-    map_line,
-    get_cursor,
-};
+/// The most arguments (including the program name) we can pass to an
+/// application.
+pub(crate) const MAX_ARGS: usize = 8;
+
+/// The most text (in total) we can pass to an application as arguments.
+const MAX_ARG_TEXT: usize = 128;
+
+/// The text of the arguments given to the application. `ARGV` points in here.
+static mut ARG_TEXT: [u8; MAX_ARG_TEXT] = [0u8; MAX_ARG_TEXT];
+
+/// The arguments given to the application.
+static mut ARGV: [BorrowedString; MAX_ARGS] = [BorrowedString {
+    ptr: core::ptr::null(),
+    length: 0,
+}; MAX_ARGS];
+
+/// How many entries in `ARGV` are valid.
+static mut ARGC: usize = 0;
+
+/// The optional features this ROM offers applications. Applications can
+/// only `open` the UART, not files on the SD card, so there's no `FILES`.
+/// `CLOCK` is added once the time has been read from the RTC - see
+/// `capabilities`.
+pub(crate) const CAPABILITIES: Capabilities = Capabilities::MAP_LINE
+    .union(Capabilities::GET_CURSOR)
+    .union(Capabilities::AUDIO);
+
+/// Set once the calendar time has been read from the RTC.
+static CLOCK_SET: AtomicBool = AtomicBool::new(false);
+
+/// Say that the calendar time has been read from the RTC, so `gettime` can
+/// be trusted.
+pub(crate) fn set_clock_valid() {
+    CLOCK_SET.store(true, Ordering::Relaxed);
+}
+
+/// The optional features this ROM offers applications right now. Without
+/// an RTC (or a battery in it), `gettime` counts from a made-up date, so we
+/// don't offer `CLOCK`.
+pub(crate) fn capabilities() -> Capabilities {
+    if CLOCK_SET.load(Ordering::Relaxed) {
+        CAPABILITIES.union(Capabilities::CLOCK)
+    } else {
+        CAPABILITIES
+    }
+}
+
+/// Set the arguments the next application will receive from `get_args`.
+/// The first should be the program name. Fails if there are too many, or
+/// they are too long.
+pub(crate) fn set_args(args: &[&str]) -> Result<(), ()> {
+    let total: usize = args.iter().map(|a| a.len()).sum();
+    if args.len() > MAX_ARGS || total > MAX_ARG_TEXT {
+        return Err(());
+    }
+    // Only the shell calls this, and never while an application is running.
+    unsafe {
+        let mut offset = 0;
+        for (arg, slot) in args.iter().zip(ARGV.iter_mut()) {
+            let text = &mut ARG_TEXT[offset..offset + arg.len()];
+            text.copy_from_slice(arg.as_bytes());
+            *slot = BorrowedString {
+                ptr: text.as_ptr(),
+                length: text.len(),
+            };
+            offset += arg.len();
+        }
+        ARGC = args.len();
+    }
+    Ok(())
+}
 
 /// Print a null-terminated 8-bit string, in Code Page 850, to the screen.
 /// Escape sequences are handled by the `vga-framebuffer` crate, but they include:
@@ -78,7 +109,7 @@ pub(crate) extern "C" fn puts(s: *const u8) -> i32 {
     unsafe {
         while *s.offset(i) != 0 {
             let ch: u8 = *s.offset(i);
-            FRAMEBUFFER.write_character(ch).unwrap();
+            crate::Console.write_u8(ch);
             i += 1;
         }
     }
@@ -88,31 +119,46 @@ pub(crate) extern "C" fn puts(s: *const u8) -> i32 {
 /// Print a single 8-bit character, in Code Page 850, to the screen. See
 /// `puts` for details.
 pub(crate) extern "C" fn putchar(ch: u8) -> i32 {
-    unsafe { FRAMEBUFFER.write_character(ch).unwrap() };
+    crate::Console.write_u8(ch);
     ch as i32
 }
 
+/// Should a blocking Api call stop waiting, for now? It should if `PendSV`
+/// is pending (to call the vertical-blank hook, or to stop the application),
+/// because that can't run until the `SVC` returns. The trampoline then makes
+/// the call again.
+pub(crate) fn should_yield() -> bool {
+    cortex_m::peripheral::SCB::is_pendsv_pending()
+}
+
 /// Read an 8-bit character from standard input (which may be UART or may be
 /// the keyboard). If there is no character waiting, this routine will block
-/// until one arrives. Call `kbhit()` to check first if you want to avoid
-/// blocking.
+/// until one arrives, or until it has to yield (see `should_yield`), in
+/// which case it returns `None`. Call `kbhit()` to check first if you want
+/// to avoid blocking.
 ///
 /// TODO: Currently UTF-8 input is passed through unchanged and there's no
 /// keyboard support.
-pub(crate) extern "C" fn readc() -> i32 {
-    let mut lock = GLOBAL_CONTEXT.lock();
-    let ctx = lock.as_mut().unwrap();
+pub(crate) fn readc() -> Option<i32> {
     loop {
-        match ctx.input_read() {
-            None => {
-                asm::wfi();
-            }
-            Some(Input::Special(_scancode)) => {
+        if crate::abort::requested() {
+            // The application is about to be stopped, so give up waiting
+            return Some(-1);
+        }
+        // Only hold the lock while we look, not while we wait
+        let input = CONSOLE_INPUT
+            .try_lock()
+            .and_then(|mut lock| lock.as_mut().and_then(|c| c.input_read()));
+        match input {
+            None | Some(Input::Special(_)) => {
                 // TODO: Handle keyboard input
+                if should_yield() {
+                    return None;
+                }
                 asm::wfi();
             }
             Some(Input::Cp850(ch)) => {
-                return ch as i32;
+                return Some(ch as i32);
             }
         }
     }
@@ -122,8 +168,9 @@ pub(crate) extern "C" fn readc() -> i32 {
 /// completed drawing. You then have a brief period of time to do some work in
 /// the frame buffer before we start drawing the next frame.
 ///
-/// Also useful for pausing for up to 1/60th of a second.
+/// Also useful for pausing for up to 1/60th of a second. Feeds the watchdog.
 pub(crate) extern "C" fn wfvbi() {
+    crate::watchdog::feed();
     let old_frame = unsafe { FRAMEBUFFER.frame() };
     loop {
         asm::wfi();
@@ -132,14 +179,30 @@ pub(crate) extern "C" fn wfvbi() {
             break;
         }
     }
+    crate::tasks::poll();
+}
+
+/// `wfvbi` for applications: wait until frame `frame` (as counted by
+/// `get_ticks`) has finished. Returns `false` if it has to yield first (see
+/// `should_yield`).
+pub(crate) fn wfvbi_after(frame: u32) -> bool {
+    crate::watchdog::feed();
+    while get_ticks() == frame {
+        if should_yield() {
+            return false;
+        }
+        asm::wfi();
+    }
+    crate::tasks::poll();
+    true
 }
 
 /// Returns 1 if there is a character in the input buffer (i.e. a key has been
 /// pressed), and returns 0 otherwise.
 pub(crate) extern "C" fn kbhit() -> i32 {
-    let mut lock = GLOBAL_CONTEXT.lock();
-    let ctx = lock.as_mut().unwrap();
-    ctx.has_char() as i32
+    CONSOLE_INPUT
+        .try_lock()
+        .map_or(false, |mut lock| lock.as_mut().unwrap().has_char()) as i32
 }
 
 /// Set the screen position for the cursor.
@@ -172,25 +235,97 @@ pub(crate) extern "C" fn get_cursor(row: *mut u8, col: *mut u8) {
     }
 }
 
+/// Get the arguments the application was run with.
+pub(crate) extern "C" fn get_args() -> Args {
+    unsafe {
+        Args {
+            argc: ARGC,
+            argv: ARGV.as_ptr(),
+        }
+    }
+}
+
+/// Find out how many CPU cycles the video and audio leave for the
+/// application.
+pub(crate) extern "C" fn get_cycle_budget() -> CycleBudget {
+    crate::cycles::budget()
+}
+
+/// Get the number of video frames since boot.
+pub(crate) extern "C" fn get_ticks() -> u32 {
+    crate::timers::ticks()
+}
+
+/// Get the number of microseconds since boot.
+pub(crate) extern "C" fn get_micros() -> u64 {
+    crate::timers::micros()
+}
+
+/// Wait until `get_micros` reaches `deadline`. Returns `false` if it has to
+/// yield first (see `should_yield`).
+pub(crate) fn sleep_until(deadline: u64) -> bool {
+    crate::timers::sleep_until(deadline)
+}
+
+/// Start a software timer.
+pub(crate) extern "C" fn timer_start(period_ms: u32, mode: TimerMode) -> TimerResult {
+    crate::timers::start(period_ms, mode)
+}
+
+/// Stop a software timer.
+pub(crate) extern "C" fn timer_stop(timer: TimerId) -> EmptyResult {
+    crate::timers::stop(timer)
+}
+
+/// Collect the next event, if there is one.
+pub(crate) extern "C" fn get_event() -> Event {
+    crate::timers::get_event()
+}
+
+/// Set (or clear) the function called once per video frame.
+pub(crate) extern "C" fn register_vblank_hook(
+    hook: Option<VblankHook>,
+    context: *mut core::ffi::c_void,
+) {
+    crate::vblank::register(hook, context)
+}
+
 /// Get the current time.
 ///
 /// The system has no concept of timezones or leap seconds. We get the
 /// calendar time from the RTC on start up, then rely on a timer tick to keep
-/// the calendar updated.
-///
-/// TODO: Actually get the RTC time on startup
+/// the calendar updated. If the RTC couldn't be read, the time is made up,
+/// and we don't offer `Capabilities::CLOCK`.
 pub(crate) extern "C" fn gettime() -> monotron_api::Timestamp {
     crate::TIME_CONTEXT.get_timestamp()
 }
 
+/// Turn a buffer an application gave us into a slice. An empty buffer can
+/// have any pointer at all (even null), which a slice can't.
+unsafe fn app_slice<'a>(ptr: *const u8, len: usize) -> &'a [u8] {
+    if len == 0 {
+        &[]
+    } else {
+        core::slice::from_raw_parts(ptr, len)
+    }
+}
+
+/// Turn a buffer an application gave us into a mutable slice. See
+/// `app_slice`.
+unsafe fn app_slice_mut<'a>(ptr: *mut u8, len: usize) -> &'a mut [u8] {
+    if len == 0 {
+        &mut []
+    } else {
+        core::slice::from_raw_parts_mut(ptr, len)
+    }
+}
+
 /// Write a UTF-8 string.
 pub(crate) extern "C" fn puts_utf8(string: *const u8, length: usize) {
     use core::fmt::Write as _;
     unsafe {
-        FRAMEBUFFER
-            .write_str(core::str::from_utf8_unchecked(core::slice::from_raw_parts(
-                string, length,
-            )))
+        crate::Console
+            .write_str(core::str::from_utf8_unchecked(app_slice(string, length)))
             .unwrap();
     }
 }
@@ -209,31 +344,7 @@ pub(crate) extern "C" fn map_line(actual_scanline: u16, drawn_scanline: u16) {
 /// `volume` - the volume to use (0..255).
 /// Returns 0 on success, anything else on error.
 pub(crate) extern "C" fn play(frequency: u32, channel: u8, waveform: u8, volume: u8) -> i32 {
-    use monotron_synth::*;
-    let frequency = Frequency::from_centi_hertz(frequency);
-    let channel = match channel {
-        0 => Channel::Channel0,
-        1 => Channel::Channel1,
-        2 => Channel::Channel2,
-        _ => {
-            return -1;
-        }
-    };
-    let waveform = match waveform {
-        0 => Waveform::Square,
-        1 => Waveform::Sine,
-        2 => Waveform::Sawtooth,
-        3 => Waveform::Noise,
-        _ => {
-            return -1;
-        }
-    };
-
-    unsafe {
-        crate::G_SYNTH.play(channel, frequency, volume, waveform);
-    }
-
-    0
+    monotron_shell::api::play(&mut Rom, frequency, channel, waveform, volume)
 }
 
 /// Set the system font.
@@ -261,15 +372,11 @@ pub(crate) extern "C" fn change_font(mode: u32, p_font: *const u8) {
     }
 }
 
-/// Get the joystick state
+/// Get the joystick state. Reads as centred if someone else is reading it.
 pub(crate) extern "C" fn get_joystick() -> u8 {
-    GLOBAL_CONTEXT
-        .lock()
-        .as_ref()
-        .unwrap()
-        .joystick
-        .get_state()
-        .as_u8()
+    JOYSTICK
+        .try_lock()
+        .map_or(0, |lock| lock.as_ref().unwrap().get_state().as_u8())
 }
 
 /// Change whether the cursor is visible
@@ -289,31 +396,18 @@ pub(crate) extern "C" fn read_char_at(row: u8, col: u8) -> u16 {
     }
 }
 
-/// Open/create a device/file. Returns a file handle, or an error.
-pub(crate) extern "C" fn open(filename: BorrowedString, _mode: OpenMode) -> HandleResult {
-    if filename == BorrowedString::new("/dev/uart0@9600") {
-        let mut lock = GLOBAL_CONTEXT.lock();
-        let ctx = lock.as_mut().unwrap();
-        ctx.rs232_uart.change_baud_rate(9600u32.bps(), &ctx.clocks);
-        HandleResult::Ok(UART0_HANDLE)
-    } else if filename == BorrowedString::new("/dev/uart0@115200") {
-        let mut lock = GLOBAL_CONTEXT.lock();
-        let ctx = lock.as_mut().unwrap();
-        ctx.rs232_uart
-            .change_baud_rate(115200u32.bps(), &ctx.clocks);
-        HandleResult::Ok(UART0_HANDLE)
-    } else {
-        HandleResult::Error(Error::FileNotFound)
+/// Open/create a device/file. Returns a file handle, or an error. There's no
+/// `OpenMode`, because the only things applications can open are the UARTs.
+pub(crate) extern "C" fn open(filename: BorrowedString) -> HandleResult {
+    match filename.as_str() {
+        Some(filename) => monotron_shell::api::open(&mut Rom, filename),
+        None => HandleResult::Error(Error::FileNotFound),
     }
 }
 
 /// Close a previously opened handle.
 pub(crate) extern "C" fn close(handle: Handle) -> EmptyResult {
-    if handle == UART0_HANDLE {
-        EmptyResult::Ok
-    } else {
-        EmptyResult::Error(Error::BadFileHandle)
-    }
+    monotron_shell::api::close(handle)
 }
 
 /// Read from a file handle into the given buffer. Returns an error, or
@@ -323,30 +417,8 @@ pub(crate) extern "C" fn read(
     buffer_ptr: *mut u8,
     buffer_len: usize,
 ) -> SizeResult {
-    if handle == UART0_HANDLE {
-        use embedded_hal::serial::Read;
-        let buffer = unsafe { core::slice::from_raw_parts_mut(buffer_ptr, buffer_len) };
-        let mut read = 0;
-        let mut lock = GLOBAL_CONTEXT.lock();
-        let ctx = lock.as_mut().unwrap();
-        while read < buffer.len() {
-            match ctx.rs232_uart.read() {
-                Ok(ch) => {
-                    buffer[read] = ch;
-                    read += 1;
-                }
-                Err(nb::Error::WouldBlock) => {
-                    break;
-                }
-                Err(_e) => {
-                    return SizeResult::Error(Error::IOError);
-                }
-            }
-        }
-        SizeResult::Ok(read)
-    } else {
-        SizeResult::Error(Error::BadFileHandle)
-    }
+    let buffer = unsafe { app_slice_mut(buffer_ptr, buffer_len) };
+    monotron_shell::api::read(&mut Rom, handle, buffer)
 }
 
 /// Write the contents of the given buffer to a file handle. Returns an
@@ -357,30 +429,8 @@ pub(crate) extern "C" fn write(
     buffer_ptr: *const u8,
     buffer_len: usize,
 ) -> SizeResult {
-    if handle == UART0_HANDLE {
-        use embedded_hal::serial::Write;
-        let buffer = unsafe { core::slice::from_raw_parts(buffer_ptr, buffer_len) };
-        let mut written = 0;
-        let mut lock = GLOBAL_CONTEXT.lock();
-        let ctx = lock.as_mut().unwrap();
-        while written < buffer.len() {
-            let ch = buffer[written];
-            match ctx.rs232_uart.write(ch) {
-                Ok(_) => {
-                    written += 1;
-                }
-                Err(nb::Error::WouldBlock) => {
-                    break;
-                }
-                Err(_e) => {
-                    return SizeResult::Error(Error::IOError);
-                }
-            }
-        }
-        SizeResult::Ok(written)
-    } else {
-        SizeResult::Error(Error::BadFileHandle)
-    }
+    let buffer = unsafe { app_slice(buffer_ptr, buffer_len) };
+    monotron_shell::api::write(&mut Rom, handle, buffer)
 }
 
 /// Write to the handle and the read from the handle. Useful when doing an
@@ -393,30 +443,30 @@ pub(crate) extern "C" fn write_then_read(
     _in_buffer: *mut u8,
     _in_buffer_len: usize,
 ) -> SizeResult {
-    unimplemented!();
+    SizeResult::Error(Error::NotSupported)
 }
 
 /// Move the read/write pointer in a file.
-pub(crate) extern "C" fn seek(_handle: Handle, _offset: Offset) -> EmptyResult {
-    unimplemented!();
+pub(crate) extern "C" fn seek(_handle: Handle) -> EmptyResult {
+    EmptyResult::Error(Error::NotSupported)
 }
 
 /// Open a directory. Returns a file handle, or an error.
 pub(crate) extern "C" fn opendir(_filename: BorrowedString) -> HandleResult {
-    unimplemented!();
+    HandleResult::Error(Error::NotSupported)
 }
 
 /// Read directory entry into given buffer.
-pub(crate) extern "C" fn readdir(_handle: Handle, _dir_entry: &mut DirEntry) -> EmptyResult {
-    unimplemented!();
+pub(crate) extern "C" fn readdir(_handle: Handle, _dir_entry: *mut DirEntry) -> EmptyResult {
+    EmptyResult::Error(Error::NotSupported)
 }
 
 /// Get information about a file by path
 pub(crate) extern "C" fn stat(
     _filename: BorrowedString,
-    _stat_entry: &mut DirEntry,
+    _stat_entry: *mut DirEntry,
 ) -> EmptyResult {
-    unimplemented!();
+    EmptyResult::Error(Error::NotSupported)
 }
 
 // End of file
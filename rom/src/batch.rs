@@ -0,0 +1,556 @@
+//! # Batch files
+//!
+//! Runs a batch file (like `AUTOEXEC.BAT`) from the SD card, a line at a
+//! time, in the style of MS-DOS. We understand:
+//!
+//! * `REM comment` and `:: comment`
+//! * `:label` and `goto label` (`goto :eof` ends the batch file)
+//! * `echo text`, `echo.` (a blank line), `echo on` and `echo off`. A line
+//!   starting with `@` is never echoed.
+//! * `pause`, which waits for a key (Escape stops the batch file)
+//! * `set NAME=value`, `set NAME=` (to delete) and `set` (to list). `%NAME%`
+//!   is replaced with the variable's value, `%ERRORLEVEL%` with the exit
+//!   status of the last program, `%0` to `%9` with the batch file's name and
+//!   arguments, and `%%` with `%`.
+//! * `if [not] errorlevel N command`, `if [not] exist FILE command` and
+//!   `if [not] A==B command`
+//!
+//! Anything else is run as if it were typed at the prompt, so it can be a
+//! shell command, a program or another batch file.
+//!
+//! The file is re-opened for every line, so that the programs it runs can
+//! use the SD card too.
+
+use crate::ui::{self, CommandLine, FoundFile};
+use crate::{print, println, ConsoleInput, Input, MenuContext, CONSOLE_INPUT, JOYSTICK};
+use core::fmt::Write as _;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+// ===========================================================================
+// Constants
+// ===========================================================================
+
+/// The longest line we will read from a batch file.
+const MAX_LINE_LEN: usize = 128;
+
+/// How many batch files can be running at once (because one ran another).
+const MAX_DEPTH: usize = 3;
+
+/// How many variables `set` can make.
+const MAX_VARIABLES: usize = 8;
+
+/// The longest variable name.
+const MAX_NAME_LEN: usize = 8;
+
+/// The longest variable value.
+const MAX_VALUE_LEN: usize = 32;
+
+/// The longest label `goto` can jump to.
+const MAX_LABEL_LEN: usize = 16;
+
+/// The file run at boot.
+const AUTOEXEC: &str = "AUTOEXEC.BAT";
+
+/// How long (in frames) we give the user to skip `AUTOEXEC.BAT`.
+const SKIP_FRAMES: u32 = 60;
+
+/// The Escape key.
+const ESCAPE: u8 = 0x1B;
+
+// ===========================================================================
+// Types
+// ===========================================================================
+
+/// A variable made with `set`.
+#[derive(Debug, Copy, Clone)]
+struct Variable {
+    name: [u8; MAX_NAME_LEN],
+    name_len: usize,
+    value: [u8; MAX_VALUE_LEN],
+    value_len: usize,
+}
+
+/// What to do after a line of the batch file.
+enum Flow {
+    /// Carry on with the next line
+    Next,
+    /// Jump to a label
+    Goto([u8; MAX_LABEL_LEN], usize),
+    /// Stop running the batch file
+    Stop,
+}
+
+/// A line of text, built up as we expand variables.
+struct Line {
+    buffer: [u8; MAX_LINE_LEN],
+    len: usize,
+}
+
+// ===========================================================================
+// Static Variables
+// ===========================================================================
+
+/// The variables made with `set`.
+static VARIABLES: spin::Mutex<[Option<Variable>; MAX_VARIABLES]> =
+    spin::Mutex::new([None; MAX_VARIABLES]);
+
+/// Whether lines are printed before they are run.
+static ECHO: AtomicBool = AtomicBool::new(true);
+
+/// How many batch files are running.
+static DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+// ===========================================================================
+// Functions and Impls
+// ===========================================================================
+
+/// Run `AUTOEXEC.BAT` from the root of the SD card, if there is one, unless
+/// the user holds down Escape (or the joystick's fire button).
+pub(crate) fn autoexec(command_line: &mut CommandLine, runner: &mut menu::Runner<MenuContext>) {
+    let found = match FoundFile::in_root(AUTOEXEC) {
+        Some(found) => found,
+        None => return,
+    };
+    if ui::with_found_file(&found, |_cont, _volume, _file| Ok(())).is_err() {
+        // No card, or no file
+        return;
+    }
+    println!("Running {} - hold Esc or Fire to skip...", AUTOEXEC);
+    for _ in 0..SKIP_FRAMES {
+        crate::api::wfvbi();
+        if skip_requested() {
+            println!("Skipped {}.", AUTOEXEC);
+            runner.prompt(true);
+            return;
+        }
+    }
+    run(command_line, runner, &found, &[]);
+    runner.prompt(true);
+}
+
+/// Is the user holding Escape or the fire button? Other keys are left for
+/// the shell.
+fn skip_requested() -> bool {
+    let fire = JOYSTICK.lock().as_ref().unwrap().get_state().fire_pressed();
+    fire || escape_pressed(CONSOLE_INPUT.lock().as_mut().unwrap())
+}
+
+/// Has Escape been pressed? Other keys are left for whoever wants them.
+fn escape_pressed(c: &mut ConsoleInput) -> bool {
+    match c.input_read() {
+        Some(Input::Cp850(ESCAPE)) | Some(Input::Special(pc_keyboard::KeyCode::Escape)) => true,
+        other => {
+            c.buffered_char = other;
+            false
+        }
+    }
+}
+
+/// Run a batch file we found on the SD card. `args` are its arguments (not
+/// including its name).
+pub(crate) fn run(
+    command_line: &mut CommandLine,
+    runner: &mut menu::Runner<MenuContext>,
+    found: &FoundFile,
+    args: &[&str],
+) {
+    if DEPTH.load(Ordering::Relaxed) >= MAX_DEPTH {
+        println!("Error: Batch files nested too deeply.");
+        return;
+    }
+    DEPTH.fetch_add(1, Ordering::Relaxed);
+    let mut offset = 0;
+    loop {
+        let stop = {
+            let mut lock = CONSOLE_INPUT.lock();
+            escape_pressed(lock.as_mut().unwrap())
+        };
+        if stop {
+            println!("Stopped {}.", found.name());
+            break;
+        }
+        let mut raw = [0u8; MAX_LINE_LEN];
+        let (len, next) = match read_line(found, offset, &mut raw) {
+            Ok(Some(result)) => result,
+            Ok(None) => break,
+            Err(e) => {
+                println!("Error: {:?}", e);
+                break;
+            }
+        };
+        offset = next;
+        let raw = match core::str::from_utf8(&raw[0..len]) {
+            Ok(raw) => raw,
+            Err(_) => {
+                println!("Error: {} has a line which isn't text.", found.name());
+                break;
+            }
+        };
+        let mut line = Line::new();
+        line.expand(raw, found.name(), args);
+        match statement(command_line, runner, line.as_str()) {
+            Flow::Next => {}
+            Flow::Stop => break,
+            Flow::Goto(label, label_len) => {
+                let label = core::str::from_utf8(&label[0..label_len]).unwrap_or("");
+                if label.eq_ignore_ascii_case("eof") {
+                    break;
+                }
+                match find_label(found, label) {
+                    Ok(Some(label_offset)) => offset = label_offset,
+                    Ok(None) => {
+                        println!("Error: Label {:?} not found.", label);
+                        break;
+                    }
+                    Err(e) => {
+                        println!("Error: {:?}", e);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    DEPTH.fetch_sub(1, Ordering::Relaxed);
+}
+
+/// Run one (expanded) line of a batch file.
+fn statement(
+    command_line: &mut CommandLine,
+    runner: &mut menu::Runner<MenuContext>,
+    line: &str,
+) -> Flow {
+    let line = line.trim();
+    let (echo, line) = if line.starts_with('@') {
+        (false, line[1..].trim_start())
+    } else {
+        (ECHO.load(Ordering::Relaxed), line)
+    };
+    if line.is_empty() || line.starts_with(':') {
+        // Blank lines, labels and `::` comments
+        return Flow::Next;
+    }
+    if echo {
+        println!("> {}", line);
+    }
+    command(command_line, runner, line)
+}
+
+/// Run a batch file command (which might have come from an `if`).
+fn command(
+    command_line: &mut CommandLine,
+    runner: &mut menu::Runner<MenuContext>,
+    line: &str,
+) -> Flow {
+    let (word, rest) = split_word(line);
+    if word.eq_ignore_ascii_case("rem") {
+        // A comment
+    } else if word.eq_ignore_ascii_case("echo") {
+        match rest {
+            "" => println!(
+                "ECHO is {}",
+                if ECHO.load(Ordering::Relaxed) {
+                    "on"
+                } else {
+                    "off"
+                }
+            ),
+            r if r.eq_ignore_ascii_case("on") => ECHO.store(true, Ordering::Relaxed),
+            r if r.eq_ignore_ascii_case("off") => ECHO.store(false, Ordering::Relaxed),
+            r => println!("{}", r),
+        }
+    } else if word.eq_ignore_ascii_case("echo.") {
+        println!();
+    } else if word.eq_ignore_ascii_case("pause") {
+        return pause();
+    } else if word.eq_ignore_ascii_case("set") {
+        set(rest);
+    } else if word.eq_ignore_ascii_case("goto") {
+        let label = rest.trim_start_matches(':');
+        let mut buffer = [0u8; MAX_LABEL_LEN];
+        let len = label.len().min(buffer.len());
+        buffer[0..len].copy_from_slice(&label.as_bytes()[0..len]);
+        return Flow::Goto(buffer, len);
+    } else if word.eq_ignore_ascii_case("if") {
+        match condition(rest) {
+            Some((true, then)) => return command(command_line, runner, then),
+            Some((false, _)) => {}
+            None => println!("Error: Bad if: {}", rest),
+        }
+    } else {
+        // Anything else is for the shell. We print our own echo of the line,
+        // so stop the menu runner echoing it (and printing a prompt).
+        let quiet = crate::set_menu_quiet(true);
+        command_line.execute(runner, line);
+        crate::set_menu_quiet(quiet);
+    }
+    Flow::Next
+}
+
+/// Wait for a key. Escape stops the batch file.
+fn pause() -> Flow {
+    print!("Press any key to continue . . . ");
+    loop {
+        crate::api::wfvbi();
+        let input = CONSOLE_INPUT.lock().as_mut().unwrap().input_read();
+        match input {
+            Some(Input::Cp850(ESCAPE)) | Some(Input::Special(pc_keyboard::KeyCode::Escape)) => {
+                println!();
+                return Flow::Stop;
+            }
+            Some(_) => {
+                println!();
+                return Flow::Next;
+            }
+            None => {}
+        }
+    }
+}
+
+/// Evaluate the condition of an `if`. Returns whether it was true, and the
+/// command to run if it was, or `None` if it didn't make sense.
+fn condition(text: &str) -> Option<(bool, &str)> {
+    let (word, rest) = split_word(text);
+    let (negate, word, rest) = if word.eq_ignore_ascii_case("not") {
+        let (word, rest) = split_word(rest);
+        (true, word, rest)
+    } else {
+        (false, word, rest)
+    };
+    let (result, then) = if word.eq_ignore_ascii_case("errorlevel") {
+        let (level, then) = split_word(rest);
+        let level: u32 = level.parse().ok()?;
+        (ui::exit_status() >= level, then)
+    } else if word.eq_ignore_ascii_case("exist") {
+        let (name, then) = split_word(rest);
+        let found = FoundFile::in_root(name)?;
+        let exists = ui::with_found_file(&found, |_cont, _volume, _file| Ok(())).is_ok();
+        (exists, then)
+    } else {
+        let mut parts = word.splitn(2, "==");
+        let left = parts.next()?;
+        let right = parts.next()?;
+        (left == right, rest)
+    };
+    if then.is_empty() {
+        None
+    } else {
+        Some((result != negate, then))
+    }
+}
+
+/// Handle `set`.
+fn set(text: &str) {
+    let mut variables = VARIABLES.lock();
+    if text.is_empty() {
+        for var in variables.iter().filter_map(|v| v.as_ref()) {
+            println!("{}={}", var.name(), var.value());
+        }
+        return;
+    }
+    let mut parts = text.splitn(2, '=');
+    let name = parts.next().unwrap_or("").trim();
+    let value = match parts.next() {
+        Some(value) => value,
+        None => {
+            match variables
+                .iter()
+                .filter_map(|v| v.as_ref())
+                .find(|v| v.is_called(name))
+            {
+                Some(var) => println!("{}={}", var.name(), var.value()),
+                None => println!("Variable {} not defined", name),
+            }
+            return;
+        }
+    };
+    if name.is_empty() || name.len() > MAX_NAME_LEN || value.len() > MAX_VALUE_LEN {
+        println!("Error: Bad variable.");
+        return;
+    }
+    // Remove any old value
+    for slot in variables.iter_mut() {
+        if slot.map(|v| v.is_called(name)).unwrap_or(false) {
+            *slot = None;
+        }
+    }
+    if value.is_empty() {
+        return;
+    }
+    match variables.iter_mut().find(|v| v.is_none()) {
+        Some(slot) => {
+            let mut var = Variable {
+                name: [0u8; MAX_NAME_LEN],
+                name_len: name.len(),
+                value: [0u8; MAX_VALUE_LEN],
+                value_len: value.len(),
+            };
+            var.name[0..name.len()].copy_from_slice(name.as_bytes());
+            var.name[0..name.len()].make_ascii_uppercase();
+            var.value[0..value.len()].copy_from_slice(value.as_bytes());
+            *slot = Some(var);
+        }
+        None => println!("Error: Too many variables."),
+    }
+}
+
+/// Split off the first word of some text. Returns the word, and the rest of
+/// the text with leading spaces removed.
+fn split_word(text: &str) -> (&str, &str) {
+    let text = text.trim_start();
+    match text.find(|c: char| c.is_whitespace()) {
+        Some(idx) => (&text[0..idx], text[idx..].trim_start()),
+        None => (text, ""),
+    }
+}
+
+/// Read a line from the file, starting at `offset`. Returns the length of
+/// the line (which is truncated if it doesn't fit in `buffer`) and the
+/// offset of the next line, or `None` at the end of the file.
+fn read_line(
+    found: &FoundFile,
+    offset: u32,
+    buffer: &mut [u8; MAX_LINE_LEN],
+) -> Result<Option<(usize, u32)>, embedded_sdmmc::Error<embedded_sdmmc::SdMmcError>> {
+    ui::with_found_file(found, |cont, volume, file| {
+        if offset >= file.length() {
+            return Ok(None);
+        }
+        let _ = file.seek_from_start(offset);
+        let mut len = 0;
+        let mut next = offset;
+        loop {
+            let mut chunk = [0u8; 32];
+            let count = cont.read(volume, file, &mut chunk)?;
+            if count == 0 {
+                break;
+            }
+            let newline = chunk[0..count].iter().position(|&b| b == b'\n');
+            let end = newline.unwrap_or(count);
+            let copy = end.min(buffer.len() - len);
+            buffer[len..len + copy].copy_from_slice(&chunk[0..copy]);
+            len += copy;
+            if let Some(idx) = newline {
+                next += idx as u32 + 1;
+                break;
+            }
+            next += count as u32;
+        }
+        // DOS line endings, and the DOS end-of-file marker
+        while len > 0 && (buffer[len - 1] == b'\r' || buffer[len - 1] == 0x1A) {
+            len -= 1;
+        }
+        Ok(Some((len, next)))
+    })
+}
+
+/// Find the line after `:label` in the file.
+fn find_label(
+    found: &FoundFile,
+    label: &str,
+) -> Result<Option<u32>, embedded_sdmmc::Error<embedded_sdmmc::SdMmcError>> {
+    let mut offset = 0;
+    loop {
+        let mut buffer = [0u8; MAX_LINE_LEN];
+        let (len, next) = match read_line(found, offset, &mut buffer)? {
+            Some(result) => result,
+            None => return Ok(None),
+        };
+        offset = next;
+        let line = core::str::from_utf8(&buffer[0..len]).unwrap_or("").trim();
+        if line.starts_with(':') && !line.starts_with("::") {
+            let (name, _) = split_word(&line[1..]);
+            if name.eq_ignore_ascii_case(label) {
+                return Ok(Some(offset));
+            }
+        }
+    }
+}
+
+impl Variable {
+    fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[0..self.name_len]).unwrap_or("")
+    }
+
+    fn value(&self) -> &str {
+        core::str::from_utf8(&self.value[0..self.value_len]).unwrap_or("")
+    }
+
+    fn is_called(&self, name: &str) -> bool {
+        self.name().eq_ignore_ascii_case(name)
+    }
+}
+
+impl Line {
+    fn new() -> Line {
+        Line {
+            buffer: [0u8; MAX_LINE_LEN],
+            len: 0,
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buffer[0..self.len]).unwrap_or("")
+    }
+
+    /// Copy `raw` into this line, replacing `%NAME%`, `%0` to `%9` and `%%`.
+    fn expand(&mut self, raw: &str, name: &str, args: &[&str]) {
+        let mut rest = raw;
+        while let Some(idx) = rest.find('%') {
+            let _ = self.write_str(&rest[0..idx]);
+            let after = &rest[idx + 1..];
+            let next = after.chars().next();
+            match next {
+                Some('%') => {
+                    let _ = self.write_str("%");
+                    rest = &after[1..];
+                }
+                Some(digit @ '0'..='9') => {
+                    let arg = match digit as usize - '0' as usize {
+                        0 => name,
+                        n => args.get(n - 1).cloned().unwrap_or(""),
+                    };
+                    let _ = self.write_str(arg);
+                    rest = &after[1..];
+                }
+                _ => match after.find('%') {
+                    Some(end) => {
+                        let var = &after[0..end];
+                        if var.eq_ignore_ascii_case("errorlevel") {
+                            let _ = write!(self, "{}", ui::exit_status());
+                        } else if let Some(v) = VARIABLES
+                            .lock()
+                            .iter()
+                            .filter_map(|v| v.as_ref())
+                            .find(|v| v.is_called(var))
+                        {
+                            let _ = self.write_str(v.value());
+                        }
+                        rest = &after[end + 1..];
+                    }
+                    None => {
+                        // A lone `%` - leave it alone
+                        let _ = self.write_str("%");
+                        rest = after;
+                    }
+                },
+            }
+        }
+        let _ = self.write_str(rest);
+    }
+}
+
+impl core::fmt::Write for Line {
+    /// Append as much of the string as fits. Anything else is lost.
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for &b in s.as_bytes() {
+            if self.len == self.buffer.len() {
+                return Err(core::fmt::Error);
+            }
+            self.buffer[self.len] = b;
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
+
+// End of file
@@ -0,0 +1,266 @@
+//! # Panic handler and crash record
+//!
+//! When the ROM panics, we draw the message in a red banner at the top of
+//! the screen, send it to the USB UART, and keep a copy in RAM which isn't
+//! cleared at boot. After a reset, the shell tells you about the crash, and
+//! `crash save` appends it to `CRASH.LOG` on the SD card.
+
+use crate::fb::{self, BaseConsole, Col, Position, Row};
+use crate::{cpu, output, println, Storage, FRAMEBUFFER};
+use core::fmt::Write as _;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, Ordering};
+use crc::Hasher32;
+
+// ===========================================================================
+// Constants
+// ===========================================================================
+
+/// Marks a crash record as (probably) valid. "CRSH".
+const CRASH_MAGIC: u32 = 0x4352_5348;
+
+/// The longest panic message we keep.
+const MAX_TEXT: usize = 160;
+
+/// The width of the screen, in characters.
+const SCREEN_COLS: usize = 48;
+
+/// Where we keep crashes on the SD card.
+const CRASH_LOG: &str = "CRASH.LOG";
+
+// ===========================================================================
+// Types
+// ===========================================================================
+
+/// What we remember about a panic, across a reset.
+#[repr(C)]
+struct CrashRecord {
+    /// `CRASH_MAGIC`, if the rest is valid
+    magic: u32,
+    /// How long (in video frames) we had been running
+    frames: u32,
+    /// How many bytes of `text` are used
+    len: u32,
+    /// The panic message
+    text: [u8; MAX_TEXT],
+    /// CRC-32 of all of the above
+    crc32: u32,
+}
+
+/// Collects formatted text, dropping anything which doesn't fit.
+struct Truncate<'a> {
+    buffer: &'a mut [u8],
+    used: usize,
+}
+
+// ===========================================================================
+// Static Variables
+// ===========================================================================
+
+/// The last crash. This lives in the `.uninit` section, so it survives a
+/// reset (but not a power cycle, where it fills with junk and fails the CRC
+/// check).
+#[link_section = ".uninit.CRASH_RECORD"]
+static mut CRASH_RECORD: MaybeUninit<CrashRecord> = MaybeUninit::uninit();
+
+/// Set once we've started panicking, in case we panic again.
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+// ===========================================================================
+// Functions and Impls
+// ===========================================================================
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    if !PANICKING.swap(true, Ordering::Relaxed) {
+        let mut text = [0u8; MAX_TEXT];
+        let mut message = Truncate {
+            buffer: &mut text,
+            used: 0,
+        };
+        let _ = write!(message, "{}", info);
+        let len = message.used;
+        let frames = unsafe { FRAMEBUFFER.frame() } as u32;
+        let mut record = CrashRecord {
+            magic: CRASH_MAGIC,
+            frames,
+            len: len as u32,
+            text,
+            crc32: 0,
+        };
+        record.crc32 = record.calculate_crc32();
+        unsafe { CRASH_RECORD.as_mut_ptr().write_volatile(record) };
+        let text = core::str::from_utf8(&text[0..len]).unwrap_or("?");
+        show_banner(text);
+        send_to_uart(text);
+    }
+    // The video keeps running (unless we panicked in an interrupt), so the
+    // banner stays up until someone resets us.
+    loop {
+        cortex_m::asm::wfi();
+    }
+}
+
+/// Draw the panic message in a red banner across the top of the screen.
+fn show_banner(text: &str) {
+    unsafe {
+        let _ = FRAMEBUFFER.set_pos(Position::new(Row(0), Col(0)));
+        let _ = FRAMEBUFFER.write_str("\u{001B}W\u{001B}r");
+    }
+    banner_line("*** ROM PANIC ***");
+    for line in text.lines() {
+        banner_line(line);
+    }
+    banner_line("Press reset to restart.");
+}
+
+/// Write a line of the banner, wrapping if it's too long and padding it to
+/// the edge of the screen.
+fn banner_line(line: &str) {
+    let mut col = 0;
+    for ch in line.chars() {
+        unsafe {
+            FRAMEBUFFER.write_glyph(fb::Char::map_char(ch), None);
+        }
+        col = (col + 1) % SCREEN_COLS;
+    }
+    if col != 0 || line.is_empty() {
+        for _ in col..SCREEN_COLS {
+            unsafe {
+                FRAMEBUFFER.write_glyph(fb::Char::map_char(' '), None);
+            }
+        }
+    }
+}
+
+/// Send the panic message to the USB UART, if it has been set up.
+fn send_to_uart(text: &str) {
+    let sysctl = unsafe { &*cpu::SYSCTL::ptr() };
+    if sysctl.rcgcuart.read().r0().bit_is_clear() {
+        // Touching the UART without a clock would just fault again
+        return;
+    }
+    for &byte in b"\r\n*** ROM PANIC ***\r\n"
+        .iter()
+        .chain(text.as_bytes())
+        .chain(b"\r\n")
+    {
+        if byte == b'\n' {
+            crate::uart_echo(b'\r');
+        }
+        crate::uart_echo(byte);
+    }
+}
+
+/// Tell the user about the last crash, if there was one. Called at boot.
+pub(crate) fn check_last_crash() {
+    if let Some((frames, text)) = last_crash() {
+        println!(
+            "\u{001B}RThe ROM crashed {}s after boot:\n{}\u{001B}W",
+            frames / 60,
+            text
+        );
+        println!("Use `crash save` to keep it in {}.", CRASH_LOG);
+    }
+}
+
+/// Get the last crash: how many frames after boot it happened, and the
+/// panic message.
+fn last_crash() -> Option<(u32, &'static str)> {
+    // After a power cycle, this is junk - but any junk will do
+    let record = unsafe { &*CRASH_RECORD.as_ptr() };
+    if record.magic != CRASH_MAGIC
+        || record.len as usize > MAX_TEXT
+        || record.crc32 != record.calculate_crc32()
+    {
+        return None;
+    }
+    let text = core::str::from_utf8(&record.text[0..record.len as usize]).ok()?;
+    Some((record.frames, text))
+}
+
+/// Show, save or forget the last crash.
+pub(crate) fn item_crash(
+    _menu: &crate::ui::Menu,
+    item: &crate::ui::Item,
+    args: &[&str],
+    _context: &mut crate::MenuContext,
+) {
+    let (frames, text) = match last_crash() {
+        Some(crash) => crash,
+        None => {
+            println!("No crash recorded.");
+            return;
+        }
+    };
+    match ::menu::argument_finder(item, args, "ACTION") {
+        Ok(None) => {
+            println!("The ROM crashed {}s after boot:\n{}", frames / 60, text);
+        }
+        Ok(Some("save")) => {
+            let mut lock = crate::STORAGE.lock();
+            match save(lock.as_mut().unwrap(), frames, text) {
+                Ok(()) => println!("Added to {}.", CRASH_LOG),
+                Err(e) => println!("Error: Couldn't write {}: {:?}", CRASH_LOG, e),
+            }
+        }
+        Ok(Some("clear")) => unsafe {
+            (*CRASH_RECORD.as_mut_ptr()).magic = 0;
+        },
+        _ => println!("Error: Say `save` or `clear`."),
+    }
+}
+
+/// Append a crash to `CRASH.LOG`.
+fn save(
+    cont: &mut Storage,
+    frames: u32,
+    text: &str,
+) -> Result<(), embedded_sdmmc::Error<embedded_sdmmc::SdMmcError>> {
+    let mut entry = [0u8; MAX_TEXT + 32];
+    let mut writer = Truncate {
+        buffer: &mut entry,
+        used: 0,
+    };
+    let _ = write!(
+        writer,
+        "--- Crashed {}s after boot\r\n{}\r\n",
+        frames / 60,
+        text
+    );
+    let used = writer.used;
+    output::write_file(
+        cont,
+        CRASH_LOG,
+        embedded_sdmmc::Mode::ReadWriteCreateOrAppend,
+        &entry[0..used],
+    )
+}
+
+impl CrashRecord {
+    /// Work out the CRC-32 of the record (not including `crc32`).
+    fn calculate_crc32(&self) -> u32 {
+        let mut digest = crc::crc32::Digest::new(crc::crc32::IEEE);
+        digest.write(&self.magic.to_le_bytes());
+        digest.write(&self.frames.to_le_bytes());
+        digest.write(&self.len.to_le_bytes());
+        digest.write(&self.text);
+        digest.sum32()
+    }
+}
+
+impl<'a> core::fmt::Write for Truncate<'a> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for &byte in s.as_bytes() {
+            if self.used == self.buffer.len() {
+                break;
+            }
+            // Keep it to something the banner (and the SD card) can show
+            self.buffer[self.used] = if byte.is_ascii() { byte } else { b'?' };
+            self.used += 1;
+        }
+        Ok(())
+    }
+}
+
+// End of file
@@ -0,0 +1,345 @@
+//! # CPU cycle accounting
+//!
+//! Everything on this machine competes with the video interrupts, so we
+//! count where the cycles go, using the DWT cycle counter. Each interrupt
+//! handler (and the draw and audio routines inside `timer1a`) is timed with
+//! a `Span`. Spans subtract any higher-priority interrupts which ran in the
+//! middle of them, so each cycle is only counted once.
+//!
+//! The cycle counter stops while we're asleep in `wfi`, so we work out the
+//! idle time as the length of the frame (from the line timer) less the
+//! cycles we were awake for.
+//!
+//! Once a frame, `on_frame` moves the counts for the frame just gone into
+//! `LAST`, where `top` and `get_cycle_budget` can see them.
+
+use crate::cpu::{DCB, DWT};
+use crate::{api, print, println, ConsoleInput, Input, CONSOLE_INPUT};
+use core::sync::atomic::{AtomicU32, Ordering};
+use monotron_api::CycleBudget;
+
+// ===========================================================================
+// Constants
+// ===========================================================================
+
+/// How many `Bucket`s there are.
+const NUM_BUCKETS: usize = 8;
+
+/// Timer1B's exception entry and pixel-start code, which we can't time
+/// without upsetting the pixel alignment.
+pub(crate) const TIMER1B_UNTIMED_CYCLES: u32 = 12 + 70;
+
+/// How often `top` updates, in frames.
+const TOP_INTERVAL_FRAMES: u32 = 30;
+
+// ===========================================================================
+// Types
+// ===========================================================================
+
+/// Somewhere we count cycles.
+#[derive(Debug, Copy, Clone)]
+pub(crate) enum Bucket {
+    /// The start-of-line interrupt, including `IsrSol` and `Synth`
+    Timer1A = 0,
+    /// Drawing the line, inside `timer1a`
+    IsrSol = 1,
+    /// Working out the next audio sample, inside `timer1a`
+    Synth = 2,
+    /// The start-of-pixels interrupt
+    Timer1B = 3,
+    /// The interrupt just before Timer1B
+    Timer2A = 4,
+    /// The application's vertical-blank hook, which runs in thread mode
+    VblankHook = 5,
+    /// The ROM's background tasks, which run in thread mode or in `SVCall`
+    Tasks = 6,
+    /// The UART receive interrupts
+    SerialRx = 7,
+}
+
+/// Something being timed.
+pub(crate) struct Span {
+    start: u32,
+    nested: u32,
+}
+
+/// Where the cycles went in one frame.
+pub(crate) struct Usage {
+    /// The length of the frame
+    pub(crate) frame: u32,
+    /// How long we were awake for
+    pub(crate) awake: u32,
+    /// Indexed by `Bucket`
+    pub(crate) buckets: [u32; NUM_BUCKETS],
+}
+
+// ===========================================================================
+// Static Variables
+// ===========================================================================
+
+/// Cycles counted so far this frame.
+static CURRENT: [AtomicU32; NUM_BUCKETS] = [
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+];
+
+/// Cycles counted in the last complete frame.
+static LAST: [AtomicU32; NUM_BUCKETS] = [
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+];
+
+/// The cycles spent in every interrupt handler so far (wrapping). A `Span`
+/// looks at how much this moves to see how long it was interrupted for.
+static NESTED: AtomicU32 = AtomicU32::new(0);
+
+/// The cycle counter at the start of this frame.
+static FRAME_START: AtomicU32 = AtomicU32::new(0);
+
+/// How many cycles we were awake for in the last complete frame.
+static LAST_AWAKE: AtomicU32 = AtomicU32::new(0);
+
+/// How many lines we've drawn so far this frame.
+static LINES: AtomicU32 = AtomicU32::new(0);
+
+/// How many lines there were in the last complete frame.
+static LAST_LINES: AtomicU32 = AtomicU32::new(0);
+
+/// How long each line is, in CPU cycles. Set when the video mode is.
+static LINE_CYCLES: AtomicU32 = AtomicU32::new(0);
+
+// ===========================================================================
+// Functions and Impls
+// ===========================================================================
+
+/// Start the cycle counter. Called once, at boot.
+pub(crate) fn init(dcb: &mut DCB, dwt: &mut DWT) {
+    dcb.enable_trace();
+    dwt.enable_cycle_counter();
+    FRAME_START.store(DWT::get_cycle_count(), Ordering::Relaxed);
+}
+
+/// Tell us how long a line is. Called when the video is set up.
+pub(crate) fn set_line_cycles(cycles: u32) {
+    LINE_CYCLES.store(cycles, Ordering::Relaxed);
+}
+
+/// Called from `timer1a`, once a line.
+pub(crate) fn on_line() {
+    LINES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Called from `timer1a`, once a frame.
+pub(crate) fn on_frame() {
+    let now = DWT::get_cycle_count();
+    let start = FRAME_START.swap(now, Ordering::Relaxed);
+    LAST_AWAKE.store(now.wrapping_sub(start), Ordering::Relaxed);
+    LAST_LINES.store(LINES.swap(0, Ordering::Relaxed), Ordering::Relaxed);
+    for (last, current) in LAST.iter().zip(CURRENT.iter()) {
+        last.store(current.swap(0, Ordering::Relaxed), Ordering::Relaxed);
+    }
+}
+
+/// Where the cycles went in the last complete frame.
+pub(crate) fn last_frame() -> Usage {
+    let mut buckets = [0u32; NUM_BUCKETS];
+    for (bucket, last) in buckets.iter_mut().zip(LAST.iter()) {
+        *bucket = last.load(Ordering::Relaxed);
+    }
+    Usage {
+        frame: LAST_LINES.load(Ordering::Relaxed) * LINE_CYCLES.load(Ordering::Relaxed),
+        awake: LAST_AWAKE.load(Ordering::Relaxed),
+        buckets,
+    }
+}
+
+/// Work out the budget for `get_cycle_budget`.
+pub(crate) fn budget() -> CycleBudget {
+    let usage = last_frame();
+    let interrupts = usage.interrupts();
+    // How far through this frame are we?
+    let lines = LINES.load(Ordering::Relaxed);
+    let elapsed = lines * LINE_CYCLES.load(Ordering::Relaxed);
+    let left = usage.frame.saturating_sub(elapsed);
+    // Assume the interrupts still to come take their usual share
+    let to_come = if usage.frame == 0 {
+        0
+    } else {
+        ((left as u64 * interrupts as u64) / usage.frame as u64) as u32
+    };
+    CycleBudget {
+        frame: usage.frame,
+        interrupts,
+        idle: usage.idle(),
+        // The interrupts can add up to more than the frame if a timing was
+        // lost, so don't wrap round
+        remaining: left.saturating_sub(to_come),
+    }
+}
+
+impl Span {
+    /// Start timing.
+    #[inline(always)]
+    pub(crate) fn start() -> Span {
+        Span {
+            start: DWT::get_cycle_count(),
+            nested: NESTED.load(Ordering::Relaxed),
+        }
+    }
+
+    /// How many cycles so far, not counting interrupts.
+    #[inline(always)]
+    pub(crate) fn elapsed(&self) -> u32 {
+        let elapsed = DWT::get_cycle_count().wrapping_sub(self.start);
+        let nested = NESTED.load(Ordering::Relaxed).wrapping_sub(self.nested);
+        elapsed.wrapping_sub(nested)
+    }
+
+    /// Stop timing some code inside an interrupt handler, and count the
+    /// cycles against `bucket`.
+    #[inline(always)]
+    pub(crate) fn finish(self, bucket: Bucket) -> u32 {
+        let cycles = self.elapsed();
+        CURRENT[bucket as usize].fetch_add(cycles, Ordering::Relaxed);
+        cycles
+    }
+
+    /// Stop timing an interrupt handler, and count the cycles against
+    /// `bucket`. `untimed` is any extra cycles which happened before we
+    /// could start timing.
+    #[inline(always)]
+    pub(crate) fn finish_interrupt(self, bucket: Bucket, untimed: u32) {
+        let cycles = self.finish(bucket) + untimed;
+        CURRENT[bucket as usize].fetch_add(untimed, Ordering::Relaxed);
+        NESTED.fetch_add(cycles, Ordering::Relaxed);
+    }
+}
+
+impl Usage {
+    /// Cycles spent in the interrupt handlers.
+    pub(crate) fn interrupts(&self) -> u32 {
+        self.buckets[Bucket::Timer1A as usize]
+            + self.buckets[Bucket::Timer1B as usize]
+            + self.buckets[Bucket::Timer2A as usize]
+            + self.buckets[Bucket::SerialRx as usize]
+    }
+
+    /// Cycles spent asleep.
+    pub(crate) fn idle(&self) -> u32 {
+        self.frame.saturating_sub(self.awake)
+    }
+
+    /// Cycles spent awake but not in an interrupt handler - that is, in the
+    /// shell or an application.
+    pub(crate) fn thread(&self) -> u32 {
+        self.awake.saturating_sub(self.interrupts())
+    }
+}
+
+/// Print a percentage of the frame, to one decimal place.
+fn print_share(name: &str, cycles: u32, frame: u32) {
+    let permille = if frame == 0 {
+        0
+    } else {
+        (cycles as u64 * 1000) / frame as u64
+    };
+    println!(
+        "{:<12}{:>8} {:>3}.{}%",
+        name,
+        cycles,
+        permille / 10,
+        permille % 10
+    );
+}
+
+/// Is there a key waiting? Swallows it if so.
+fn key_pressed(c: &mut ConsoleInput) -> bool {
+    match c.input_read() {
+        Some(Input::Cp850(_)) | Some(Input::Special(_)) => true,
+        None => false,
+    }
+}
+
+/// Show where the CPU's time goes, updating twice a second until a key is
+/// pressed.
+pub(crate) fn item_top(
+    _menu: &crate::ui::Menu,
+    _item: &crate::ui::Item,
+    _args: &[&str],
+    _context: &mut crate::MenuContext,
+) {
+    print!("\u{001B}Z");
+    let mut frames = TOP_INTERVAL_FRAMES;
+    loop {
+        api::wfvbi();
+        if key_pressed(CONSOLE_INPUT.lock().as_mut().unwrap()) {
+            break;
+        }
+        frames += 1;
+        if frames < TOP_INTERVAL_FRAMES {
+            continue;
+        }
+        frames = 0;
+        let usage = last_frame();
+        api::move_cursor(0, 0);
+        println!("Cycles per frame: {}", usage.frame);
+        println!("{:<12}{:>8} {:>6}", "", "cycles", "share");
+        print_share(
+            "timer1a",
+            usage.buckets[Bucket::Timer1A as usize],
+            usage.frame,
+        );
+        print_share(
+            "  isr_sol",
+            usage.buckets[Bucket::IsrSol as usize],
+            usage.frame,
+        );
+        print_share(
+            "  synth",
+            usage.buckets[Bucket::Synth as usize],
+            usage.frame,
+        );
+        print_share(
+            "timer1b",
+            usage.buckets[Bucket::Timer1B as usize],
+            usage.frame,
+        );
+        print_share(
+            "timer2a",
+            usage.buckets[Bucket::Timer2A as usize],
+            usage.frame,
+        );
+        print_share(
+            "uart rx",
+            usage.buckets[Bucket::SerialRx as usize],
+            usage.frame,
+        );
+        print_share("shell/app", usage.thread(), usage.frame);
+        print_share(
+            "  vblank",
+            usage.buckets[Bucket::VblankHook as usize],
+            usage.frame,
+        );
+        print_share(
+            "  tasks",
+            usage.buckets[Bucket::Tasks as usize],
+            usage.frame,
+        );
+        print_share("idle (wfi)", usage.idle(), usage.frame);
+        println!("\nPress any key to stop.");
+    }
+}
+
+// End of file
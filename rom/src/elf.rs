@@ -0,0 +1,298 @@
+//! # ELF loader
+//!
+//! Loads ELF32 ARM executables (as produced by the linker) into application
+//! RAM.
+//!
+//! We check everything we can before we touch RAM, so a bad build is reported
+//! as an error, rather than turning into a HardFault when it runs:
+//!
+//! * It must be a little-endian, 32-bit, ARM, EABI version 5 executable.
+//! * Every `PT_LOAD` segment must sit entirely within the application window,
+//!   and within the file.
+//! * No two `PT_LOAD` segments may overlap.
+//! * The entry point must be a Thumb address (i.e. odd) within an executable
+//!   segment.
+//!
+//! The file is read a piece at a time, so it can be bigger than application
+//! RAM (for example, if it contains debug info).
+
+// ===========================================================================
+// Constants
+// ===========================================================================
+
+/// The most `PT_LOAD` segments we will load.
+const MAX_SEGMENTS: usize = 8;
+
+/// The size of an ELF32 file header.
+const FILE_HEADER_LEN: usize = 52;
+
+/// The size of an ELF32 program header.
+const PROGRAM_HEADER_LEN: usize = 32;
+
+/// `EI_CLASS` value for 32-bit files.
+const ELFCLASS32: u8 = 1;
+
+/// `EI_DATA` value for little-endian files.
+const ELFDATA2LSB: u8 = 1;
+
+/// `EI_OSABI` value for 'no particular OS' (as used by `arm-none-eabi`).
+const ELFOSABI_NONE: u8 = 0;
+
+/// `e_type` value for executable files.
+const ET_EXEC: u16 = 2;
+
+/// `e_machine` value for 32-bit ARM.
+const EM_ARM: u16 = 40;
+
+/// The ARM EABI version lives in the top byte of `e_flags`.
+const EF_ARM_EABIMASK: u32 = 0xFF00_0000;
+
+/// We only understand EABI version 5.
+const EF_ARM_EABI_VER5: u32 = 0x0500_0000;
+
+/// `p_type` value for a loadable segment.
+const PT_LOAD: u32 = 1;
+
+/// `p_flags` bit for an executable segment.
+const PF_X: u32 = 1;
+
+// ===========================================================================
+// Types
+// ===========================================================================
+
+/// The reasons we might refuse to load a file.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Error {
+    /// Doesn't start with the ELF magic number.
+    NotElf,
+    /// It's ELF, but not 32-bit little-endian.
+    WrongClass,
+    /// It's not for a bare-metal ARM EABI v5 system.
+    WrongAbi,
+    /// It's not for an ARM processor.
+    WrongMachine(u16),
+    /// It's an object file or a shared library, not an executable.
+    NotExecutable,
+    /// The program headers are missing, or the wrong size.
+    BadProgramHeaders,
+    /// More `PT_LOAD` segments than we can handle.
+    TooManySegments,
+    /// A segment is bigger in the file than in memory.
+    BadSegment {
+        /// Where the segment should be loaded
+        addr: u32,
+    },
+    /// A segment lies (at least partly) outside application RAM.
+    SegmentOutsideWindow {
+        /// Where the segment should be loaded
+        addr: u32,
+        /// How long the segment is in memory
+        len: u32,
+    },
+    /// A segment says its contents are beyond the end of the file.
+    SegmentOutsideFile {
+        /// Where the segment should be loaded
+        addr: u32,
+    },
+    /// Two segments want the same piece of RAM.
+    SegmentsOverlap {
+        /// Where the first segment should be loaded
+        first: u32,
+        /// Where the second segment should be loaded
+        second: u32,
+    },
+    /// The entry point isn't a Thumb address in an executable segment.
+    BadEntryPoint(u32),
+    /// We couldn't read the file.
+    ReadFailed,
+}
+
+/// A `PT_LOAD` program header.
+#[derive(Debug, Copy, Clone, Default)]
+struct Segment {
+    /// Where the contents start in the file.
+    offset: u32,
+    /// Where the segment goes in memory.
+    addr: u32,
+    /// How many bytes come from the file.
+    file_len: u32,
+    /// How many bytes the segment takes in memory. Anything beyond
+    /// `file_len` is `.bss`, and is zeroed.
+    mem_len: u32,
+    /// The `PF_x` flags.
+    flags: u32,
+}
+
+/// Details about an ELF file that has been loaded.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Loaded {
+    /// The address to jump to (with the Thumb bit set).
+    pub(crate) entry: u32,
+    /// The number of `PT_LOAD` segments loaded.
+    pub(crate) num_segments: usize,
+    /// The total number of bytes copied from the file.
+    pub(crate) file_bytes: u32,
+    /// The total number of bytes of `.bss` zeroed.
+    pub(crate) bss_bytes: u32,
+}
+
+// ===========================================================================
+// Functions and Impls
+// ===========================================================================
+
+/// Does this look like the start of an ELF file?
+pub(crate) fn is_elf(data: &[u8]) -> bool {
+    data.starts_with(b"\x7FELF")
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from(data[offset]) | (u16::from(data[offset + 1]) << 8)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from(read_u16(data, offset)) | (u32::from(read_u16(data, offset + 2)) << 16)
+}
+
+impl Segment {
+    fn end(&self) -> u32 {
+        self.addr + self.mem_len
+    }
+}
+
+/// Load an ELF file into the application window, which starts at address
+/// `base`. `read` is called to fill a buffer from a given offset in the
+/// file, which is `file_len` bytes long.
+///
+/// Nothing in `window` is changed unless the file passes all our checks.
+pub(crate) fn load<R>(
+    read: &mut R,
+    file_len: u32,
+    window: &mut [u8],
+    base: u32,
+) -> Result<Loaded, Error>
+where
+    R: FnMut(u32, &mut [u8]) -> Result<(), ()>,
+{
+    // Check the file header
+    let mut header = [0u8; FILE_HEADER_LEN];
+    if (file_len as usize) < header.len() {
+        return Err(Error::NotElf);
+    }
+    read(0, &mut header).map_err(|_| Error::ReadFailed)?;
+    if !is_elf(&header) {
+        return Err(Error::NotElf);
+    }
+    if header[4] != ELFCLASS32 || header[5] != ELFDATA2LSB || header[6] != 1 {
+        return Err(Error::WrongClass);
+    }
+    if header[7] != ELFOSABI_NONE || (read_u32(&header, 36) & EF_ARM_EABIMASK) != EF_ARM_EABI_VER5 {
+        return Err(Error::WrongAbi);
+    }
+    if read_u16(&header, 16) != ET_EXEC {
+        return Err(Error::NotExecutable);
+    }
+    let machine = read_u16(&header, 18);
+    if machine != EM_ARM {
+        return Err(Error::WrongMachine(machine));
+    }
+    let entry = read_u32(&header, 24);
+    let ph_offset = read_u32(&header, 28);
+    let ph_entry_len = read_u16(&header, 42) as usize;
+    let ph_count = read_u16(&header, 44) as u32;
+    if ph_count == 0
+        || ph_entry_len != PROGRAM_HEADER_LEN
+        || u64::from(ph_offset) + u64::from(ph_count) * (PROGRAM_HEADER_LEN as u64)
+            > u64::from(file_len)
+    {
+        return Err(Error::BadProgramHeaders);
+    }
+
+    // Collect the loadable segments
+    let mut segments = [Segment::default(); MAX_SEGMENTS];
+    let mut num_segments = 0;
+    for idx in 0..ph_count {
+        let mut ph = [0u8; PROGRAM_HEADER_LEN];
+        read(ph_offset + idx * PROGRAM_HEADER_LEN as u32, &mut ph)
+            .map_err(|_| Error::ReadFailed)?;
+        if read_u32(&ph, 0) != PT_LOAD {
+            continue;
+        }
+        let segment = Segment {
+            offset: read_u32(&ph, 4),
+            addr: read_u32(&ph, 8),
+            file_len: read_u32(&ph, 16),
+            mem_len: read_u32(&ph, 20),
+            flags: read_u32(&ph, 24),
+        };
+        if segment.mem_len == 0 {
+            // Nothing to load
+            continue;
+        }
+        if num_segments == MAX_SEGMENTS {
+            return Err(Error::TooManySegments);
+        }
+        segments[num_segments] = segment;
+        num_segments += 1;
+    }
+    let segments = &segments[0..num_segments];
+
+    // Check them all before we touch any RAM
+    let window_end = u64::from(base) + window.len() as u64;
+    for (idx, segment) in segments.iter().enumerate() {
+        if segment.file_len > segment.mem_len {
+            return Err(Error::BadSegment { addr: segment.addr });
+        }
+        if segment.addr < base || u64::from(segment.addr) + u64::from(segment.mem_len) > window_end
+        {
+            return Err(Error::SegmentOutsideWindow {
+                addr: segment.addr,
+                len: segment.mem_len,
+            });
+        }
+        if u64::from(segment.offset) + u64::from(segment.file_len) > u64::from(file_len) {
+            return Err(Error::SegmentOutsideFile { addr: segment.addr });
+        }
+        for other in &segments[0..idx] {
+            if segment.addr < other.end() && other.addr < segment.end() {
+                return Err(Error::SegmentsOverlap {
+                    first: other.addr,
+                    second: segment.addr,
+                });
+            }
+        }
+    }
+    let entry_ok = (entry & 1) == 1
+        && segments.iter().any(|s| {
+            let addr = entry & !1;
+            (s.flags & PF_X) != 0 && addr >= s.addr && addr < s.addr + s.file_len
+        });
+    if !entry_ok {
+        return Err(Error::BadEntryPoint(entry));
+    }
+
+    // Now we can load it
+    for b in window.iter_mut() {
+        *b = 0x00;
+    }
+    let mut loaded = Loaded {
+        entry,
+        num_segments,
+        file_bytes: 0,
+        bss_bytes: 0,
+    };
+    for segment in segments {
+        let start = (segment.addr - base) as usize;
+        let file_end = start + segment.file_len as usize;
+        let mem_end = start + segment.mem_len as usize;
+        read(segment.offset, &mut window[start..file_end]).map_err(|_| Error::ReadFailed)?;
+        // The whole window was zeroed above, but be explicit about `.bss`
+        for b in window[file_end..mem_end].iter_mut() {
+            *b = 0x00;
+        }
+        loaded.file_bytes += segment.file_len;
+        loaded.bss_bytes += segment.mem_len - segment.file_len;
+    }
+    Ok(loaded)
+}
+
+// End of file
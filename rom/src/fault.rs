@@ -0,0 +1,245 @@
+//! # Fault handlers
+//!
+//! HardFault, MemManage, BusFault and UsageFault all end up in
+//! `fault_handler`. If the fault interrupted an application, we stop the
+//! application (see `sandbox`) and the shell prints the register dump and
+//! carries on. If the ROM itself faulted, there's nothing to go back to, so
+//! we panic.
+
+use crate::sandbox;
+
+// ===========================================================================
+// Constants
+// ===========================================================================
+
+/// In the SHCSR, enable the MemManage, BusFault and UsageFault handlers
+/// (otherwise they all escalate to HardFault).
+const SHCSR_FAULTS_ENA: u32 = 0b111 << 16;
+
+/// The names of the bits in the CFSR. MMFSR is bits 0..7, BFSR is bits
+/// 8..15 and UFSR is bits 16..31.
+const CFSR_BITS: [(u32, &str); 19] = [
+    (1 << 0, "IACCVIOL"),
+    (1 << 1, "DACCVIOL"),
+    (1 << 3, "MUNSTKERR"),
+    (1 << 4, "MSTKERR"),
+    (1 << 5, "MLSPERR"),
+    (1 << 7, "MMARVALID"),
+    (1 << 8, "IBUSERR"),
+    (1 << 9, "PRECISERR"),
+    (1 << 10, "IMPRECISERR"),
+    (1 << 11, "UNSTKERR"),
+    (1 << 12, "STKERR"),
+    (1 << 13, "LSPERR"),
+    (1 << 15, "BFARVALID"),
+    (1 << 16, "UNDEFINSTR"),
+    (1 << 17, "INVSTATE"),
+    (1 << 18, "INVPC"),
+    (1 << 19, "NOCP"),
+    (1 << 24, "UNALIGNED"),
+    (1 << 25, "DIVBYZERO"),
+];
+
+/// The names of the bits in the HFSR.
+const HFSR_BITS: [(u32, &str); 3] = [
+    (1 << 1, "VECTTBL"),
+    (1 << 30, "FORCED"),
+    (1 << 31, "DEBUGEVT"),
+];
+
+/// In the CFSR, MMFAR holds the address of the MemManage fault.
+const CFSR_MMARVALID: u32 = 1 << 7;
+
+/// In the CFSR, BFAR holds the address of the BusFault.
+const CFSR_BFARVALID: u32 = 1 << 15;
+
+/// In the CFSR, the fault happened while stacking or unstacking an
+/// exception frame, so the frame can't be trusted.
+const CFSR_STACKING_ERRORS: u32 = (1 << 3) | (1 << 4) | (1 << 11) | (1 << 12);
+
+/// The names of the registers in an exception frame, in order.
+const REGISTER_NAMES: [&str; 8] = ["r0", "r1", "r2", "r3", "r12", "lr", "pc", "xPSR"];
+
+// ===========================================================================
+// Types
+// ===========================================================================
+
+/// The fault handlers we have.
+#[derive(Debug, Copy, Clone)]
+pub(crate) enum Kind {
+    HardFault,
+    MemManage,
+    BusFault,
+    UsageFault,
+}
+
+/// Everything we know about a fault.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Report {
+    kind: Kind,
+    /// r0, r1, r2, r3, r12, lr, pc and xPSR, if they were stacked OK
+    registers: Option<[u32; 8]>,
+    /// Configurable Fault Status Register
+    cfsr: u32,
+    /// HardFault Status Register
+    hfsr: u32,
+    /// MemManage Fault Address Register, if valid
+    mmfar: Option<u32>,
+    /// BusFault Address Register, if valid
+    bfar: Option<u32>,
+}
+
+// ===========================================================================
+// Assembly
+// ===========================================================================
+
+core::arch::global_asm!(
+    r#"
+    .syntax unified
+    .thumb
+
+    /* Like SVCall and PendSV in sandbox.rs, these global symbols take the
+    place of cortex-m-rt's default handlers, and memory.x checks they do. */
+
+    /* Call fault_handler with the exception frame, EXC_RETURN and the Kind */
+    .macro fault_stub name, kind, section
+    .section \section,"ax",%progbits
+    .global \name
+    .type \name,%function
+    .thumb_func
+\name:
+    tst lr, #4
+    ite eq
+    mrseq r0, msp
+    mrsne r0, psp
+    mov r1, lr
+    movs r2, #\kind
+    b fault_handler
+    .endm
+
+    /* cortex-m-rt's HardFaultTrampoline reaches HardFault with a short
+    branch, so it has to go in .HardFault.user, which link.x puts right
+    after the trampoline (it's where #[exception] would have put it). */
+    fault_stub HardFault, 0, .HardFault.user
+    fault_stub MemoryManagement, 1, .text.MemoryManagement
+    fault_stub BusFault, 2, .text.BusFault
+    fault_stub UsageFault, 3, .text.UsageFault
+"#
+);
+
+// ===========================================================================
+// Functions and Impls
+// ===========================================================================
+
+/// Turn on the MemManage, BusFault and UsageFault handlers. Called once, at
+/// boot.
+pub(crate) fn init(scb: &mut crate::cpu::SCB) {
+    // cortex-m 0.5 (which the PAC uses) calls the SHCSR `shcrs`
+    unsafe { scb.shcrs.modify(|r| r | SHCSR_FAULTS_ENA) };
+}
+
+/// Called by the fault handlers with the exception frame, `EXC_RETURN` and
+/// which handler it was.
+#[no_mangle]
+extern "C" fn fault_handler(frame: *const u32, exc_return: u32, kind: u32) {
+    let kind = match kind {
+        1 => Kind::MemManage,
+        2 => Kind::BusFault,
+        3 => Kind::UsageFault,
+        _ => Kind::HardFault,
+    };
+    let report = Report::capture(kind, frame);
+    if !sandbox::is_application(exc_return) {
+        panic!("{}", report);
+    }
+    // The status bits are write-one-to-clear
+    let scb = unsafe { &*cortex_m::peripheral::SCB::ptr() };
+    unsafe {
+        scb.cfsr.write(report.cfsr);
+        scb.hfsr.write(report.hfsr);
+    }
+    sandbox::stop(sandbox::Fault::Exception(report));
+}
+
+impl Report {
+    /// Read the fault status registers, and the exception frame if it's
+    /// any good.
+    fn capture(kind: Kind, frame: *const u32) -> Report {
+        let scb = unsafe { &*cortex_m::peripheral::SCB::ptr() };
+        let cfsr = scb.cfsr.read();
+        let registers = if (cfsr & CFSR_STACKING_ERRORS) == 0 {
+            let mut registers = [0u32; 8];
+            for (idx, register) in registers.iter_mut().enumerate() {
+                *register = unsafe { core::ptr::read_volatile(frame.add(idx)) };
+            }
+            Some(registers)
+        } else {
+            None
+        };
+        Report {
+            kind,
+            registers,
+            cfsr,
+            hfsr: scb.hfsr.read(),
+            mmfar: if (cfsr & CFSR_MMARVALID) != 0 {
+                Some(scb.mmfar.read())
+            } else {
+                None
+            },
+            bfar: if (cfsr & CFSR_BFARVALID) != 0 {
+                Some(scb.bfar.read())
+            } else {
+                None
+            },
+        }
+    }
+}
+
+/// Write the names of the bits which are set.
+fn write_bits(
+    f: &mut core::fmt::Formatter,
+    value: u32,
+    names: &[(u32, &str)],
+) -> core::fmt::Result {
+    for (bit, name) in names {
+        if (value & bit) != 0 {
+            write!(f, " {}", name)?;
+        }
+    }
+    Ok(())
+}
+
+impl core::fmt::Display for Report {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        writeln!(f, "{:?}", self.kind)?;
+        match self.registers {
+            Some(registers) => {
+                for (idx, (name, value)) in REGISTER_NAMES.iter().zip(registers.iter()).enumerate()
+                {
+                    write!(f, "{:>4} {:08x}", name, value)?;
+                    if idx % 3 == 2 {
+                        writeln!(f)?;
+                    } else {
+                        write!(f, " ")?;
+                    }
+                }
+                writeln!(f)?;
+            }
+            None => writeln!(f, "Registers lost (bad stack)")?,
+        }
+        write!(f, "CFSR {:08x}", self.cfsr)?;
+        write_bits(f, self.cfsr, &CFSR_BITS)?;
+        writeln!(f)?;
+        write!(f, "HFSR {:08x}", self.hfsr)?;
+        write_bits(f, self.hfsr, &HFSR_BITS)?;
+        if let Some(mmfar) = self.mmfar {
+            write!(f, "\nMMFAR {:08x}", mmfar)?;
+        }
+        if let Some(bfar) = self.bfar {
+            write!(f, "\nBFAR {:08x}", bfar)?;
+        }
+        Ok(())
+    }
+}
+
+// End of file
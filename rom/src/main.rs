@@ -45,27 +45,43 @@
 #![no_main]
 #![no_std]
 #![allow(deprecated)]
-#![feature(llvm_asm)]
 
 // ===========================================================================
 // Sub-modules
 // ===========================================================================
 
+mod abort;
 mod api;
+mod batch;
+mod crash;
+mod cycles;
+mod elf;
+mod fault;
+mod memory;
+mod output;
+mod platform;
+mod ring;
+mod sandbox;
+mod sdcard;
+mod serial;
+mod tasks;
+mod timers;
 mod ui;
+mod vblank;
+mod watchdog;
 
 // ===========================================================================
 // Imports
 // ===========================================================================
 
-extern crate panic_halt;
-
 use cortex_m_rt::{entry, exception};
 use fb::AsciiConsole;
 use monotron_synth::*;
 use tm4c123x_hal as hal;
 use vga_framebuffer as fb;
 
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use self::cpu::{interrupt, Interrupt};
 use self::hal::bb;
 use self::hal::i2c::I2c;
@@ -84,91 +100,100 @@ pub struct TimeContext {
     inner: spin::Mutex<TimeContextInner>,
 }
 
-/// Holds all of the system peripheral objects and state.
-pub struct Context {
-    /// The UART connected to the USB-CDC virtual COM port function on the on-board debugger.
-    usb_uart: hal::serial::Serial<
+/// Turns what arrives from the keyboard controller and the USB UART into
+/// `Input`.
+pub struct ConsoleInput {
+    /// The UART connected to the keyboard / mouse controller chip.
+    keyboard_mouse_uart: KeyboardUart,
+    /// Processes scan-codes into key events
+    keyboard: pc_keyboard::Keyboard<pc_keyboard::layouts::Uk105Key, pc_keyboard::ScancodeSet2>,
+    /// A single item buffer so that we can 'peek' at the input stream.
+    buffered_char: Option<Input>,
+    /// If `false`, input errors are squashed (in case we reboot in the middle
+    /// of a message from the keyboard controller). Set to `true` when a valid
+    /// message has been received.
+    seen_keypress: bool,
+}
+
+/// The UART connected to the USB-CDC virtual COM port function on the
+/// on-board debugger.
+type UsbUart = serial::BufferedSerial<
+    hal::serial::Serial<
         hal::serial::UART0,
         hal::gpio::gpioa::PA1<hal::gpio::AlternateFunction<hal::gpio::AF1, hal::gpio::PushPull>>,
         hal::gpio::gpioa::PA0<hal::gpio::AlternateFunction<hal::gpio::AF1, hal::gpio::PushPull>>,
         (),
         (),
     >,
-    /// The UART connected to the keyboard / mouse controller chip.
-    keyboard_mouse_uart: hal::serial::Serial<
+>;
+
+/// The UART connected to the keyboard / mouse controller chip.
+type KeyboardUart = serial::BufferedSerial<
+    hal::serial::Serial<
         hal::serial::UART7,
         hal::gpio::gpioe::PE1<hal::gpio::AlternateFunction<hal::gpio::AF1, hal::gpio::PushPull>>,
         hal::gpio::gpioe::PE0<hal::gpio::AlternateFunction<hal::gpio::AF1, hal::gpio::PushPull>>,
         (),
         (),
     >,
-    /// The UART connected to the MIDI interface.
-    /// * UART Transmit -> MIDI Out
-    /// * MIDI In -> UART Receive + MIDI Through
-    midi_uart: hal::serial::Serial<
+>;
+
+/// The UART connected to the MIDI interface.
+/// * UART Transmit -> MIDI Out
+/// * MIDI In -> UART Receive + MIDI Through
+type MidiUart = serial::BufferedSerial<
+    hal::serial::Serial<
         hal::serial::UART3,
         hal::gpio::gpioc::PC7<hal::gpio::AlternateFunction<hal::gpio::AF1, hal::gpio::PushPull>>,
         hal::gpio::gpioc::PC6<hal::gpio::AlternateFunction<hal::gpio::AF1, hal::gpio::PushPull>>,
         (),
         (),
     >,
-    /// The UART connected to the RS-232 level shifter
-    rs232_uart: hal::serial::Serial<
+>;
+
+/// The UART connected to the RS-232 level shifter
+type Rs232Uart = serial::BufferedSerial<
+    hal::serial::Serial<
         hal::serial::UART1,
         hal::gpio::gpiob::PB1<hal::gpio::AlternateFunction<hal::gpio::AF1, hal::gpio::PushPull>>,
         hal::gpio::gpiob::PB0<hal::gpio::AlternateFunction<hal::gpio::AF1, hal::gpio::PushPull>>,
         hal::gpio::gpioc::PC4<hal::gpio::AlternateFunction<hal::gpio::AF8, hal::gpio::PushPull>>,
         hal::gpio::gpioc::PC5<hal::gpio::AlternateFunction<hal::gpio::AF8, hal::gpio::PushPull>>,
     >,
-    /// Processes scan-codes into key events
-    keyboard: pc_keyboard::Keyboard<pc_keyboard::layouts::Uk105Key, pc_keyboard::ScancodeSet2>,
-    /// Our I2C bus.
-    i2c_bus: I2c<
-        cpu::I2C1,
-        (
-            hal::gpio::gpioa::PA6<
-                hal::gpio::AlternateFunction<hal::gpio::AF3, hal::gpio::PushPull>,
-            >,
-            hal::gpio::gpioa::PA7<
-                hal::gpio::AlternateFunction<
-                    hal::gpio::AF3,
-                    hal::gpio::OpenDrain<hal::gpio::Floating>,
+>;
+
+/// Our I2C bus.
+type I2cBus1 = I2c<
+    cpu::I2C1,
+    (
+        hal::gpio::gpioa::PA6<hal::gpio::AlternateFunction<hal::gpio::AF3, hal::gpio::PushPull>>,
+        hal::gpio::gpioa::PA7<
+            hal::gpio::AlternateFunction<hal::gpio::AF3, hal::gpio::OpenDrain<hal::gpio::Floating>>,
+        >,
+    ),
+>;
+
+/// Our SD card controller (which counts open directories and files)
+type Storage = sdcard::Controller<
+    embedded_sdmmc::SdMmcSpi<
+        hal::spi::Spi<
+            cpu::SSI0,
+            (
+                hal::gpio::gpioa::PA2<
+                    hal::gpio::AlternateFunction<hal::gpio::AF2, hal::gpio::PushPull>,
                 >,
-            >,
-        ),
-    >,
-    /// A single item buffer so that we can 'peek' at the input stream.
-    buffered_char: Option<Input>,
-    /// Our joystick interface
-    joystick: Joystick,
-    /// Our SD card controller
-    cont: embedded_sdmmc::Controller<
-        embedded_sdmmc::SdMmcSpi<
-            hal::spi::Spi<
-                cpu::SSI0,
-                (
-                    hal::gpio::gpioa::PA2<
-                        hal::gpio::AlternateFunction<hal::gpio::AF2, hal::gpio::PushPull>,
-                    >,
-                    hal::gpio::gpioa::PA4<
-                        hal::gpio::AlternateFunction<hal::gpio::AF2, hal::gpio::PushPull>,
-                    >,
-                    hal::gpio::gpioa::PA5<
-                        hal::gpio::AlternateFunction<hal::gpio::AF2, hal::gpio::PushPull>,
-                    >,
-                ),
-            >,
-            hal::gpio::gpioa::PA3<hal::gpio::Output<hal::gpio::PushPull>>,
+                hal::gpio::gpioa::PA4<
+                    hal::gpio::AlternateFunction<hal::gpio::AF2, hal::gpio::PushPull>,
+                >,
+                hal::gpio::gpioa::PA5<
+                    hal::gpio::AlternateFunction<hal::gpio::AF2, hal::gpio::PushPull>,
+                >,
+            ),
         >,
-        &'static TimeContext,
+        hal::gpio::gpioa::PA3<hal::gpio::Output<hal::gpio::PushPull>>,
     >,
-    /// Information about the clock speeds we have configured
-    clocks: hal::sysctl::Clocks,
-    /// If `false`, input errors are squashed (in case we reboot in the middle
-    /// of a message from the keyboard controller). Set to `true` when a valid
-    /// message has been received.
-    seen_keypress: bool,
-}
+    &'static TimeContext,
+>;
 
 /// Describes the current position of the joystick.
 #[derive(Copy, Clone, Debug)]
@@ -177,6 +202,11 @@ pub struct JoystickState(u8);
 /// Used by our menu runner.
 pub struct MenuContext;
 
+/// Where `print!`, `println!` and the menu send their output. Everything goes
+/// to the screen and, if `remote on` has been used, to the USB UART as well -
+/// unless the current command line has redirected it (see `output`).
+pub struct Console;
+
 /// Tracks the most recent date/time stamp, and the frame count at which we
 /// calculated that date/time stamp. Whenever we ask for the time, we move the
 /// date/time stamp forwards based on how many frames had elapsed since we
@@ -259,21 +289,55 @@ const CLOCK_SPEED: u32 = 80_000_000;
 // Global Variables
 // ===========================================================================
 
-/// Stores all the system state (that isn't accessed by interrupts).
-/// * We have to use Option<Context> because we can't statically initialise
-///   the Context (it needs some single hardware).
-/// * We use a spin::Mutex because statics need to be read-only to be
-///   shareable, but we do need to mutate the Context.
-///
-/// Access this object with:
-///
-/// ```ignore
-/// // Lock the mutex
-/// let lock = GLOBAL_CONTEXT.lock();
-/// // Convert to mutable reference and unwrap the Option
-/// let ctx = lock.as_mut().unwrap();
-/// ```
-pub static GLOBAL_CONTEXT: spin::Mutex<Option<Context>> = spin::Mutex::new(None);
+// Each subsystem has its own lock, so that (say) a background task can use
+// the I2C bus while a command is reading the SD card. They're all `Option`s
+// because we can't statically initialise the hardware. The rules are:
+//
+// 1. Don't wait for the user (or for the next frame) with a lock held. Take
+//    the lock, do the work and drop it.
+// 2. If you need more than one lock at once, take them in the order they're
+//    declared below.
+// 3. Anything which can run in the middle of something else - background
+//    tasks, redirected output, the vertical-blank hook - uses `try_lock`, and
+//    tries again later if it can't have the lock.
+//
+// The shell never holds a lock while an application runs, so an
+// application's Api calls (including those from its vertical-blank hook,
+// which can't run in the middle of another Api call) can't deadlock.
+//
+// Access a subsystem with:
+//
+// ```ignore
+// // Lock the mutex
+// let mut lock = STORAGE.lock();
+// // Convert to mutable reference and unwrap the Option
+// let cont = lock.as_mut().unwrap();
+// ```
+
+/// The keyboard and the USB UART, as seen by the shell and `readc`.
+pub(crate) static CONSOLE_INPUT: spin::Mutex<Option<ConsoleInput>> = spin::Mutex::new(None);
+
+/// The SD card.
+pub(crate) static STORAGE: spin::Mutex<Option<Storage>> = spin::Mutex::new(None);
+
+/// The USB UART, for writing and for file transfers. Reads usually come
+/// through `CONSOLE_INPUT`.
+pub(crate) static USB_UART: spin::Mutex<Option<UsbUart>> = spin::Mutex::new(None);
+
+/// The RS-232 UART.
+pub(crate) static RS232_UART: spin::Mutex<Option<Rs232Uart>> = spin::Mutex::new(None);
+
+/// The MIDI UART.
+pub(crate) static MIDI_UART: spin::Mutex<Option<MidiUart>> = spin::Mutex::new(None);
+
+/// The I2C bus, with the real-time clock on it.
+pub(crate) static I2C_BUS: spin::Mutex<Option<I2cBus1>> = spin::Mutex::new(None);
+
+/// The joystick.
+pub(crate) static JOYSTICK: spin::Mutex<Option<Joystick>> = spin::Mutex::new(None);
+
+/// Information about the clock speeds we have configured. Set once, at boot.
+static CLOCKS: spin::Once<hal::sysctl::Clocks> = spin::Once::new();
 
 /// Tracks the current system time in a race-hazard safe way.
 pub static TIME_CONTEXT: TimeContext = TimeContext {
@@ -303,6 +367,15 @@ static mut G_SYNTH: Synth = Synth::new(CLOCK_SPEED / 2112);
 /// characters are drawn. These should probably be two separate things.
 static mut FRAMEBUFFER: fb::FrameBuffer<VideoHardware> = fb::FrameBuffer::new();
 
+/// When set (by the `remote` command), everything written to the `Console` is
+/// also sent to the USB UART, in Code Page 850. This lets a program on the
+/// host (like `monotron-cli`) drive the shell.
+static UART_ECHO: AtomicBool = AtomicBool::new(false);
+
+/// When set, anything the `menu` runner writes is thrown away. Lets us edit
+/// the runner's buffer behind its back.
+static MENU_QUIET: AtomicBool = AtomicBool::new(false);
+
 // ===========================================================================
 // Macros
 // ===========================================================================
@@ -313,7 +386,7 @@ macro_rules! print {
     ($($arg:tt)*) => {
         {
             use core::fmt::Write as _;
-            write!(unsafe { &mut FRAMEBUFFER }, $($arg)*).unwrap();
+            write!($crate::Console, $($arg)*).unwrap();
         }
     };
 }
@@ -325,7 +398,7 @@ macro_rules! println {
     ($($arg:tt)*) => {
         {
             use core::fmt::Write as _;
-            writeln!(unsafe { &mut FRAMEBUFFER }, $($arg)*).unwrap();
+            writeln!($crate::Console, $($arg)*).unwrap();
         }
     };
 }
@@ -466,13 +539,84 @@ impl Joystick {
 
 impl core::fmt::Write for MenuContext {
     /// The `menu` runner will `write!` to the menu context for output. We
-    /// just pass on the output to the screen.
+    /// just pass on the output to the console.
     fn write_str(&mut self, string: &str) -> core::fmt::Result {
+        if MENU_QUIET.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        Console.write_str(string)
+    }
+}
+
+impl Console {
+    /// Write an 8-bit ASCII/CodePage 850 character to the console, or to
+    /// wherever the output of the current command line is going.
+    fn write_u8(&mut self, ch: u8) {
+        if !output::write_u8(ch) {
+            self.screen_u8(ch);
+        }
+    }
+
+    /// Write an 8-bit ASCII/CodePage 850 character to the screen, ignoring
+    /// any redirection.
+    fn screen_u8(&mut self, ch: u8) {
+        if UART_ECHO.load(Ordering::Relaxed) {
+            uart_echo(ch);
+        }
+        unsafe { FRAMEBUFFER.write_character(ch).unwrap() }
+    }
+
+    /// Write a string to the screen, ignoring any redirection.
+    fn screen_str(&mut self, string: &str) -> core::fmt::Result {
+        use core::fmt::Write as _;
+        if UART_ECHO.load(Ordering::Relaxed) {
+            for ch in string.chars() {
+                if ch.is_ascii() {
+                    uart_echo(ch as u8);
+                } else {
+                    uart_echo(fb::Char::map_char(ch) as u8);
+                }
+            }
+        }
         unsafe { FRAMEBUFFER.write_str(string) }
     }
 }
 
-impl Context {
+impl core::fmt::Write for Console {
+    fn write_str(&mut self, string: &str) -> core::fmt::Result {
+        if output::write_str(string) {
+            Ok(())
+        } else {
+            self.screen_str(string)
+        }
+    }
+}
+
+/// Turn the copying of console output to the USB UART on or off. Returns the
+/// old setting, so you can put it back afterwards.
+fn set_uart_echo(enabled: bool) -> bool {
+    UART_ECHO.swap(enabled, Ordering::Relaxed)
+}
+
+/// Stop (or restart) output from the `menu` runner. Returns the old
+/// setting, so you can put it back afterwards.
+fn set_menu_quiet(quiet: bool) -> bool {
+    MENU_QUIET.swap(quiet, Ordering::Relaxed)
+}
+
+/// Send a byte to the USB UART. We can't use the `Serial` object in
+/// `USB_UART` because we are often called with that lock held. The
+/// only other user of the UART0 transmit FIFO is that `Serial` object, which
+/// only runs in thread mode, so it's safe to poke the registers directly.
+fn uart_echo(ch: u8) {
+    let uart = unsafe { &*cpu::UART0::ptr() };
+    while uart.fr.read().txff().bit_is_set() {
+        // Spin until there's space in the FIFO
+    }
+    uart.dr.write(|w| unsafe { w.data().bits(ch) });
+}
+
+impl ConsoleInput {
     /// Is there a character in the input buffer?
     fn has_char(&mut self) -> bool {
         let attempt = self.input_read();
@@ -490,12 +634,14 @@ impl Context {
     ///
     /// Returns `None` if there's nothing waiting.
     fn input_read(&mut self) -> Option<Input> {
+        // Anything waiting for input is still alive
+        watchdog::feed();
         if self.buffered_char.is_some() {
             let mut x = None;
             core::mem::swap(&mut self.buffered_char, &mut x);
             return x;
         }
-        if let Ok(ch) = self.usb_uart.read() {
+        if let Some(ch) = serial::read(serial::Port::Usb) {
             // Got some serial input
             // Backspace key in screen seems to generate 0x7F (delete).
             // Map it to backspace (0x08)
@@ -506,7 +652,8 @@ impl Context {
                 Some(Input::Cp850(ch))
             }
         } else {
-            let key = if let Ok(ch) = self.keyboard_mouse_uart.read() {
+            let byte = self.keyboard_mouse_uart.read().ok();
+            let key = if let Some(ch) = byte {
                 // Got something in the buffer from the keyboard/mouse
                 // controller.
                 match self.keyboard.add_byte(ch) {
@@ -548,11 +695,18 @@ impl Context {
             }
         }
     }
+}
 
-    /// Write an 8-bit ASCII/CodePgae 850 character to the screen.
-    fn write_u8(&mut self, ch: u8) {
-        unsafe { FRAMEBUFFER.write_character(ch).unwrap() }
-    }
+/// The clock speeds we have configured.
+fn clocks() -> &'static hal::sysctl::Clocks {
+    CLOCKS.wait().unwrap()
+}
+
+/// Is anyone holding a lock which an application's Api calls need?
+fn api_locks_held() -> bool {
+    CONSOLE_INPUT.try_lock().is_none()
+        || RS232_UART.try_lock().is_none()
+        || JOYSTICK.try_lock().is_none()
 }
 
 /// Power on a peripheral and then reset it.
@@ -566,6 +720,8 @@ fn enable(p: sysctl::Domain, sc: &mut hal::sysctl::PowerControl) {
 /// routines are complete.
 #[entry]
 fn main() -> ! {
+    // So `debug mem` can tell how much stack we've used
+    memory::paint_stack();
     let p = hal::Peripherals::take().unwrap();
     let cp = hal::CorePeripherals::take().unwrap();
 
@@ -589,6 +745,18 @@ fn main() -> ! {
         nvic.set_priority(Interrupt::TIMER1B, 4 * 16);
     }
 
+    // Count where the CPU's time goes
+    let mut dcb = cp.DCB;
+    let mut dwt = cp.DWT;
+    cycles::init(&mut dcb, &mut dwt);
+
+    // Applications can only write to application RAM
+    let mut mpu = cp.MPU;
+    let mut scb = cp.SCB;
+    sandbox::init(&mut mpu, &mut scb);
+    // Faults in applications take us back to the shell
+    fault::init(&mut scb);
+
     enable(sysctl::Domain::Timer1, &mut sc.power_control);
     enable(sysctl::Domain::Timer2, &mut sc.power_control);
     enable(sysctl::Domain::Ssi0, &mut sc.power_control);
@@ -596,6 +764,10 @@ fn main() -> ! {
     enable(sysctl::Domain::Ssi2, &mut sc.power_control);
     enable(sysctl::Domain::Ssi3, &mut sc.power_control);
     enable(sysctl::Domain::Pwm0, &mut sc.power_control);
+    enable(sysctl::Domain::Watchdog0, &mut sc.power_control);
+    let watchdog0 = p.WATCHDOG0;
+    enable(sysctl::Domain::WideTimer0, &mut sc.power_control);
+    timers::init(&p.WTIMER0);
 
     let mut porta = p.GPIO_PORTA.split(&sc.power_control);
     let mut portb = p.GPIO_PORTB.split(&sc.power_control);
@@ -769,37 +941,36 @@ fn main() -> ! {
         pc_keyboard::HandleControl::MapLettersToUnicode,
     );
 
-    *GLOBAL_CONTEXT.lock() = Some(Context {
-        usb_uart,
-        keyboard_mouse_uart,
-        midi_uart,
-        rs232_uart,
+    *CONSOLE_INPUT.lock() = Some(ConsoleInput {
+        keyboard_mouse_uart: serial::BufferedSerial::new(
+            keyboard_mouse_uart,
+            serial::Port::Keyboard,
+        ),
         keyboard,
-        i2c_bus,
         buffered_char: None,
-        joystick: Joystick {
-            up: porte.pe2.into_pull_up_input(),
-            down: porte.pe3.into_pull_up_input(),
-            left: portd.pd6.into_pull_up_input(),
-            right: portd.pd7.unlock(&mut portd.control).into_pull_up_input(),
-            fire: portf.pf4.into_pull_up_input(),
-        },
-        cont: embedded_sdmmc::Controller::new(
-            embedded_sdmmc::SdMmcSpi::new(sdmmc_spi, sdmmc_cs),
-            &TIME_CONTEXT,
-        ),
-        clocks,
         seen_keypress: false,
     });
+    *STORAGE.lock() = Some(sdcard::Controller::new(
+        embedded_sdmmc::SdMmcSpi::new(sdmmc_spi, sdmmc_cs),
+        &TIME_CONTEXT,
+    ));
+    *USB_UART.lock() = Some(serial::BufferedSerial::new(usb_uart, serial::Port::Usb));
+    *RS232_UART.lock() = Some(serial::BufferedSerial::new(rs232_uart, serial::Port::Rs232));
+    *MIDI_UART.lock() = Some(serial::BufferedSerial::new(midi_uart, serial::Port::Midi));
+    *I2C_BUS.lock() = Some(i2c_bus);
+    *JOYSTICK.lock() = Some(Joystick {
+        up: porte.pe2.into_pull_up_input(),
+        down: porte.pe3.into_pull_up_input(),
+        left: portd.pd6.into_pull_up_input(),
+        right: portd.pd7.unlock(&mut portd.control).into_pull_up_input(),
+        fire: portf.pf4.into_pull_up_input(),
+    });
+    CLOCKS.call_once(|| clocks);
+
+    // From now on, the UARTs' interrupts collect what they receive
+    serial::init(&mut nvic);
 
-    while GLOBAL_CONTEXT
-        .lock()
-        .as_mut()
-        .unwrap()
-        .usb_uart
-        .read()
-        .is_ok()
-    {
+    while serial::read(serial::Port::Usb).is_some() {
         // Try again to empty the buffer
     }
 
@@ -816,39 +987,38 @@ fn main() -> ! {
     println!("* Copyright © theJPster 2019");
     println!("* https://github.com/thejpster/monotron");
 
-    // Calculates the size of the stack by looking at the size of the data and
-    // bss segments. Assumes bss comes after data.
-    let stack_space = unsafe {
-        extern "C" {
-            static __ebss: u32;
-            static __sdata: u32;
-        }
-        let ebss = &__ebss as *const u32 as usize;
-        let sdata = &__sdata as *const u32 as usize;
-        let total = ebss - sdata;
-        8192 - total
-    };
     println!(
         "{} bytes stack, {} bytes free.",
-        stack_space, APPLICATION_LEN
+        memory::stack_size(),
+        APPLICATION_LEN
     );
 
+    // Did we panic, or hang, last time?
+    crash::check_last_crash();
+    if watchdog::caused_reset() {
+        println!("\u{001B}RThe watchdog reset the system.\u{001B}W");
+    }
+    watchdog::init(&watchdog0);
+
     // Set up our menu system.
     let mut buffer = [0u8; 64];
     let mut r = menu::Runner::new(&ui::ROOT_MENU, &mut buffer, MenuContext);
+    let mut command_line = ui::CommandLine::new();
+    batch::autoexec(&mut command_line, &mut r);
 
     loop {
-        // Wait For Vertical Blanking Interval
+        // Wait For Vertical Blanking Interval (which also feeds the
+        // watchdog and polls the background tasks)
         api::wfvbi();
         // Grab the lock, convert to mutable reference and unwrap the
         // Option<>, then grab any new input
-        let input = GLOBAL_CONTEXT.lock().as_mut().unwrap().input_read();
+        let input = CONSOLE_INPUT.lock().as_mut().unwrap().input_read();
         // Now we do the match having released the lock
         match input {
             Some(Input::Cp850(octet)) => {
                 // Feed the menu system. It expects UTF-8 but it's happy with
                 // CP850 as long as all our commands are ASCII.
-                r.input_byte(octet);
+                command_line.input_byte(&mut r, octet);
             }
             Some(Input::Special(code)) => {
                 // Can't handle special chars yet.
@@ -977,6 +1147,7 @@ impl fb::Hardware for VideoHardware {
         // We're counting down in PWM mode, so start at the end
         // We start 16 pixels early
         let convert_to_clockset = |i: u32| -> u32 { (ratio * i) - 1 };
+        cycles::set_line_cycles(ratio * mode_info.width);
         self.h_timer
             .tailr
             .modify(|_, w| unsafe { w.bits(convert_to_clockset(mode_info.width)) });
@@ -1086,16 +1257,14 @@ impl fb::Hardware for VideoHardware {
 fn load_time_from_rtc() {
     use mcp794xx::Rtcc;
     // Grab the lock
-    let mut lock = GLOBAL_CONTEXT.lock();
+    let mut lock = I2C_BUS.lock();
     // Convert to mutable reference and unwrap the Option<>
-    let ctx = lock.as_mut().unwrap();
-    let bus = I2cBus(&mut ctx.i2c_bus);
+    let bus = I2cBus(lock.as_mut().unwrap());
     let mut rtc = mcp794xx::Mcp794xx::new_mcp7940n(bus);
     let dt = match rtc.get_datetime() {
         Ok(dt) => dt,
         Err(e) => {
             drop(rtc);
-            drop(ctx);
             drop(lock);
             println!("Error reading RTC: {:?}", e);
             return;
@@ -1110,6 +1279,7 @@ fn load_time_from_rtc() {
         seconds: dt.time().second() as u8,
     };
     TIME_CONTEXT.set_timestamp(timestamp);
+    api::set_clock_valid();
 }
 
 // ===========================================================================
@@ -1120,11 +1290,13 @@ interrupt!(TIMER2A, timer2a);
 
 /// Called just before Timer1B, which gives Timer1B lower interrupt jitter.
 fn timer2a() {
+    let span = cycles::Span::start();
     unsafe {
-        llvm_asm!("wfi");
+        core::arch::asm!("wfi");
         let timer = &*cpu::TIMER2::ptr();
         timer.icr.write(|w| w.caecint().set_bit());
     }
+    span.finish_interrupt(cycles::Bucket::Timer2A, 0);
 }
 
 interrupt!(TIMER1A, timer1a);
@@ -1133,6 +1305,7 @@ interrupt!(TIMER1A, timer1a);
 /// we mutate statics while the main thread might be using them at the same
 /// time (technically this is undefined behaviour).
 fn timer1a() {
+    let span = cycles::Span::start();
     unsafe {
         let pwm = &*cpu::PWM0::ptr();
         let ssi_r = &*cpu::SSI1::ptr();
@@ -1152,13 +1325,29 @@ fn timer1a() {
         ssi_r.dr.write(|w| w.data().bits(0));
         ssi_g.dr.write(|w| w.data().bits(0));
         // Run the draw routine
+        let draw = cycles::Span::start();
         FRAMEBUFFER.isr_sol();
+        draw.finish(cycles::Bucket::IsrSol);
+        // Once a frame, look for Ctrl-Break, prod the application's
+        // vertical-blank hook and add up the cycle counts
+        static mut LAST_FRAME: u32 = 0;
+        let frame = FRAMEBUFFER.frame() as u32;
+        if frame != LAST_FRAME {
+            LAST_FRAME = frame;
+            abort::on_frame();
+            vblank::on_frame();
+            cycles::on_frame();
+        }
+        cycles::on_line();
         // Run the audio routine
+        let synth = cycles::Span::start();
         NEXT_SAMPLE = G_SYNTH.next().into();
+        synth.finish(cycles::Bucket::Synth);
         // Clear timer A interrupt
         let timer = &*cpu::TIMER1::ptr();
         timer.icr.write(|w| w.caecint().set_bit());
     }
+    span.finish_interrupt(cycles::Bucket::Timer1A, 0);
 }
 
 interrupt!(TIMER1B, timer1b);
@@ -1169,7 +1358,7 @@ fn timer1b() {
     // gets the colour video lined up, as we preload the red channel with 0x00
     // 0x00 and the green channel with 0x00.
     unsafe {
-        llvm_asm!(
+        core::arch::asm!(
             "movs    r0, #132;
             movs    r1, #1;
             movt    r0, #16914;
@@ -1238,21 +1427,22 @@ fn timer1b() {
             nop;
             nop;
             str r1, [r0, r3];
-            "
-            :
-            :
-            : "r0" "r1" "r2" "r3"
-            : "volatile");
+            ",
+            out("r0") _,
+            out("r1") _,
+            out("r2") _,
+            out("r3") _,
+            options(nostack)
+        );
+    }
+    // Start timing after the pixels are lined up, so we don't upset them
+    let span = cycles::Span::start();
+    unsafe {
         // Clear timer B interrupt
         let timer = &*cpu::TIMER1::ptr();
         timer.icr.write(|w| w.cbecint().set_bit());
     }
-}
-
-#[exception]
-/// The hard fault handler
-fn HardFault(ef: &cortex_m_rt::ExceptionFrame) -> ! {
-    panic!("HardFault at {:#?}", ef);
+    span.finish_interrupt(cycles::Bucket::Timer1B, cycles::TIMER1B_UNTIMED_CYCLES);
 }
 
 #[exception]
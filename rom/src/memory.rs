@@ -0,0 +1,157 @@
+//! # Memory usage
+//!
+//! The OS gets 8 KiB of RAM, shared between `.data`, `.bss`, `.uninit` and
+//! the stack, which grows down from the top. At boot we paint the unused
+//! part of the stack with `STACK_PAINT`, so later we can see how deep it has
+//! ever been by looking for the first word which has been overwritten.
+//!
+//! `debug mem` prints all of this, plus everything else which might be
+//! holding on to resources.
+
+use crate::{print, println, APPLICATION_LEN};
+
+// ===========================================================================
+// Constants
+// ===========================================================================
+
+/// What we fill the unused stack with.
+const STACK_PAINT: u32 = 0xCCCC_CCCC;
+
+/// How much of the stack below the current stack pointer to leave alone
+/// when painting, in case we're wrong about how much we're using.
+const PAINT_MARGIN: usize = 64;
+
+// ===========================================================================
+// Types
+// ===========================================================================
+
+/// Where the linker put things in the OS RAM.
+struct Layout {
+    data: usize,
+    bss: usize,
+    uninit: usize,
+    /// The lowest address the stack can reach
+    stack_bottom: usize,
+    /// The address just above the stack
+    stack_top: usize,
+}
+
+// ===========================================================================
+// Functions and Impls
+// ===========================================================================
+
+/// Fill the stack, from the bottom up to just below where we are now, with
+/// `STACK_PAINT`. Called as early as possible at boot.
+pub(crate) fn paint_stack() {
+    let layout = Layout::get();
+    let sp = cortex_m::register::msp::read() as usize;
+    let mut p = layout.stack_bottom as *mut u32;
+    let end = (sp - PAINT_MARGIN) as *mut u32;
+    while p < end {
+        unsafe {
+            p.write_volatile(STACK_PAINT);
+            p = p.add(1);
+        }
+    }
+}
+
+/// How many bytes of stack the OS has, in total.
+pub(crate) fn stack_size() -> usize {
+    let layout = Layout::get();
+    layout.stack_top - layout.stack_bottom
+}
+
+/// The most stack we've used since boot, in bytes.
+fn stack_high_water() -> usize {
+    let layout = Layout::get();
+    let mut p = layout.stack_bottom as *const u32;
+    let top = layout.stack_top as *const u32;
+    while p < top && unsafe { p.read_volatile() } == STACK_PAINT {
+        p = unsafe { p.add(1) };
+    }
+    layout.stack_top - p as usize
+}
+
+/// Print everything we know about memory usage. Handles `debug mem`.
+pub(crate) fn report() {
+    let layout = Layout::get();
+    let stack_size = stack_size();
+    let high_water = stack_high_water();
+    println!(".data:  {:5} bytes", layout.data);
+    println!(".bss:   {:5} bytes", layout.bss);
+    println!(".uninit:{:5} bytes", layout.uninit);
+    println!(
+        "Stack:  {:5} bytes, {} used at most ({}%)",
+        stack_size,
+        high_water,
+        (high_water * 100) / stack_size
+    );
+    if high_water >= stack_size - PAINT_MARGIN {
+        println!("\u{001B}RThe stack has probably overflowed!\u{001B}W");
+    }
+    let used = crate::ui::loaded_len();
+    println!(
+        "App RAM:{:5} bytes loaded, {} free",
+        used,
+        APPLICATION_LEN - used
+    );
+    // Don't print with a lock held - the output might be going to a file
+    let held = [
+        ("input", crate::CONSOLE_INPUT.try_lock().is_none()),
+        ("storage", crate::STORAGE.try_lock().is_none()),
+        ("usb", crate::USB_UART.try_lock().is_none()),
+        ("rs232", crate::RS232_UART.try_lock().is_none()),
+        ("midi", crate::MIDI_UART.try_lock().is_none()),
+        ("i2c", crate::I2C_BUS.try_lock().is_none()),
+        ("joystick", crate::JOYSTICK.try_lock().is_none()),
+    ];
+    print!("Locks held:");
+    for &(name, is_held) in held.iter() {
+        if is_held {
+            print!(" {}", name);
+        }
+    }
+    if held.iter().all(|&(_, is_held)| !is_held) {
+        print!(" none");
+    }
+    println!();
+    let open_counts = crate::STORAGE
+        .try_lock()
+        .map(|mut lock| lock.as_mut().unwrap().open_counts());
+    match open_counts {
+        Some((dirs, files)) => println!("SD card: {} dir(s), {} file(s) open", dirs, files),
+        None => println!("SD card: busy"),
+    }
+}
+
+impl Layout {
+    /// Ask the linker.
+    fn get() -> Layout {
+        extern "C" {
+            static __sdata: u32;
+            static __edata: u32;
+            static __sbss: u32;
+            static __ebss: u32;
+            static __sheap: u32;
+            static _stack_start: u32;
+        }
+        unsafe {
+            let sdata = &__sdata as *const u32 as usize;
+            let edata = &__edata as *const u32 as usize;
+            let sbss = &__sbss as *const u32 as usize;
+            let ebss = &__ebss as *const u32 as usize;
+            let sheap = &__sheap as *const u32 as usize;
+            let stack_start = &_stack_start as *const u32 as usize;
+            Layout {
+                data: edata - sdata,
+                bss: ebss - sbss,
+                // `.uninit` goes between `.bss` and the (unused) heap
+                uninit: sheap - ebss,
+                stack_bottom: sheap,
+                stack_top: stack_start,
+            }
+        }
+    }
+}
+
+// End of file
@@ -0,0 +1,434 @@
+//! # Output redirection
+//!
+//! Everything printed with `print!` and `println!`, by the `menu` runner or
+//! by an application goes through the `Console`. Normally that means the
+//! screen (and the USB UART, after `remote on`), but for the length of one
+//! command line it can be sent somewhere else instead:
+//!
+//! * `> FILE` and `>> FILE` - write (or append) to a file on the SD card
+//! * `> COM1` (or `> AUX`) - the USB UART
+//! * `> COM2` - the RS-232 UART
+//! * `> NUL` - nowhere
+//! * `| more` - the screen, a page at a time
+//!
+//! Output for a file is collected in a buffer, and written out each time the
+//! buffer fills up, as long as the SD card is free. A command which prints
+//! more than the buffer holds while it is using the SD card itself loses the
+//! excess, and that's reported as an error, because the file is incomplete.
+
+use crate::serial::{self, Port};
+use crate::ui::ShortName;
+use crate::{cpu, fb, println, Console, Storage, STORAGE};
+
+// ===========================================================================
+// Constants
+// ===========================================================================
+
+/// How much output we can hold for a file while the SD card is busy.
+const FILE_BUFFER_LEN: usize = 1024;
+
+/// How many lines the pager shows before it waits for a key. This is one
+/// less than the height of the screen, to leave room for the prompt.
+const PAGE_LINES: usize = 35;
+
+/// The width of the screen, in characters.
+const SCREEN_COLS: usize = 48;
+
+/// The escape character, which the screen uses to change colour.
+const ESC: u8 = 0x1B;
+
+/// The PS/2 scan code which means 'the next key is being released'.
+const PS2_RELEASE: u8 = 0xF0;
+
+/// PS/2 scan codes for Escape and `Q`, either of which stop the pager.
+const PS2_QUIT_KEYS: [u8; 2] = [0x76, 0x15];
+
+/// How long (in 100us steps) to wait for a key to be released.
+const RELEASE_TIMEOUT: u32 = 10_000;
+
+// ===========================================================================
+// Types
+// ===========================================================================
+
+/// Where the output from a command line should go.
+#[derive(Debug, Copy, Clone)]
+pub(crate) enum Target {
+    /// A file in the root directory of the SD card
+    File {
+        /// The file's name
+        name: ShortName,
+        /// Add to the end of the file, rather than replacing it
+        append: bool,
+    },
+    /// The USB UART
+    UsbUart,
+    /// The RS-232 UART
+    Rs232Uart,
+    /// Throw it all away
+    Null,
+    /// The screen, a page at a time
+    Pager,
+}
+
+/// Somewhere output is currently going.
+enum Sink {
+    File {
+        name: ShortName,
+        buffer: [u8; FILE_BUFFER_LEN],
+        used: usize,
+        /// Bytes which didn't fit in the buffer
+        lost: usize,
+        /// Set if writing to the file failed
+        failed: bool,
+        /// Set if the last byte was an `ESC`
+        in_escape: bool,
+    },
+    UsbUart,
+    Rs232Uart,
+    Null,
+    Pager {
+        /// Lines shown since we last waited
+        lines: usize,
+        /// Where we are on the current line
+        col: usize,
+        /// Set if the last byte was an `ESC`
+        in_escape: bool,
+        /// Set if the user has had enough
+        quit: bool,
+    },
+}
+
+// ===========================================================================
+// Static Variables
+// ===========================================================================
+
+/// Where output is going, if not to the console.
+static OUTPUT: spin::Mutex<Option<Sink>> = spin::Mutex::new(None);
+
+// ===========================================================================
+// Functions and Impls
+// ===========================================================================
+
+/// Split a command line into the command, and where its output should go.
+/// Returns an error message if the redirection doesn't make sense.
+pub(crate) fn parse(line: &str) -> Result<(&str, Option<Target>), &'static str> {
+    let idx = match line.find(|c| c == '>' || c == '|') {
+        Some(idx) => idx,
+        None => return Ok((line, None)),
+    };
+    let command = line[0..idx].trim();
+    if command.is_empty() {
+        return Err("Nothing to redirect.");
+    }
+    let rest = &line[idx..];
+    let (append, rest) = if rest.starts_with(">>") {
+        (true, &rest[2..])
+    } else {
+        (false, &rest[1..])
+    };
+    let mut words = rest.split_whitespace();
+    let name = words.next().ok_or("Redirect to where?")?;
+    if words.next().is_some() || name.contains(|c| c == '>' || c == '|') {
+        return Err("Only one redirection is allowed.");
+    }
+    if line[idx..].starts_with('|') {
+        return if name.eq_ignore_ascii_case("more") {
+            Ok((command, Some(Target::Pager)))
+        } else {
+            Err("Only `| more` is supported.")
+        };
+    }
+    let target = if name.eq_ignore_ascii_case("NUL") {
+        Target::Null
+    } else if name.eq_ignore_ascii_case("COM1") || name.eq_ignore_ascii_case("AUX") {
+        Target::UsbUart
+    } else if name.eq_ignore_ascii_case("COM2") {
+        Target::Rs232Uart
+    } else if name.eq_ignore_ascii_case("PRN") || name.eq_ignore_ascii_case("LPT1") {
+        // The I/O controller doesn't drive the printer port yet
+        return Err("The printer port isn't supported yet.");
+    } else if name.eq_ignore_ascii_case("CON") {
+        return Ok((command, None));
+    } else {
+        Target::File {
+            name: ShortName::new(name).ok_or("Bad file name.")?,
+            append,
+        }
+    };
+    Ok((command, Some(target)))
+}
+
+/// Start sending output to `target`. For a file, this creates (or
+/// truncates) it. Must not be called with the `STORAGE` lock held.
+pub(crate) fn start(target: Target) -> Result<(), &'static str> {
+    if OUTPUT.lock().is_some() {
+        return Err("Output is already redirected.");
+    }
+    let sink = match target {
+        Target::File { name, append } => {
+            let mode = if append {
+                embedded_sdmmc::Mode::ReadWriteCreateOrAppend
+            } else {
+                embedded_sdmmc::Mode::ReadWriteCreateOrTruncate
+            };
+            write_file(STORAGE.lock().as_mut().unwrap(), name.as_str(), mode, &[])
+                .map_err(|_| "Can't open the file.")?;
+            Sink::File {
+                name,
+                buffer: [0u8; FILE_BUFFER_LEN],
+                used: 0,
+                lost: 0,
+                failed: false,
+                in_escape: false,
+            }
+        }
+        Target::UsbUart => Sink::UsbUart,
+        Target::Rs232Uart => Sink::Rs232Uart,
+        Target::Null => Sink::Null,
+        Target::Pager => Sink::Pager {
+            lines: 0,
+            col: 0,
+            in_escape: false,
+            quit: false,
+        },
+    };
+    *OUTPUT.lock() = Some(sink);
+    Ok(())
+}
+
+/// Stop redirecting output, writing out anything left in the buffer. Must
+/// not be called with the `STORAGE` lock held.
+pub(crate) fn finish() {
+    let sink = OUTPUT.lock().take();
+    if let Some(Sink::File {
+        name,
+        buffer,
+        used,
+        lost,
+        failed,
+        ..
+    }) = sink
+    {
+        let failed = failed
+            || write_file(
+                STORAGE.lock().as_mut().unwrap(),
+                name.as_str(),
+                embedded_sdmmc::Mode::ReadWriteCreateOrAppend,
+                &buffer[0..used],
+            )
+            .is_err();
+        if failed {
+            println!("Error: Couldn't write to {}.", name.as_str());
+        } else if lost != 0 {
+            println!(
+                "Error: {} is incomplete - {} bytes were lost while the SD card was busy.",
+                name.as_str(),
+                lost
+            );
+        }
+    }
+}
+
+/// Redirect a string, if we are redirecting. Returns `false` if it should go
+/// to the console as normal.
+pub(crate) fn write_str(s: &str) -> bool {
+    let mut lock = match OUTPUT.try_lock() {
+        Some(lock) => lock,
+        None => return false,
+    };
+    match lock.as_mut() {
+        Some(sink) => {
+            for ch in s.chars() {
+                let byte = if ch.is_ascii() {
+                    ch as u8
+                } else {
+                    fb::Char::map_char(ch) as u8
+                };
+                sink.write_u8(byte);
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+/// Redirect a Code Page 850 byte, if we are redirecting. Returns `false` if
+/// it should go to the console as normal.
+pub(crate) fn write_u8(byte: u8) -> bool {
+    let mut lock = match OUTPUT.try_lock() {
+        Some(lock) => lock,
+        None => return false,
+    };
+    match lock.as_mut() {
+        Some(sink) => {
+            sink.write_u8(byte);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Show a byte on the screen, stopping every page to wait for a key.
+fn page(sink: &mut Sink, byte: u8) {
+    if let Sink::Pager {
+        lines,
+        col,
+        in_escape,
+        quit,
+    } = sink
+    {
+        if *quit {
+            return;
+        }
+        Console.screen_u8(byte);
+        if *in_escape {
+            *in_escape = false;
+            return;
+        }
+        match byte {
+            ESC => *in_escape = true,
+            b'\n' => {
+                *lines += 1;
+                *col = 0;
+            }
+            b'\r' => *col = 0,
+            _ => {
+                *col += 1;
+                if *col == SCREEN_COLS {
+                    *lines += 1;
+                    *col = 0;
+                }
+            }
+        }
+        if *lines == PAGE_LINES {
+            *lines = 0;
+            let _ = Console.screen_str("-- More --");
+            *quit = wait_for_key();
+            let _ = Console.screen_str("\r          \r");
+        }
+    }
+}
+
+impl Sink {
+    /// Send a Code Page 850 byte to wherever it's going.
+    fn write_u8(&mut self, byte: u8) {
+        match self {
+            Sink::File {
+                name,
+                buffer,
+                used,
+                lost,
+                failed,
+                in_escape,
+            } => {
+                // Colour changes mean nothing in a file
+                if *in_escape {
+                    *in_escape = false;
+                    return;
+                }
+                if byte == ESC {
+                    *in_escape = true;
+                    return;
+                }
+                if *used == buffer.len() {
+                    // Write it out, if the SD card isn't busy
+                    if let Some(mut lock) = STORAGE.try_lock() {
+                        if write_file(
+                            lock.as_mut().unwrap(),
+                            name.as_str(),
+                            embedded_sdmmc::Mode::ReadWriteCreateOrAppend,
+                            &buffer[..],
+                        )
+                        .is_err()
+                        {
+                            *failed = true;
+                        }
+                        *used = 0;
+                    }
+                }
+                if *used == buffer.len() {
+                    *lost += 1;
+                } else {
+                    buffer[*used] = byte;
+                    *used += 1;
+                }
+            }
+            Sink::UsbUart => uart_send(unsafe { &*cpu::UART0::ptr() }, byte),
+            Sink::Rs232Uart => uart_send(unsafe { &*cpu::UART1::ptr() }, byte),
+            Sink::Null => {}
+            Sink::Pager { .. } => page(self, byte),
+        }
+    }
+}
+
+/// Open a file in the root directory with the given mode, write `data` to
+/// it, and close it again.
+pub(crate) fn write_file(
+    cont: &mut Storage,
+    name: &str,
+    mode: embedded_sdmmc::Mode,
+    data: &[u8],
+) -> Result<(), embedded_sdmmc::Error<embedded_sdmmc::SdMmcError>> {
+    let mut volume = cont.get_volume(embedded_sdmmc::VolumeIdx(0))?;
+    let dir = cont.open_root_dir(&volume)?;
+    let result = match cont.open_file_in_dir(&mut volume, &dir, name, mode) {
+        Ok(mut file) => {
+            let result = if data.is_empty() {
+                Ok(())
+            } else {
+                cont.write(&mut volume, &mut file, data).map(|_| ())
+            };
+            cont.close_file(&volume, file).and(result)
+        }
+        Err(e) => Err(e),
+    };
+    cont.close_dir(&volume, dir);
+    result
+}
+
+/// Send a byte to a UART, with a carriage return before every new-line. Like
+/// `uart_echo`, we poke the registers directly because we might be called
+/// with the UART's lock held.
+fn uart_send(uart: &cpu::uart0::RegisterBlock, byte: u8) {
+    if byte == b'\n' {
+        uart_send(uart, b'\r');
+    }
+    while uart.fr.read().txff().bit_is_set() {
+        // Spin until there's space in the FIFO
+    }
+    uart.dr.write(|w| unsafe { w.data().bits(byte) });
+}
+
+/// Wait for a key on the USB UART or the keyboard. Returns `true` if it was
+/// Escape or Q.
+///
+/// We might be called with the `CONSOLE_INPUT` lock held, so we take the
+/// bytes straight from the UARTs' receive rings. To keep the keyboard
+/// decoder happy, we swallow the keyboard's scan codes until the key is
+/// released again.
+fn wait_for_key() -> bool {
+    loop {
+        crate::watchdog::feed();
+        if let Some(byte) = serial::read(Port::Usb) {
+            return byte == ESC || byte == b'q' || byte == b'Q';
+        }
+        if let Some(code) = serial::read(Port::Keyboard) {
+            let mut quit = PS2_QUIT_KEYS.contains(&code);
+            let mut releasing = false;
+            for _ in 0..RELEASE_TIMEOUT {
+                if let Some(code) = serial::read(Port::Keyboard) {
+                    if releasing {
+                        break;
+                    }
+                    releasing = code == PS2_RELEASE;
+                    quit |= PS2_QUIT_KEYS.contains(&code);
+                } else {
+                    cortex_m::asm::delay(8_000);
+                }
+            }
+            return quit;
+        }
+    }
+}
+
+// End of file
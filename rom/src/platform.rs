@@ -0,0 +1,212 @@
+//! # The Monotron, as a `monotron_shell::Platform`
+//!
+//! The commands and Api functions in `monotron-shell` don't know anything
+//! about the Tiva-C. `Rom` gives them the screen, the keyboard, the SD card,
+//! the RS-232 UART, the synthesiser and the clock.
+//!
+//! The Api calls some of these methods from inside an application, so
+//! anything an application could be in the middle of using (the keyboard
+//! and the RS-232 UART) is taken with `try_lock`, and reported as busy if
+//! we can't have it. The SD card is only used by shell commands, which
+//! follow the lock rules in `main`.
+
+use crate::hal::time::U32Ext;
+use crate::ui::{self, FoundFile, ShortName};
+use crate::{api, Input, Storage, CONSOLE_INPUT, G_SYNTH, RS232_UART, STORAGE, TIME_CONTEXT};
+use core::fmt::Write as _;
+use monotron_api::{Error, Timestamp};
+use monotron_shell::{FileInfo, Key, Waveform};
+
+// ===========================================================================
+// Types
+// ===========================================================================
+
+/// The machine we're running on.
+pub(crate) struct Rom;
+
+// ===========================================================================
+// Functions and Impls
+// ===========================================================================
+
+/// Convert an SD card error into something the Api can report.
+fn api_error(e: embedded_sdmmc::Error<embedded_sdmmc::SdMmcError>) -> Error {
+    match e {
+        embedded_sdmmc::Error::FileNotFound | embedded_sdmmc::Error::FilenameError(_) => {
+            Error::FileNotFound
+        }
+        embedded_sdmmc::Error::OpenedDirAsFile | embedded_sdmmc::Error::Unsupported => {
+            Error::NotSupported
+        }
+        _ => Error::IOError,
+    }
+}
+
+/// Convert a timestamp from the SD card into one the Api uses.
+fn api_timestamp(t: &embedded_sdmmc::Timestamp) -> Timestamp {
+    Timestamp {
+        year_from_1970: t.year_since_1970,
+        month: t.zero_indexed_month + 1,
+        days: t.zero_indexed_day + 1,
+        hours: t.hours,
+        minutes: t.minutes,
+        seconds: t.seconds,
+    }
+}
+
+impl core::fmt::Write for Rom {
+    fn write_str(&mut self, string: &str) -> core::fmt::Result {
+        crate::Console.write_str(string)
+    }
+}
+
+impl monotron_shell::Console for Rom {
+    fn write_u8(&mut self, ch: u8) {
+        crate::Console.write_u8(ch);
+    }
+}
+
+impl monotron_shell::Input for Rom {
+    fn read_key(&mut self) -> Option<Key> {
+        let input = CONSOLE_INPUT
+            .try_lock()
+            .and_then(|mut lock| lock.as_mut().and_then(|c| c.input_read()));
+        match input? {
+            Input::Cp850(ch) => Some(Key::Cp850(ch)),
+            Input::Special(_) => Some(Key::Special),
+        }
+    }
+}
+
+impl monotron_shell::Storage for Rom {
+    fn list_root(&mut self, f: &mut dyn FnMut(&FileInfo)) -> Result<(), Error> {
+        let mut list = |cont: &mut Storage| -> Result<(), embedded_sdmmc::Error<_>> {
+            let volume = cont.get_volume(embedded_sdmmc::VolumeIdx(0))?;
+            let dir = cont.open_root_dir(&volume)?;
+            let result = cont.iterate_dir(&volume, &dir, |x| {
+                if !x.attributes.is_hidden() && !x.attributes.is_volume() {
+                    let mut name = ShortName::new("").unwrap();
+                    let _ = write!(name, "{}", x.name);
+                    f(&FileInfo {
+                        name: name.as_str(),
+                        size: x.size,
+                        mtime: api_timestamp(&x.mtime),
+                        is_dir: x.attributes.is_directory(),
+                    });
+                }
+            });
+            cont.close_dir(&volume, dir);
+            result
+        };
+        list(STORAGE.lock().as_mut().unwrap()).map_err(api_error)
+    }
+
+    fn read_file(&mut self, name: &str, offset: u32, buffer: &mut [u8]) -> Result<usize, Error> {
+        let found = FoundFile::in_root(name).ok_or(Error::FileNotFound)?;
+        ui::with_found_file(&found, |cont, volume, file| {
+            if offset >= file.length() {
+                return Ok(0);
+            }
+            let _ = file.seek_from_start(offset);
+            cont.read(volume, file, buffer)
+        })
+        .map_err(api_error)
+    }
+}
+
+impl monotron_shell::Serial for Rom {
+    fn set_bitrate(&mut self, bps: u32) -> Result<(), Error> {
+        let mut lock = RS232_UART.try_lock().ok_or(Error::IOError)?;
+        let uart = lock.as_mut().unwrap();
+        uart.change_baud_rate(bps.bps(), crate::clocks());
+        Ok(())
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Error> {
+        use embedded_hal::serial::Read;
+        let mut lock = RS232_UART.try_lock().ok_or(Error::IOError)?;
+        let uart = lock.as_mut().unwrap();
+        let mut read = 0;
+        while read < buffer.len() {
+            match uart.read() {
+                Ok(ch) => {
+                    buffer[read] = ch;
+                    read += 1;
+                }
+                Err(nb::Error::WouldBlock) => {
+                    break;
+                }
+                Err(_e) => {
+                    return Err(Error::IOError);
+                }
+            }
+        }
+        Ok(read)
+    }
+
+    fn write(&mut self, buffer: &[u8]) -> Result<usize, Error> {
+        use embedded_hal::serial::Write;
+        let mut lock = RS232_UART.try_lock().ok_or(Error::IOError)?;
+        let uart = lock.as_mut().unwrap();
+        let mut written = 0;
+        while written < buffer.len() {
+            match uart.write(buffer[written]) {
+                Ok(_) => {
+                    written += 1;
+                }
+                Err(nb::Error::WouldBlock) => {
+                    break;
+                }
+                Err(_e) => {
+                    return Err(Error::IOError);
+                }
+            }
+        }
+        Ok(written)
+    }
+}
+
+impl monotron_shell::Audio for Rom {
+    fn play(&mut self, channel: u8, centi_hertz: u32, volume: u8, waveform: Waveform) {
+        use monotron_synth::{Channel, Frequency};
+        let channel = match channel {
+            0 => Channel::Channel0,
+            1 => Channel::Channel1,
+            _ => Channel::Channel2,
+        };
+        let waveform = match waveform {
+            Waveform::Square => monotron_synth::Waveform::Square,
+            Waveform::Sine => monotron_synth::Waveform::Sine,
+            Waveform::Sawtooth => monotron_synth::Waveform::Sawtooth,
+            Waveform::Noise => monotron_synth::Waveform::Noise,
+        };
+        unsafe {
+            G_SYNTH.play(
+                channel,
+                Frequency::from_centi_hertz(centi_hertz),
+                volume,
+                waveform,
+            );
+        }
+    }
+}
+
+impl monotron_shell::Time for Rom {
+    fn now(&mut self) -> Timestamp {
+        TIME_CONTEXT.get_timestamp()
+    }
+
+    fn set_now(&mut self, timestamp: Timestamp) {
+        TIME_CONTEXT.set_timestamp(timestamp);
+    }
+
+    fn wait_frame(&mut self) {
+        api::wfvbi();
+    }
+
+    fn idle(&mut self) {
+        crate::tasks::poll();
+        cortex_m::asm::wfi();
+    }
+}
+
+// End of file
@@ -0,0 +1,73 @@
+//! # Ring buffers
+//!
+//! A byte queue with one writer (an interrupt handler) and one reader
+//! (thread mode), which doesn't need a lock.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// ===========================================================================
+// Constants
+// ===========================================================================
+
+/// The size of a ring - about 11 ms of data at 115,200 bps. We always leave
+/// one slot empty, so we can tell full from empty.
+const RING_LEN: usize = 128;
+
+// ===========================================================================
+// Types
+// ===========================================================================
+
+/// A byte queue. Only one context may `push` and only one may `pop`.
+pub(crate) struct Ring {
+    buffer: UnsafeCell<[u8; RING_LEN]>,
+    /// Where the next byte goes. Only `push` changes this.
+    head: AtomicUsize,
+    /// Where the next byte comes from. Only `pop` changes this.
+    tail: AtomicUsize,
+}
+
+// ===========================================================================
+// Functions and Impls
+// ===========================================================================
+
+/// The writer only touches the slot at `head`, and the reader only touches
+/// the slot at `tail`, and they never point at the same slot unless the
+/// ring is empty.
+unsafe impl Sync for Ring {}
+
+impl Ring {
+    /// Make an empty ring.
+    pub(crate) const fn new() -> Ring {
+        Ring {
+            buffer: UnsafeCell::new([0u8; RING_LEN]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Add a byte. Returns `false` (and drops the byte) if the ring is full.
+    pub(crate) fn push(&self, byte: u8) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        let next = (head + 1) % RING_LEN;
+        if next == self.tail.load(Ordering::Acquire) {
+            return false;
+        }
+        unsafe { (*self.buffer.get())[head] = byte };
+        self.head.store(next, Ordering::Release);
+        true
+    }
+
+    /// Take the oldest byte, if there is one.
+    pub(crate) fn pop(&self) -> Option<u8> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail == self.head.load(Ordering::Acquire) {
+            return None;
+        }
+        let byte = unsafe { (*self.buffer.get())[tail] };
+        self.tail.store((tail + 1) % RING_LEN, Ordering::Release);
+        Some(byte)
+    }
+}
+
+// End of file
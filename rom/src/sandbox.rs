@@ -0,0 +1,1037 @@
+//! # Application sandbox
+//!
+//! Applications run unprivileged, on their own stack at the top of
+//! application RAM, with the Memory Protection Unit set up so they can only
+//! write to `0x2000_2000..0x2000_8000`. They can still read (but not write)
+//! the OS RAM below that, and read and execute the flash.
+//!
+//! The `Api` table we give them is full of trampolines. Each one packs its
+//! arguments into a `Call` on the application's stack and raises an `SVC`.
+//! The `SVCall` handler checks the `Call` is one we know, and any pointers
+//! the application gave us (including where the results go), then makes the
+//! real call (in `api`) with privileges. It runs at the lowest priority, so
+//! the video interrupts carry on as normal.
+//!
+//! An application can raise the `SVC` itself, with whatever it likes in the
+//! `Call`, so nothing in a `Call` is trusted: there are no references or
+//! enums in it which could hold an invalid value, just integers and raw
+//! pointers.
+//!
+//! When the application returns (or does something it shouldn't, or `abort`
+//! pends `PendSV` because someone pressed Ctrl-Break), the handler unwinds
+//! straight back to the OS stack as it was in `run`.
+//!
+//! `PendSV` can't run in the middle of an Api call, so the calls which wait
+//! (`readc`, `wfvbi` and `sleep_ms`) give up whenever it's pending. Their
+//! trampolines make the call again (in thread mode, so `PendSV` gets its
+//! turn in between) until it's done.
+//!
+//! `PendSV` also calls the application's vertical-blank hook (see `vblank`).
+//! It leaves the application's exception frame where it is, and returns into
+//! the hook through a new frame pushed below it. The hook returns to
+//! `sandbox_hook_return`, which raises an `SVC` so we can drop that frame and
+//! return to wherever the application was interrupted.
+
+use crate::api::{self, Api, ApiHeader};
+use crate::{abort, fault, vblank};
+use crate::{APPLICATION_LEN, APPLICATION_START_ADDR, OS_RAM_LEN, TOTAL_RAM_LEN};
+use core::sync::atomic::{AtomicBool, Ordering};
+use monotron_api::{
+    Args, BorrowedString, Capabilities, CycleBudget, DirEntry, EmptyResult, Error, Event, Handle,
+    HandleResult, Offset, OpenMode, ServiceId, SizeResult, TimerId, TimerMode, TimerResult,
+    Timestamp, VblankHook,
+};
+
+// ===========================================================================
+// Constants
+// ===========================================================================
+
+/// `SVC` number for an Api call. `r0` points at the `Call`.
+const SVC_CALL: u8 = 0;
+
+/// `SVC` number for 'the application has returned'. `r0` is its result.
+/// `monotron-app` uses it to exit early (e.g. on panic), so it can't change.
+const SVC_EXIT: u8 = 1;
+
+/// `SVC` number for 'the vertical-blank hook has returned'.
+const SVC_HOOK_DONE: u8 = 2;
+
+/// The bits in `EXC_RETURN` which mean 'came from thread mode, using the
+/// process stack'. Only applications run like that.
+const EXC_RETURN_THREAD_PSP: u32 = 0b1101;
+
+/// `EXC_RETURN` for 'return to thread mode, using the process stack, with
+/// a basic (non-FP) frame'.
+const EXC_RETURN_THREAD_PSP_BASIC: u32 = 0xFFFF_FFFD;
+
+/// How many bytes of font data `change_font` reads.
+const FONT_LEN: usize = 4096;
+
+/// The size of a basic exception frame (`r0-r3`, `r12`, `lr`, `pc`, `xPSR`).
+const BASIC_FRAME_LEN: usize = 8 * 4;
+
+/// xPSR with just the Thumb bit set.
+const XPSR_THUMB: u32 = 1 << 24;
+
+/// The Floating-point Context Control Register.
+const FPCCR: *mut u32 = 0xE000_EF34 as *mut u32;
+
+/// FPCCR: there is FP state waiting to be lazily stacked.
+const FPCCR_LSPACT: u32 = 1 << 0;
+
+/// The end of the flash, which applications may read and execute.
+const FLASH_END: usize = 0x0004_0000;
+
+/// The start of the RAM, which applications may read.
+const RAM_START: usize = 0x2000_0000;
+
+/// The end of the RAM.
+const RAM_END: usize = RAM_START + TOTAL_RAM_LEN;
+
+/// The flash: read-only, executable, for everyone.
+const REGION_FLASH: u8 = 0;
+
+/// All the RAM: read/write for the OS, read-only for applications, never
+/// executable.
+const REGION_RAM: u8 = 1;
+
+/// The application RAM (all of the RAM, but with the first two 4 KiB
+/// sub-regions disabled): read/write and executable for everyone.
+const REGION_APP_RAM: u8 = 2;
+
+/// MPU_CTRL: enable the MPU, using the default memory map for privileged
+/// accesses outside the regions we set up.
+const MPU_CTRL_ENABLE_PRIVDEFENA: u32 = 0b101;
+
+/// MPU_RASR: don't execute from this region.
+const RASR_XN: u32 = 1 << 28;
+
+/// MPU_RASR: privileged read-only, unprivileged read-only.
+const RASR_AP_RO_RO: u32 = 0b110 << 24;
+
+/// MPU_RASR: privileged read/write, unprivileged read-only.
+const RASR_AP_RW_RO: u32 = 0b010 << 24;
+
+/// MPU_RASR: privileged read/write, unprivileged read/write.
+const RASR_AP_RW_RW: u32 = 0b011 << 24;
+
+/// MPU_RASR: normal memory, write-through, no write allocate (flash).
+const RASR_FLASH_ATTRS: u32 = 0b000_010 << 16;
+
+/// MPU_RASR: normal memory, shareable, write-through (SRAM).
+const RASR_SRAM_ATTRS: u32 = 0b000_110 << 16;
+
+/// MPU_RASR: the region is enabled.
+const RASR_ENABLE: u32 = 1;
+
+// ===========================================================================
+// Types
+// ===========================================================================
+
+/// Why an application was stopped.
+#[derive(Debug, Copy, Clone)]
+pub(crate) enum Fault {
+    /// The processor faulted while running the application.
+    Exception(fault::Report),
+    /// The application asked the ROM to use memory it isn't allowed to.
+    BadPointer { address: u32, pc: u32 },
+    /// The application raised an `SVC` we don't understand.
+    BadCall { pc: u32 },
+    /// Someone pressed Ctrl-Break.
+    Aborted,
+    /// The vertical-blank hook ran for too long.
+    HookOverran,
+}
+
+/// An Api call, made by one of our trampolines (or by an application
+/// pretending to be one). `#[repr(u32)]` puts the discriminant in the first
+/// word, so `sandbox_svc` can check it before reading the rest. The last
+/// pointer in each variant says where the result goes.
+///
+/// `RegisterVblankHook` must stay last - see `is_call`.
+#[repr(u32)]
+enum Call {
+    Putchar(u8, *mut i32),
+    Puts(*const u8, *mut i32),
+    /// `None` means 'call me again'.
+    Readc(*mut Option<i32>),
+    /// Wait for the end of this frame. `false` means 'call me again'.
+    Wfvbi(u32, *mut bool),
+    Kbhit(*mut i32),
+    MoveCursor(u8, u8),
+    Play(u32, u8, u8, u8, *mut i32),
+    ChangeFont(u32, *const u8),
+    GetJoystick(*mut u8),
+    SetCursorVisible(u8),
+    ReadCharAt(u8, u8, *mut u16),
+    /// We don't send the `OpenMode`, as `api::open` doesn't use it.
+    Open(BorrowedString, *mut HandleResult),
+    Close(Handle, *mut EmptyResult),
+    Read(Handle, *mut u8, usize, *mut SizeResult),
+    Write(Handle, *const u8, usize, *mut SizeResult),
+    WriteThenRead(Handle, *const u8, usize, *mut u8, usize, *mut SizeResult),
+    /// We don't send the `Offset`, as `api::seek` doesn't use it.
+    Seek(Handle, *mut EmptyResult),
+    Opendir(BorrowedString, *mut HandleResult),
+    Readdir(Handle, *mut DirEntry, *mut EmptyResult),
+    Stat(BorrowedString, *mut DirEntry, *mut EmptyResult),
+    Gettime(*mut Timestamp),
+    PutsUtf8(*const u8, usize),
+    MapLine(u16, u16),
+    GetCursor(*mut u8, *mut u8),
+    GetArgs(*mut Args),
+    GetCycleBudget(*mut CycleBudget),
+    GetTicks(*mut u32),
+    GetMicros(*mut u64),
+    /// Wait until this many microseconds. `false` means 'call me again'.
+    SleepUntil(u64, *mut bool),
+    /// The `TimerMode`, as a `u32`.
+    TimerStart(u32, u32, *mut TimerResult),
+    TimerStop(TimerId, *mut EmptyResult),
+    GetEvent(*mut Event),
+    RegisterVblankHook(Option<VblankHook>, *mut core::ffi::c_void),
+}
+
+// ===========================================================================
+// Static Variables
+// ===========================================================================
+
+/// The table we give to applications. Every call goes through a trampoline
+/// to the real function in `api`.
+const TABLE: Api = Api {
+    header: ApiHeader::new(api::CAPABILITIES),
+    putchar,
+    puts,
+    readc,
+    wfvbi,
+    kbhit,
+    move_cursor,
+    play,
+    change_font,
+    get_joystick,
+    set_cursor_visible,
+    read_char_at,
+    open,
+    close,
+    read,
+    write,
+    write_then_read,
+    seek,
+    opendir,
+    readdir,
+    stat,
+    gettime,
+    puts_utf8,
+    map_line,
+    get_cursor,
+    get_service,
+    get_args,
+    get_cycle_budget,
+    get_ticks,
+    get_micros,
+    sleep_ms,
+    timer_start,
+    timer_stop,
+    get_event,
+    register_vblank_hook,
+};
+
+/// The table applications get when the clock hasn't been set.
+static APP_TABLE: Api = TABLE;
+
+/// The table applications get once the clock has been read from the RTC.
+/// Only the header is different.
+static APP_TABLE_WITH_CLOCK: Api = Api {
+    header: ApiHeader::new(api::CAPABILITIES.union(Capabilities::CLOCK)),
+    ..TABLE
+};
+
+/// Why the last application was stopped, if it didn't return.
+static FAULT: spin::Mutex<Option<Fault>> = spin::Mutex::new(None);
+
+/// Set while an application is running.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// While the vertical-blank hook runs, where the application's stack was
+/// and the `EXC_RETURN` which takes us back there. Only touched by the
+/// `SVCall` and `PendSV` handlers, which can't interrupt each other.
+static mut HOOK_RETURN: Option<(u32, u32)> = None;
+
+// ===========================================================================
+// Assembly
+// ===========================================================================
+
+core::arch::global_asm!(
+    r#"
+    .syntax unified
+    .thumb
+
+    /* The OS stack pointer, saved while an application runs */
+    .section .bss.sandbox_os_sp,"aw",%nobits
+    .align 2
+sandbox_os_sp:
+    .space 4
+
+    /* u32 sandbox_enter(entry, api, stack_top) - run an application */
+    .section .text.sandbox_enter,"ax",%progbits
+    .global sandbox_enter
+    .type sandbox_enter,%function
+    .thumb_func
+sandbox_enter:
+    push {{r4-r12, lr}}
+    vpush {{s16-s31}}
+    ldr r3, =sandbox_os_sp
+    mov r12, sp
+    str r12, [r3]
+    msr psp, r2
+    mov r3, r0
+    mov r0, r1
+    /* Unprivileged, on the process stack */
+    movs r1, #3
+    msr control, r1
+    isb
+    blx r3
+    svc #1
+    b .
+    .ltorg
+
+    /* ! sandbox_return(result) - from handler mode, abandon the application
+    and return `result` from sandbox_enter */
+    .section .text.sandbox_return,"ax",%progbits
+    .global sandbox_return
+    .type sandbox_return,%function
+    .thumb_func
+sandbox_return:
+    ldr r1, =sandbox_os_sp
+    ldr r1, [r1]
+    /* Build an exception frame on the OS stack which returns to
+    sandbox_resume with r0 = result */
+    subs r1, #32
+    str r0, [r1, #0]
+    ldr r2, =sandbox_resume
+    bic r2, r2, #1
+    str r2, [r1, #24]
+    mov r2, #0x01000000
+    str r2, [r1, #28]
+    msr msp, r1
+    /* Privileged, on the main stack */
+    movs r2, #0
+    msr control, r2
+    isb
+    /* Forget any lazily-stacked FP state the application left behind */
+    ldr r2, =0xE000EF34
+    ldr r3, [r2]
+    bic r3, r3, #1
+    str r3, [r2]
+    ldr r2, =0xFFFFFFF9
+    bx r2
+    .ltorg
+
+    .thumb_func
+sandbox_resume:
+    vpop {{s16-s31}}
+    pop {{r4-r12, pc}}
+
+    /* The vertical-blank hook returns here, unprivileged */
+    .section .text.sandbox_hook_return,"ax",%progbits
+    .global sandbox_hook_return
+    .type sandbox_hook_return,%function
+    .thumb_func
+sandbox_hook_return:
+    svc #2
+    b .
+
+    /* SVCall and PendSV are plain global symbols, which take the place of
+    the default handlers cortex-m-rt's link.x only PROVIDEs. memory.x checks
+    at link time that ours are the ones in the vector table. */
+
+    /* Call the Rust code with the exception frame and EXC_RETURN, then
+    return with whatever EXC_RETURN it gives back */
+    .section .text.SVCall,"ax",%progbits
+    .global SVCall
+    .type SVCall,%function
+    .thumb_func
+SVCall:
+    tst lr, #4
+    ite eq
+    mrseq r0, msp
+    mrsne r0, psp
+    mov r1, lr
+    bl sandbox_svc
+    bx r0
+
+    /* Call the Rust code with EXC_RETURN, then return with whatever
+    EXC_RETURN it gives back. If the FP registers are waiting to be lazily
+    stacked, touch one so they're stacked now - we might leave that frame
+    alone for a while to run the vertical-blank hook. */
+    .section .text.PendSV,"ax",%progbits
+    .global PendSV
+    .type PendSV,%function
+    .thumb_func
+PendSV:
+    tst lr, #16
+    bne 1f
+    vmov.f32 s0, s0
+1:
+    mov r0, lr
+    bl sandbox_pendsv
+    bx r0
+"#
+);
+
+extern "C" {
+    fn sandbox_enter(entry: u32, api: *const Api, stack_top: u32) -> u32;
+    fn sandbox_return(result: u32) -> !;
+    fn sandbox_hook_return();
+}
+
+// ===========================================================================
+// Functions and Impls
+// ===========================================================================
+
+/// Set up the MPU regions, and the priorities of the `SVCall` and `PendSV`
+/// handlers. Called once, at boot.
+pub(crate) fn init(mpu: &mut crate::cpu::MPU, scb: &mut crate::cpu::SCB) {
+    let regions = [
+        (
+            REGION_FLASH,
+            0x0000_0000,
+            RASR_AP_RO_RO | RASR_FLASH_ATTRS | size_bits(FLASH_END),
+        ),
+        (
+            REGION_RAM,
+            RAM_START as u32,
+            RASR_XN | RASR_AP_RW_RO | RASR_SRAM_ATTRS | size_bits(TOTAL_RAM_LEN),
+        ),
+        (
+            REGION_APP_RAM,
+            RAM_START as u32,
+            RASR_AP_RW_RW | RASR_SRAM_ATTRS | size_bits(TOTAL_RAM_LEN) | os_subregions(),
+        ),
+    ];
+    unsafe {
+        mpu.ctrl.write(0);
+        for &(number, base, rasr) in regions.iter() {
+            mpu.rnr.write(number as u32);
+            mpu.rbar.write(base);
+            mpu.rasr.write(rasr | RASR_ENABLE);
+        }
+        mpu.ctrl.write(MPU_CTRL_ENABLE_PRIVDEFENA);
+        // Lower than every interrupt, so the video keeps going while we
+        // handle Api calls.
+        scb.set_priority(cortex_m::peripheral::scb::SystemHandler::SVCall, 0xFF);
+        // The same as SVCall, so we never stop an application half-way
+        // through an Api call.
+        scb.set_priority(cortex_m::peripheral::scb::SystemHandler::PendSV, 0xFF);
+    }
+    cortex_m::asm::dsb();
+    cortex_m::asm::isb();
+}
+
+/// Work out the SIZE field of MPU_RASR for a power-of-two length.
+const fn size_bits(len: usize) -> u32 {
+    (len.trailing_zeros() - 1) << 1
+}
+
+/// Work out the SRD field of MPU_RASR which disables the sub-regions (each
+/// an eighth of the RAM) holding the OS RAM.
+const fn os_subregions() -> u32 {
+    let os_subregions = OS_RAM_LEN / (TOTAL_RAM_LEN / 8);
+    ((1 << os_subregions) - 1) << 8
+}
+
+/// Run an application, starting at `entry`, with its stack at the top of
+/// application RAM. Returns what the application returned, or why we had to
+/// stop it.
+pub(crate) fn run(entry: u32) -> Result<u32, Fault> {
+    *FAULT.lock() = None;
+    unsafe {
+        HOOK_RETURN = None;
+    }
+    let stack_top = APPLICATION_START_ADDR as u32 + APPLICATION_LEN as u32;
+    abort::reset();
+    RUNNING.store(true, Ordering::Relaxed);
+    let table = if api::capabilities().contains(Capabilities::CLOCK) {
+        &APP_TABLE_WITH_CLOCK
+    } else {
+        &APP_TABLE
+    };
+    let result = unsafe { sandbox_enter(entry, table, stack_top) };
+    RUNNING.store(false, Ordering::Relaxed);
+    match FAULT.lock().take() {
+        Some(fault) => Err(fault),
+        None => Ok(result),
+    }
+}
+
+/// Is an application running?
+pub(crate) fn is_running() -> bool {
+    RUNNING.load(Ordering::Relaxed)
+}
+
+/// Did an exception with this `EXC_RETURN` interrupt the application
+/// (rather than the ROM)?
+pub(crate) fn is_application(exc_return: u32) -> bool {
+    exc_return & EXC_RETURN_THREAD_PSP == EXC_RETURN_THREAD_PSP
+}
+
+/// Stop the application, remembering why. Only call this from an exception
+/// handler which interrupted the application.
+pub(crate) fn stop(fault: Fault) -> ! {
+    *FAULT.lock() = Some(fault);
+    unsafe { sandbox_return(0) }
+}
+
+/// Can the application write to all of this memory?
+fn app_writable(ptr: *const u8, len: usize) -> bool {
+    let start = ptr as usize;
+    let app_start = APPLICATION_START_ADDR as usize;
+    let app_end = app_start + APPLICATION_LEN;
+    start >= app_start && start < app_end && len <= app_end - start
+}
+
+/// Can the application read all of this memory?
+fn app_readable(ptr: *const u8, len: usize) -> bool {
+    let start = ptr as usize;
+    let in_flash = start < FLASH_END && len <= FLASH_END - start;
+    let in_ram = start >= RAM_START && start < RAM_END && len <= RAM_END - start;
+    in_flash || in_ram
+}
+
+/// Called by `SVCall` with the exception frame and `EXC_RETURN`. Returns
+/// the `EXC_RETURN` to use.
+#[no_mangle]
+extern "C" fn sandbox_svc(frame: *mut u32, exc_return: u32) -> u32 {
+    if !is_application(exc_return) {
+        // The OS doesn't use SVC
+        return exc_return;
+    }
+    let frame = unsafe { core::slice::from_raw_parts_mut(frame, 8) };
+    let pc = frame[6];
+    // The SVC instruction is just before the return address
+    let number = unsafe { core::ptr::read((pc - 2) as *const u8) };
+    match number {
+        SVC_CALL => {
+            let call = frame[0] as *const Call;
+            if (call as usize) % core::mem::align_of::<Call>() != 0
+                || !app_writable(call as *const u8, core::mem::size_of::<Call>())
+            {
+                stop(Fault::BadPointer {
+                    address: frame[0],
+                    pc,
+                });
+            }
+            // Don't read it as a `Call` until we know it is one
+            if !is_call(unsafe { core::ptr::read(call as *const u32) }) {
+                stop(Fault::BadCall { pc });
+            }
+            dispatch(unsafe { core::ptr::read(call) }, pc);
+        }
+        SVC_EXIT => unsafe { sandbox_return(frame[0]) },
+        SVC_HOOK_DONE => match unsafe { HOOK_RETURN.take() } {
+            Some((psp, exc_return)) => {
+                vblank::finished();
+                return leave_hook(psp, exc_return);
+            }
+            None => stop(Fault::BadCall { pc }),
+        },
+        _ => stop(Fault::BadCall { pc }),
+    }
+    exc_return
+}
+
+/// Called by `PendSV` with `EXC_RETURN`. Returns the `EXC_RETURN` to use.
+#[no_mangle]
+extern "C" fn sandbox_pendsv(exc_return: u32) -> u32 {
+    // If the application finished before we got here, there's nothing to
+    // stop.
+    if abort::take_request() && is_application(exc_return) {
+        stop(Fault::Aborted);
+    }
+    if vblank::take_overrun() && is_application(exc_return) {
+        stop(Fault::HookOverran);
+    }
+    if !is_application(exc_return) {
+        return exc_return;
+    }
+    match vblank::take_pending() {
+        Some(hook) => enter_hook(hook, exc_return),
+        None => exc_return,
+    }
+}
+
+/// Make the application call its vertical-blank hook, by pushing an
+/// exception frame which 'returns' into it. Returns the `EXC_RETURN` to
+/// use.
+fn enter_hook(hook: vblank::Hook, exc_return: u32) -> u32 {
+    let psp = read_psp();
+    let sp = (psp - BASIC_FRAME_LEN as u32) & !7;
+    if !app_writable(sp as *const u8, BASIC_FRAME_LEN) {
+        // The application's stack is full
+        stop(Fault::BadPointer {
+            address: sp,
+            pc: unsafe { ((psp + 24) as *const u32).read() },
+        });
+    }
+    let frame = unsafe { core::slice::from_raw_parts_mut(sp as *mut u32, 8) };
+    frame[0] = hook.context;
+    frame[1] = 0;
+    frame[2] = 0;
+    frame[3] = 0;
+    frame[4] = 0;
+    frame[5] = sandbox_hook_return as u32;
+    frame[6] = hook.function & !1;
+    frame[7] = XPSR_THUMB;
+    unsafe {
+        HOOK_RETURN = Some((psp, exc_return));
+    }
+    vblank::started();
+    write_psp(sp);
+    EXC_RETURN_THREAD_PSP_BASIC
+}
+
+/// Drop the hook's exception frame, and go back to where the application
+/// was when `enter_hook` was called. Returns the `EXC_RETURN` to use.
+fn leave_hook(psp: u32, exc_return: u32) -> u32 {
+    write_psp(psp);
+    // Forget any FP state the hook left to be lazily stacked - the
+    // application's own is already on its stack (see `PendSV`).
+    unsafe {
+        FPCCR.write_volatile(FPCCR.read_volatile() & !FPCCR_LSPACT);
+    }
+    exc_return
+}
+
+/// Read the process stack pointer.
+fn read_psp() -> u32 {
+    let psp: u32;
+    unsafe {
+        core::arch::asm!("mrs {}, psp", out(reg) psp, options(nomem, nostack, preserves_flags));
+    }
+    psp
+}
+
+/// Set the process stack pointer. Only call this from a handler which
+/// returns to the application.
+fn write_psp(psp: u32) {
+    unsafe {
+        core::arch::asm!("msr psp, {}", in(reg) psp, options(nostack, preserves_flags));
+    }
+}
+
+/// Check a pointer the application wants us to write through, stopping the
+/// application if it's no good.
+fn check_writable(ptr: *const u8, len: usize, pc: u32) {
+    // An empty buffer can point anywhere, as we won't touch it
+    if len != 0 && !app_writable(ptr, len) {
+        stop(Fault::BadPointer {
+            address: ptr as u32,
+            pc,
+        });
+    }
+}
+
+/// Check a pointer the application wants us to read through, stopping the
+/// application if it's no good.
+fn check_readable(ptr: *const u8, len: usize, pc: u32) {
+    // An empty buffer can point anywhere, as we won't touch it
+    if len != 0 && !app_readable(ptr, len) {
+        stop(Fault::BadPointer {
+            address: ptr as u32,
+            pc,
+        });
+    }
+}
+
+/// Check a null-terminated string the application wants us to read, up to
+/// and including the null, stopping the application if it's no good.
+fn check_string(ptr: *const u8, pc: u32) {
+    let mut p = ptr;
+    loop {
+        check_readable(p, 1, pc);
+        if unsafe { p.read() } == 0 {
+            return;
+        }
+        p = p.wrapping_add(1);
+    }
+}
+
+/// Check where the application wants a result written, then make the Api
+/// call and write the result there. Stops the application (without making
+/// the call) if the pointer is no good.
+fn reply<T>(result: *mut T, pc: u32, call: impl FnOnce() -> T) {
+    if (result as usize) % core::mem::align_of::<T>() != 0 {
+        stop(Fault::BadPointer {
+            address: result as u32,
+            pc,
+        });
+    }
+    check_writable(result as *const u8, core::mem::size_of::<T>(), pc);
+    // Whatever was there might not be a valid `T`, so don't drop it
+    unsafe { result.write(call()) };
+}
+
+/// Is this the discriminant of a `Call`?
+fn is_call(discriminant: u32) -> bool {
+    let last = Call::RegisterVblankHook(None, core::ptr::null_mut());
+    discriminant <= unsafe { core::ptr::read(&last as *const Call as *const u32) }
+}
+
+/// Make an Api call on behalf of the application. Results go wherever the
+/// `Call` says, which is normally the trampoline's stack frame.
+fn dispatch(call: Call, pc: u32) {
+    match call {
+        Call::Putchar(ch, result) => reply(result, pc, || api::putchar(ch)),
+        Call::Puts(s, result) => {
+            check_string(s, pc);
+            reply(result, pc, || api::puts(s));
+        }
+        Call::Readc(result) => reply(result, pc, api::readc),
+        Call::Wfvbi(frame, result) => reply(result, pc, || api::wfvbi_after(frame)),
+        Call::Kbhit(result) => reply(result, pc, || api::kbhit()),
+        Call::MoveCursor(row, col) => api::move_cursor(row, col),
+        Call::Play(frequency, channel, waveform, volume, result) => reply(result, pc, || {
+            api::play(frequency, channel, waveform, volume)
+        }),
+        Call::ChangeFont(mode, font) => {
+            if mode == 2 && !font.is_null() {
+                check_readable(font, FONT_LEN, pc);
+            }
+            api::change_font(mode, font);
+        }
+        Call::GetJoystick(result) => reply(result, pc, || api::get_joystick()),
+        Call::SetCursorVisible(visible) => api::set_cursor_visible(visible),
+        Call::ReadCharAt(row, col, result) => reply(result, pc, || api::read_char_at(row, col)),
+        Call::Open(filename, result) => {
+            check_readable(filename.ptr, filename.length, pc);
+            reply(result, pc, || api::open(filename));
+        }
+        Call::Close(handle, result) => reply(result, pc, || api::close(handle)),
+        Call::Read(handle, buffer, len, result) => {
+            check_writable(buffer, len, pc);
+            reply(result, pc, || api::read(handle, buffer, len));
+        }
+        Call::Write(handle, buffer, len, result) => {
+            check_readable(buffer, len, pc);
+            reply(result, pc, || api::write(handle, buffer, len));
+        }
+        Call::WriteThenRead(handle, out_buffer, out_len, in_buffer, in_len, result) => {
+            check_readable(out_buffer, out_len, pc);
+            check_writable(in_buffer, in_len, pc);
+            reply(result, pc, || {
+                api::write_then_read(handle, out_buffer, out_len, in_buffer, in_len)
+            });
+        }
+        Call::Seek(handle, result) => reply(result, pc, || api::seek(handle)),
+        Call::Opendir(filename, result) => {
+            check_readable(filename.ptr, filename.length, pc);
+            reply(result, pc, || api::opendir(filename));
+        }
+        Call::Readdir(handle, entry, result) => {
+            check_writable(entry as *const u8, core::mem::size_of::<DirEntry>(), pc);
+            reply(result, pc, || api::readdir(handle, entry));
+        }
+        Call::Stat(filename, entry, result) => {
+            check_readable(filename.ptr, filename.length, pc);
+            check_writable(entry as *const u8, core::mem::size_of::<DirEntry>(), pc);
+            reply(result, pc, || api::stat(filename, entry));
+        }
+        Call::Gettime(result) => reply(result, pc, || api::gettime()),
+        Call::PutsUtf8(string, length) => {
+            check_readable(string, length, pc);
+            api::puts_utf8(string, length);
+        }
+        Call::MapLine(actual, drawn) => api::map_line(actual, drawn),
+        Call::GetCursor(row, col) => {
+            if !row.is_null() {
+                check_writable(row, 1, pc);
+            }
+            if !col.is_null() {
+                check_writable(col, 1, pc);
+            }
+            api::get_cursor(row, col);
+        }
+        Call::GetArgs(result) => reply(result, pc, || api::get_args()),
+        Call::GetCycleBudget(result) => reply(result, pc, || api::get_cycle_budget()),
+        Call::GetTicks(result) => reply(result, pc, || api::get_ticks()),
+        Call::GetMicros(result) => reply(result, pc, || api::get_micros()),
+        Call::SleepUntil(deadline, result) => reply(result, pc, || api::sleep_until(deadline)),
+        Call::TimerStart(period_ms, mode, result) => {
+            let mode = match mode {
+                0 => TimerMode::OneShot,
+                1 => TimerMode::Periodic,
+                _ => stop(Fault::BadCall { pc }),
+            };
+            reply(result, pc, || api::timer_start(period_ms, mode));
+        }
+        Call::TimerStop(timer, result) => reply(result, pc, || api::timer_stop(timer)),
+        Call::GetEvent(result) => reply(result, pc, || api::get_event()),
+        Call::RegisterVblankHook(hook, context) => api::register_vblank_hook(hook, context),
+    }
+}
+
+/// Raise an `SVC` to make an Api call.
+fn syscall(call: &mut Call) {
+    unsafe {
+        core::arch::asm!("svc 0", in("r0") call as *mut Call);
+    }
+}
+
+impl core::fmt::Display for Fault {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Fault::Exception(report) => write!(f, "{}", report),
+            Fault::BadPointer { address, pc } => write!(
+                f,
+                "Bad pointer 0x{:08x} given to the ROM at PC 0x{:08x}",
+                address, pc
+            ),
+            Fault::BadCall { pc } => write!(f, "Unknown SVC at PC 0x{:08x}", pc),
+            Fault::Aborted => write!(f, "Stopped by Ctrl-Break"),
+            Fault::HookOverran => write!(
+                f,
+                "The vertical-blank hook used more than {} cycles",
+                monotron_api::VBLANK_HOOK_BUDGET_CYCLES
+            ),
+        }
+    }
+}
+
+// ===========================================================================
+// Trampolines
+//
+// These run unprivileged, in the application's context.
+// ===========================================================================
+
+extern "C" fn putchar(ch: u8) -> i32 {
+    let mut result = 0;
+    syscall(&mut Call::Putchar(ch, &mut result));
+    result
+}
+
+extern "C" fn puts(string: *const u8) -> i32 {
+    let mut result = 0;
+    syscall(&mut Call::Puts(string, &mut result));
+    result
+}
+
+extern "C" fn readc() -> i32 {
+    loop {
+        let mut result = None;
+        syscall(&mut Call::Readc(&mut result));
+        if let Some(ch) = result {
+            return ch;
+        }
+    }
+}
+
+extern "C" fn wfvbi() {
+    let frame = get_ticks();
+    let mut done = false;
+    while !done {
+        syscall(&mut Call::Wfvbi(frame, &mut done));
+    }
+}
+
+extern "C" fn kbhit() -> i32 {
+    let mut result = 0;
+    syscall(&mut Call::Kbhit(&mut result));
+    result
+}
+
+extern "C" fn move_cursor(row: u8, col: u8) {
+    syscall(&mut Call::MoveCursor(row, col));
+}
+
+extern "C" fn play(frequency: u32, channel: u8, waveform: u8, volume: u8) -> i32 {
+    let mut result = 0;
+    syscall(&mut Call::Play(
+        frequency,
+        channel,
+        waveform,
+        volume,
+        &mut result,
+    ));
+    result
+}
+
+extern "C" fn change_font(mode: u32, font: *const u8) {
+    syscall(&mut Call::ChangeFont(mode, font));
+}
+
+extern "C" fn get_joystick() -> u8 {
+    let mut result = 0;
+    syscall(&mut Call::GetJoystick(&mut result));
+    result
+}
+
+extern "C" fn set_cursor_visible(visible: u8) {
+    syscall(&mut Call::SetCursorVisible(visible));
+}
+
+extern "C" fn read_char_at(row: u8, col: u8) -> u16 {
+    let mut result = 0;
+    syscall(&mut Call::ReadCharAt(row, col, &mut result));
+    result
+}
+
+extern "C" fn open(filename: BorrowedString, _mode: OpenMode) -> HandleResult {
+    let mut result = HandleResult::Error(Error::Unknown);
+    syscall(&mut Call::Open(filename, &mut result));
+    result
+}
+
+extern "C" fn close(handle: Handle) -> EmptyResult {
+    let mut result = EmptyResult::Error(Error::Unknown);
+    syscall(&mut Call::Close(handle, &mut result));
+    result
+}
+
+extern "C" fn read(handle: Handle, buffer: *mut u8, buffer_len: usize) -> SizeResult {
+    let mut result = SizeResult::Error(Error::Unknown);
+    syscall(&mut Call::Read(handle, buffer, buffer_len, &mut result));
+    result
+}
+
+extern "C" fn write(handle: Handle, buffer: *const u8, buffer_len: usize) -> SizeResult {
+    let mut result = SizeResult::Error(Error::Unknown);
+    syscall(&mut Call::Write(handle, buffer, buffer_len, &mut result));
+    result
+}
+
+extern "C" fn write_then_read(
+    handle: Handle,
+    out_buffer: *const u8,
+    out_buffer_len: usize,
+    in_buffer: *mut u8,
+    in_buffer_len: usize,
+) -> SizeResult {
+    let mut result = SizeResult::Error(Error::Unknown);
+    syscall(&mut Call::WriteThenRead(
+        handle,
+        out_buffer,
+        out_buffer_len,
+        in_buffer,
+        in_buffer_len,
+        &mut result,
+    ));
+    result
+}
+
+extern "C" fn seek(handle: Handle, _offset: Offset) -> EmptyResult {
+    let mut result = EmptyResult::Error(Error::Unknown);
+    syscall(&mut Call::Seek(handle, &mut result));
+    result
+}
+
+extern "C" fn opendir(filename: BorrowedString) -> HandleResult {
+    let mut result = HandleResult::Error(Error::Unknown);
+    syscall(&mut Call::Opendir(filename, &mut result));
+    result
+}
+
+extern "C" fn readdir(handle: Handle, dir_entry: &mut DirEntry) -> EmptyResult {
+    let mut result = EmptyResult::Error(Error::Unknown);
+    syscall(&mut Call::Readdir(handle, dir_entry, &mut result));
+    result
+}
+
+extern "C" fn stat(filename: BorrowedString, stat_entry: &mut DirEntry) -> EmptyResult {
+    let mut result = EmptyResult::Error(Error::Unknown);
+    syscall(&mut Call::Stat(filename, stat_entry, &mut result));
+    result
+}
+
+extern "C" fn gettime() -> Timestamp {
+    let mut result = Timestamp {
+        year_from_1970: 0,
+        month: 1,
+        days: 1,
+        hours: 0,
+        minutes: 0,
+        seconds: 0,
+    };
+    syscall(&mut Call::Gettime(&mut result));
+    result
+}
+
+extern "C" fn puts_utf8(string: *const u8, length: usize) {
+    syscall(&mut Call::PutsUtf8(string, length));
+}
+
+extern "C" fn map_line(actual_scanline: u16, drawn_scanline: u16) {
+    syscall(&mut Call::MapLine(actual_scanline, drawn_scanline));
+}
+
+extern "C" fn get_cursor(row: *mut u8, col: *mut u8) {
+    syscall(&mut Call::GetCursor(row, col));
+}
+
+/// Look up an optional service. Returns null if we don't have it. This
+/// needs no privileges, so it doesn't need an `SVC`.
+extern "C" fn get_service(id: ServiceId) -> *const core::ffi::c_void {
+    match id {
+        ServiceId::MAP_LINE => map_line as *const core::ffi::c_void,
+        ServiceId::GET_CURSOR => get_cursor as *const core::ffi::c_void,
+        _ => core::ptr::null(),
+    }
+}
+
+extern "C" fn get_args() -> Args {
+    let mut result = Args {
+        argc: 0,
+        argv: core::ptr::null(),
+    };
+    syscall(&mut Call::GetArgs(&mut result));
+    result
+}
+
+extern "C" fn get_cycle_budget() -> CycleBudget {
+    let mut result = CycleBudget::default();
+    syscall(&mut Call::GetCycleBudget(&mut result));
+    result
+}
+
+extern "C" fn get_ticks() -> u32 {
+    let mut result = 0;
+    syscall(&mut Call::GetTicks(&mut result));
+    result
+}
+
+extern "C" fn get_micros() -> u64 {
+    let mut result = 0;
+    syscall(&mut Call::GetMicros(&mut result));
+    result
+}
+
+extern "C" fn sleep_ms(ms: u32) {
+    let deadline = get_micros() + u64::from(ms) * 1000;
+    let mut done = false;
+    while !done {
+        syscall(&mut Call::SleepUntil(deadline, &mut done));
+    }
+}
+
+extern "C" fn timer_start(period_ms: u32, mode: TimerMode) -> TimerResult {
+    let mut result = TimerResult::Error(Error::Unknown);
+    syscall(&mut Call::TimerStart(period_ms, mode as u32, &mut result));
+    result
+}
+
+extern "C" fn timer_stop(timer: TimerId) -> EmptyResult {
+    let mut result = EmptyResult::Error(Error::Unknown);
+    syscall(&mut Call::TimerStop(timer, &mut result));
+    result
+}
+
+extern "C" fn get_event() -> Event {
+    let mut result = Event::None;
+    syscall(&mut Call::GetEvent(&mut result));
+    result
+}
+
+extern "C" fn register_vblank_hook(hook: Option<VblankHook>, context: *mut core::ffi::c_void) {
+    syscall(&mut Call::RegisterVblankHook(hook, context));
+}
+
+// End of file
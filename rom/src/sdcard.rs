@@ -0,0 +1,115 @@
+//! # SD card controller
+//!
+//! `embedded_sdmmc::Controller` only has room for a few open directories and
+//! files, and it won't tell us how many it has open. This wrapper counts
+//! them (so `debug mem` can show any we've leaked) and otherwise just passes
+//! everything through.
+
+use embedded_sdmmc::{BlockDevice, Directory, Error, File, Mode, TimeSource, Volume};
+
+// ===========================================================================
+// Types
+// ===========================================================================
+
+/// An `embedded_sdmmc::Controller` which counts what it has open.
+pub(crate) struct Controller<D, T>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    inner: embedded_sdmmc::Controller<D, T>,
+    open_dirs: usize,
+    open_files: usize,
+}
+
+// ===========================================================================
+// Functions and Impls
+// ===========================================================================
+
+impl<D, T> Controller<D, T>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    /// Make a controller for this block device.
+    pub(crate) fn new(block_device: D, timesource: T) -> Controller<D, T> {
+        Controller {
+            inner: embedded_sdmmc::Controller::new(block_device, timesource),
+            open_dirs: 0,
+            open_files: 0,
+        }
+    }
+
+    /// How many directories and files are open.
+    pub(crate) fn open_counts(&self) -> (usize, usize) {
+        (self.open_dirs, self.open_files)
+    }
+
+    pub(crate) fn open_root_dir(&mut self, volume: &Volume) -> Result<Directory, Error<D::Error>> {
+        let dir = self.inner.open_root_dir(volume)?;
+        self.open_dirs += 1;
+        Ok(dir)
+    }
+
+    pub(crate) fn open_dir(
+        &mut self,
+        volume: &Volume,
+        parent_dir: &Directory,
+        name: &str,
+    ) -> Result<Directory, Error<D::Error>> {
+        let dir = self.inner.open_dir(volume, parent_dir, name)?;
+        self.open_dirs += 1;
+        Ok(dir)
+    }
+
+    pub(crate) fn close_dir(&mut self, volume: &Volume, dir: Directory) {
+        self.inner.close_dir(volume, dir);
+        self.open_dirs = self.open_dirs.saturating_sub(1);
+    }
+
+    pub(crate) fn open_file_in_dir(
+        &mut self,
+        volume: &mut Volume,
+        dir: &Directory,
+        name: &str,
+        mode: Mode,
+    ) -> Result<File, Error<D::Error>> {
+        let file = self.inner.open_file_in_dir(volume, dir, name, mode)?;
+        self.open_files += 1;
+        Ok(file)
+    }
+
+    pub(crate) fn close_file(
+        &mut self,
+        volume: &Volume,
+        file: File,
+    ) -> Result<(), Error<D::Error>> {
+        self.inner.close_file(volume, file)?;
+        self.open_files = self.open_files.saturating_sub(1);
+        Ok(())
+    }
+}
+
+impl<D, T> core::ops::Deref for Controller<D, T>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    type Target = embedded_sdmmc::Controller<D, T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl<D, T> core::ops::DerefMut for Controller<D, T>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+// End of file
@@ -0,0 +1,302 @@
+//! # Buffered serial ports
+//!
+//! The UARTs only have 16-byte receive FIFOs, which overflow whenever the
+//! shell is busy (say, reading the SD card) for more than a millisecond or
+//! so. Instead, each UART raises an interrupt when it has received
+//! something, and we move the bytes into a `Ring`. The interrupts are less
+//! urgent than the video timers, but more urgent than everything else.
+//!
+//! `BufferedSerial` wraps a HAL `Serial`: writes go straight to the UART as
+//! before, but reads come from the ring. We count overruns, dropped bytes
+//! and framing, parity and break errors for each port; `debug` shows them.
+//! Bytes received with errors are thrown away.
+//!
+//! The keyboard's bytes go through `abort` first, so it can look for
+//! Ctrl-Break.
+
+use crate::cpu::{self, interrupt, Interrupt};
+use crate::cycles::{Bucket, Span};
+use crate::ring::Ring;
+use crate::{abort, println};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+// ===========================================================================
+// Constants
+// ===========================================================================
+
+/// How many ports there are.
+const NUM_PORTS: usize = 4;
+
+/// Less urgent than all the video timers (see `main`), more urgent than
+/// `SVCall` and `PendSV`.
+const RX_PRIORITY: u8 = 10 * 16;
+
+/// UARTIM/UARTICR: the receive FIFO has reached its trigger level.
+const UART_RXI: u32 = 1 << 4;
+
+/// UARTIM/UARTICR: there's something in the receive FIFO, and nothing more
+/// has arrived for a while.
+const UART_RTI: u32 = 1 << 6;
+
+/// UARTDR: framing error.
+const UARTDR_FE: u32 = 1 << 8;
+
+/// UARTDR: parity error.
+const UARTDR_PE: u32 = 1 << 9;
+
+/// UARTDR: break (the line was held low for a whole character or more).
+const UARTDR_BE: u32 = 1 << 10;
+
+/// UARTDR: the FIFO overflowed, some time before this byte.
+const UARTDR_OE: u32 = 1 << 11;
+
+// ===========================================================================
+// Types
+// ===========================================================================
+
+/// One of our UARTs.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Port {
+    /// UART0, to the USB-CDC virtual COM port on the on-board debugger
+    Usb = 0,
+    /// UART1, to the RS-232 level shifter
+    Rs232 = 1,
+    /// UART3, to the MIDI interface
+    Midi = 2,
+    /// UART7, to the keyboard / mouse controller
+    Keyboard = 3,
+}
+
+/// What's gone wrong on a port.
+struct Counters {
+    /// The FIFO filled up before we emptied it
+    overruns: AtomicU32,
+    /// The ring filled up before anyone read it
+    dropped: AtomicU32,
+    framing: AtomicU32,
+    parity: AtomicU32,
+    breaks: AtomicU32,
+}
+
+/// A HAL `Serial` which reads from one of our rings.
+pub(crate) struct BufferedSerial<S> {
+    inner: S,
+    port: Port,
+}
+
+// ===========================================================================
+// Static Variables
+// ===========================================================================
+
+/// Received bytes, indexed by `Port`.
+static RX: [Ring; NUM_PORTS] = [Ring::new(), Ring::new(), Ring::new(), Ring::new()];
+
+/// Errors, indexed by `Port`.
+static COUNTERS: [Counters; NUM_PORTS] = [
+    Counters::new(),
+    Counters::new(),
+    Counters::new(),
+    Counters::new(),
+];
+
+// ===========================================================================
+// Functions and Impls
+// ===========================================================================
+
+/// Turn on the receive interrupts. The UARTs must already be set up.
+pub(crate) fn init(nvic: &mut crate::cpu::NVIC) {
+    for &port in Port::ALL.iter() {
+        port.registers()
+            .im
+            .write(|w| unsafe { w.bits(UART_RXI | UART_RTI) });
+        unsafe {
+            nvic.set_priority(port.interrupt(), RX_PRIORITY);
+        }
+        nvic.enable(port.interrupt());
+    }
+}
+
+/// Take a received byte from a port, if there is one. Safe to call with any
+/// lock held (or none).
+pub(crate) fn read(port: Port) -> Option<u8> {
+    RX[port as usize].pop()
+}
+
+/// Queue a received byte for a port, counting it if there's no room.
+pub(crate) fn deliver(port: Port, byte: u8) {
+    if !RX[port as usize].push(byte) {
+        COUNTERS[port as usize]
+            .dropped
+            .fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Empty a UART's receive FIFO. Called from the UART's interrupt.
+fn on_rx(port: Port) {
+    let span = Span::start();
+    let uart = port.registers();
+    let counters = &COUNTERS[port as usize];
+    while uart.fr.read().rxfe().bit_is_clear() {
+        let word = uart.dr.read().bits();
+        if (word & UARTDR_OE) != 0 {
+            counters.overruns.fetch_add(1, Ordering::Relaxed);
+        }
+        if (word & UARTDR_BE) != 0 {
+            counters.breaks.fetch_add(1, Ordering::Relaxed);
+        } else if (word & UARTDR_FE) != 0 {
+            counters.framing.fetch_add(1, Ordering::Relaxed);
+        } else if (word & UARTDR_PE) != 0 {
+            counters.parity.fetch_add(1, Ordering::Relaxed);
+        } else if port == Port::Keyboard {
+            abort::on_keyboard_byte(word as u8);
+        } else {
+            deliver(port, word as u8);
+        }
+    }
+    uart.icr.write(|w| unsafe { w.bits(UART_RXI | UART_RTI) });
+    span.finish_interrupt(Bucket::SerialRx, 0);
+}
+
+/// Print the error counts for every port. Part of `debug`.
+pub(crate) fn report() {
+    println!(
+        "{:<9}{:>8}{:>8}{:>8}{:>8}{:>8}",
+        "UART", "Overrun", "Dropped", "Framing", "Parity", "Break"
+    );
+    for &port in Port::ALL.iter() {
+        let counters = &COUNTERS[port as usize];
+        println!(
+            "{:<9}{:>8}{:>8}{:>8}{:>8}{:>8}",
+            port.name(),
+            counters.overruns.load(Ordering::Relaxed),
+            counters.dropped.load(Ordering::Relaxed),
+            counters.framing.load(Ordering::Relaxed),
+            counters.parity.load(Ordering::Relaxed),
+            counters.breaks.load(Ordering::Relaxed)
+        );
+    }
+}
+
+impl Port {
+    /// Every port.
+    const ALL: [Port; NUM_PORTS] = [Port::Usb, Port::Rs232, Port::Midi, Port::Keyboard];
+
+    /// The UART's registers. They all have the same layout as UART0.
+    fn registers(self) -> &'static cpu::uart0::RegisterBlock {
+        unsafe {
+            match self {
+                Port::Usb => &*cpu::UART0::ptr(),
+                Port::Rs232 => &*cpu::UART1::ptr(),
+                Port::Midi => &*cpu::UART3::ptr(),
+                Port::Keyboard => &*cpu::UART7::ptr(),
+            }
+        }
+    }
+
+    /// The UART's interrupt.
+    fn interrupt(self) -> Interrupt {
+        match self {
+            Port::Usb => Interrupt::UART0,
+            Port::Rs232 => Interrupt::UART1,
+            Port::Midi => Interrupt::UART3,
+            Port::Keyboard => Interrupt::UART7,
+        }
+    }
+
+    /// What `debug` calls it.
+    fn name(self) -> &'static str {
+        match self {
+            Port::Usb => "usb",
+            Port::Rs232 => "rs232",
+            Port::Midi => "midi",
+            Port::Keyboard => "keyboard",
+        }
+    }
+}
+
+impl Counters {
+    const fn new() -> Counters {
+        Counters {
+            overruns: AtomicU32::new(0),
+            dropped: AtomicU32::new(0),
+            framing: AtomicU32::new(0),
+            parity: AtomicU32::new(0),
+            breaks: AtomicU32::new(0),
+        }
+    }
+}
+
+impl<S> BufferedSerial<S> {
+    /// Read from `port`'s ring instead of from `inner`.
+    pub(crate) fn new(inner: S, port: Port) -> BufferedSerial<S> {
+        BufferedSerial { inner, port }
+    }
+}
+
+impl<S> embedded_hal::serial::Read<u8> for BufferedSerial<S> {
+    /// Errors are counted (and the bytes dropped) as they're received.
+    type Error = core::convert::Infallible;
+
+    fn read(&mut self) -> nb::Result<u8, Self::Error> {
+        read(self.port).ok_or(nb::Error::WouldBlock)
+    }
+}
+
+impl<S> embedded_hal::serial::Write<u8> for BufferedSerial<S>
+where
+    S: embedded_hal::serial::Write<u8>,
+{
+    type Error = S::Error;
+
+    fn write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
+        self.inner.write(word)
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Self::Error> {
+        self.inner.flush()
+    }
+}
+
+impl<S> core::ops::Deref for BufferedSerial<S> {
+    type Target = S;
+
+    fn deref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S> core::ops::DerefMut for BufferedSerial<S> {
+    fn deref_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+}
+
+// ===========================================================================
+// Interrupts
+// ===========================================================================
+
+interrupt!(UART0, uart0);
+
+fn uart0() {
+    on_rx(Port::Usb);
+}
+
+interrupt!(UART1, uart1);
+
+fn uart1() {
+    on_rx(Port::Rs232);
+}
+
+interrupt!(UART3, uart3);
+
+fn uart3() {
+    on_rx(Port::Midi);
+}
+
+interrupt!(UART7, uart7);
+
+fn uart7() {
+    on_rx(Port::Keyboard);
+}
+
+// End of file
@@ -0,0 +1,216 @@
+//! # Background tasks
+//!
+//! A very small cooperative scheduler. Each task is a function which does a
+//! little work and returns; we call every running task at most once per
+//! video frame. Tasks are polled from `wfvbi` (so while the shell waits for
+//! input, and whenever an application calls it) and from commands which sit
+//! waiting for input, like `rterm` and `dpage`.
+//!
+//! We might poll in the middle of a command which is using the SD card or a
+//! UART, so a task must only take a subsystem's lock with `try_lock`, and
+//! if it can't have it, try again next time. A task mustn't wait for
+//! anything. Each has a budget of CPU cycles per poll; one which goes over
+//! its budget `MAX_OVERRUNS` times in a row is stopped.
+//!
+//! The `tasks` command lists the tasks, and starts and stops them.
+
+use crate::cycles::{Bucket, Span};
+use crate::fb::{self, Col, Position, Row};
+use crate::{println, FRAMEBUFFER, TIME_CONTEXT};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+// ===========================================================================
+// Constants
+// ===========================================================================
+
+/// How many polls in a row a task can go over its budget before we stop it.
+const MAX_OVERRUNS: u8 = 3;
+
+/// Where the clock goes - the top right-hand corner.
+const CLOCK_COL: u8 = 40;
+
+// ===========================================================================
+// Types
+// ===========================================================================
+
+/// What a task says after it's been polled.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Status {
+    /// Poll again next frame
+    Pending,
+    /// The task has finished
+    Done,
+}
+
+/// Whether a task is being polled.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum State {
+    Running,
+    Stopped,
+    /// Stopped because it kept going over its budget
+    Overran,
+}
+
+/// A background task.
+struct Task {
+    /// What `tasks` calls it
+    name: &'static str,
+    /// Does a little work
+    poll: fn() -> Status,
+    /// The most CPU cycles a poll should take
+    budget: u32,
+    state: State,
+    /// How many cycles the last poll took
+    last: u32,
+    /// The most cycles any poll has taken
+    worst: u32,
+    /// How many polls in a row went over budget
+    overruns: u8,
+}
+
+// ===========================================================================
+// Static Variables
+// ===========================================================================
+
+/// Every task the ROM has. None of them run until they're started.
+static TASKS: spin::Mutex<[Task; 1]> = spin::Mutex::new([Task {
+    name: "clock",
+    poll: clock_task,
+    budget: 20_000,
+    state: State::Stopped,
+    last: 0,
+    worst: 0,
+    overruns: 0,
+}]);
+
+/// The frame we last polled in, so we only poll once a frame.
+static LAST_POLL: AtomicU32 = AtomicU32::new(u32::max_value());
+
+/// The time the clock task last drew, as seconds since midnight.
+static CLOCK_SHOWN: AtomicU32 = AtomicU32::new(u32::max_value());
+
+// ===========================================================================
+// Functions and Impls
+// ===========================================================================
+
+/// Poll the tasks, if we haven't this frame. Called from `wfvbi`, and from
+/// commands which wait without calling it.
+pub(crate) fn poll() {
+    let frame = crate::timers::ticks();
+    if LAST_POLL.load(Ordering::Relaxed) == frame {
+        return;
+    }
+    // If a task calls `wfvbi`, we mustn't poll it again from in there
+    let mut tasks = match TASKS.try_lock() {
+        Some(tasks) => tasks,
+        None => return,
+    };
+    LAST_POLL.store(frame, Ordering::Relaxed);
+    for task in tasks.iter_mut().filter(|t| t.state == State::Running) {
+        task.run();
+    }
+}
+
+impl Task {
+    /// Poll the task once, and check it kept to its budget.
+    fn run(&mut self) {
+        let span = Span::start();
+        let status = (self.poll)();
+        let cycles = span.finish(Bucket::Tasks);
+        self.last = cycles;
+        self.worst = self.worst.max(cycles);
+        if cycles > self.budget {
+            self.overruns += 1;
+        } else {
+            self.overruns = 0;
+        }
+        if status == Status::Done {
+            self.state = State::Stopped;
+        } else if self.overruns >= MAX_OVERRUNS {
+            self.state = State::Overran;
+        }
+    }
+}
+
+/// Shows the time in the top right-hand corner of the screen.
+fn clock_task() -> Status {
+    let now = TIME_CONTEXT.get_timestamp();
+    let seconds =
+        (u32::from(now.hours) * 3600) + (u32::from(now.minutes) * 60) + u32::from(now.seconds);
+    if CLOCK_SHOWN.swap(seconds, Ordering::Relaxed) != seconds {
+        let digits = [
+            b'0' + now.hours / 10,
+            b'0' + now.hours % 10,
+            b':',
+            b'0' + now.minutes / 10,
+            b'0' + now.minutes % 10,
+            b':',
+            b'0' + now.seconds / 10,
+            b'0' + now.seconds % 10,
+        ];
+        for (idx, &digit) in digits.iter().enumerate() {
+            let pos = Position::new(Row(0), Col(CLOCK_COL + idx as u8));
+            unsafe {
+                FRAMEBUFFER.write_glyph_at(fb::Char::map_char(digit as char), pos, None);
+            }
+        }
+    }
+    Status::Pending
+}
+
+/// List the tasks, or start or stop one.
+pub(crate) fn item_tasks(
+    _menu: &crate::ui::Menu,
+    item: &crate::ui::Item,
+    args: &[&str],
+    _context: &mut crate::MenuContext,
+) {
+    let action = ::menu::argument_finder(item, args, "ACTION");
+    let name = ::menu::argument_finder(item, args, "NAME");
+    let state = match (action, name) {
+        (Ok(None), _) => {
+            list();
+            return;
+        }
+        (Ok(Some("start")), Ok(Some(_))) => State::Running,
+        (Ok(Some("stop")), Ok(Some(_))) => State::Stopped,
+        _ => {
+            println!("Error: ACTION must be start or stop, with a NAME");
+            return;
+        }
+    };
+    let name = name.unwrap().unwrap();
+    let mut tasks = TASKS.lock();
+    match tasks.iter_mut().find(|t| t.name == name) {
+        Some(task) => {
+            if state == State::Running {
+                task.overruns = 0;
+                task.worst = 0;
+            }
+            task.state = state;
+        }
+        None => println!("Error: No task called {:?}", name),
+    }
+}
+
+/// Print every task, with how long it takes.
+fn list() {
+    let tasks = TASKS.lock();
+    println!(
+        "{:<10}{:<9}{:>8}{:>8}{:>8}",
+        "Name", "State", "Budget", "Last", "Worst"
+    );
+    for task in tasks.iter() {
+        let state = match task.state {
+            State::Running => "running",
+            State::Stopped => "stopped",
+            State::Overran => "overran",
+        };
+        println!(
+            "{:<10}{:<9}{:>8}{:>8}{:>8}",
+            task.name, state, task.budget, task.last, task.worst
+        );
+    }
+}
+
+// End of file
@@ -0,0 +1,185 @@
+//! # Timing for applications
+//!
+//! Wide Timer 0 runs as one 64-bit timer, counting up at the CPU clock from
+//! boot, so `micros` can always say how long we've been running. It won't
+//! wrap for over seven thousand years.
+//!
+//! Applications can also start a few software timers. Nothing happens when
+//! one goes off - the application finds out the next time it calls
+//! `get_event`, which is when periodic timers are rescheduled. All the
+//! timers are stopped when the application exits.
+
+use crate::{abort, cpu, watchdog, FRAMEBUFFER};
+use monotron_api::{EmptyResult, Error, Event, TimerId, TimerMode, TimerResult};
+
+// ===========================================================================
+// Constants
+// ===========================================================================
+
+/// How many software timers an application can have running.
+const MAX_TIMERS: usize = 8;
+
+/// Timer cycles in a microsecond.
+const CYCLES_PER_MICRO: u64 = (crate::CLOCK_SPEED / 1_000_000) as u64;
+
+/// GPTMCTL: Timer A enable.
+const GPTMCTL_TAEN: u32 = 1 << 0;
+
+/// GPTMCTL: Timer A stops while the debugger has us halted.
+const GPTMCTL_TASTALL: u32 = 1 << 1;
+
+/// GPTMCFG: Timers A and B run as one 64-bit timer.
+const GPTMCFG_CONCATENATED: u32 = 0;
+
+/// GPTMTAMR: Periodic mode.
+const GPTMTAMR_PERIODIC: u32 = 0x2;
+
+/// GPTMTAMR: Count up, not down.
+const GPTMTAMR_TACDIR: u32 = 1 << 4;
+
+// ===========================================================================
+// Types
+// ===========================================================================
+
+/// A software timer.
+#[derive(Debug, Copy, Clone)]
+struct Timer {
+    /// When it next goes off, in `micros`
+    deadline: u64,
+    /// How often it goes off, in microseconds, or `None` if it only goes off
+    /// once
+    period: Option<u64>,
+}
+
+// ===========================================================================
+// Static Variables
+// ===========================================================================
+
+/// The software timers, indexed by `TimerId`.
+static TIMERS: spin::Mutex<[Option<Timer>; MAX_TIMERS]> = spin::Mutex::new([None; MAX_TIMERS]);
+
+// ===========================================================================
+// Functions and Impls
+// ===========================================================================
+
+/// Start the free-running timer. Its clock must already be turned on.
+pub(crate) fn init(wtimer: &cpu::WTIMER0) {
+    wtimer.ctl.write(|w| unsafe { w.bits(0) });
+    wtimer
+        .cfg
+        .write(|w| unsafe { w.bits(GPTMCFG_CONCATENATED) });
+    wtimer
+        .tamr
+        .write(|w| unsafe { w.bits(GPTMTAMR_PERIODIC | GPTMTAMR_TACDIR) });
+    // In 64-bit mode, Timer A holds the bottom half and Timer B the top
+    wtimer.tailr.write(|w| unsafe { w.bits(0xFFFF_FFFF) });
+    wtimer.tbilr.write(|w| unsafe { w.bits(0xFFFF_FFFF) });
+    wtimer
+        .ctl
+        .write(|w| unsafe { w.bits(GPTMCTL_TAEN | GPTMCTL_TASTALL) });
+}
+
+/// How many video frames there have been since boot.
+pub(crate) fn ticks() -> u32 {
+    unsafe { FRAMEBUFFER.frame() as u32 }
+}
+
+/// How many microseconds since boot.
+pub(crate) fn micros() -> u64 {
+    let wtimer = unsafe { &*cpu::WTIMER0::ptr() };
+    // If the bottom half wraps between reading the two halves, try again
+    loop {
+        let high = wtimer.tbv.read().bits();
+        let low = wtimer.tav.read().bits();
+        if wtimer.tbv.read().bits() == high {
+            return ((u64::from(high) << 32) | u64::from(low)) / CYCLES_PER_MICRO;
+        }
+    }
+}
+
+/// Wait until `micros` reaches `deadline`, or until someone presses
+/// Ctrl-Break. Returns `false` if we have to stop waiting early, so
+/// `PendSV` can run (see `api::should_yield`).
+pub(crate) fn sleep_until(deadline: u64) -> bool {
+    while micros() < deadline && !abort::requested() {
+        if crate::api::should_yield() {
+            return false;
+        }
+        watchdog::feed();
+        // The line interrupt wakes us up
+        cortex_m::asm::wfi();
+    }
+    true
+}
+
+/// Start a software timer.
+pub(crate) fn start(period_ms: u32, mode: TimerMode) -> TimerResult {
+    let period = u64::from(period_ms.max(1)) * 1000;
+    let timer = Timer {
+        deadline: micros() + period,
+        period: match mode {
+            TimerMode::OneShot => None,
+            TimerMode::Periodic => Some(period),
+        },
+    };
+    let mut timers = TIMERS.lock();
+    match timers.iter().position(|t| t.is_none()) {
+        Some(idx) => {
+            timers[idx] = Some(timer);
+            TimerResult::Ok(TimerId(idx as u8))
+        }
+        None => TimerResult::Error(Error::NoFreeTimers),
+    }
+}
+
+/// Stop a software timer.
+pub(crate) fn stop(timer: TimerId) -> EmptyResult {
+    let mut timers = TIMERS.lock();
+    match timers.get_mut(timer.0 as usize) {
+        Some(slot) if slot.is_some() => {
+            *slot = None;
+            EmptyResult::Ok
+        }
+        _ => EmptyResult::Error(Error::BadTimerId),
+    }
+}
+
+/// Stop every software timer. Called when an application exits.
+pub(crate) fn stop_all() {
+    *TIMERS.lock() = [None; MAX_TIMERS];
+}
+
+/// Find the timer which went off first, if any have, and reschedule (or
+/// stop) it.
+pub(crate) fn get_event() -> Event {
+    let now = micros();
+    let mut timers = TIMERS.lock();
+    let mut expired: Option<(usize, u64)> = None;
+    for (idx, timer) in timers.iter().enumerate() {
+        if let Some(timer) = timer {
+            let earliest = expired.map_or(true, |(_, deadline)| timer.deadline < deadline);
+            if timer.deadline <= now && earliest {
+                expired = Some((idx, timer.deadline));
+            }
+        }
+    }
+    match expired {
+        Some((idx, _)) => {
+            let timer = timers[idx].as_mut().unwrap();
+            match timer.period {
+                Some(period) => {
+                    timer.deadline += period;
+                    // If the application fell behind, don't try and catch up
+                    if timer.deadline <= now {
+                        timer.deadline = now + period;
+                    }
+                }
+                None => timers[idx] = None,
+            }
+            Event::Timer(TimerId(idx as u8))
+        }
+        None => Event::None,
+    }
+}
+
+// End of file
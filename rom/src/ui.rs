@@ -1,9 +1,14 @@
-use crate::fb::{self, AsciiConsole, BaseConsole};
+use crate::fb::{self, BaseConsole};
 use crate::hal::prelude::*;
+use crate::platform::Rom;
 use crate::MenuContext;
-use crate::GLOBAL_CONTEXT;
-use crate::{api, Context, Input, APPLICATION_LEN, APPLICATION_START_ADDR, FRAMEBUFFER};
+use crate::{
+    api, output, sandbox, Input, Storage, APPLICATION_LEN, APPLICATION_START_ADDR, FRAMEBUFFER,
+};
 use crate::{print, println};
+use crate::{CONSOLE_INPUT, I2C_BUS, MIDI_UART, RS232_UART, STORAGE, USB_UART};
+use core::fmt::Write as _;
+use core::sync::atomic::{AtomicU32, Ordering};
 use embedded_hal::prelude::*;
 use menu;
 use monotron_synth;
@@ -12,6 +17,60 @@ use nb::block;
 pub(crate) type Menu<'a> = menu::Menu<'a, MenuContext>;
 pub(crate) type Item<'a> = menu::Item<'a, MenuContext>;
 
+/// Where `run` should jump to, if the program in application RAM was loaded
+/// from an ELF file. Zero means it's a flat binary, with the address to jump
+/// to in its first word.
+static ELF_ENTRY: AtomicU32 = AtomicU32::new(0);
+
+/// How many bytes of application RAM the last program we loaded takes up.
+static LOADED_LEN: AtomicU32 = AtomicU32::new(0);
+
+/// The exit status of the last program to run.
+static EXIT_STATUS: AtomicU32 = AtomicU32::new(0);
+
+/// The exit status we record for a program we had to stop.
+const CRASHED_EXIT_STATUS: u32 = 255;
+
+/// How many scan-lines `map_line` can move around.
+const SCAN_LINES: u16 = 576;
+
+/// The name of the file `dload` last loaded, which `run` passes to the
+/// program as its first argument. Empty if it came over the UART.
+static PROGRAM_NAME: spin::Mutex<([u8; 12], usize)> = spin::Mutex::new(([0u8; 12], 0));
+
+/// The directories (separated by `;`) which are searched for a program when
+/// a command isn't one of ours. The root directory is always searched first.
+static SEARCH_PATH: spin::Mutex<Option<([u8; 64], usize)>> = spin::Mutex::new(None);
+
+/// The search path until someone sets another one.
+const DEFAULT_SEARCH_PATH: &str = "BIN";
+
+/// Extensions we try, in order, when looking for a program by name.
+const PROGRAM_EXTENSIONS: [&str; 3] = ["BIN", "ELF", "BAT"];
+
+/// Watches what is typed at the prompt, so we can spot commands the menu
+/// doesn't know and run them from the SD card instead.
+pub(crate) struct CommandLine {
+    buffer: [u8; 64],
+    used: usize,
+}
+
+/// A file or directory name, in 8.3 format.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct ShortName {
+    bytes: [u8; 12],
+    len: usize,
+}
+
+/// Where a program (or batch file) was found on the SD card.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct FoundFile {
+    /// The directory it's in (empty for the root directory)
+    dir: ShortName,
+    /// Its name
+    file: ShortName,
+}
+
 pub(crate) static ROOT_MENU: Menu = Menu {
     label: "root",
     items: &[
@@ -72,7 +131,10 @@ pub(crate) static ROOT_MENU: Menu = Menu {
         &Item {
             item_type: menu::ItemType::Callback {
                 function: item_load_from_uart,
-                parameters: &[],
+                parameters: &[menu::Parameter::Named {
+                    parameter_name: "binary",
+                    help: Some("Use the framed binary protocol instead of hex."),
+                }],
             },
             command: "load",
             help: Some("Load from UART."),
@@ -80,19 +142,117 @@ pub(crate) static ROOT_MENU: Menu = Menu {
         &Item {
             item_type: menu::ItemType::Callback {
                 function: item_debug_info,
-                parameters: &[],
+                parameters: &[menu::Parameter::Optional {
+                    parameter_name: "VIEW",
+                    help: Some("'mem' for memory usage."),
+                }],
             },
             command: "debug",
             help: Some("Show some debug."),
         },
+        &Item {
+            item_type: menu::ItemType::Callback {
+                function: item_remote,
+                parameters: &[menu::Parameter::Mandatory {
+                    parameter_name: "STATE",
+                    help: Some("Either 'on' or 'off'."),
+                }],
+            },
+            command: "remote",
+            help: Some("Copy console output to the USB UART."),
+        },
         &Item {
             item_type: menu::ItemType::Callback {
                 function: item_run_program,
-                parameters: &[],
+                parameters: &[
+                    menu::Parameter::Optional {
+                        parameter_name: "ARG1",
+                        help: Some("Arguments to pass to the program."),
+                    },
+                    menu::Parameter::Optional {
+                        parameter_name: "ARG2",
+                        help: None,
+                    },
+                    menu::Parameter::Optional {
+                        parameter_name: "ARG3",
+                        help: None,
+                    },
+                    menu::Parameter::Optional {
+                        parameter_name: "ARG4",
+                        help: None,
+                    },
+                    menu::Parameter::Optional {
+                        parameter_name: "ARG5",
+                        help: None,
+                    },
+                    menu::Parameter::Optional {
+                        parameter_name: "ARG6",
+                        help: None,
+                    },
+                    menu::Parameter::Optional {
+                        parameter_name: "ARG7",
+                        help: None,
+                    },
+                ],
             },
             command: "run",
             help: Some("Run program."),
         },
+        &Item {
+            item_type: menu::ItemType::Callback {
+                function: item_path,
+                parameters: &[menu::Parameter::Optional {
+                    parameter_name: "DIRS",
+                    help: Some("Directories to search, separated by ';'."),
+                }],
+            },
+            command: "path",
+            help: Some("Get/set where to look for programs."),
+        },
+        &Item {
+            item_type: menu::ItemType::Callback {
+                function: crate::crash::item_crash,
+                parameters: &[menu::Parameter::Optional {
+                    parameter_name: "ACTION",
+                    help: Some("`save` to add it to CRASH.LOG, `clear` to forget it."),
+                }],
+            },
+            command: "crash",
+            help: Some("Show the last ROM crash."),
+        },
+        &Item {
+            item_type: menu::ItemType::Callback {
+                function: crate::cycles::item_top,
+                parameters: &[],
+            },
+            command: "top",
+            help: Some("Show where the CPU's time goes."),
+        },
+        &Item {
+            item_type: menu::ItemType::Callback {
+                function: crate::tasks::item_tasks,
+                parameters: &[
+                    menu::Parameter::Optional {
+                        parameter_name: "ACTION",
+                        help: Some("'start' or 'stop'. Lists the tasks if not given."),
+                    },
+                    menu::Parameter::Optional {
+                        parameter_name: "NAME",
+                        help: Some("The task to start or stop."),
+                    },
+                ],
+            },
+            command: "tasks",
+            help: Some("List, start or stop background tasks."),
+        },
+        &Item {
+            item_type: menu::ItemType::Callback {
+                function: item_status,
+                parameters: &[],
+            },
+            command: "status",
+            help: Some("Show the exit status of the last program."),
+        },
         &Item {
             item_type: menu::ItemType::Callback {
                 function: item_beep,
@@ -151,7 +311,7 @@ pub(crate) static ROOT_MENU: Menu = Menu {
                 function: item_dload,
                 parameters: &[menu::Parameter::Mandatory {
                     parameter_name: "FILE",
-                    help: Some("The file to load."),
+                    help: Some("The file to load (a flat binary or an ELF file)."),
                 }],
             },
             command: "dload",
@@ -160,7 +320,10 @@ pub(crate) static ROOT_MENU: Menu = Menu {
         &Item {
             item_type: menu::ItemType::Callback {
                 function: item_ddump,
-                parameters: &[],
+                parameters: &[menu::Parameter::Mandatory {
+                    parameter_name: "FILE",
+                    help: Some("The file to dump."),
+                }],
             },
             command: "ddump",
             help: Some("Hexdump a file"),
@@ -168,11 +331,84 @@ pub(crate) static ROOT_MENU: Menu = Menu {
         &Item {
             item_type: menu::ItemType::Callback {
                 function: item_dpage,
-                parameters: &[],
+                parameters: &[menu::Parameter::Mandatory {
+                    parameter_name: "FILE",
+                    help: Some("The file to show."),
+                }],
             },
             command: "dpage",
             help: Some("Show a text file"),
         },
+        &Item {
+            item_type: menu::ItemType::Callback {
+                function: item_xsend,
+                parameters: &[
+                    menu::Parameter::Mandatory {
+                        parameter_name: "FILE",
+                        help: Some("The file to send."),
+                    },
+                    menu::Parameter::NamedValue {
+                        parameter_name: "port",
+                        argument_name: "PORT",
+                        help: Some("The UART to use - usb (the default) or rs232."),
+                    },
+                    menu::Parameter::Named {
+                        parameter_name: "1k",
+                        help: Some("Use 1 KiB blocks (XMODEM-1K)."),
+                    },
+                ],
+            },
+            command: "xsend",
+            help: Some("Send a file with XMODEM"),
+        },
+        &Item {
+            item_type: menu::ItemType::Callback {
+                function: item_xrecv,
+                parameters: &[
+                    menu::Parameter::Mandatory {
+                        parameter_name: "FILE",
+                        help: Some("The file to create."),
+                    },
+                    menu::Parameter::NamedValue {
+                        parameter_name: "port",
+                        argument_name: "PORT",
+                        help: Some("The UART to use - usb (the default) or rs232."),
+                    },
+                ],
+            },
+            command: "xrecv",
+            help: Some("Receive a file with XMODEM"),
+        },
+        &Item {
+            item_type: menu::ItemType::Callback {
+                function: item_ysend,
+                parameters: &[
+                    menu::Parameter::Mandatory {
+                        parameter_name: "FILE",
+                        help: Some("The file to send."),
+                    },
+                    menu::Parameter::NamedValue {
+                        parameter_name: "port",
+                        argument_name: "PORT",
+                        help: Some("The UART to use - usb (the default) or rs232."),
+                    },
+                ],
+            },
+            command: "ysend",
+            help: Some("Send a file with YMODEM"),
+        },
+        &Item {
+            item_type: menu::ItemType::Callback {
+                function: item_yrecv,
+                parameters: &[menu::Parameter::NamedValue {
+                    parameter_name: "port",
+                    argument_name: "PORT",
+                    help: Some("The UART to use - usb (the default) or rs232."),
+                }],
+            },
+            command: "yrecv",
+            help: Some("Receive files with YMODEM"),
+        },
         &Item {
             item_type: menu::ItemType::Callback {
                 function: rs232_term,
@@ -364,27 +600,38 @@ fn item_dump<'a>(_menu: &Menu, item: &Item, args: &[&str], _context: &mut MenuCo
     }
 }
 
-/// Reads raw binary from the UART and dumps it into application RAM.
-fn item_load_from_uart<'a>(_menu: &Menu, _item: &Item, _args: &[&str], _context: &mut MenuContext) {
+/// Reads an application from the UART and dumps it into application RAM.
+fn item_load_from_uart<'a>(_menu: &Menu, item: &Item, args: &[&str], _context: &mut MenuContext) {
     let application_ram: &'static mut [u8] =
         unsafe { core::slice::from_raw_parts_mut(APPLICATION_START_ADDR, APPLICATION_LEN) };
+    // Anything we print now would get mixed up with the protocol
+    let echo = crate::set_uart_echo(false);
+    ELF_ENTRY.store(0, Ordering::Relaxed);
+    LOADED_LEN.store(0, Ordering::Relaxed);
+    set_program_name("");
+    if let Ok(Some(_)) = ::menu::argument_finder(item, args, "binary") {
+        load_binary(application_ram);
+    } else {
+        load_hex(application_ram);
+    }
+    crate::set_uart_echo(echo);
+}
+
+/// Reads ASCII hex from the UART and dumps it into application RAM.
+fn load_hex(application_ram: &mut [u8]) {
     for b in application_ram.iter_mut() {
         *b = 0x00;
     }
     println!("Reading hex...");
-    GLOBAL_CONTEXT
-        .lock()
-        .as_mut()
-        .unwrap()
-        .usb_uart
-        .write_all(b"READY\r\n");
+    USB_UART.lock().as_mut().unwrap().write_all(b"READY\r\n");
     let mut i = 0;
     let max_bytes = application_ram.len();
     const ACK_EVERY: usize = 4;
     let mut ack_count = 0;
     while i < max_bytes {
         let ch = loop {
-            match GLOBAL_CONTEXT.lock().as_mut().unwrap().usb_uart.read() {
+            crate::watchdog::feed();
+            match USB_UART.lock().as_mut().unwrap().read() {
                 Ok(x) => break x,
                 _ => {}
             }
@@ -415,7 +662,8 @@ fn item_load_from_uart<'a>(_menu: &Menu, _item: &Item, _args: &[&str], _context:
             _ => break,
         };
         let ch = loop {
-            match GLOBAL_CONTEXT.lock().as_mut().unwrap().usb_uart.read() {
+            crate::watchdog::feed();
+            match USB_UART.lock().as_mut().unwrap().read() {
                 Ok(x) => break x,
                 _ => {}
             }
@@ -448,36 +696,567 @@ fn item_load_from_uart<'a>(_menu: &Menu, _item: &Item, _args: &[&str], _context:
         application_ram[i] = byte;
         ack_count += 1;
         if ack_count >= ACK_EVERY {
-            let _ = GLOBAL_CONTEXT.lock().as_mut().unwrap().usb_uart.write(b'X');
+            let _ = USB_UART.lock().as_mut().unwrap().write(b'X');
             ack_count = 0;
         }
         i = i + 1;
     }
     let digest = crc::crc32::checksum_ieee(&application_ram[0..i]);
     println!("Loaded {} bytes, CRC32 0x{:08x}", i, digest);
+    LOADED_LEN.store(i as u32, Ordering::Relaxed);
+}
+
+/// Reads framed binary from the UART (see `monotron-load-protocol`) and
+/// dumps it into application RAM.
+fn load_binary(application_ram: &mut [u8]) {
+    println!("Reading binary...");
+    let mut loader = monotron_load_protocol::Loader::new(application_ram);
+    let mut lock = USB_UART.lock();
+    let uart = lock.as_mut().unwrap();
+    uart.write_all(b"READY\r\n");
+    let mut last_activity = frame_count();
+    while loader.status() == monotron_load_protocol::Status::Running {
+        crate::watchdog::feed();
+        let response = match uart.read() {
+            Ok(byte) => {
+                last_activity = frame_count();
+                loader.handle_byte(byte)
+            }
+            Err(_) if frame_count().wrapping_sub(last_activity) > LOAD_TIMEOUT_FRAMES => {
+                last_activity = frame_count();
+                loader.handle_timeout()
+            }
+            Err(_) => None,
+        };
+        if let Some(response) = response {
+            uart.write_all(&response.to_bytes());
+        }
+    }
+    drop(lock);
+    match loader.status() {
+        monotron_load_protocol::Status::Complete { length, crc32 } => {
+            println!("Loaded {} bytes, CRC32 0x{:08x}", length, crc32);
+            LOADED_LEN.store(length as u32, Ordering::Relaxed);
+        }
+        status => println!("Load failed: {:?}", status),
+    }
 }
 
+/// How many frames to wait for the host before asking it to send again.
+const LOAD_TIMEOUT_FRAMES: u32 = 60;
+
 /// Print some debug info.
-fn item_debug_info<'a>(_menu: &Menu, _item: &Item, _args: &[&str], _context: &mut MenuContext) {
-    println!("Framebuffer: {:08p}", unsafe { &FRAMEBUFFER as *const _ });
-    println!("Application: {:08p}", APPLICATION_START_ADDR);
-    println!("Chip:\n{:#?}", tm4c123x_hal::sysctl::chip_id::get());
+fn item_debug_info<'a>(_menu: &Menu, item: &Item, args: &[&str], _context: &mut MenuContext) {
+    match ::menu::argument_finder(item, args, "VIEW") {
+        Ok(None) => {
+            println!("Framebuffer: {:08p}", unsafe { &FRAMEBUFFER as *const _ });
+            println!("Application: {:08p}", APPLICATION_START_ADDR);
+            println!("Chip:\n{:#?}", tm4c123x_hal::sysctl::chip_id::get());
+            crate::serial::report();
+        }
+        Ok(Some("mem")) => crate::memory::report(),
+        _ => println!("Error: VIEW must be mem"),
+    }
+}
+
+/// Turns on (or off) the copying of console output to the USB UART, so that
+/// a host can drive the shell.
+fn item_remote<'a>(_menu: &Menu, item: &Item, args: &[&str], _context: &mut MenuContext) {
+    match ::menu::argument_finder(item, args, "STATE") {
+        Ok(Some("on")) => {
+            crate::set_uart_echo(true);
+        }
+        Ok(Some("off")) => {
+            crate::set_uart_echo(false);
+        }
+        _ => println!("Error: STATE must be on or off"),
+    }
 }
 
 /// Runs a program from application RAM, then returns.
-fn item_run_program<'a>(_menu: &Menu, _item: &Item, _args: &[&str], _context: &mut MenuContext) {
+fn item_run_program<'a>(_menu: &Menu, _item: &Item, args: &[&str], _context: &mut MenuContext) {
+    let mut argv = [""; api::MAX_ARGS];
+    if args.len() >= argv.len() {
+        println!("Error: Too many arguments.");
+        return;
+    }
+    // Take a copy, so we don't hold the lock while the program runs
+    let name = {
+        let name = PROGRAM_NAME.lock();
+        ShortName::new(core::str::from_utf8(&name.0[0..name.1]).unwrap_or(""))
+    };
+    argv[0] = match name.as_ref().map(ShortName::as_str) {
+        Some(name) if !name.is_empty() => name,
+        _ => "APP",
+    };
+    argv[1..=args.len()].copy_from_slice(args);
+    if let Some(result) = run_program(&argv[0..=args.len()]) {
+        println!("\u{001B}W\u{001B}k\n\nResult: {}", result);
+    }
+}
+
+/// Shows the exit status of the last program.
+fn item_status<'a>(_menu: &Menu, _item: &Item, _args: &[&str], _context: &mut MenuContext) {
+    println!("Last exit status: {}", exit_status());
+}
+
+/// The exit status of the last program to run.
+pub(crate) fn exit_status() -> u32 {
+    EXIT_STATUS.load(Ordering::Relaxed)
+}
+
+/// How many bytes of application RAM the last program we loaded takes up.
+pub(crate) fn loaded_len() -> usize {
+    LOADED_LEN.load(Ordering::Relaxed) as usize
+}
+
+/// Remember the name of the program in application RAM.
+fn set_program_name(filename: &str) {
+    let mut name = PROGRAM_NAME.lock();
+    let len = filename.len().min(name.0.len());
+    name.0[0..len].copy_from_slice(&filename.as_bytes()[0..len]);
+    name.1 = len;
+}
+
+/// Gets or sets the program search path.
+fn item_path<'a>(_menu: &Menu, item: &Item, args: &[&str], _context: &mut MenuContext) {
+    if let Ok(Some(dirs)) = ::menu::argument_finder(item, args, "DIRS") {
+        let mut buffer = [0u8; 64];
+        if dirs.len() > buffer.len() {
+            println!("Error: Path too long.");
+            return;
+        }
+        buffer[0..dirs.len()].copy_from_slice(dirs.as_bytes());
+        *SEARCH_PATH.lock() = Some((buffer, dirs.len()));
+    }
+    let path = *SEARCH_PATH.lock();
+    println!("PATH={}", search_path(&path));
+}
+
+/// Get the search path out of `SEARCH_PATH`.
+fn search_path(path: &Option<([u8; 64], usize)>) -> &str {
+    match path {
+        Some((buffer, len)) => core::str::from_utf8(&buffer[0..*len]).unwrap_or(""),
+        None => DEFAULT_SEARCH_PATH,
+    }
+}
+
+impl CommandLine {
+    /// Make a new, empty, command line.
+    pub(crate) const fn new() -> CommandLine {
+        CommandLine {
+            buffer: [0u8; 64],
+            used: 0,
+        }
+    }
+
+    /// Handle a byte typed at the prompt. Most just go to the menu runner,
+    /// but if Enter is pressed on a command the menu doesn't know, we look
+    /// for a program with that name instead.
+    pub(crate) fn input_byte(&mut self, runner: &mut menu::Runner<MenuContext>, octet: u8) {
+        match octet {
+            b'\r' => {
+                let buffer = self.buffer;
+                let used = self.used;
+                self.used = 0;
+                let line = core::str::from_utf8(&buffer[0..used]).unwrap_or("");
+                let redirect = output::parse(line);
+                if let Ok((command, None)) = redirect {
+                    if command.len() == line.len() && is_menu_command(line) {
+                        runner.input_byte(octet);
+                        return;
+                    }
+                }
+                // Quietly empty the runner's buffer, leaving the command on
+                // screen.
+                let quiet = crate::set_menu_quiet(true);
+                for _ in 0..used {
+                    runner.input_byte(0x08);
+                }
+                crate::set_menu_quiet(quiet);
+                let _ = MenuContext.write_str("\n");
+                match redirect {
+                    Ok((line, None)) if is_menu_command(line) => call_menu_command(line),
+                    Ok((line, None)) => self.run_external(runner, line),
+                    Ok((line, Some(target))) => match output::start(target) {
+                        Ok(()) => {
+                            if is_menu_command(line) {
+                                call_menu_command(line);
+                            } else {
+                                self.run_external(runner, line);
+                            }
+                            output::finish();
+                        }
+                        Err(message) => println!("Error: {}", message),
+                    },
+                    Err(message) => println!("Error: {}", message),
+                }
+                runner.prompt(true);
+            }
+            0x08 | 0x7F => {
+                self.used = self.used.saturating_sub(1);
+                runner.input_byte(octet);
+            }
+            _ => {
+                if self.used < self.buffer.len() {
+                    self.buffer[self.used] = octet;
+                    self.used += 1;
+                }
+                runner.input_byte(octet);
+            }
+        }
+    }
+
+    /// Run a whole command line, as if it had been typed in.
+    pub(crate) fn execute(&mut self, runner: &mut menu::Runner<MenuContext>, line: &str) {
+        for &octet in line.as_bytes() {
+            self.input_byte(runner, octet);
+        }
+        self.input_byte(runner, b'\r');
+    }
+
+    /// Look for a program (or batch file) named by the first word on the
+    /// line, and run it with the rest of the line as its arguments.
+    fn run_external(&mut self, runner: &mut menu::Runner<MenuContext>, line: &str) {
+        let mut argv = [""; api::MAX_ARGS];
+        let mut argc = 0;
+        for word in line.split_whitespace() {
+            if argc == argv.len() {
+                println!("Error: Too many arguments.");
+                return;
+            }
+            argv[argc] = word;
+            argc += 1;
+        }
+        let found = match find_program(argv[0]) {
+            Ok(Some(found)) => found,
+            Ok(None) => {
+                println!("Command {:?} not found.", argv[0]);
+                return;
+            }
+            Err(e) => {
+                println!("Error: {:?}", e);
+                return;
+            }
+        };
+        if found.is_batch() {
+            crate::batch::run(self, runner, &found, &argv[1..argc]);
+        } else {
+            run_found(&found, &argv[1..argc]);
+        }
+    }
+}
+
+/// Run one of the commands in the root menu, as the `menu` runner would.
+/// We need this when the output is redirected, because the runner only
+/// sees the command line before we take the redirection off the end.
+fn call_menu_command(line: &str) {
+    let mut words = line.split_whitespace();
+    let command = words.next().unwrap_or("");
+    let item = match ROOT_MENU.items.iter().find(|item| item.command == command) {
+        Some(item) => item,
+        None => {
+            println!("Error: {:?} can't be redirected.", command);
+            return;
+        }
+    };
+    let (function, parameters) = match item.item_type {
+        menu::ItemType::Callback {
+            function,
+            parameters,
+        } => (function, parameters),
+        _ => {
+            println!("Error: {:?} can't be redirected.", command);
+            return;
+        }
+    };
+    let mut args = [""; 16];
+    let mut argc = 0;
+    for word in words {
+        if argc == args.len() {
+            println!("Error: Too many arguments.");
+            return;
+        }
+        args[argc] = word;
+        argc += 1;
+    }
+    let mandatory = parameters
+        .iter()
+        .filter(|p| match p {
+            menu::Parameter::Mandatory { .. } => true,
+            _ => false,
+        })
+        .count();
+    let positional = args[0..argc]
+        .iter()
+        .filter(|arg| !arg.starts_with("--"))
+        .count();
+    if positional < mandatory {
+        println!("Error: Insufficient arguments given.");
+        return;
+    }
+    function(&ROOT_MENU, item, &args[0..argc], &mut MenuContext);
+}
+
+/// Is this command line empty, or something the menu will understand?
+fn is_menu_command(line: &str) -> bool {
+    match line.split_whitespace().next() {
+        None | Some("help") => true,
+        Some(command) => ROOT_MENU.items.iter().any(|item| item.command == command),
+    }
+}
+
+impl ShortName {
+    /// Copy a name. Returns `None` if it's too long.
+    pub(crate) fn new(name: &str) -> Option<ShortName> {
+        let mut result = ShortName {
+            bytes: [0u8; 12],
+            len: name.len(),
+        };
+        result
+            .bytes
+            .get_mut(0..name.len())?
+            .copy_from_slice(name.as_bytes());
+        Some(result)
+    }
+
+    /// Get the name back.
+    pub(crate) fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[0..self.len]).unwrap_or("")
+    }
+}
+
+impl core::fmt::Write for ShortName {
+    /// Fails if the name would get too long.
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let end = self.len + s.len();
+        let bytes = self.bytes.get_mut(self.len..end).ok_or(core::fmt::Error)?;
+        bytes.copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+impl FoundFile {
+    /// A file in the root directory.
+    pub(crate) fn in_root(name: &str) -> Option<FoundFile> {
+        Some(FoundFile {
+            dir: ShortName::new("")?,
+            file: ShortName::new(name)?,
+        })
+    }
+
+    /// The file's name (without the directory).
+    pub(crate) fn name(&self) -> &str {
+        self.file.as_str()
+    }
+
+    /// Is this a batch file, rather than a program?
+    pub(crate) fn is_batch(&self) -> bool {
+        self.name().ends_with(".BAT")
+    }
+}
+
+/// Look for a program called `NAME.BIN`, `NAME.ELF` or `NAME.BAT` (or just
+/// `NAME`, if it has an extension), first in the root directory and then in
+/// each directory in the search path.
+fn find_program(
+    name: &str,
+) -> Result<Option<FoundFile>, embedded_sdmmc::Error<embedded_sdmmc::SdMmcError>> {
+    let path = *SEARCH_PATH.lock();
+    let path = search_path(&path);
+    let f = |cont: &mut Storage| -> Result<Option<FoundFile>, embedded_sdmmc::Error<_>> {
+        let mut volume = cont.get_volume(embedded_sdmmc::VolumeIdx(0))?;
+        let root = cont.open_root_dir(&volume)?;
+        let mut found = None;
+        for dir_name in core::iter::once("").chain(path.split(';')) {
+            let dir_name = match ShortName::new(dir_name.trim()) {
+                Some(dir_name) => dir_name,
+                None => continue,
+            };
+            let subdir = if dir_name.as_str().is_empty() {
+                None
+            } else {
+                match cont.open_dir(&volume, &root, dir_name.as_str()) {
+                    Ok(dir) => Some(dir),
+                    Err(_) => continue,
+                }
+            };
+            for ext in PROGRAM_EXTENSIONS.iter() {
+                let mut buffer = [0u8; 12];
+                let filename = match program_filename(name, ext, &mut buffer) {
+                    Some(filename) => filename,
+                    None => continue,
+                };
+                let dir = subdir.as_ref().unwrap_or(&root);
+                if let Ok(file) = cont.open_file_in_dir(
+                    &mut volume,
+                    dir,
+                    filename,
+                    embedded_sdmmc::Mode::ReadOnly,
+                ) {
+                    let _ = cont.close_file(&volume, file);
+                    found = ShortName::new(filename).map(|file| FoundFile {
+                        dir: dir_name,
+                        file,
+                    });
+                    break;
+                }
+            }
+            if let Some(dir) = subdir {
+                cont.close_dir(&volume, dir);
+            }
+            if found.is_some() {
+                break;
+            }
+        }
+        cont.close_dir(&volume, root);
+        Ok(found)
+    };
+    f(STORAGE.lock().as_mut().unwrap())
+}
+
+/// Open a file we found with `find_program` (or made with
+/// `FoundFile::in_root`), and pass it to `f`. The file and its directories
+/// are closed again afterwards.
+pub(crate) fn with_found_file<F, R>(
+    found: &FoundFile,
+    mut f: F,
+) -> Result<R, embedded_sdmmc::Error<embedded_sdmmc::SdMmcError>>
+where
+    F: FnMut(
+        &mut Storage,
+        &embedded_sdmmc::Volume,
+        &mut embedded_sdmmc::File,
+    ) -> Result<R, embedded_sdmmc::Error<embedded_sdmmc::SdMmcError>>,
+{
+    let mut lock = STORAGE.lock();
+    let cont = lock.as_mut().unwrap();
+    let mut volume = cont.get_volume(embedded_sdmmc::VolumeIdx(0))?;
+    let root = cont.open_root_dir(&volume)?;
+    let subdir = if found.dir.as_str().is_empty() {
+        None
+    } else {
+        match cont.open_dir(&volume, &root, found.dir.as_str()) {
+            Ok(dir) => Some(dir),
+            Err(e) => {
+                cont.close_dir(&volume, root);
+                return Err(e);
+            }
+        }
+    };
+    let result = match cont.open_file_in_dir(
+        &mut volume,
+        subdir.as_ref().unwrap_or(&root),
+        found.name(),
+        embedded_sdmmc::Mode::ReadOnly,
+    ) {
+        Ok(mut file) => {
+            let result = f(cont, &volume, &mut file);
+            let _ = cont.close_file(&volume, file);
+            result
+        }
+        Err(e) => Err(e),
+    };
+    if let Some(dir) = subdir {
+        cont.close_dir(&volume, dir);
+    }
+    cont.close_dir(&volume, root);
+    result
+}
+
+/// Load a program we found with `find_program`, and run it.
+fn run_found(found: &FoundFile, args: &[&str]) {
+    let name = found.name();
+    match with_found_file(found, |cont, volume, file| {
+        load_program(cont, volume, file, name)
+    }) {
+        Ok(true) => {}
+        Ok(false) => return,
+        Err(e) => {
+            println!("Error: {:?}", e);
+            return;
+        }
+    }
+    let mut argv = [""; api::MAX_ARGS];
+    argv[0] = name;
+    argv[1..=args.len()].copy_from_slice(args);
+    if let Some(result) = run_program(&argv[0..=args.len()]) {
+        println!("\u{001B}W\u{001B}k\n\nResult: {}", result);
+    }
+}
+
+/// Work out the filename to try for a program. If `name` already has an
+/// extension we use it as-is (but only once), otherwise we add `ext`.
+fn program_filename<'b>(name: &str, ext: &str, buffer: &'b mut [u8; 12]) -> Option<&'b str> {
+    let len = if name.contains('.') {
+        if ext != PROGRAM_EXTENSIONS[0] {
+            return None;
+        }
+        if name.len() > buffer.len() {
+            return None;
+        }
+        buffer[0..name.len()].copy_from_slice(name.as_bytes());
+        name.len()
+    } else {
+        if name.len() > 8 {
+            return None;
+        }
+        buffer[0..name.len()].copy_from_slice(name.as_bytes());
+        buffer[name.len()] = b'.';
+        buffer[name.len() + 1..name.len() + 1 + ext.len()].copy_from_slice(ext.as_bytes());
+        name.len() + 1 + ext.len()
+    };
+    buffer[0..len].make_ascii_uppercase();
+    core::str::from_utf8(&buffer[0..len]).ok()
+}
+
+/// Runs the program in application RAM, passing it `args` (the first of
+/// which is its name). Returns its exit status, or `None` if it couldn't be
+/// run.
+pub(crate) fn run_program(args: &[&str]) -> Option<u32> {
     let application_ram: &'static mut [u8] =
         unsafe { core::slice::from_raw_parts_mut(APPLICATION_START_ADDR, APPLICATION_LEN) };
-    let addr = ((application_ram[3] as u32) << 24)
-        | ((application_ram[2] as u32) << 16)
-        | ((application_ram[1] as u32) << 8)
-        | ((application_ram[0] as u32) << 0);
+    let addr = match ELF_ENTRY.load(Ordering::Relaxed) {
+        0 => {
+            ((application_ram[3] as u32) << 24)
+                | ((application_ram[2] as u32) << 16)
+                | ((application_ram[1] as u32) << 8)
+                | ((application_ram[0] as u32) << 0)
+        }
+        entry => entry,
+    };
+    // Must be a Thumb address, inside application RAM
+    let start = APPLICATION_START_ADDR as u32;
+    if (addr & 1) == 0 || addr < start || addr >= start + APPLICATION_LEN as u32 {
+        println!("Error: 0x{:08x} is not a valid entry point.", addr);
+        return None;
+    }
+    if !check_app_header(application_ram) {
+        return None;
+    }
+    if api::set_args(args).is_err() {
+        println!("Error: Arguments too long.");
+        return None;
+    }
     println!("Executing from 0x{:08x}", addr);
-    let ptr = addr as *const ();
-    let result = unsafe {
-        let code: extern "C" fn(*const api::Api) -> u32 = ::core::mem::transmute(ptr);
-        code(&api::CALLBACK_TABLE)
+    let result = match sandbox::run(addr) {
+        Ok(result) => result,
+        Err(fault) => {
+            println!("\u{001B}W\u{001B}k\n\u{001B}R{}\u{001B}W", fault);
+            CRASHED_EXIT_STATUS
+        }
     };
+    reset_app_state();
+    EXIT_STATUS.store(result, Ordering::Relaxed);
+    Some(result)
+}
+
+/// Undo anything an application might have changed: stop the audio, put the
+/// font and the line mapping back, turn the cursor back on, and stop any
+/// timers and the vertical-blank hook. We do this however the application
+/// stopped.
+fn reset_app_state() {
     // Stop any audio
     unsafe {
         crate::G_SYNTH.play(
@@ -503,100 +1282,102 @@ fn item_run_program<'a>(_menu: &Menu, _item: &Item, _args: &[&str], _context: &m
     api::change_font(0, core::ptr::null());
     // Turn the cursor on
     api::set_cursor_visible(1);
-    // clear the screen
-    println!("\u{001B}W\u{001B}k\n\nResult: {}", result);
-}
-
-/// Makes a short beep.
-///
-/// The first argument sets the waveform (sine, sawtooth, square or noise).
-/// The second sets the frequency (in Hz).
-/// The third sets the duration (in 60Hz frames).
-/// The fourth sets the channel.
-fn item_beep<'a>(_menu: &Menu, item: &Item, args: &[&str], _context: &mut MenuContext) {
-    use monotron_synth::*;
-    let waveform_arg = ::menu::argument_finder(item, args, "wave");
-    let frequency_arg = ::menu::argument_finder(item, args, "freq");
-    let length_arg = ::menu::argument_finder(item, args, "len");
-    let channel_arg = ::menu::argument_finder(item, args, "chan");
+    // Put every scan-line back where it belongs
+    for line in 0..SCAN_LINES {
+        api::map_line(line, line);
+    }
+    // Stop any software timers
+    crate::timers::stop_all();
+    // Forget the vertical-blank hook
+    crate::vblank::reset();
+}
 
-    let waveform = match waveform_arg {
-        Ok(Some("square")) | Ok(None) => Waveform::Square,
-        Ok(Some("sine")) => Waveform::Sine,
-        Ok(Some("sawtooth")) => Waveform::Sawtooth,
-        Ok(Some("noise")) => Waveform::Noise,
-        e => {
-            println!("Unknown wave argument {:?}", e);
-            return;
-        }
+/// Print the details from the application's header, if it has one.
+fn show_app_header(application_ram: &[u8]) {
+    let header = match monotron_api::AppHeader::parse(application_ram) {
+        Some(header) => header,
+        None => return,
     };
-    let frequency = if let Ok(Some(arg)) = frequency_arg {
-        match u16::from_str_radix(arg, 10) {
-            Ok(f) => f,
-            Err(e) => {
-                println!("Bad frequency argument {:?}", e);
-                return;
-            }
-        }
-    } else {
-        440
-    };
-    let duration = if let Ok(Some(arg)) = length_arg {
-        match usize::from_str_radix(arg, 10) {
-            Ok(f) => f,
-            Err(e) => {
-                println!("Bad duration argument {:?}", e);
-                return;
-            }
-        }
+    print!(
+        "Application {:?}, needs API {}, {} bytes stack",
+        header.name(),
+        header.min_api_version,
+        header.stack_len
+    );
+    if !header.is_stamped() {
+        println!(", not stamped");
+    } else if header.check_crc32(application_ram) {
+        println!(", {} bytes, CRC OK", header.image_len);
     } else {
-        60
-    };
-    let channel = match channel_arg {
-        Ok(Some("0")) | Ok(None) => CHANNEL_0,
-        Ok(Some("1")) => CHANNEL_1,
-        Ok(Some("2")) => CHANNEL_2,
-        e => {
-            println!("Unknown duration argument {:?}", e);
-            return;
+        println!(", {} bytes, CRC BAD", header.image_len);
+    }
+}
+
+/// Check the application's header (if it has one) says it will work with
+/// this ROM. Returns `false` if it won't.
+fn check_app_header(application_ram: &[u8]) -> bool {
+    let header = match monotron_api::AppHeader::parse(application_ram) {
+        Some(header) => header,
+        None => {
+            println!("Warning: No application header.");
+            return true;
         }
     };
-
-    println!(
-        "Playing...\r\nWaveform: {:?}\r\nFreq: {} Hz\r\nDuration: {} frames",
-        waveform, frequency, duration
-    );
-
-    unsafe {
-        crate::G_SYNTH.play(
-            channel,
-            Frequency::from_hertz(frequency),
-            MAX_VOLUME,
-            waveform,
+    if !monotron_api::API_VERSION.satisfies(header.min_api_version) {
+        println!(
+            "Error: {:?} needs API {}, but this ROM has API {}.",
+            header.name(),
+            header.min_api_version,
+            monotron_api::API_VERSION
         );
+        return false;
     }
-
-    for _ in 0..duration {
-        api::wfvbi();
+    if header.image_len as usize > application_ram.len() {
+        println!(
+            "Error: {:?} claims to be {} bytes long, which is too big.",
+            header.name(),
+            header.image_len
+        );
+        return false;
     }
-
-    unsafe {
-        crate::G_SYNTH.play(channel, Frequency::from_hertz(frequency), 0, waveform);
+    // Applications get their own stack, at the top of application RAM
+    let free_stack = application_ram.len() - header.image_len as usize;
+    if header.stack_len as usize > free_stack {
+        println!(
+            "Error: {:?} needs {} bytes of stack, but only {} are free.",
+            header.name(),
+            header.stack_len,
+            free_stack
+        );
+        return false;
     }
+    if header.is_stamped() && !header.check_crc32(application_ram) {
+        // It might have been run before, and changed its own variables.
+        println!(
+            "Warning: {:?} has a bad CRC. It may be corrupt, or it may have already been run.",
+            header.name()
+        );
+    }
+    true
+}
+
+/// Makes a short beep. See `monotron_shell::commands::beep`.
+fn item_beep<'a>(_menu: &Menu, _item: &Item, args: &[&str], _context: &mut MenuContext) {
+    monotron_shell::commands::beep(&mut Rom, args);
 }
 
 /// Init the card and dump some details
 fn item_mount<'a>(_menu: &Menu, _item: &Item, _args: &[&str], _context: &mut MenuContext) {
-    let f = |c: &mut Context| -> Result<(), embedded_sdmmc::SdMmcError> {
+    let f = |cont: &mut Storage| -> Result<(), embedded_sdmmc::SdMmcError> {
         print!("Init SD card...");
-        c.cont.device().init()?;
-        c.cont.device().spi().reclock(10u32.mhz(), &c.clocks);
+        cont.device().init()?;
+        cont.device().spi().reclock(10u32.mhz(), crate::clocks());
         print!("OK!\nCard size...");
-        let size = c.cont.device().card_size_bytes()?;
+        let size = cont.device().card_size_bytes()?;
         println!("{}", size);
         Ok(())
     };
-    match f(GLOBAL_CONTEXT.lock().as_mut().unwrap()) {
+    match f(STORAGE.lock().as_mut().unwrap()) {
         Err(e) => println!("Error: {:?}", e),
         _ => (),
     }
@@ -605,247 +1386,514 @@ fn item_mount<'a>(_menu: &Menu, _item: &Item, _args: &[&str], _context: &mut Men
 /// De-init the card so it can't be used.
 fn item_unmount<'a>(_menu: &Menu, _item: &Item, _args: &[&str], _context: &mut MenuContext) {
     print!("De-init SD card...");
-    GLOBAL_CONTEXT
-        .lock()
-        .as_mut()
-        .unwrap()
-        .cont
-        .device()
-        .deinit();
+    STORAGE.lock().as_mut().unwrap().device().deinit();
     println!("OK!");
 }
 
 /// List the root directory
-fn item_dir<'a>(_menu: &Menu, _item: &Item, _args: &[&str], _context: &mut MenuContext) {
-    let f = |c: &mut Context| -> Result<(), embedded_sdmmc::Error<_>> {
-        let v = c.cont.get_volume(embedded_sdmmc::VolumeIdx(0))?;
-        let dir = c.cont.open_root_dir(&v)?;
-        c.cont.iterate_dir(&v, &dir, |x| {
-            if !x.attributes.is_hidden() && !x.attributes.is_volume() {
-                if x.attributes.is_directory() {
-                    println!("{:13} {} <DIR>", x.name, x.mtime);
-                } else {
-                    println!("{:13} {} {} bytes", x.name, x.mtime, x.size);
-                }
-            }
-        })?;
-        c.cont.close_dir(&v, dir);
-        Ok(())
-    };
-    match f(GLOBAL_CONTEXT.lock().as_mut().unwrap()) {
-        Err(e) => println!("Error: {:?}", e),
-        _ => (),
-    }
+fn item_dir<'a>(_menu: &Menu, _item: &Item, args: &[&str], _context: &mut MenuContext) {
+    monotron_shell::commands::dir(&mut Rom, args);
 }
 
 /// Load a file from the SD card.
 /// TODO work out how to release the directory handle and file handle when the
 /// function aborts (e.g. with file not found).
 fn item_dload<'a>(_menu: &Menu, item: &Item, args: &[&str], _context: &mut MenuContext) {
-    let f = |c: &mut Context| -> Result<(), embedded_sdmmc::Error<_>> {
+    let f = |cont: &mut Storage| -> Result<(), embedded_sdmmc::Error<_>> {
         let filename = ::menu::argument_finder(item, args, "FILE")
             .unwrap()
             .unwrap();
         print!("Loading {:?}...", filename);
-        let volume = c.cont.get_volume(embedded_sdmmc::VolumeIdx(0))?;
-        let dir = c.cont.open_root_dir(&volume)?;
-        let mut f =
-            match c
-                .cont
-                .open_file_in_dir(&volume, &dir, filename, embedded_sdmmc::Mode::ReadOnly)
-            {
-                Ok(f) => f,
-                Err(e) => {
-                    c.cont.close_dir(&volume, dir);
-                    return Err(e);
+        let mut volume = cont.get_volume(embedded_sdmmc::VolumeIdx(0))?;
+        let dir = cont.open_root_dir(&volume)?;
+        let mut f = match cont.open_file_in_dir(
+            &mut volume,
+            &dir,
+            filename,
+            embedded_sdmmc::Mode::ReadOnly,
+        ) {
+            Ok(f) => f,
+            Err(e) => {
+                cont.close_dir(&volume, dir);
+                return Err(e);
+            }
+        };
+        let result = load_program(cont, &volume, &mut f, filename);
+        cont.close_file(&volume, f)?;
+        cont.close_dir(&volume, dir);
+        result.map(|_| ())
+    };
+    match f(STORAGE.lock().as_mut().unwrap()) {
+        Err(e) => println!("Error: {:?}", e),
+        _ => (),
+    }
+}
+
+/// Load a program (a flat binary or an ELF file) from an open file into
+/// application RAM, ready for `run_program`. Returns `Ok(false)` if it was a
+/// bad ELF file.
+fn load_program(
+    cont: &mut Storage,
+    volume: &embedded_sdmmc::Volume,
+    f: &mut embedded_sdmmc::File,
+    filename: &str,
+) -> Result<bool, embedded_sdmmc::Error<embedded_sdmmc::SdMmcError>> {
+    let application_ram: &'static mut [u8] =
+        unsafe { core::slice::from_raw_parts_mut(APPLICATION_START_ADDR, APPLICATION_LEN) };
+    ELF_ENTRY.store(0, Ordering::Relaxed);
+    LOADED_LEN.store(0, Ordering::Relaxed);
+    set_program_name(filename);
+    let mut magic = [0u8; 4];
+    cont.read(volume, f, &mut magic)?;
+    if crate::elf::is_elf(&magic) {
+        let file_len = f.length();
+        let mut read = |offset: u32, buffer: &mut [u8]| -> Result<(), ()> {
+            f.seek_from_start(offset)?;
+            let mut done = 0;
+            while done < buffer.len() {
+                match cont.read(volume, f, &mut buffer[done..]) {
+                    Ok(0) | Err(_) => return Err(()),
+                    Ok(n) => done += n,
                 }
-            };
-        let application_ram: &'static mut [u8] =
-            unsafe { core::slice::from_raw_parts_mut(APPLICATION_START_ADDR, APPLICATION_LEN) };
+            }
+            Ok(())
+        };
+        match crate::elf::load(
+            &mut read,
+            file_len,
+            application_ram,
+            APPLICATION_START_ADDR as u32,
+        ) {
+            Ok(loaded) => {
+                println!(
+                    "Loaded ELF: {} segment(s), {} bytes + {} bytes zeroed, entry 0x{:08x}",
+                    loaded.num_segments, loaded.file_bytes, loaded.bss_bytes, loaded.entry
+                );
+                ELF_ENTRY.store(loaded.entry, Ordering::Relaxed);
+                LOADED_LEN.store(loaded.file_bytes + loaded.bss_bytes, Ordering::Relaxed);
+                show_app_header(application_ram);
+                Ok(true)
+            }
+            Err(e) => {
+                println!("Bad ELF file: {:?}", e);
+                Ok(false)
+            }
+        }
+    } else {
         for b in application_ram.iter_mut() {
             *b = 0x00;
         }
-        c.cont.read(&volume, &mut f, application_ram)?;
-        let digest = crc::crc32::checksum_ieee(&application_ram[0..f.length() as usize]);
+        let _ = f.seek_from_start(0);
+        cont.read(volume, f, application_ram)?;
+        let len = core::cmp::min(f.length() as usize, APPLICATION_LEN);
+        let digest = crc::crc32::checksum_ieee(&application_ram[0..len]);
         println!("Loaded {} bytes, CRC32 0x{:08x}", f.length(), digest);
-        c.cont.close_file(&volume, f)?;
-        c.cont.close_dir(&volume, dir);
-        Ok(())
-    };
-    match f(GLOBAL_CONTEXT.lock().as_mut().unwrap()) {
-        Err(e) => println!("Error: {:?}", e),
-        _ => (),
+        LOADED_LEN.store(len as u32, Ordering::Relaxed);
+        show_app_header(application_ram);
+        Ok(true)
     }
 }
 
 /// Do a hex-dump of a file on disk
-/// TODO work out how to release the directory handle and file handle when the
-/// function aborts (e.g. with file not found).
-fn item_ddump<'a>(_menu: &Menu, item: &Item, args: &[&str], _context: &mut MenuContext) {
-    let f = |c: &mut Context| -> Result<(), embedded_sdmmc::Error<_>> {
-        let filename = ::menu::argument_finder(item, args, "FILE")
-            .unwrap()
-            .unwrap();
-        print!("Dumping {:?}...", filename);
-        let volume = c.cont.get_volume(embedded_sdmmc::VolumeIdx(0))?;
-        let dir = c.cont.open_root_dir(&volume)?;
-        let mut f =
-            c.cont
-                .open_file_in_dir(&volume, &dir, filename, embedded_sdmmc::Mode::ReadOnly)?;
-        let application_ram: &'static mut [u8] =
-            unsafe { core::slice::from_raw_parts_mut(APPLICATION_START_ADDR, APPLICATION_LEN) };
-        c.cont.read(&volume, &mut f, application_ram)?;
-        let digest = crc::crc32::checksum_ieee(&application_ram[0..f.length() as usize]);
-        println!("Loaded {} bytes, CRC32 0x{:08x}", f.length(), digest);
-        const CHUNK_SIZE: usize = 16;
-        let mut lines_printed = 0;
-        const MAX_LINES: usize = 35;
-        for (idx, line) in application_ram[0..f.length() as usize]
-            .chunks(CHUNK_SIZE)
-            .enumerate()
-        {
-            print!("{:06x}:", idx * CHUNK_SIZE);
-            for (idx, b) in line.iter().enumerate() {
-                if (idx % 4) == 0 {
-                    print!(" ");
-                }
-                print!("{:02x}", b);
-            }
-            println!("");
-            lines_printed += 1;
-            if lines_printed == MAX_LINES {
-                lines_printed = 0;
-                print!("Press a key...");
-                loop {
-                    crate::api::wfvbi();
-                    // Wait for new input
-                    match c.input_read() {
-                        None => {}
-                        _ => break,
-                    }
-                }
-                print!("\r                \r");
+fn item_ddump<'a>(_menu: &Menu, _item: &Item, args: &[&str], _context: &mut MenuContext) {
+    monotron_shell::commands::ddump(&mut Rom, args);
+}
+
+/// Display a text file on disk a page at a time
+fn item_dpage<'a>(_menu: &Menu, _item: &Item, args: &[&str], _context: &mut MenuContext) {
+    monotron_shell::commands::dpage(&mut Rom, args);
+}
+
+/// Send a file from the SD card with XMODEM (or XMODEM-1K).
+fn item_xsend<'a>(_menu: &Menu, item: &Item, args: &[&str], _context: &mut MenuContext) {
+    let protocol = match ::menu::argument_finder(item, args, "1k") {
+        Ok(Some(_)) => monotron_xmodem::Protocol::Xmodem1k,
+        _ => monotron_xmodem::Protocol::Xmodem,
+    };
+    send_file(item, args, protocol);
+}
+
+/// Receive a file onto the SD card with XMODEM.
+fn item_xrecv<'a>(_menu: &Menu, item: &Item, args: &[&str], _context: &mut MenuContext) {
+    receive_files(item, args, monotron_xmodem::Protocol::Xmodem);
+}
+
+/// Send a file from the SD card with YMODEM.
+fn item_ysend<'a>(_menu: &Menu, item: &Item, args: &[&str], _context: &mut MenuContext) {
+    send_file(item, args, monotron_xmodem::Protocol::Ymodem);
+}
+
+/// Receive a batch of files onto the SD card with YMODEM.
+fn item_yrecv<'a>(_menu: &Menu, item: &Item, args: &[&str], _context: &mut MenuContext) {
+    receive_files(item, args, monotron_xmodem::Protocol::Ymodem);
+}
+
+/// The UARTs we can do file transfers over.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Port {
+    /// The USB-CDC virtual COM port on the Launchpad
+    Usb,
+    /// The RS-232 port on the Monotron PCB
+    Rs232,
+}
+
+/// How many frames to wait for the remote end before poking the transfer
+/// engine.
+const TRANSFER_TIMEOUT_FRAMES: u32 = (monotron_xmodem::TIMEOUT_MS * 60) / 1000;
+
+/// Passes bytes from the transfer engine to a UART.
+struct SerialChannel<'a, U>(&'a mut U);
+
+impl<'a, U> monotron_xmodem::Channel for SerialChannel<'a, U>
+where
+    U: embedded_hal::serial::Write<u8>,
+{
+    fn write(&mut self, data: &[u8]) {
+        for &b in data {
+            let _ = block!(self.0.write(b));
+        }
+    }
+}
+
+/// Feeds a file on the SD card to the transfer engine.
+struct FileSource<'a, D, T>
+where
+    D: embedded_sdmmc::BlockDevice,
+    T: embedded_sdmmc::TimeSource,
+{
+    cont: &'a mut crate::sdcard::Controller<D, T>,
+    volume: embedded_sdmmc::Volume,
+    dir: embedded_sdmmc::Directory,
+    file: embedded_sdmmc::File,
+    name: &'a str,
+    /// YMODEM asks for the file details once, then asks for the next file.
+    announced: bool,
+}
+
+impl<'a, D, T> monotron_xmodem::Source for FileSource<'a, D, T>
+where
+    D: embedded_sdmmc::BlockDevice,
+    T: embedded_sdmmc::TimeSource,
+{
+    type Error = embedded_sdmmc::Error<D::Error>;
+
+    fn next_file(&mut self) -> Option<monotron_xmodem::FileInfo<'_>> {
+        if self.announced {
+            None
+        } else {
+            self.announced = true;
+            Some(monotron_xmodem::FileInfo {
+                name: self.name,
+                size: self.file.length(),
+            })
+        }
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+        if self.file.eof() {
+            Ok(0)
+        } else {
+            self.cont.read(&self.volume, &mut self.file, buffer)
+        }
+    }
+}
+
+/// Writes files from the transfer engine to the root directory of the SD
+/// card.
+struct FileSink<'a, D, T>
+where
+    D: embedded_sdmmc::BlockDevice,
+    T: embedded_sdmmc::TimeSource,
+{
+    cont: &'a mut crate::sdcard::Controller<D, T>,
+    volume: embedded_sdmmc::Volume,
+    dir: embedded_sdmmc::Directory,
+    /// XMODEM doesn't send a file name, so the user has to give us one.
+    default_name: Option<&'a str>,
+    file: Option<embedded_sdmmc::File>,
+    /// Bytes written to the current file
+    written: usize,
+    /// Files completely received
+    count: usize,
+}
+
+impl<'a, D, T> monotron_xmodem::Sink for FileSink<'a, D, T>
+where
+    D: embedded_sdmmc::BlockDevice,
+    T: embedded_sdmmc::TimeSource,
+{
+    type Error = embedded_sdmmc::Error<D::Error>;
+
+    fn start_file(&mut self, name: Option<&str>, _size: Option<u32>) -> Result<(), Self::Error> {
+        // We only have a root directory, so drop any path the sender gave us
+        let name = match name {
+            Some(name) => name.rsplit('/').next().unwrap_or(name),
+            None => self.default_name.unwrap_or("XMODEM.BIN"),
+        };
+        print!("Receiving {:?}...", name);
+        let file = self.cont.open_file_in_dir(
+            &mut self.volume,
+            &self.dir,
+            name,
+            embedded_sdmmc::Mode::ReadWriteCreateOrTruncate,
+        )?;
+        self.file = Some(file);
+        self.written = 0;
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+        if let Some(file) = self.file.as_mut() {
+            let written = self.cont.write(&mut self.volume, file, data)?;
+            self.written += written;
+            if written != data.len() {
+                // The card is full
+                return Err(embedded_sdmmc::Error::NotEnoughSpace);
             }
         }
-        c.cont.close_file(&volume, f)?;
-        c.cont.close_dir(&volume, dir);
         Ok(())
-    };
-    match f(GLOBAL_CONTEXT.lock().as_mut().unwrap()) {
-        Err(e) => println!("Error: {:?}", e),
-        _ => (),
+    }
+
+    fn end_file(&mut self) -> Result<(), Self::Error> {
+        if let Some(file) = self.file.take() {
+            self.cont.close_file(&self.volume, file)?;
+            println!("OK ({} bytes)", self.written);
+            self.count += 1;
+        }
+        Ok(())
     }
 }
 
-/// Display a text file on disk a page at a time
-/// TODO work out how to release the directory handle and file handle when the
-/// function aborts (e.g. with file not found).
-fn item_dpage<'a>(_menu: &Menu, item: &Item, args: &[&str], _context: &mut MenuContext) {
-    let f = |c: &mut Context| -> Result<(), embedded_sdmmc::Error<_>> {
-        let filename = ::menu::argument_finder(item, args, "FILE")
-            .unwrap()
-            .unwrap();
-        println!("Displaying {:?}...", filename);
-        let volume = c.cont.get_volume(embedded_sdmmc::VolumeIdx(0))?;
-        let dir = c.cont.open_root_dir(&volume)?;
-        let mut f =
-            c.cont
-                .open_file_in_dir(&volume, &dir, filename, embedded_sdmmc::Mode::ReadOnly)?;
-        let application_ram: &'static mut [u8] =
-            unsafe { core::slice::from_raw_parts_mut(APPLICATION_START_ADDR, APPLICATION_LEN) };
-        c.cont.read(&volume, &mut f, application_ram)?;
-        let mut lines_printed = 0;
-        let mut line_length = 0;
-        const MAX_LINES: usize = 35;
-        for &b in application_ram[0..f.length() as usize].iter() {
-            unsafe {
-                let _ = FRAMEBUFFER.write_character(b);
+/// Run the sending side of a transfer until it completes or fails.
+fn drive_sender<U, S>(
+    uart: &mut U,
+    sender: &mut monotron_xmodem::Sender,
+    source: &mut S,
+) -> monotron_xmodem::Status
+where
+    U: embedded_hal::serial::Read<u8> + embedded_hal::serial::Write<u8>,
+    S: monotron_xmodem::Source,
+{
+    let mut last_activity = frame_count();
+    loop {
+        crate::watchdog::feed();
+        let status = match uart.read() {
+            Ok(byte) => {
+                last_activity = frame_count();
+                sender.handle_byte(byte, &mut SerialChannel(uart), source)
             }
-            line_length += 1;
-            if (b == b'\n') || (line_length == 48) {
-                lines_printed += 1;
-                line_length = 0;
-                if lines_printed == MAX_LINES {
-                    lines_printed = 0;
-                    print!("Press a key...");
-                    loop {
-                        crate::api::wfvbi();
-                        // Wait for new input
-                        match c.input_read() {
-                            None => {}
-                            _ => break,
-                        }
-                    }
-                    print!("\r                \r");
-                }
+            Err(_) if frame_count().wrapping_sub(last_activity) > TRANSFER_TIMEOUT_FRAMES => {
+                last_activity = frame_count();
+                sender.handle_timeout(&mut SerialChannel(uart))
             }
+            Err(_) => monotron_xmodem::Status::Running,
+        };
+        if status != monotron_xmodem::Status::Running {
+            return status;
+        }
+    }
+}
+
+/// Run the receiving side of a transfer until it completes or fails.
+fn drive_receiver<U, S>(
+    uart: &mut U,
+    receiver: &mut monotron_xmodem::Receiver,
+    sink: &mut S,
+) -> monotron_xmodem::Status
+where
+    U: embedded_hal::serial::Read<u8> + embedded_hal::serial::Write<u8>,
+    S: monotron_xmodem::Sink,
+{
+    receiver.start(&mut SerialChannel(uart));
+    let mut last_activity = frame_count();
+    loop {
+        crate::watchdog::feed();
+        let status = match uart.read() {
+            Ok(byte) => {
+                last_activity = frame_count();
+                receiver.handle_byte(byte, &mut SerialChannel(uart), sink)
+            }
+            Err(_) if frame_count().wrapping_sub(last_activity) > TRANSFER_TIMEOUT_FRAMES => {
+                last_activity = frame_count();
+                receiver.handle_timeout(&mut SerialChannel(uart))
+            }
+            Err(_) => monotron_xmodem::Status::Running,
+        };
+        if status != monotron_xmodem::Status::Running {
+            return status;
+        }
+    }
+}
+
+/// If we're about to do a transfer over the USB UART, stop the console
+/// output being copied to it. Returns the old setting, for
+/// `crate::set_uart_echo`.
+fn pause_echo(port: Port) -> bool {
+    let echo = crate::set_uart_echo(false);
+    if port != Port::Usb {
+        crate::set_uart_echo(echo);
+    }
+    echo
+}
+
+/// Work out which UART the user asked for.
+fn parse_port(item: &Item, args: &[&str]) -> Option<Port> {
+    match ::menu::argument_finder(item, args, "port") {
+        Ok(Some("usb")) | Ok(None) => Some(Port::Usb),
+        Ok(Some("rs232")) => Some(Port::Rs232),
+        _ => None,
+    }
+}
+
+/// Send the file named in the `FILE` argument.
+fn send_file(item: &Item, args: &[&str], protocol: monotron_xmodem::Protocol) {
+    let port = match parse_port(item, args) {
+        Some(port) => port,
+        None => {
+            println!("Error: PORT must be usb or rs232");
+            return;
         }
-        c.cont.close_file(&volume, f)?;
-        c.cont.close_dir(&volume, dir);
-        Ok(())
     };
-    match f(GLOBAL_CONTEXT.lock().as_mut().unwrap()) {
+    let filename = ::menu::argument_finder(item, args, "FILE")
+        .unwrap()
+        .unwrap();
+    let f = |cont: &mut Storage| -> Result<monotron_xmodem::Status, embedded_sdmmc::Error<_>> {
+        let mut volume = cont.get_volume(embedded_sdmmc::VolumeIdx(0))?;
+        let dir = cont.open_root_dir(&volume)?;
+        let file = match cont.open_file_in_dir(
+            &mut volume,
+            &dir,
+            filename,
+            embedded_sdmmc::Mode::ReadOnly,
+        ) {
+            Ok(f) => f,
+            Err(e) => {
+                cont.close_dir(&volume, dir);
+                return Err(e);
+            }
+        };
+        println!(
+            "Sending {:?} ({} bytes) with {:?} over {:?}. Start your receiver now.",
+            filename,
+            file.length(),
+            protocol,
+            port
+        );
+        let mut source = FileSource {
+            cont: &mut *cont,
+            volume,
+            dir,
+            file,
+            name: filename,
+            announced: false,
+        };
+        let mut sender = monotron_xmodem::Sender::new(protocol);
+        let status = match port {
+            Port::Usb => drive_sender(USB_UART.lock().as_mut().unwrap(), &mut sender, &mut source),
+            Port::Rs232 => drive_sender(
+                RS232_UART.lock().as_mut().unwrap(),
+                &mut sender,
+                &mut source,
+            ),
+        };
+        let FileSource {
+            volume, dir, file, ..
+        } = source;
+        cont.close_file(&volume, file)?;
+        cont.close_dir(&volume, dir);
+        Ok(status)
+    };
+    // Anything we print now would get mixed up with the transfer
+    let echo = pause_echo(port);
+    let result = f(STORAGE.lock().as_mut().unwrap());
+    crate::set_uart_echo(echo);
+    match result {
+        Ok(monotron_xmodem::Status::Complete) => println!("Transfer complete."),
+        Ok(status) => println!("Transfer failed: {:?}", status),
         Err(e) => println!("Error: {:?}", e),
-        _ => (),
     }
 }
 
-fn rs232_term<'a>(_menu: &Menu, item: &Item, args: &[&str], _context: &mut MenuContext) {
-    if let Some(bitrate) = ::menu::argument_finder(item, args, "bitrate")
-        .unwrap_or(Some("115200"))
-        .map_or(None, |p| u32::from_str_radix(p, 10).ok())
-    {
-        {
-            // Grab the lock
-            let mut lock = GLOBAL_CONTEXT.lock();
-            // Convert to mutable reference and unwrap the Option
-            let ctx = lock.as_mut().unwrap();
-            ctx.rs232_uart.change_baud_rate(bitrate.bps(), &ctx.clocks);
+/// Receive one file (XMODEM) or a batch of files (YMODEM) into the root
+/// directory.
+fn receive_files(item: &Item, args: &[&str], protocol: monotron_xmodem::Protocol) {
+    let port = match parse_port(item, args) {
+        Some(port) => port,
+        None => {
+            println!("Error: PORT must be usb or rs232");
+            return;
         }
-        println!("Connected at {} bps. Ctrl-Q to quit.", bitrate);
-        loop {
-            let mut lock = GLOBAL_CONTEXT.lock();
-            let ctx = lock.as_mut().unwrap();
-            match ctx.rs232_uart.read() {
-                Ok(ch) => {
-                    ctx.write_u8(ch);
-                }
-                Err(_e) => {
-                    // Ignore
-                }
-            }
-            match ctx.input_read() {
-                Some(Input::Cp850(17)) => {
-                    // User pressed Ctrl-Q
-                    break;
-                }
-                Some(Input::Cp850(b'\r')) => {
-                    // User pressed enter
-                    let _ = ctx.rs232_uart.write(b'\r');
-                    let _ = ctx.rs232_uart.write(b'\n');
-                }
-                Some(Input::Cp850(ch)) => {
-                    let _ = ctx.rs232_uart.write(ch);
-                }
-                Some(Input::Special(_code)) => {
-                    // Drop it on the floor
-                }
-                None => {
-                    // Do nothing
+    };
+    // YMODEM tells us the file names
+    let filename = if protocol == monotron_xmodem::Protocol::Ymodem {
+        None
+    } else {
+        ::menu::argument_finder(item, args, "FILE").unwrap()
+    };
+    let f =
+        |cont: &mut Storage| -> Result<(monotron_xmodem::Status, usize), embedded_sdmmc::Error<_>> {
+            let volume = cont.get_volume(embedded_sdmmc::VolumeIdx(0))?;
+            let dir = cont.open_root_dir(&volume)?;
+            println!(
+                "Receiving with {:?} over {:?}. Start your sender now.",
+                protocol, port
+            );
+            let mut sink = FileSink {
+                cont: &mut *cont,
+                volume,
+                dir,
+                default_name: filename,
+                file: None,
+                written: 0,
+                count: 0,
+            };
+            let mut receiver = monotron_xmodem::Receiver::new(protocol);
+            let status = match port {
+                Port::Usb => {
+                    drive_receiver(USB_UART.lock().as_mut().unwrap(), &mut receiver, &mut sink)
                 }
+                Port::Rs232 => drive_receiver(
+                    RS232_UART.lock().as_mut().unwrap(),
+                    &mut receiver,
+                    &mut sink,
+                ),
+            };
+            let FileSink {
+                volume,
+                dir,
+                file,
+                count,
+                ..
+            } = sink;
+            if let Some(file) = file {
+                // The transfer failed part way through. Keep what we have.
+                cont.close_file(&volume, file)?;
             }
-            cortex_m::asm::wfi();
+            cont.close_dir(&volume, dir);
+            Ok((status, count))
+        };
+    // Anything we print now would get mixed up with the transfer
+    let echo = pause_echo(port);
+    let result = f(STORAGE.lock().as_mut().unwrap());
+    crate::set_uart_echo(echo);
+    match result {
+        Ok((monotron_xmodem::Status::Complete, count)) => {
+            println!("Transfer complete. {} file(s) received.", count)
         }
-        println!("Disconnected!");
-    } else {
-        println!("Error: Need an integer baud rate (e.g. 115200)");
+        Ok((status, _)) => println!("Transfer failed: {:?}", status),
+        Err(e) => println!("Error: {:?}", e),
     }
 }
 
+/// Connect the keyboard and screen to the RS-232 port.
+fn rs232_term<'a>(_menu: &Menu, _item: &Item, args: &[&str], _context: &mut MenuContext) {
+    monotron_shell::commands::rterm(&mut Rom, args);
+}
+
 fn midi_term<'a>(_menu: &Menu, _item: &Item, _args: &[&str], _context: &mut MenuContext) {
     println!("Connected at 31,250 bps. Ctrl-Q to quit.");
     loop {
-        match GLOBAL_CONTEXT.lock().as_mut().unwrap().midi_uart.read() {
+        match MIDI_UART.lock().as_mut().unwrap().read() {
             Ok(0xFE) => {
                 // The 'Active Sensing' keep-alive byte
             }
@@ -856,7 +1904,7 @@ fn midi_term<'a>(_menu: &Menu, _item: &Item, _args: &[&str], _context: &mut Menu
                 // Do nothing
             }
         }
-        match GLOBAL_CONTEXT.lock().as_mut().unwrap().input_read() {
+        match CONSOLE_INPUT.lock().as_mut().unwrap().input_read() {
             Some(Input::Cp850(17)) => {
                 // User pressed Ctrl-Q
                 break;
@@ -906,17 +1954,16 @@ fn midi_play<'a>(_menu: &Menu, item: &Item, args: &[&str], _context: &mut MenuCo
         },
     ];
     loop {
-        let byte = GLOBAL_CONTEXT.lock().as_mut().unwrap().midi_uart.read();
+        crate::watchdog::feed();
+        let byte = MIDI_UART.lock().as_mut().unwrap().read();
         match byte {
             Ok(0xFE) => {
                 // The 'Active Sensing' keep-alive byte
             }
             Ok(0x90) => {
                 // Note On, channel 0
-                let midi_note =
-                    block!(GLOBAL_CONTEXT.lock().as_mut().unwrap().midi_uart.read()).unwrap();
-                let mut velocity =
-                    block!(GLOBAL_CONTEXT.lock().as_mut().unwrap().midi_uart.read()).unwrap();
+                let midi_note = block!(MIDI_UART.lock().as_mut().unwrap().read()).unwrap();
+                let mut velocity = block!(MIDI_UART.lock().as_mut().unwrap().read()).unwrap();
                 // Max it easier to get max volume
                 if velocity >= 0x60 {
                     velocity = 0x75;
@@ -1052,10 +2099,8 @@ fn midi_play<'a>(_menu: &Menu, item: &Item, args: &[&str], _context: &mut MenuCo
             }
             Ok(0x80) => {
                 // Note Off, channel 0
-                let note =
-                    block!(GLOBAL_CONTEXT.lock().as_mut().unwrap().midi_uart.read()).unwrap();
-                let _velocity =
-                    block!(GLOBAL_CONTEXT.lock().as_mut().unwrap().midi_uart.read()).unwrap();
+                let note = block!(MIDI_UART.lock().as_mut().unwrap().read()).unwrap();
+                let _velocity = block!(MIDI_UART.lock().as_mut().unwrap().read()).unwrap();
                 let mut channel = None;
                 for p in playing_notes.iter_mut() {
                     // Find the channel playing that note (if any)
@@ -1083,7 +2128,7 @@ fn midi_play<'a>(_menu: &Menu, item: &Item, args: &[&str], _context: &mut MenuCo
                 // Do nothing
             }
         }
-        match GLOBAL_CONTEXT.lock().as_mut().unwrap().input_read() {
+        match CONSOLE_INPUT.lock().as_mut().unwrap().input_read() {
             Some(Input::Cp850(17)) => {
                 // User pressed Ctrl-Q
                 break;
@@ -1116,7 +2161,7 @@ fn i2c_tx<'a>(_menu: &Menu, item: &Item, args: &[&str], _context: &mut MenuConte
                     "i2c_addr={}, reg_addr={}, value={}",
                     i2c_addr, reg_addr, byte
                 );
-                let result = GLOBAL_CONTEXT.lock().as_mut().unwrap().i2c_bus.write_read(
+                let result = I2C_BUS.lock().as_mut().unwrap().write_read(
                     i2c_addr as u8,
                     &command,
                     &mut read_buffer,
@@ -1155,7 +2200,7 @@ fn i2c_rx<'a>(_menu: &Menu, item: &Item, args: &[&str], _context: &mut MenuConte
                     reg_addr as u8,
                     read_buffer.len()
                 );
-                let result = GLOBAL_CONTEXT.lock().as_mut().unwrap().i2c_bus.write_read(
+                let result = I2C_BUS.lock().as_mut().unwrap().write_read(
                     i2c_addr as u8,
                     &command,
                     &mut read_buffer,
@@ -1189,49 +2234,7 @@ fn charset<'a>(_menu: &Menu, _item: &Item, _args: &[&str], _context: &mut MenuCo
 
 /// Get/set the date
 fn date<'a>(_menu: &Menu, _item: &Item, args: &[&str], _context: &mut MenuContext) {
-    if args.len() > 0 {
-        let f = || -> Result<(), (&'static str, core::num::ParseIntError)> {
-            // Set new date
-            let mut iter = args[0].split(|c| " -T:/".contains(c));
-            let timestamp = monotron_api::Timestamp {
-                year_from_1970: (iter
-                    .next()
-                    .unwrap_or("1970")
-                    .parse::<u32>()
-                    .map_err(|e| ("Bad year", e))?
-                    - 1970) as u8,
-                month: iter
-                    .next()
-                    .unwrap_or("1")
-                    .parse::<u8>()
-                    .map_err(|e| ("Bad month", e))?,
-                days: iter
-                    .next()
-                    .unwrap_or("1")
-                    .parse::<u8>()
-                    .map_err(|e| ("Bad days", e))?,
-                hours: iter
-                    .next()
-                    .unwrap_or("0")
-                    .parse::<u8>()
-                    .map_err(|e| ("Bad hours", e))?,
-                minutes: iter
-                    .next()
-                    .unwrap_or("0")
-                    .parse::<u8>()
-                    .map_err(|e| ("Bad minutes", e))?,
-                seconds: iter
-                    .next()
-                    .unwrap_or("0")
-                    .parse::<u8>()
-                    .map_err(|e| ("Bad seconds", e))?,
-            };
-            crate::TIME_CONTEXT.set_timestamp(timestamp);
-            Ok(())
-        };
-        println!("Setting the time - {:?}", f());
-    }
-    println!("Date: {}", crate::TIME_CONTEXT.get_timestamp());
+    monotron_shell::commands::date(&mut Rom, args);
 }
 
 fn rtc_get<'a>(_menu: &Menu, _item: &Item, _args: &[&str], _context: &mut MenuContext) {
@@ -1243,10 +2246,9 @@ fn rtc_set<'a>(_menu: &Menu, _item: &Item, _args: &[&str], _context: &mut MenuCo
     let timestamp = crate::TIME_CONTEXT.get_timestamp();
     use mcp794xx::Rtcc;
     // Grab the lock
-    let mut lock = GLOBAL_CONTEXT.lock();
+    let mut lock = I2C_BUS.lock();
     // Convert to mutable reference and unwrap the Option<>
-    let ctx = lock.as_mut().unwrap();
-    let bus = crate::I2cBus(&mut ctx.i2c_bus);
+    let bus = crate::I2cBus(lock.as_mut().unwrap());
     let mut rtc = mcp794xx::Mcp794xx::new_mcp7940n(bus);
     let dt = mcp794xx::NaiveDateTime::new(
         mcp794xx::NaiveDate::from_ymd(
@@ -1333,6 +2335,11 @@ fn flip<'a>(_menu: &Menu, _item: &Item, _args: &[&str], _context: &mut MenuConte
     }
 }
 
+/// The number of video frames drawn since boot. Ticks at 60 Hz.
+fn frame_count() -> u32 {
+    unsafe { FRAMEBUFFER.frame() as u32 }
+}
+
 fn parse_u32(s: &str) -> Option<u32> {
     if s.starts_with("0x") {
         // Assume hex
@@ -0,0 +1,144 @@
+//! # Vertical-blank hooks
+//!
+//! An application can register a function for us to call once a frame. We
+//! can't call it from the video interrupt: it has to run unprivileged, in
+//! the application's context, and it mustn't hold up the next line. So
+//! `on_frame` just pends `PendSV`. Next time the application is in thread
+//! mode, `sandbox` borrows the application's stack to call the hook, much
+//! like a Unix signal handler.
+//!
+//! `PendSV` has the same priority as `SVCall`, so it can't run in the middle
+//! of an Api call. The calls which wait (`readc`, `wfvbi` and `sleep_ms`)
+//! return to thread mode whenever `PendSV` is pending, then carry on waiting,
+//! so the hook still runs while the application is blocked in one of them.
+//!
+//! Only one call is ever in progress - if the hook is still running when the
+//! next frame starts, that frame is skipped. If it uses more than
+//! `VBLANK_HOOK_BUDGET_CYCLES` (not counting the video and audio
+//! interrupts, but counting any time spent waiting in Api calls), we stop
+//! the application. A hook which blocks in `readc`, say, is stopped the same
+//! way, as the call returns to thread mode once we've pended `PendSV`.
+
+use crate::cycles::{Bucket, Span};
+use crate::sandbox;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use monotron_api::{VblankHook, VBLANK_HOOK_BUDGET_CYCLES};
+
+// ===========================================================================
+// Types
+// ===========================================================================
+
+/// A registered hook.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Hook {
+    /// The address of the hook function
+    pub(crate) function: u32,
+    /// What to pass it
+    pub(crate) context: u32,
+}
+
+// ===========================================================================
+// Static Variables
+// ===========================================================================
+
+/// The address of the hook function, or zero if there isn't one.
+static FUNCTION: AtomicU32 = AtomicU32::new(0);
+
+/// What to pass the hook function.
+static CONTEXT: AtomicU32 = AtomicU32::new(0);
+
+/// A frame has started, and the hook hasn't been called for it yet.
+static PENDING: AtomicBool = AtomicBool::new(false);
+
+/// The hook is running.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// The hook has used up its budget.
+static OVERRAN: AtomicBool = AtomicBool::new(false);
+
+/// Times the running hook. Only written by `started`, from `PendSV`, while
+/// `ACTIVE` is clear; only read while it's set.
+static mut SPAN: Option<Span> = None;
+
+// ===========================================================================
+// Functions and Impls
+// ===========================================================================
+
+/// Set (or with `None`, clear) the application's hook.
+pub(crate) fn register(hook: Option<VblankHook>, context: *mut core::ffi::c_void) {
+    // We're called from `SVCall`, so `PendSV` can't see this half-done
+    CONTEXT.store(context as u32, Ordering::Relaxed);
+    FUNCTION.store(hook.map_or(0, |f| f as u32), Ordering::Relaxed);
+}
+
+/// Forget the hook. Called when an application exits, however it exits.
+pub(crate) fn reset() {
+    FUNCTION.store(0, Ordering::Relaxed);
+    CONTEXT.store(0, Ordering::Relaxed);
+    PENDING.store(false, Ordering::Relaxed);
+    ACTIVE.store(false, Ordering::Relaxed);
+    OVERRAN.store(false, Ordering::Relaxed);
+}
+
+/// Called from the video interrupt, once a frame.
+pub(crate) fn on_frame() {
+    if !sandbox::is_running() || FUNCTION.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    if ACTIVE.load(Ordering::Acquire) {
+        let used = unsafe { SPAN.as_ref() }.map_or(0, |span| span.elapsed());
+        if used > VBLANK_HOOK_BUDGET_CYCLES {
+            OVERRAN.store(true, Ordering::Relaxed);
+            cortex_m::peripheral::SCB::set_pendsv();
+        }
+    } else {
+        PENDING.store(true, Ordering::Relaxed);
+        cortex_m::peripheral::SCB::set_pendsv();
+    }
+}
+
+/// If the hook should be called now, return it. Called from `PendSV`, once
+/// we know it interrupted the application (which may be part-way through
+/// waiting in a blocking Api call - see the module docs).
+pub(crate) fn take_pending() -> Option<Hook> {
+    if ACTIVE.load(Ordering::Relaxed) || !PENDING.load(Ordering::Relaxed) {
+        return None;
+    }
+    // The shell should never be holding a lock while the application is in
+    // thread mode, but if it is, the hook's Api calls would fail. Leave it
+    // pending for next time.
+    if crate::api_locks_held() {
+        return None;
+    }
+    PENDING.store(false, Ordering::Relaxed);
+    match FUNCTION.load(Ordering::Relaxed) {
+        0 => None,
+        function => Some(Hook {
+            function,
+            context: CONTEXT.load(Ordering::Relaxed),
+        }),
+    }
+}
+
+/// The hook is about to run.
+pub(crate) fn started() {
+    unsafe {
+        SPAN = Some(Span::start());
+    }
+    ACTIVE.store(true, Ordering::Release);
+}
+
+/// The hook has returned.
+pub(crate) fn finished() {
+    ACTIVE.store(false, Ordering::Relaxed);
+    if let Some(span) = unsafe { SPAN.take() } {
+        span.finish(Bucket::VblankHook);
+    }
+}
+
+/// Has the hook used up its budget? Clears the flag.
+pub(crate) fn take_overrun() -> bool {
+    OVERRAN.swap(false, Ordering::Relaxed)
+}
+
+// End of file
@@ -0,0 +1,72 @@
+//! # Watchdog
+//!
+//! Watchdog Timer 0 counts down from `TIMEOUT_CYCLES`. The first time it
+//! runs out it raises its interrupt (which we leave switched off in the
+//! NVIC); if nobody has fed it by the time it runs out again, it resets the
+//! chip. The shell feeds it once a frame, as does anything which waits for
+//! input, so only a real hang reboots us.
+
+use crate::cpu;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+// ===========================================================================
+// Constants
+// ===========================================================================
+
+/// Three seconds at 80 MHz. We reset after two of these.
+const TIMEOUT_CYCLES: u32 = 3 * crate::CLOCK_SPEED;
+
+/// WDTCTL: raise the interrupt when the counter runs out.
+const WDTCTL_INTEN: u32 = 1 << 0;
+
+/// WDTCTL: reset the chip when the counter runs out with the interrupt
+/// still raised.
+const WDTCTL_RESEN: u32 = 1 << 1;
+
+/// WDTTEST: stop counting while the debugger has us halted.
+const WDTTEST_STALL: u32 = 1 << 8;
+
+/// RESC: the last reset was caused by Watchdog Timer 0.
+const RESC_WDT0: u32 = 1 << 3;
+
+// ===========================================================================
+// Static Variables
+// ===========================================================================
+
+/// Set once the watchdog has a clock, so it's safe to touch.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+// ===========================================================================
+// Functions and Impls
+// ===========================================================================
+
+/// Start the watchdog. Its clock must already be turned on. Once started,
+/// it can't be stopped.
+pub(crate) fn init(wdt: &cpu::WATCHDOG0) {
+    wdt.load.write(|w| unsafe { w.bits(TIMEOUT_CYCLES) });
+    wdt.test.write(|w| unsafe { w.bits(WDTTEST_STALL) });
+    wdt.ctl
+        .write(|w| unsafe { w.bits(WDTCTL_INTEN | WDTCTL_RESEN) });
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Tell the watchdog we're still alive. Safe to call from anywhere, at any
+/// time.
+pub(crate) fn feed() {
+    if ENABLED.load(Ordering::Relaxed) {
+        let wdt = unsafe { &*cpu::WATCHDOG0::ptr() };
+        // Any write clears the interrupt and reloads the counter
+        wdt.icr.write(|w| unsafe { w.bits(0) });
+    }
+}
+
+/// Did the watchdog cause the last reset? Clears the reset cause, so only
+/// the first call after boot says yes.
+pub(crate) fn caused_reset() -> bool {
+    let sysctl = unsafe { &*cpu::SYSCTL::ptr() };
+    let resc = sysctl.resc.read().bits();
+    sysctl.resc.write(|w| unsafe { w.bits(resc & !RESC_WDT0) });
+    (resc & RESC_WDT0) != 0
+}
+
+// End of file
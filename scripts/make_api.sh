@@ -1,3 +1,5 @@
 #!/bin/sh
 
-cbindgen ./monotron-api -l c -o ./monotron-api/generated/monotron_api.h -s both
+# Regenerates the checked-in C header, using the same cbindgen as the test
+# which checks it is up to date.
+UPDATE_HEADER=1 cargo test -p monotron-api --target x86_64-unknown-linux-gnu c_header